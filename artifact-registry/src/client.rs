@@ -67,10 +67,7 @@ pub struct Client {
 
 impl Client {
     pub async fn new(config: ClientConfig) -> Result<Self, Error> {
-        let conn_options = ConnectionOptions {
-            timeout: config.timeout,
-            connect_timeout: config.connect_timeout,
-        };
+        let conn_options = ConnectionOptions::new(config.timeout, config.connect_timeout);
         let conn_pool = ConnectionManager::new(
             1,
             config.artifact_registry_endpoint,
@@ -171,10 +171,7 @@ impl ChannelConfig {
         ConnectionManager::new(
             self.num_channels,
             environment,
-            &ConnectionOptions {
-                timeout: self.timeout,
-                connect_timeout: self.connect_timeout,
-            },
+            &ConnectionOptions::new(self.timeout, self.connect_timeout),
         )
         .await
     }
@@ -41,6 +41,9 @@ pub enum Error {
     #[error("invalid authentication token")]
     InvalidToken,
 
+    #[error("token refresh failed: {0}")]
+    TokenRefreshFailed(String),
+
     #[error(transparent)]
     TimeParse(#[from] time::error::Parse),
 
@@ -1,3 +1,6 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
 use async_trait::async_trait;
 
 use crate::error::Error;
@@ -9,6 +12,10 @@ pub struct ReuseTokenSource {
     target: Box<dyn TokenSource>,
     current_token: std::sync::RwLock<Token>,
     guard: tokio::sync::Mutex<()>,
+    // number of finished refresh attempts, used to share a failed refresh with the tasks waiting for it
+    attempts: AtomicU64,
+    // the message of the error of the last refresh attempt, None if it succeeded
+    last_error: Mutex<Option<String>>,
 }
 
 impl ReuseTokenSource {
@@ -17,6 +24,8 @@ impl ReuseTokenSource {
             target,
             current_token: std::sync::RwLock::new(token),
             guard: tokio::sync::Mutex::new(()),
+            attempts: AtomicU64::new(0),
+            last_error: Mutex::new(None),
         }
     }
 }
@@ -29,13 +38,30 @@ impl TokenSource for ReuseTokenSource {
         }
 
         // Only single task can refresh token
+        let attempts = self.attempts.load(Ordering::SeqCst);
         let _locking = self.guard.lock().await;
 
         if let Some(token) = self.r_lock_token() {
             return Ok(token);
         }
+        // The refresh that ran while waiting failed: don't stampede the token endpoint with the same request.
+        if self.attempts.load(Ordering::SeqCst) != attempts {
+            if let Some(e) = self.last_error.lock().unwrap().clone() {
+                return Err(Error::TokenRefreshFailed(e));
+            }
+        }
 
-        let token = self.target.token().await?;
+        let token = self.target.token().await;
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => {
+                // The refreshing task gets the error as is, and the waiting tasks get its message.
+                *self.last_error.lock().unwrap() = Some(e.to_string());
+                return Err(e);
+            }
+        };
+        *self.last_error.lock().unwrap() = None;
         tracing::debug!("token refresh success : expiry={:?}", token.expiry);
         *self.current_token.write().unwrap() = token.clone();
         Ok(token)
@@ -56,6 +82,7 @@ impl ReuseTokenSource {
 #[cfg(test)]
 mod test {
     use std::fmt::Debug;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
 
     use async_trait::async_trait;
@@ -82,6 +109,27 @@ mod test {
         }
     }
 
+    #[derive(Debug)]
+    struct CountingTokenSource {
+        pub calls: Arc<AtomicUsize>,
+        pub fail: bool,
+    }
+    #[async_trait]
+    impl TokenSource for CountingTokenSource {
+        async fn token(&self) -> Result<Token, Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            if self.fail {
+                return Err(Error::InvalidToken);
+            }
+            Ok(Token {
+                access_token: "counting".to_string(),
+                token_type: "counting".to_string(),
+                expiry: Some(OffsetDateTime::now_utc() + time::Duration::seconds(100)),
+            })
+        }
+    }
+
     #[ctor::ctor]
     fn init() {
         let filter = tracing_subscriber::filter::EnvFilter::from_default_env().add_directive(LevelFilter::DEBUG.into());
@@ -125,6 +173,48 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_single_flight_refresh() {
+        for fail in [false, true] {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let ts = Arc::new(ReuseTokenSource::new(
+                Box::new(CountingTokenSource {
+                    calls: calls.clone(),
+                    fail,
+                }),
+                Token {
+                    access_token: "".to_string(),
+                    token_type: "".to_string(),
+                    expiry: None,
+                },
+            ));
+            let tasks: Vec<_> = (0..100)
+                .map(|_| {
+                    let ts = ts.clone();
+                    tokio::spawn(async move { ts.token().await })
+                })
+                .collect();
+            let mut refreshed = 0;
+            for task in tasks {
+                match task.await.unwrap() {
+                    Ok(_) => assert!(!fail),
+                    // The task that refreshed the token gets the error as is.
+                    Err(Error::InvalidToken) => {
+                        assert!(fail);
+                        refreshed += 1;
+                    }
+                    Err(Error::TokenRefreshFailed(e)) => {
+                        assert!(fail);
+                        assert_eq!(e, Error::InvalidToken.to_string());
+                    }
+                    Err(e) => panic!("unexpected error {e:?}"),
+                }
+            }
+            assert_eq!(refreshed, usize::from(fail));
+            assert_eq!(1, calls.load(Ordering::SeqCst), "fail={fail}");
+        }
+    }
+
     async fn run_task(ts: Box<EmptyTokenSource>, first_token: Token) -> Vec<bool> {
         let ts = Arc::new(ReuseTokenSource::new(ts, first_token));
         let mut tasks = Vec::with_capacity(100);
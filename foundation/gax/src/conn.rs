@@ -3,7 +3,7 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use http::header::AUTHORIZATION;
 use http::{HeaderValue, Request};
@@ -85,15 +85,88 @@ where
         //clone() reuses http/2 connection
         self.values[current % self.values.len()].clone()
     }
+
+    fn get(&self, index: usize) -> T {
+        self.values[index % self.values.len()].clone()
+    }
 }
 
+/// ConnectionOptions are the options of the connections of the `ConnectionManager`.
+/// Besides a struct literal, they can be built with `ConnectionOptions::new` or `Default` and the `with_*` methods.
 #[derive(Debug, Clone, Default)]
 pub struct ConnectionOptions {
+    /// The timeout of each request. No timeout if `None`.
     pub timeout: Option<Duration>,
+    /// The timeout of establishing a connection. No timeout if `None`.
     pub connect_timeout: Option<Duration>,
+    /// Keeps retrying to establish the connection with `connect_backoff` until this duration elapses.
+    /// This allows the client to start even if DNS resolution or the network is briefly unavailable.
+    /// If `None`, the connection is attempted only once.
+    pub connect_retry: Option<Duration>,
+    /// The delay between the attempts to establish the connection with `connect_retry`.
+    pub connect_backoff: ConnectBackoff,
+    /// The interval of the HTTP/2 PING frames that keep the connection alive, so that the proxies don't drop
+    /// the connections that look idle. The PINGs are not sent if `None`.
+    pub keep_alive_interval: Option<Duration>,
+    /// The connection is closed if a PING is not acknowledged within this duration. 20 seconds if `None`.
+    pub keep_alive_timeout: Option<Duration>,
+    /// Sends the PINGs even while the connection has no open stream.
+    pub keep_alive_while_idle: bool,
+}
+
+/// ConnectBackoff is the delay between the attempts to establish a connection with `ConnectionOptions::connect_retry`.
+#[derive(Debug, Clone)]
+pub struct ConnectBackoff {
+    /// The delay after the first failure, doubled on each consecutive failure.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ConnectBackoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            initial_delay,
+            max_delay,
+        }
+    }
+}
+
+impl Default for ConnectBackoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(5))
+    }
 }
 
 impl ConnectionOptions {
+    pub fn new(timeout: Option<Duration>, connect_timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            connect_timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_connect_retry(mut self, v: Duration) -> Self {
+        self.connect_retry = Some(v);
+        self
+    }
+
+    pub fn with_connect_backoff(mut self, v: ConnectBackoff) -> Self {
+        self.connect_backoff = v;
+        self
+    }
+
+    pub fn with_keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keep_alive_interval = Some(interval);
+        self.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_keep_alive_while_idle(mut self, v: bool) -> Self {
+        self.keep_alive_while_idle = v;
+        self
+    }
+
     fn apply(&self, mut endpoint: Endpoint) -> Endpoint {
         endpoint = match self.timeout {
             Some(t) => endpoint.timeout(t),
@@ -103,8 +176,23 @@ impl ConnectionOptions {
             Some(t) => endpoint.connect_timeout(t),
             None => endpoint,
         };
+        if let Some(interval) = self.keep_alive_interval {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_while_idle(self.keep_alive_while_idle);
+            if let Some(t) = self.keep_alive_timeout {
+                endpoint = endpoint.keep_alive_timeout(t);
+            }
+        }
         endpoint
     }
+
+    fn connect_delays(&self) -> impl Iterator<Item = Duration> {
+        let max_delay = self.connect_backoff.max_delay;
+        std::iter::successors(Some(self.connect_backoff.initial_delay.min(max_delay)), move |v| {
+            Some(v.saturating_mul(2).min(max_delay))
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -150,7 +238,7 @@ impl<'a> ConnectionManager {
             let endpoint = TonicChannel::from_static(audience).tls_config(tls_config.clone())?;
             let endpoint = conn_options.apply(endpoint);
 
-            let con = Self::connect(endpoint).await?;
+            let con = Self::connect(endpoint, conn_options).await?;
             // use GCP token per call
             let auth_layer = Some(AsyncFilterLayer::new(AsyncAuthInterceptor::new(Arc::clone(&ts))));
             let auth_con = ServiceBuilder::new().option_layer(auth_layer).service(con);
@@ -168,7 +256,7 @@ impl<'a> ConnectionManager {
             .map_err(|_| Error::InvalidEmulatorHOST(host.to_string()))?;
         let endpoint = conn_options.apply(endpoint);
 
-        let con = Self::connect(endpoint).await?;
+        let con = Self::connect(endpoint, conn_options).await?;
         conns.push(
             ServiceBuilder::new()
                 .option_layer::<AsyncFilterLayer<AsyncAuthInterceptor>>(None)
@@ -177,9 +265,28 @@ impl<'a> ConnectionManager {
         Ok(conns)
     }
 
-    async fn connect(endpoint: Endpoint) -> Result<TonicChannel, tonic::transport::Error> {
-        let channel = endpoint.connect().await?;
-        Ok(channel)
+    async fn connect(
+        endpoint: Endpoint,
+        conn_options: &'a ConnectionOptions,
+    ) -> Result<TonicChannel, tonic::transport::Error> {
+        let deadline = match conn_options.connect_retry {
+            Some(d) => Instant::now() + d,
+            None => return endpoint.connect().await,
+        };
+        let mut backoff = conn_options.connect_delays();
+        loop {
+            let err = match endpoint.connect().await {
+                Ok(channel) => return Ok(channel),
+                Err(e) => e,
+            };
+            let now = Instant::now();
+            let delay = match backoff.next() {
+                Some(delay) if now < deadline => delay.min(deadline - now),
+                _ => return Err(err),
+            };
+            tracing::warn!("failed to connect to {}, retrying in {:?}: {:?}", endpoint.uri(), delay, err);
+            tokio::time::sleep(delay).await;
+        }
     }
 
     pub fn num(&self) -> usize {
@@ -189,14 +296,20 @@ impl<'a> ConnectionManager {
     pub fn conn(&self) -> Channel {
         self.inner.next()
     }
+
+    /// conn_at returns the channel at the index (modulo the pool size) instead of the next one.
+    pub fn conn_at(&self, index: usize) -> Channel {
+        self.inner.get(index)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::collections::HashSet;
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
 
-    use crate::conn::AtomicRing;
+    use crate::conn::{AtomicRing, ConnectBackoff, ConnectionManager, ConnectionOptions, Environment};
 
     #[test]
     fn test_atomic_ring() {
@@ -217,4 +330,33 @@ mod test {
         assert!(!values.insert(cm.next()));
         assert_eq!(3, cm.index.load(Ordering::SeqCst));
     }
+
+    #[test]
+    fn test_connect_backoff() {
+        let options = ConnectionOptions::default();
+        let delays: Vec<Duration> = options.connect_delays().take(8).collect();
+        assert_eq!(delays[0], Duration::from_millis(100));
+        assert_eq!(delays[1], Duration::from_millis(200));
+        assert_eq!(delays[4], Duration::from_millis(1600));
+        assert_eq!(delays[7], Duration::from_secs(5));
+
+        let options = ConnectionOptions::default()
+            .with_connect_backoff(ConnectBackoff::new(Duration::from_secs(1), Duration::from_secs(3)));
+        let delays: Vec<Duration> = options.connect_delays().take(3).collect();
+        assert_eq!(
+            delays,
+            vec![Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_retry() {
+        // nothing listens on the discard port
+        let environment = Environment::Emulator("127.0.0.1:9".to_string());
+        let options = ConnectionOptions::default().with_connect_retry(Duration::from_millis(500));
+        let start = Instant::now();
+        let result = ConnectionManager::new(1, "localhost", "", &environment, &options).await;
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
 }
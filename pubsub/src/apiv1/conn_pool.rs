@@ -1,3 +1,5 @@
+use std::env::var;
+
 use google_cloud_gax::conn::{Channel, Environment};
 use google_cloud_gax::conn::{ConnectionManager as GRPCConnectionManager, ConnectionOptions, Error};
 
@@ -7,6 +9,16 @@ pub const SCOPES: [&str; 2] = [
     "https://www.googleapis.com/auth/cloud-platform",
     "https://www.googleapis.com/auth/pubsub",
 ];
+/// EMULATOR_HOST is the environment variable of the address of the Pub/Sub emulator.
+pub const EMULATOR_HOST: &str = "PUBSUB_EMULATOR_HOST";
+
+/// emulator_environment returns the environment of the emulator if `PUBSUB_EMULATOR_HOST` is set and not empty.
+pub fn emulator_environment() -> Option<Environment> {
+    var(EMULATOR_HOST)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(Environment::Emulator)
+}
 
 #[derive(Debug)]
 pub struct ConnectionManager {
@@ -25,6 +37,21 @@ impl ConnectionManager {
         })
     }
 
+    /// from_env connects to the emulator if `PUBSUB_EMULATOR_HOST` is set, otherwise to the domain with the
+    /// fallback environment. The token source of the fallback is not used when the emulator is used, so
+    /// the credentials are never looked up and the requests never reach the production by mistake.
+    pub async fn from_env(
+        pool_size: usize,
+        domain: &str,
+        fallback: &Environment,
+        conn_options: &ConnectionOptions,
+    ) -> Result<Self, Error> {
+        match emulator_environment() {
+            Some(environment) => Self::new(pool_size, domain, &environment, conn_options).await,
+            None => Self::new(pool_size, domain, fallback, conn_options).await,
+        }
+    }
+
     pub fn num(&self) -> usize {
         self.inner.num()
     }
@@ -32,4 +59,35 @@ impl ConnectionManager {
     pub fn conn(&self) -> Channel {
         self.inner.conn()
     }
+
+    pub fn conn_at(&self, index: usize) -> Channel {
+        self.inner.conn_at(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use google_cloud_gax::conn::{ConnectionOptions, Environment};
+    use token_source::NoopTokenSourceProvider;
+
+    use crate::apiv1::conn_pool::{emulator_environment, ConnectionManager, EMULATOR_HOST, PUBSUB};
+
+    #[tokio::test]
+    async fn test_from_env() {
+        let host = std::env::var(EMULATOR_HOST).unwrap_or_default();
+        match emulator_environment() {
+            Some(Environment::Emulator(v)) => assert_eq!(v, host),
+            Some(Environment::GoogleCloud(_)) => unreachable!(),
+            None => assert!(host.is_empty()),
+        }
+        if host.is_empty() {
+            return;
+        }
+        // The fallback is ignored while the emulator is set.
+        let fallback = Environment::GoogleCloud(Box::new(NoopTokenSourceProvider {}));
+        let cm = ConnectionManager::from_env(1, PUBSUB, &fallback, &ConnectionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(cm.num(), 1);
+    }
 }
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use google_cloud_gax::grpc::Code;
 use google_cloud_gax::retry::RetrySetting;
 
@@ -15,3 +17,90 @@ pub fn default_retry_setting() -> RetrySetting {
     setting.codes.push(Code::ResourceExhausted);
     setting
 }
+
+/// PubsubRetry builds a `RetrySetting` starting from `default_retry_setting`, so that one field can be
+/// changed without spelling out the others.
+///
+/// ```
+/// use google_cloud_gax::grpc::Code;
+/// use google_cloud_pubsub::apiv1::PubsubRetry;
+///
+/// let setting = PubsubRetry::new().with_codes([Code::Unavailable]).with_max_attempts(5).build();
+/// assert_eq!(setting.take, 4);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PubsubRetry(RetrySetting);
+
+impl PubsubRetry {
+    pub fn new() -> Self {
+        Self(default_retry_setting())
+    }
+
+    /// with_codes replaces the codes of the errors to retry.
+    pub fn with_codes(mut self, codes: impl IntoIterator<Item = Code>) -> Self {
+        self.0.codes = codes.into_iter().collect();
+        self
+    }
+
+    /// with_max_attempts sets the number of the attempts including the first one, 1 not to retry.
+    pub fn with_max_attempts(mut self, attempts: usize) -> Self {
+        self.0.take = attempts.saturating_sub(1);
+        self
+    }
+
+    /// with_backoff sets the delay before the first retry and the max delay between the retries.
+    /// The delay in milliseconds is raised to the power of the number of the retry.
+    pub fn with_backoff(mut self, initial: Duration, max_delay: Option<Duration>) -> Self {
+        self.0.from_millis = initial.as_millis() as u64;
+        self.0.max_delay = max_delay;
+        self
+    }
+
+    pub fn build(self) -> RetrySetting {
+        self.0
+    }
+}
+
+impl Default for PubsubRetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<PubsubRetry> for RetrySetting {
+    fn from(v: PubsubRetry) -> Self {
+        v.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use google_cloud_gax::grpc::Code;
+
+    use crate::apiv1::{default_retry_setting, PubsubRetry};
+
+    #[test]
+    fn test_pubsub_retry() {
+        let setting = PubsubRetry::new().build();
+        let default = default_retry_setting();
+        assert_eq!(setting.codes, default.codes);
+        assert_eq!(setting.take, default.take);
+
+        // The other fields keep the defaults.
+        let setting = PubsubRetry::new().with_max_attempts(1).build();
+        assert_eq!(setting.take, 0);
+        assert_eq!(setting.codes, default.codes);
+        assert_eq!(setting.from_millis, default.from_millis);
+
+        let setting = PubsubRetry::new()
+            .with_codes([Code::Unavailable])
+            .with_backoff(Duration::from_millis(50), None)
+            .build();
+        assert_eq!(setting.codes, vec![Code::Unavailable]);
+        assert_eq!(setting.from_millis, 50);
+        assert_eq!(setting.max_delay, None);
+        assert_eq!(setting.take, default.take);
+    }
+}
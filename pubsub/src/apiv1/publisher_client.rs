@@ -27,6 +27,11 @@ impl PublisherClient {
         PublisherClient { cm: Arc::new(cm) }
     }
 
+    /// connection_manager returns the connections to share with the other clients.
+    pub(crate) fn connection_manager(&self) -> Arc<ConnectionManager> {
+        self.cm.clone()
+    }
+
     #[inline]
     fn client(&self) -> InternalPublisherClient<Channel> {
         InternalPublisherClient::new(self.cm.conn())
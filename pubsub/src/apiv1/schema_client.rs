@@ -20,11 +20,16 @@ pub(crate) struct SchemaClient {
 
 #[allow(dead_code)]
 impl SchemaClient {
-    /// create new publisher client
+    /// create new schema client
     pub fn new(cm: ConnectionManager) -> SchemaClient {
         SchemaClient { cm: Arc::new(cm) }
     }
 
+    /// create new schema client sharing the connections with another client
+    pub fn shared(cm: Arc<ConnectionManager>) -> SchemaClient {
+        SchemaClient { cm }
+    }
+
     fn client(&self) -> SchemaServiceClient<Channel> {
         SchemaServiceClient::new(self.cm.conn())
     }
@@ -102,7 +107,7 @@ impl SchemaClient {
         invoke(retry, action).await
     }
 
-    /// validate_schema deletes a schema.
+    /// validate_schema validates a schema.
     pub async fn validate_schema(
         &self,
         req: ValidateSchemaRequest,
@@ -1,8 +1,13 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tokio_util::sync::{CancellationToken, DropGuard};
 
 use google_cloud_gax::conn::Channel;
 use google_cloud_gax::create_request;
-use google_cloud_gax::grpc::Status;
+use google_cloud_gax::grpc::{Code, Status};
 use google_cloud_gax::grpc::{IntoStreamingRequest, Response, Streaming};
 use google_cloud_gax::retry::{invoke, MapErr, RetrySetting};
 use google_cloud_googleapis::pubsub::v1::subscriber_client::SubscriberClient as InternalSubscriberClient;
@@ -16,6 +21,7 @@ use google_cloud_googleapis::pubsub::v1::{
 
 use crate::apiv1::conn_pool::ConnectionManager;
 use crate::apiv1::PUBSUB_MESSAGE_LIMIT;
+use crate::subscriber::SubscriberConfig;
 
 pub(crate) fn create_empty_streaming_pull_request() -> StreamingPullRequest {
     StreamingPullRequest {
@@ -30,19 +36,106 @@ pub(crate) fn create_empty_streaming_pull_request() -> StreamingPullRequest {
     }
 }
 
+/// ChannelSelection decides which channel of the streaming pull connection pool a new stream uses.
+/// Long-lived streams are pinned to their channel, so the selection matters when many streams are opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelSelection {
+    /// Use the channels in turn.
+    #[default]
+    RoundRobin,
+    /// Use the channel with the fewest open streams.
+    LeastLoaded,
+}
+
+/// StreamingChannel is a channel of the streaming pull connection pool reserved by a stream.
+/// The reservation is released on drop.
+#[derive(Debug)]
+pub(crate) struct StreamingChannel {
+    index: usize,
+    open_streams: Arc<Vec<AtomicUsize>>,
+}
+
+impl Drop for StreamingChannel {
+    fn drop(&mut self) {
+        self.open_streams[self.index].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// PingSender keeps the stream opened by `SubscriberClient::open_streaming_pull` alive.
+/// The server closes a stream on which nothing is sent for a while, so ping it periodically,
+/// e.g. every `SubscriberConfig::ping_interval`. Dropping it ends the request stream, and the server closes the stream.
+#[derive(Debug)]
+pub struct PingSender {
+    sender: async_channel::Sender<bool>,
+    _closed: DropGuard,
+    _channel: Option<StreamingChannel>,
+}
+
+impl PingSender {
+    /// ping sends an empty request on the stream. Returns false if the request stream has ended.
+    pub async fn ping(&self) -> bool {
+        self.sender.send(true).await.is_ok()
+    }
+}
+
+/// next_request waits for the next ping or control request on the stream.
+async fn next_request(
+    ping_receiver: &async_channel::Receiver<bool>,
+    control: Option<&async_channel::Receiver<StreamingPullRequest>>,
+) -> Option<StreamingPullRequest> {
+    match control {
+        Some(control) if !control.is_closed() => tokio::select! {
+            r = ping_receiver.recv() => r.ok().map(|_| create_empty_streaming_pull_request()),
+            Ok(r) = control.recv() => Some(r),
+        },
+        _ => ping_receiver
+            .recv()
+            .await
+            .ok()
+            .map(|_| create_empty_streaming_pull_request()),
+    }
+}
+
+fn least_loaded(open_streams: &[AtomicUsize]) -> usize {
+    open_streams
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, v)| v.load(Ordering::SeqCst))
+        .map_or(0, |(i, _)| i)
+}
+
 #[derive(Clone, Debug)]
 pub struct SubscriberClient {
     cm: Arc<ConnectionManager>,
     streaming_pull_cm: Arc<ConnectionManager>,
+    next_streaming_channel: Arc<AtomicUsize>,
+    open_streams: Arc<Vec<AtomicUsize>>,
+    /// When the streams that failed to get a token reconnect, see `token_retry_at`.
+    token_retry_at: Arc<Mutex<Option<Instant>>>,
 }
 
 #[allow(dead_code)]
 impl SubscriberClient {
     /// create new Subscriber client
     pub fn new(cm: ConnectionManager, streaming_pull_cm: ConnectionManager) -> SubscriberClient {
+        let open_streams = (0..streaming_pull_cm.num()).map(|_| AtomicUsize::new(0)).collect();
         SubscriberClient {
             cm: Arc::new(cm),
             streaming_pull_cm: Arc::new(streaming_pull_cm),
+            next_streaming_channel: Arc::new(AtomicUsize::new(0)),
+            open_streams: Arc::new(open_streams),
+            token_retry_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// token_retry_at returns when a stream that failed to get a token reconnects. The first stream to fail schedules
+    /// the reconnect after `delay`, and the streams failing until then reconnect at the same time, so that all the
+    /// streams of the client wait for a single token refresh instead of each calling the token endpoint.
+    pub(crate) fn token_retry_at(&self, now: Instant, delay: Duration) -> Instant {
+        let mut retry_at = self.token_retry_at.lock().unwrap();
+        match *retry_at {
+            Some(v) if v > now => v,
+            _ => *retry_at.insert(now + delay),
         }
     }
 
@@ -54,16 +147,40 @@ impl SubscriberClient {
     }
 
     #[inline]
-    fn client_for_streaming_pull(&self) -> InternalSubscriberClient<Channel> {
-        InternalSubscriberClient::new(self.streaming_pull_cm.conn())
+    fn client_for_streaming_pull(&self, channel: Option<&StreamingChannel>) -> InternalSubscriberClient<Channel> {
+        let conn = match channel {
+            Some(channel) => self.streaming_pull_cm.conn_at(channel.index),
+            None => self.streaming_pull_cm.conn(),
+        };
+        InternalSubscriberClient::new(conn)
             .max_decoding_message_size(PUBSUB_MESSAGE_LIMIT)
             .max_encoding_message_size(PUBSUB_MESSAGE_LIMIT)
     }
 
-    pub(crate) fn streaming_pool_size(&self) -> usize {
+    /// streaming_pool_size returns the number of channels the streams are spread over by `ChannelSelection`.
+    pub fn streaming_pool_size(&self) -> usize {
         self.streaming_pull_cm.num()
     }
 
+    /// reserve_streaming_channel chooses the channel for a new stream.
+    /// Returns None when the pool is empty, in which case the stream uses the default channel without a reservation.
+    pub(crate) fn reserve_streaming_channel(&self, selection: ChannelSelection) -> Option<StreamingChannel> {
+        if self.open_streams.is_empty() {
+            return None;
+        }
+        let index = match selection {
+            ChannelSelection::RoundRobin => {
+                self.next_streaming_channel.fetch_add(1, Ordering::SeqCst) % self.open_streams.len()
+            }
+            ChannelSelection::LeastLoaded => least_loaded(&self.open_streams),
+        };
+        self.open_streams[index].fetch_add(1, Ordering::SeqCst);
+        Some(StreamingChannel {
+            index,
+            open_streams: self.open_streams.clone(),
+        })
+    }
+
     /// create_subscription creates a subscription to a given topic. See the [resource name rules]
     /// (https://cloud.google.com/pubsub/docs/admin#resource_names (at https://cloud.google.com/pubsub/docs/admin#resource_names)).
     /// If the subscription already exists, returns ALREADY_EXISTS.
@@ -231,6 +348,21 @@ impl SubscriberClient {
         invoke(retry, action).await
     }
 
+    /// pull_with_timeout sends a single Pull request with `timeout` as the deadline of the RPC.
+    /// The server stops waiting for messages at the deadline, so no message is leased to a request
+    /// abandoned by the client. The request is not retried.
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    pub(crate) async fn pull_with_timeout(
+        &self,
+        req: PullRequest,
+        timeout: Duration,
+    ) -> Result<Response<PullResponse>, Status> {
+        let mut client = self.client();
+        let mut request = create_request(format!("subscription={}", req.subscription), req);
+        request.set_timeout(timeout);
+        client.pull(request).await
+    }
+
     /// streaming_pull establishes a stream with the server, which sends messages down to the
     /// client. The client streams acknowledgements and ack deadline modifications
     /// back to the server. The server will close the stream and return the status
@@ -245,17 +377,85 @@ impl SubscriberClient {
         ping_receiver: async_channel::Receiver<bool>,
         retry: Option<RetrySetting>,
     ) -> Result<Response<Streaming<StreamingPullResponse>>, Status> {
+        self.streaming_pull_on(None, req, ping_receiver, None, CancellationToken::new(), retry)
+            .await
+    }
+
+    /// open_streaming_pull establishes a stream of the subscription with the flow control, the channel selection
+    /// and the retry setting of the config, for a custom pull loop instead of `Subscription::subscribe`.
+    /// The messages received from the stream are acked with `acknowledge` and kept alive with the returned `PingSender`.
+    /// Nothing else of the config applies: the stream is not re-established when it fails.
+    pub async fn open_streaming_pull(
+        &self,
+        subscription: &str,
+        config: &SubscriberConfig,
+    ) -> Result<(Streaming<StreamingPullResponse>, PingSender), Status> {
+        let mut req = create_empty_streaming_pull_request();
+        req.subscription = subscription.to_string();
+        req.stream_ack_deadline_seconds = config.stream_ack_deadline_seconds;
+        req.max_outstanding_messages = config.max_outstanding_messages;
+        req.max_outstanding_bytes = config.max_outstanding_bytes;
+        let channel = self.reserve_streaming_channel(config.channel_selection);
+        let (sender, receiver) = async_channel::unbounded();
+        let closed = CancellationToken::new();
+        let stream = self
+            .streaming_pull_on(
+                channel.as_ref(),
+                req,
+                receiver,
+                None,
+                closed.clone(),
+                config.retry_setting.clone(),
+            )
+            .await?
+            .into_inner();
+        let ping_sender = PingSender {
+            sender,
+            _closed: closed.drop_guard(),
+            _channel: channel,
+        };
+        Ok((stream, ping_sender))
+    }
+
+    /// streaming_pull_on establishes a stream on the reserved channel.
+    /// The requests received from `control` are sent on the stream in addition to the pings.
+    /// The request stream ends when `closed` is cancelled. Dropping the response stream alone leaves the
+    /// stream open on the server, which keeps delivering messages to it.
+    pub(crate) async fn streaming_pull_on(
+        &self,
+        channel: Option<&StreamingChannel>,
+        req: StreamingPullRequest,
+        ping_receiver: async_channel::Receiver<bool>,
+        control: Option<async_channel::Receiver<StreamingPullRequest>>,
+        closed: CancellationToken,
+        retry: Option<RetrySetting>,
+    ) -> Result<Response<Streaming<StreamingPullResponse>>, Status> {
+        // PERMISSION_DENIED is never retried even if it is included in the retry codes,
+        // because it only recovers after the IAM policy of the subscription is fixed.
+        let retry = retry.map(|mut retry| {
+            retry.codes.retain(|code| *code != Code::PermissionDenied);
+            retry
+        });
         let action = || async {
-            let mut client = self.client_for_streaming_pull();
+            let mut client = self.client_for_streaming_pull(channel);
             let base_req = req.clone();
             let rx = ping_receiver.clone();
+            let control = control.clone();
+            let closed = closed.clone();
             let request = Box::pin(async_stream::stream! {
                 yield base_req.clone();
 
-                // ping message.
-                // must be empty request
-                while let Ok(_r) = rx.recv().await {
-                   yield create_empty_streaming_pull_request();
+                // ping message must be empty request
+                loop {
+                    let r = tokio::select! {
+                        biased;
+                        _ = closed.cancelled() => break,
+                        r = next_request(&rx, control.as_ref()) => r,
+                    };
+                    match r {
+                        Some(r) => yield r,
+                        None => break,
+                    }
                 }
             });
             let mut v = request.into_streaming_request();
@@ -433,3 +633,76 @@ impl SubscriberClient {
         invoke(retry, action).await
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use google_cloud_gax::conn::{ConnectionOptions, Environment};
+    use token_source::{TokenSource, TokenSourceProvider};
+    use tokio::time::Instant;
+
+    use crate::apiv1::conn_pool::{ConnectionManager, PUBSUB};
+    use crate::apiv1::subscriber_client::{least_loaded, ChannelSelection, SubscriberClient};
+
+    #[test]
+    fn test_least_loaded() {
+        let open_streams: Vec<AtomicUsize> = [2, 1, 0, 1].into_iter().map(AtomicUsize::new).collect();
+        assert_eq!(2, least_loaded(&open_streams));
+        let open_streams: Vec<AtomicUsize> = [1, 1].into_iter().map(AtomicUsize::new).collect();
+        assert_eq!(0, least_loaded(&open_streams));
+        assert_eq!(0, least_loaded(&[]));
+    }
+
+    #[derive(Debug)]
+    struct StaticTokenSource;
+
+    #[async_trait::async_trait]
+    impl TokenSource for StaticTokenSource {
+        async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            Ok("Bearer static".to_string())
+        }
+    }
+
+    #[derive(Debug)]
+    struct StaticTokenSourceProvider;
+
+    impl TokenSourceProvider for StaticTokenSourceProvider {
+        fn token_source(&self) -> Arc<dyn TokenSource> {
+            Arc::new(StaticTokenSource)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_retry_at() {
+        let client = empty_client().await;
+        let now = Instant::now();
+        let first = client.token_retry_at(now, Duration::from_secs(1));
+        assert_eq!(first, now + Duration::from_secs(1));
+        // The streams failing until the reconnect share it.
+        let second = client.token_retry_at(now + Duration::from_millis(500), Duration::from_secs(2));
+        assert_eq!(first, second);
+        let third = client.clone().token_retry_at(first, Duration::from_secs(2));
+        assert_eq!(third, first + Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_reserve_streaming_channel_empty_pool() {
+        let client = empty_client().await;
+        assert!(client.reserve_streaming_channel(ChannelSelection::RoundRobin).is_none());
+        assert!(client
+            .reserve_streaming_channel(ChannelSelection::LeastLoaded)
+            .is_none());
+    }
+
+    /// empty_client returns a client without any channel, which doesn't connect to the server.
+    pub(crate) async fn empty_client() -> SubscriberClient {
+        let environment = Environment::GoogleCloud(Box::new(StaticTokenSourceProvider));
+        let options = ConnectionOptions::default();
+        let cm = ConnectionManager::new(0, PUBSUB, &environment, &options).await.unwrap();
+        let streaming_pull_cm = ConnectionManager::new(0, PUBSUB, &environment, &options).await.unwrap();
+        SubscriberClient::new(cm, streaming_pull_cm)
+    }
+}
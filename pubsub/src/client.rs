@@ -1,17 +1,22 @@
-use std::env::var;
+use std::time::Duration;
 
 use google_cloud_gax::conn::{ConnectionOptions, Environment};
-use google_cloud_gax::grpc::Status;
+use google_cloud_gax::grpc::{Code, Status};
 use google_cloud_gax::retry::RetrySetting;
+use google_cloud_googleapis::pubsub::v1::validate_message_request::SchemaSpec;
 use google_cloud_googleapis::pubsub::v1::{
-    DetachSubscriptionRequest, ListSnapshotsRequest, ListSubscriptionsRequest, ListTopicsRequest, Snapshot,
+    DetachSubscriptionRequest, Encoding, GetSchemaRequest, ListSnapshotsRequest, ListSubscriptionsRequest,
+    ListTopicsRequest, PublishRequest, PubsubMessage, Schema, SchemaView, Snapshot, ValidateMessageRequest,
 };
-use token_source::NoopTokenSourceProvider;
+use token_source::{NoopTokenSourceProvider, TokenSourceProvider};
 
-use crate::apiv1::conn_pool::{ConnectionManager, PUBSUB};
+use crate::apiv1::conn_pool::{emulator_environment, ConnectionManager, PUBSUB};
 use crate::apiv1::publisher_client::PublisherClient;
+use crate::apiv1::schema_client::SchemaClient;
 use crate::apiv1::subscriber_client::SubscriberClient;
-use crate::subscription::{Subscription, SubscriptionConfig};
+use crate::error::PubSubError;
+use crate::subscriber::ReceivedMessage;
+use crate::subscription::{Subscription, SubscriptionConfig, SubscriptionName};
 use crate::topic::{Topic, TopicConfig};
 
 #[derive(Debug)]
@@ -31,14 +36,11 @@ pub struct ClientConfig {
 /// ClientConfigs created by default will prefer to use `PUBSUB_EMULATOR_HOST`
 impl Default for ClientConfig {
     fn default() -> Self {
-        let emulator = var("PUBSUB_EMULATOR_HOST").ok();
+        let emulator = emulator_environment();
         let default_project_id = emulator.as_ref().map(|_| "local-project".to_string());
         Self {
             pool_size: Some(4),
-            environment: match emulator {
-                Some(v) => Environment::Emulator(v),
-                None => Environment::GoogleCloud(Box::new(NoopTokenSourceProvider {})),
-            },
+            environment: emulator.unwrap_or_else(|| Environment::GoogleCloud(Box::new(NoopTokenSourceProvider {}))),
             project_id: default_project_id,
             endpoint: PUBSUB.to_string(),
             connection_option: ConnectionOptions::default(),
@@ -46,6 +48,30 @@ impl Default for ClientConfig {
     }
 }
 
+impl ClientConfig {
+    /// with_token_source_provider authenticates the client with the token source instead of the ambient credentials.
+    /// In a multi-tenant service, create a client for each identity with its own token source so that
+    /// a single process can consume subscriptions across multiple projects or identities.
+    /// `token_source()` should return the same shared instance on every call so that the streams reconnecting
+    /// after a token expiry wait for a single refresh instead of each calling the token endpoint.
+    /// The token source is ignored when the emulator is used.
+    pub fn with_token_source_provider(mut self, provider: Box<dyn TokenSourceProvider>) -> Self {
+        if let Environment::GoogleCloud(_) = self.environment {
+            self.environment = Environment::GoogleCloud(provider)
+        }
+        self
+    }
+
+    /// with_keep_alive sends HTTP/2 PINGs on the gRPC connections at the interval and closes the connections
+    /// not acknowledging them within the timeout, so that the proxies don't drop the streaming pulls that
+    /// receive nothing for a while. The empty requests sent at `SubscriberConfig::ping_interval` only keep
+    /// the stream open on the server.
+    pub fn with_keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.connection_option = self.connection_option.with_keep_alive(interval, timeout);
+        self
+    }
+}
+
 #[cfg(feature = "auth")]
 pub use google_cloud_auth;
 
@@ -100,6 +126,7 @@ pub struct Client {
     project_id: String,
     pubc: PublisherClient,
     subc: SubscriberClient,
+    schc: SchemaClient,
 }
 
 impl Client {
@@ -134,6 +161,7 @@ impl Client {
         );
         Ok(Self {
             project_id: config.project_id.ok_or(Error::ProjectIdNotFound)?,
+            schc: SchemaClient::shared(pubc.connection_manager()),
             pubc,
             subc,
         })
@@ -172,6 +200,24 @@ impl Client {
             .map(|_v| subscription)
     }
 
+    /// ensure_subscription creates the subscription on a topic like `create_subscription` if it doesn't exist,
+    /// such as before starting to receive the messages. The cfg, e.g. the expiration policy, the ack deadline and
+    /// the dead letter policy, is used only to create it: the configuration of an existing subscription is kept as is.
+    /// The filter of an existing subscription must match `cfg.filter`. See `Subscription::ensure`.
+    pub async fn ensure_subscription(
+        &self,
+        id: &str,
+        topic_id: &str,
+        cfg: SubscriptionConfig,
+        retry: Option<RetrySetting>,
+    ) -> Result<Subscription, Status> {
+        let subscription = self.subscription(id);
+        subscription
+            .ensure(self.fully_qualified_topic_name(topic_id).as_str(), cfg, retry)
+            .await
+            .map(|_v| subscription)
+    }
+
     /// subscriptions returns an iterator which returns all of the subscriptions for the client's project.
     pub async fn get_subscriptions(&self, retry: Option<RetrySetting>) -> Result<Vec<Subscription>, Status> {
         let req = ListSubscriptionsRequest {
@@ -191,6 +237,19 @@ impl Client {
         Subscription::new(self.fully_qualified_subscription_name(id), self.subc.clone())
     }
 
+    /// try_subscription creates a reference to a subscription like `subscription`, but fails with
+    /// `PubSubError::InvalidSubscriptionName` if the id or the fully qualified name is malformed,
+    /// instead of failing the requests to the subscription.
+    pub fn try_subscription(&self, id: &str) -> Result<Subscription, PubSubError> {
+        let name = SubscriptionName::parse(&self.fully_qualified_subscription_name(id))?;
+        Ok(self.subscription_by_name(&name))
+    }
+
+    /// subscription_by_name creates a reference to the subscription of the validated name.
+    pub fn subscription_by_name(&self, name: &SubscriptionName) -> Subscription {
+        Subscription::new(name.to_string(), self.subc.clone())
+    }
+
     /// detach_subscription detaches a subscription from its topic. All messages
     /// retained in the subscription are dropped. Subsequent `Pull` and `StreamingPull`
     /// requests will return FAILED_PRECONDITION. If the subscription is a push
@@ -202,6 +261,80 @@ impl Client {
         self.pubc.detach_subscription(req, retry).await.map(|_v| ())
     }
 
+    /// move_to_dead_letter publishes a copy of the message to the topic and acks the original message.
+    /// It returns the message_id of the published copy, even if the ack fails afterwards: the original message is then
+    /// redelivered, so a message may be moved more than once.
+    ///
+    /// If `topic` is `None`, the dead letter topic configured in the dead letter policy of the subscription
+    /// the message was received from is used, so the move stays consistent with the subscription's own configuration.
+    /// If the subscription has no dead letter policy, the topic must be specified explicitly.
+    pub async fn move_to_dead_letter(
+        &self,
+        message: &ReceivedMessage,
+        topic: Option<&str>,
+        retry: Option<RetrySetting>,
+    ) -> Result<String, PubSubError> {
+        let topic = match topic {
+            Some(topic) => self.fully_qualified_topic_name(topic),
+            None => Subscription::new(message.subscription().to_string(), self.subc.clone())
+                .dead_letter_topic(retry.clone())
+                .await?
+                .ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "subscription {} has no dead letter policy: specify the topic explicitly",
+                        message.subscription()
+                    ))
+                })?,
+        };
+        self.republish(message, topic, None, retry).await
+    }
+
+    /// move_to_retry_topic publishes a copy of the message to the retry topic and acks the original message,
+    /// incrementing the retry count in the attribute `retry_count_attribute`.
+    /// The count is read with `ReceivedMessage::retry_count_from_attr`, so it persists across the topics of
+    /// a multi-topic retry pipeline even without `delivery_attempt`.
+    /// It returns the message_id of the published copy. Like `move_to_dead_letter` the copy may be published
+    /// more than once if the ack of the original message fails.
+    pub async fn move_to_retry_topic(
+        &self,
+        message: &ReceivedMessage,
+        topic: &str,
+        retry_count_attribute: &str,
+        retry: Option<RetrySetting>,
+    ) -> Result<String, PubSubError> {
+        let retry_count = message.retry_count_from_attr(retry_count_attribute).saturating_add(1);
+        let attribute = (retry_count_attribute.to_string(), retry_count.to_string());
+        self.republish(message, self.fully_qualified_topic_name(topic), Some(attribute), retry)
+            .await
+    }
+
+    /// republish publishes a copy of the message to the topic with `ReceivedMessage::republish`.
+    async fn republish(
+        &self,
+        message: &ReceivedMessage,
+        topic: String,
+        attribute: Option<(String, String)>,
+        retry: Option<RetrySetting>,
+    ) -> Result<String, PubSubError> {
+        message
+            .republish(attribute, |copy| async move {
+                let req = PublishRequest {
+                    topic,
+                    messages: vec![copy],
+                };
+                let message_id = self
+                    .pubc
+                    .publish(req, retry)
+                    .await?
+                    .into_inner()
+                    .message_ids
+                    .pop()
+                    .ok_or_else(|| Status::internal("no message_id returned for the published copy"))?;
+                Ok(message_id)
+            })
+            .await
+    }
+
     /// create_topic creates a new topic.
     ///
     /// The specified topic ID must start with a letter, and contain only letters
@@ -257,6 +390,43 @@ impl Client {
         self.subc.list_snapshots(req, retry).await
     }
 
+    /// get_schema gets the schema registered with the id.
+    pub async fn get_schema(&self, id: &str, view: SchemaView, retry: Option<RetrySetting>) -> Result<Schema, Status> {
+        let req = GetSchemaRequest {
+            name: self.fully_qualified_schema_name(id),
+            view: view.into(),
+        };
+        self.schc.get_schema(req, retry).await.map(|v| v.into_inner())
+    }
+
+    /// validate_message validates the data of the message against the schema registered with the id
+    /// before publishing it to a topic with the schema, since the server rejects the whole publish request
+    /// containing a message that doesn't conform to the schema.
+    /// The encoding is the one of the `SchemaSettings` of the topic.
+    /// A message that doesn't conform to the schema fails with `PubSubError::SchemaViolation`, and the other
+    /// INVALID_ARGUMENT, e.g. for a malformed schema name, with `PubSubError::Status`.
+    pub async fn validate_message(
+        &self,
+        id: &str,
+        message: &PubsubMessage,
+        encoding: Encoding,
+        retry: Option<RetrySetting>,
+    ) -> Result<(), PubSubError> {
+        let req = ValidateMessageRequest {
+            parent: self.fully_qualified_project_name(),
+            message: message.data.clone(),
+            encoding: encoding.into(),
+            schema_spec: Some(SchemaSpec::Name(self.fully_qualified_schema_name(id))),
+        };
+        match self.schc.validate_message(req, retry).await {
+            Ok(_) => Ok(()),
+            Err(status) if is_schema_violation(&status) => {
+                Err(PubSubError::SchemaViolation(status.message().to_string()))
+            }
+            Err(status) => Err(status.into()),
+        }
+    }
+
     pub fn fully_qualified_topic_name(&self, id: &str) -> String {
         if id.contains('/') {
             id.to_string()
@@ -273,25 +443,56 @@ impl Client {
         }
     }
 
+    pub fn fully_qualified_schema_name(&self, id: &str) -> String {
+        if id.contains('/') {
+            id.to_string()
+        } else {
+            format!("projects/{}/schemas/{}", self.project_id, id)
+        }
+    }
+
     fn fully_qualified_project_name(&self) -> String {
         format!("projects/{}", self.project_id)
     }
 }
 
+/// The prefix of the INVALID_ARGUMENT message returned for the data that doesn't conform to the schema.
+/// Prefixes of the INVALID_ARGUMENT messages for the data that doesn't conform to the schema:
+/// the service reports "Invalid data in message", and the emulator reports the parse failure of the data.
+const SCHEMA_VIOLATION_MESSAGES: [&str; 2] = ["Invalid data in message", "Could not parse"];
+
+/// is_schema_violation returns whether the status is the rejection of the data that doesn't conform to the schema,
+/// rather than of the other fields of the request.
+fn is_schema_violation(status: &Status) -> bool {
+    status.code() == Code::InvalidArgument
+        && SCHEMA_VIOLATION_MESSAGES
+            .iter()
+            .any(|prefix| status.message().starts_with(prefix))
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use std::thread;
     use std::time::Duration;
 
     use serial_test::serial;
+    use token_source::{NoopTokenSourceProvider, TokenSource, TokenSourceProvider};
     use tokio_util::sync::CancellationToken;
     use uuid::Uuid;
 
-    use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+    use google_cloud_gax::conn::{ConnectionOptions, Environment};
+    use google_cloud_gax::grpc::{Code, Status};
+    use google_cloud_googleapis::pubsub::v1::{
+        schema, CreateSchemaRequest, DeadLetterPolicy, DeleteSchemaRequest, Encoding, PubsubMessage, Schema, SchemaView,
+    };
 
-    use crate::client::Client;
-    use crate::subscriber::SubscriberConfig;
+    use crate::apiv1::conn_pool::{ConnectionManager, PUBSUB};
+    use crate::client::{Client, ClientConfig};
+    use crate::error::PubSubError;
+    use crate::subscriber::{ReceivedMessage, SubscriberConfig, ORIGINAL_MESSAGE_ID_ATTRIBUTE};
     use crate::subscription::{ReceiveConfig, SubscriptionConfig};
 
     #[ctor::ctor]
@@ -299,12 +500,120 @@ mod tests {
         let _ = tracing_subscriber::fmt().try_init();
     }
 
+    #[derive(Debug)]
+    struct TenantTokenSource;
+
+    #[async_trait::async_trait]
+    impl TokenSource for TenantTokenSource {
+        async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            Ok("Bearer tenant".to_string())
+        }
+    }
+
+    #[derive(Debug)]
+    struct TenantTokenSourceProvider {
+        token_source: Arc<TenantTokenSource>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl TenantTokenSourceProvider {
+        fn new() -> Self {
+            Self {
+                token_source: Arc::new(TenantTokenSource),
+                calls: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl TokenSourceProvider for TenantTokenSourceProvider {
+        fn token_source(&self) -> Arc<dyn TokenSource> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.token_source.clone()
+        }
+    }
+
+    #[test]
+    fn test_is_schema_violation() {
+        use crate::client::is_schema_violation;
+
+        assert!(is_schema_violation(&Status::invalid_argument(
+            "Invalid data in message: Message failed schema validation."
+        )));
+        assert!(is_schema_violation(&Status::invalid_argument(
+            "Could not parse JSON Avro message"
+        )));
+        assert!(!is_schema_violation(&Status::invalid_argument(
+            "Invalid resource name given (name=projects/p/schemas/a b)."
+        )));
+        assert!(!is_schema_violation(&Status::not_found("Invalid data in message")));
+    }
+
+    #[test]
+    fn test_with_token_source_provider() {
+        let config = ClientConfig {
+            environment: Environment::GoogleCloud(Box::new(NoopTokenSourceProvider {})),
+            ..Default::default()
+        }
+        .with_token_source_provider(Box::new(TenantTokenSourceProvider::new()));
+        match config.environment {
+            Environment::GoogleCloud(provider) => {
+                assert!(format!("{provider:?}").starts_with("TenantTokenSourceProvider"))
+            }
+            Environment::Emulator(_) => unreachable!(),
+        }
+
+        // The emulator is not authenticated.
+        let config = ClientConfig {
+            environment: Environment::Emulator("localhost:8681".to_string()),
+            ..Default::default()
+        }
+        .with_token_source_provider(Box::new(TenantTokenSourceProvider::new()));
+        assert!(matches!(config.environment, Environment::Emulator(_)));
+    }
+
+    #[tokio::test]
+    async fn test_token_source_provider_connection() {
+        let provider = TenantTokenSourceProvider::new();
+        let token_source = provider.token_source.clone();
+        let calls = provider.calls.clone();
+        let environment = Environment::GoogleCloud(Box::new(provider));
+        // tonic requires the crypto provider of the application.
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let options = ConnectionOptions::new(Some(Duration::from_secs(1)), Some(Duration::from_secs(1)));
+        // The token source is taken before connecting, so the result depends only on the network.
+        let cm = ConnectionManager::new(1, PUBSUB, &environment, &options).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        if cm.is_ok() {
+            // held by the auth layer of the channel
+            assert_eq!(Arc::strong_count(&token_source), 3);
+        }
+        assert_eq!(token_source.token().await.unwrap(), "Bearer tenant");
+    }
+
     async fn create_client() -> Client {
         std::env::set_var("PUBSUB_EMULATOR_HOST", "localhost:8681");
 
         Client::new(Default::default()).await.unwrap()
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_keep_alive() {
+        std::env::set_var("PUBSUB_EMULATOR_HOST", "localhost:8681");
+        let config = ClientConfig::default().with_keep_alive(Duration::from_secs(10), Duration::from_secs(5));
+        assert_eq!(config.connection_option.keep_alive_interval, Some(Duration::from_secs(10)));
+        assert_eq!(config.connection_option.keep_alive_timeout, Some(Duration::from_secs(5)));
+        assert!(!config.connection_option.keep_alive_while_idle);
+
+        // The connections with the keepalive serve the requests.
+        let client = Client::new(config).await.unwrap();
+        let topic = client
+            .create_topic(&format!("t{}", Uuid::new_v4().hyphenated()), None, None)
+            .await
+            .unwrap();
+        topic.delete(None).await.unwrap();
+    }
+
     async fn do_publish_and_subscribe(ordering_key: &str, bulk: bool) {
         let client = create_client().await;
 
@@ -333,15 +642,19 @@ mod tests {
                 ping_interval: Duration::from_secs(1),
                 ..Default::default()
             }),
+            ..Default::default()
         };
         let cancel_receiver = cancellation_token.clone();
+        let expected_key = order.then(|| ordering_key.to_string());
         let (s, mut r) = tokio::sync::mpsc::channel(100);
         let handle = tokio::spawn(async move {
             let _ = subscription
                 .receive(
                     move |v, _ctx| {
                         let s2 = s.clone();
+                        let expected_key = expected_key.clone();
                         async move {
+                            assert_eq!(v.ordering_key(), expected_key.as_deref());
                             let _ = v.ack().await;
                             let data = std::str::from_utf8(&v.message.data).unwrap().to_string();
                             tracing::info!(
@@ -458,6 +771,384 @@ mod tests {
         assert_eq!(1, subs_after.len() - subs.len());
         assert_eq!(1, snapshots_after.len() - snapshots.len());
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_ensure_subscription() {
+        let client = create_client().await;
+        let uuid = Uuid::new_v4().hyphenated().to_string();
+        let topic_id = &format!("t{}", &uuid);
+        let subscription_id = &format!("s{}", &uuid);
+        client.create_topic(topic_id, None, None).await.unwrap();
+
+        let config = |ack_deadline_seconds| SubscriptionConfig {
+            ack_deadline_seconds,
+            ..Default::default()
+        };
+        let subscription = client
+            .ensure_subscription(subscription_id, topic_id, config(20), None)
+            .await
+            .unwrap();
+        assert_eq!(subscription.config(None).await.unwrap().1.ack_deadline_seconds, 20);
+
+        // The existing subscription is kept as is.
+        let subscription = client
+            .ensure_subscription(subscription_id, topic_id, config(30), None)
+            .await
+            .unwrap();
+        assert_eq!(subscription.config(None).await.unwrap().1.ack_deadline_seconds, 20);
+        assert!(!subscription
+            .ensure(&client.fully_qualified_topic_name(topic_id), config(30), None)
+            .await
+            .unwrap());
+
+        // The filter is set at the creation and must match afterwards.
+        subscription.delete(None).await.unwrap();
+        let filtered = |filter: &str| SubscriptionConfig {
+            filter: filter.to_string(),
+            ..Default::default()
+        };
+        let subscription = client
+            .ensure_subscription(subscription_id, topic_id, filtered("attributes.k = \"v\""), None)
+            .await
+            .unwrap();
+        assert_eq!(subscription.config(None).await.unwrap().1.filter, "attributes.k = \"v\"");
+        client
+            .ensure_subscription(subscription_id, topic_id, filtered("attributes.k = \"v\""), None)
+            .await
+            .unwrap();
+        for filter in ["attributes.k = \"w\"", ""] {
+            let err = client
+                .ensure_subscription(subscription_id, topic_id, filtered(filter), None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::FailedPrecondition);
+        }
+
+        // The missing topic is reported by the creation.
+        subscription.delete(None).await.unwrap();
+        let err = client
+            .ensure_subscription(subscription_id, "missing", config(20), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_validate_message() {
+        let client = create_client().await;
+        let schema_id = format!("schema{}", Uuid::new_v4().simple());
+        let definition = r#"{"type":"record","name":"User","fields":[{"name":"name","type":"string"}]}"#;
+        client
+            .schc
+            .create_schema(
+                CreateSchemaRequest {
+                    parent: client.fully_qualified_project_name(),
+                    schema: Some(Schema {
+                        r#type: schema::Type::Avro.into(),
+                        definition: definition.to_string(),
+                        ..Default::default()
+                    }),
+                    schema_id: schema_id.clone(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let schema = client.get_schema(&schema_id, SchemaView::Full, None).await.unwrap();
+        assert_eq!(schema.name, client.fully_qualified_schema_name(&schema_id));
+        assert_eq!(schema.definition, definition);
+
+        let message = |data: &str| PubsubMessage {
+            data: data.to_string().into(),
+            ..Default::default()
+        };
+        client
+            .validate_message(&schema_id, &message(r#"{"name":"a"}"#), Encoding::Json, None)
+            .await
+            .unwrap();
+        let err = client
+            .validate_message(&schema_id, &message(r#"{"age":1}"#), Encoding::Json, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PubSubError::SchemaViolation(_)), "{err}");
+        let err = client
+            .validate_message("missing", &message(r#"{"name":"a"}"#), Encoding::Json, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound, "{err}");
+
+        client
+            .schc
+            .delete_schema(
+                DeleteSchemaRequest {
+                    name: client.fully_qualified_schema_name(&schema_id),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_move_to_dead_letter() {
+        let client = create_client().await;
+
+        let uuid = Uuid::new_v4().hyphenated().to_string();
+        let topic_id = &format!("t{}", &uuid);
+        let dlq_topic_id = &format!("dlq{}", &uuid);
+        let topic = client.create_topic(topic_id, None, None).await.unwrap();
+        let dlq_topic = client.create_topic(dlq_topic_id, None, None).await.unwrap();
+        let dlq_subscription = client
+            .create_subscription(&format!("sdlq{}", &uuid), dlq_topic_id, SubscriptionConfig::default(), None)
+            .await
+            .unwrap();
+        let config = SubscriptionConfig {
+            dead_letter_policy: Some(DeadLetterPolicy {
+                dead_letter_topic: dlq_topic.fully_qualified_name().to_string(),
+                max_delivery_attempts: 5,
+            }),
+            ..Default::default()
+        };
+        let subscription = client
+            .create_subscription(&format!("s{}", &uuid), topic_id, config, None)
+            .await
+            .unwrap();
+        let plain_subscription = client
+            .create_subscription(&format!("sp{}", &uuid), topic_id, SubscriptionConfig::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(dlq_topic.fully_qualified_name().to_string()),
+            subscription.dead_letter_topic(None).await.unwrap()
+        );
+        assert_eq!(None, plain_subscription.dead_letter_topic(None).await.unwrap());
+
+        let publisher = topic.new_publisher(None);
+        let message = PubsubMessage {
+            data: "poison".into(),
+            attributes: HashMap::from([("k".to_string(), "v".to_string())]),
+            ..Default::default()
+        };
+        publisher.publish(message).await.get().await.unwrap();
+
+        // The dead letter topic of the subscription is used by default.
+        let received = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        let moved_id = client.move_to_dead_letter(&received, None, None).await.unwrap();
+        let moved = dlq_subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(moved_id, moved.message.message_id);
+        assert_eq!(received.message.data, moved.message.data);
+        assert_eq!("v", moved.message.attributes["k"]);
+        assert_eq!(
+            received.message.message_id,
+            moved.message.attributes[ORIGINAL_MESSAGE_ID_ATTRIBUTE]
+        );
+        moved.ack().await.unwrap();
+
+        // Without a dead letter policy the topic must be specified.
+        let received = plain_subscription.pull(1, None).await.unwrap().pop().unwrap();
+        let err = client.move_to_dead_letter(&received, None, None).await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+        client
+            .move_to_dead_letter(&received, Some(dlq_topic_id), None)
+            .await
+            .unwrap();
+        let moved = dlq_subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(received.message.data, moved.message.data);
+        moved.ack().await.unwrap();
+
+        // The move succeeds once the copy is published even if the ack fails.
+        let unknown = ReceivedMessage::new(
+            format!("projects/local-project/subscriptions/missing{uuid}"),
+            client.subc.clone(),
+            received.message.clone(),
+            "unknown".to_string(),
+            None,
+        );
+        let moved_id = client
+            .move_to_dead_letter(&unknown, Some(dlq_topic_id), None)
+            .await
+            .unwrap();
+        let moved = dlq_subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(moved_id, moved.message.message_id);
+
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_try_subscription() {
+        let client = create_client().await;
+        let subscription = client.try_subscription("sub").unwrap();
+        assert_eq!("projects/local-project/subscriptions/sub", subscription.fully_qualified_name());
+        let subscription = client
+            .try_subscription("projects/other-project/subscriptions/sub")
+            .unwrap();
+        assert_eq!("projects/other-project/subscriptions/sub", subscription.fully_qualified_name());
+
+        // The missing prefix is detected before the requests.
+        let err = client.try_subscription("other-project/subscriptions/sub").unwrap_err();
+        assert!(matches!(err, PubSubError::InvalidSubscriptionName(_)), "{err:?}");
+        assert!(client.try_subscription("goog").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_forward_to() {
+        let client = create_client().await;
+
+        let uuid = Uuid::new_v4().hyphenated().to_string();
+        let topic_id = &format!("t{}", &uuid);
+        let dlq_topic_id = &format!("dlq{}", &uuid);
+        let topic = client.create_topic(topic_id, None, None).await.unwrap();
+        let dlq_topic = client.create_topic(dlq_topic_id, None, None).await.unwrap();
+        let subscription = client
+            .create_subscription(&format!("s{}", &uuid), topic_id, SubscriptionConfig::default(), None)
+            .await
+            .unwrap();
+        let dlq_subscription = client
+            .create_subscription(&format!("sdlq{}", &uuid), dlq_topic_id, SubscriptionConfig::default(), None)
+            .await
+            .unwrap();
+
+        let mut publisher = topic.new_publisher(None);
+        let message = PubsubMessage {
+            data: "poison".into(),
+            attributes: HashMap::from([("k".to_string(), "v".to_string())]),
+            ..Default::default()
+        };
+        publisher.publish(message).await.get().await.unwrap();
+
+        let mut dlq_publisher = dlq_topic.new_publisher(None);
+        let received = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        let forwarded_id = received.forward_to(&dlq_publisher).await.unwrap();
+        let forwarded = dlq_subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(forwarded_id, forwarded.message.message_id);
+        assert_eq!(received.message.data, forwarded.message.data);
+        assert_eq!("v", forwarded.message.attributes["k"]);
+        assert_eq!(
+            received.message.message_id,
+            forwarded.message.attributes[ORIGINAL_MESSAGE_ID_ATTRIBUTE]
+        );
+        forwarded.ack().await.unwrap();
+        // The original message is acked.
+        let messages = subscription.pull_once(1, Duration::from_secs(1), None).await.unwrap();
+        assert!(messages.is_empty());
+
+        // The original message is not acked if the publish fails.
+        publisher
+            .publish(PubsubMessage {
+                data: "poison".into(),
+                ..Default::default()
+            })
+            .await
+            .get()
+            .await
+            .unwrap();
+        let received = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        dlq_publisher.shutdown().await;
+        dlq_topic.delete(None).await.unwrap();
+        let mut dlq_publisher = dlq_topic.new_publisher(None);
+        assert!(received.forward_to(&dlq_publisher).await.is_err());
+        received.nack().await.unwrap();
+        let redelivered = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(received.message.message_id, redelivered.message.message_id);
+        redelivered.ack().await.unwrap();
+
+        dlq_publisher.shutdown().await;
+        publisher.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_move_to_retry_topic() {
+        let client = create_client().await;
+
+        let uuid = Uuid::new_v4().hyphenated().to_string();
+        let mut subscriptions = Vec::new();
+        for id in ["t", "retry1", "retry2"] {
+            let topic_id = format!("{id}{uuid}");
+            client.create_topic(&topic_id, None, None).await.unwrap();
+            let subscription = client
+                .create_subscription(&format!("s{id}{uuid}"), &topic_id, SubscriptionConfig::default(), None)
+                .await
+                .unwrap();
+            subscriptions.push((topic_id, subscription));
+        }
+        let key = "retry-count";
+        let publisher = client.topic(&subscriptions[0].0).new_publisher(None);
+        let message = |retry_count: Option<&str>| PubsubMessage {
+            data: "retry".into(),
+            attributes: retry_count
+                .map(|v| HashMap::from([(key.to_string(), v.to_string())]))
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        publisher.publish(message(None)).await.get().await.unwrap();
+
+        // The retry count is incremented on each hop.
+        let received = subscriptions[0].1.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(0, received.retry_count_from_attr(key));
+        client
+            .move_to_retry_topic(&received, &subscriptions[1].0, key, None)
+            .await
+            .unwrap();
+        let received = subscriptions[1].1.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(1, received.retry_count_from_attr(key));
+        client
+            .move_to_retry_topic(&received, &subscriptions[2].0, key, None)
+            .await
+            .unwrap();
+        let received = subscriptions[2].1.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(2, received.retry_count_from_attr(key));
+        assert_eq!("retry", std::str::from_utf8(&received.message.data).unwrap());
+        received.ack().await.unwrap();
+
+        // A malformed count is treated as 0.
+        publisher.publish(message(Some("x"))).await.get().await.unwrap();
+        let received = subscriptions[0].1.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(0, received.retry_count_from_attr(key));
+        client
+            .move_to_retry_topic(&received, &subscriptions[1].0, key, None)
+            .await
+            .unwrap();
+        let received = subscriptions[1].1.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(1, received.retry_count_from_attr(key));
+        received.ack().await.unwrap();
+
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_publish_json() {
+        let client = create_client().await;
+        let uuid = Uuid::new_v4().hyphenated().to_string();
+        let topic_id = format!("t{uuid}");
+        client.create_topic(&topic_id, None, None).await.unwrap();
+        let subscription = client
+            .create_subscription(&format!("s{uuid}"), &topic_id, SubscriptionConfig::default(), None)
+            .await
+            .unwrap();
+
+        let publisher = client.topic(&topic_id).new_publisher(None);
+        let value = HashMap::from([("n".to_string(), 1)]);
+        publisher.publish_json(&value).await.unwrap().get().await.unwrap();
+
+        let received = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(value, received.deserialize_json::<HashMap<String, i32>>().unwrap());
+        assert!(received.deserialize_json::<Vec<i32>>().is_err());
+        received.ack().await.unwrap();
+
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+    }
 }
 
 #[cfg(test)]
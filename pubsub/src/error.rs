@@ -0,0 +1,89 @@
+use google_cloud_gax::grpc::{Code, Status};
+
+use crate::subscriber::MAX_STREAM_ACK_DEADLINE_SECONDS;
+
+/// PubSubError is the error of receiving messages: subscribing, pulling, acking, nacking, extending the deadline
+/// and moving messages to another topic, and of the checks of the messages before publishing them. It is either a `Status` or an error detected on the client side before
+/// sending the request. The administrative methods, such as creating a topic or a subscription, return the
+/// `Status` of the request as is.
+///
+/// `From<Status>` and `From<PubSubError> for Status` are implemented, so the `?` operator works in both
+/// directions while the code is migrated from `Status`. The `Status` is boxed to keep the `Result` small.
+#[derive(thiserror::Error, Debug)]
+pub enum PubSubError {
+    #[error(transparent)]
+    Status(Box<Status>),
+    #[error("invalid config: {0}")]
+    ConfigInvalid(String),
+    #[error("ack_deadline_seconds must be between 0 and {MAX_STREAM_ACK_DEADLINE_SECONDS} but was {0}")]
+    DeadlineOutOfRange(i32),
+    #[error("serialization error: {0}")]
+    SerializationError(String),
+    #[error("schema violation: {0}")]
+    SchemaViolation(String),
+    #[error("message of {size} bytes exceeds the limit of {max} bytes")]
+    MessageTooLarge { size: usize, max: usize },
+    #[error("invalid subscription name: {0}")]
+    InvalidSubscriptionName(String),
+}
+
+impl PubSubError {
+    /// status returns the underlying `Status`, None if the error is one of the client-side variants.
+    pub fn status(&self) -> Option<&Status> {
+        match self {
+            Self::Status(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// code returns the code of the `Status` that `into_status` returns.
+    pub fn code(&self) -> Code {
+        match self {
+            Self::Status(v) => v.code(),
+            _ => Code::InvalidArgument,
+        }
+    }
+
+    /// into_status returns the underlying `Status`.
+    /// The errors detected on the client side are converted into INVALID_ARGUMENT with the error message.
+    pub fn into_status(self) -> Status {
+        match self {
+            Self::Status(v) => *v,
+            e => Status::invalid_argument(e.to_string()),
+        }
+    }
+}
+
+impl From<Status> for PubSubError {
+    fn from(status: Status) -> Self {
+        Self::Status(Box::new(status))
+    }
+}
+
+impl From<PubSubError> for Status {
+    fn from(e: PubSubError) -> Self {
+        e.into_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use google_cloud_gax::grpc::{Code, Status};
+
+    use crate::error::PubSubError;
+
+    #[test]
+    fn test_into_status() {
+        let err = PubSubError::from(Status::not_found("missing"));
+        assert_eq!(err.code(), Code::NotFound);
+        assert_eq!(err.status().unwrap().message(), "missing");
+        assert_eq!(Status::from(err).message(), "missing");
+
+        let err = PubSubError::DeadlineOutOfRange(601);
+        assert!(err.status().is_none());
+        assert_eq!(err.code(), Code::InvalidArgument);
+        let status = err.into_status();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "ack_deadline_seconds must be between 0 and 600 but was 601");
+    }
+}
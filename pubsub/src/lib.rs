@@ -50,6 +50,9 @@
 //! export PUBSUB_EMULATOR_HOST=localhost:8681
 //! ```
 //!
+//! `ClientConfig::default()` and `ConnectionManager::from_env` use the emulator while the variable is set,
+//! and `with_auth` and `with_credentials` skip looking up the credentials.
+//!
 //! ### Publish Message
 //!
 //! ```
@@ -229,6 +232,7 @@
 //! ```
 pub mod apiv1;
 pub mod client;
+pub mod error;
 pub mod publisher;
 pub mod subscriber;
 pub mod subscription;
@@ -1,20 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
 
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_channel::Receiver;
+use prost::Message;
 use tokio::sync::oneshot;
 use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
-use tokio::time::timeout;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{timeout_at, Instant};
 
 use google_cloud_gax::grpc::Status;
 use google_cloud_gax::retry::RetrySetting;
 use google_cloud_googleapis::pubsub::v1::{PublishRequest, PubsubMessage};
 
 use crate::apiv1::publisher_client::PublisherClient;
+use crate::error::PubSubError;
 use crate::util::ToUsize;
 
 pub(crate) struct ReservedMessage {
@@ -31,11 +33,26 @@ pub(crate) enum Reserved {
 pub struct PublisherConfig {
     /// worker count. each workers have gRPC channel
     pub workers: usize,
-    /// interval for flush bundle message
+    /// max latency of a message in the bundle. The bundle is flushed when the interval has elapsed
+    /// since the first message was added to it.
     pub flush_interval: Duration,
     /// max bundle size to flush
     pub bundle_size: usize,
+    /// max total bytes of the encoded messages in a bundle. The bundle is flushed before
+    /// a message is added to it beyond the size, and a message larger than the size is sent alone.
+    /// The size should be less than the 10MB limit of a publish request.
+    pub bundle_byte_size: usize,
+    /// max bytes of a message, the length of the data plus the lengths of the keys and the values of the attributes.
+    /// A larger message fails with `PubSubError::MessageTooLarge` before it is sent.
+    /// The default is the 10MB limit of the server.
+    pub max_message_bytes: usize,
     pub retry_setting: Option<RetrySetting>,
+    /// gzip compression level from 0 to 9 of the data of the messages, None to send the data as is.
+    /// The compressed messages have the `content-encoding: gzip` attribute, which
+    /// `ReceivedMessage::data_decompressed` checks to inflate the data.
+    /// The messages already having the `content-encoding` attribute are sent as is.
+    #[cfg(feature = "gzip")]
+    pub gzip_level: Option<u32>,
 }
 
 impl Default for PublisherConfig {
@@ -44,11 +61,54 @@ impl Default for PublisherConfig {
             workers: 3,
             flush_interval: Duration::from_millis(100),
             bundle_size: 3,
+            bundle_byte_size: 1_000_000,
+            max_message_bytes: MAX_MESSAGE_BYTES,
             retry_setting: None,
+            #[cfg(feature = "gzip")]
+            gzip_level: None,
         }
     }
 }
 
+/// The limit of the server on the size of a message.
+pub const MAX_MESSAGE_BYTES: usize = 10_000_000;
+
+/// message_size returns the size of the message checked against `PublisherConfig::max_message_bytes`:
+/// the length of the data plus the lengths of the keys and the values of the attributes.
+pub fn message_size(message: &PubsubMessage) -> usize {
+    message.data.len() + message.attributes.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+}
+
+/// The attribute of the encoding of the data.
+#[cfg(feature = "gzip")]
+pub const CONTENT_ENCODING: &str = "content-encoding";
+/// The value of `CONTENT_ENCODING` for the data compressed with gzip.
+#[cfg(feature = "gzip")]
+pub const GZIP: &str = "gzip";
+
+/// gzip compresses the data of the message and sets the `content-encoding` attribute.
+/// The message is returned as is if it is already encoded.
+#[cfg(feature = "gzip")]
+#[allow(clippy::useless_conversion)] // the data is Bytes with the bytes feature
+fn gzip(mut message: PubsubMessage, level: u32) -> PubsubMessage {
+    use std::io::Write;
+
+    if message.attributes.contains_key(CONTENT_ENCODING) {
+        return message;
+    }
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    match encoder.write_all(&message.data).and_then(|_| encoder.finish()) {
+        Ok(data) => {
+            message.data = data.into();
+            message
+                .attributes
+                .insert(CONTENT_ENCODING.to_string(), GZIP.to_string());
+        }
+        Err(err) => tracing::warn!("failed to compress the data, so send it as is : {err}"),
+    }
+    message
+}
+
 pub struct Awaiter {
     consumer: oneshot::Receiver<Result<String, Status>>,
 }
@@ -57,6 +117,13 @@ impl Awaiter {
     pub(crate) fn new(consumer: oneshot::Receiver<Result<String, Status>>) -> Self {
         Self { consumer }
     }
+
+    /// failed returns the Awaiter which is ready with the error.
+    fn failed(status: Status) -> Self {
+        let (producer, consumer) = oneshot::channel();
+        let _ = producer.send(Err(status));
+        Self::new(consumer)
+    }
     pub async fn get(self) -> Result<String, Status> {
         match self.consumer.await {
             Ok(v) => v,
@@ -65,17 +132,52 @@ impl Awaiter {
     }
 }
 
+/// PausedKeys holds the ordering keys whose publish failed.
+#[derive(Debug, Default)]
+struct PausedKeys {
+    inner: std::sync::Mutex<HashSet<String>>,
+}
+
+impl PausedKeys {
+    fn pause(&self, key: &str) {
+        self.inner.lock().unwrap().insert(key.to_string());
+    }
+
+    fn resume(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        !key.is_empty() && self.inner.lock().unwrap().contains(key)
+    }
+}
+
+fn paused_error(key: &str) -> Status {
+    Status::failed_precondition(format!(
+        "publishing for the ordering key {key} is paused due to a previous error. call resume_publish to resume"
+    ))
+}
+
 /// Publisher is a scheduler which is designed for Pub/Sub's Publish flow.
 /// Each item is added with a given key.
 /// Items added to the empty string key are handled in random order.
-/// Items added to any other key are handled sequentially.
+/// Items added to any other key are handled sequentially, while the items of the different keys are
+/// published concurrently.
+///
+/// When the publish of an ordering key fails, the key is paused to preserve the order:
+/// the messages of the key waiting in the bundle and the messages published afterwards fail with
+/// FAILED_PRECONDITION until `resume_publish` is called for the key.
 #[derive(Clone, Debug)]
 pub struct Publisher {
     ordering_senders: Arc<Vec<async_channel::Sender<Reserved>>>,
     sender: async_channel::Sender<Reserved>,
     tasks: Arc<Mutex<Tasks>>,
+    paused: Arc<PausedKeys>,
     fqtn: String,
     pubc: PublisherClient,
+    max_message_bytes: usize,
+    #[cfg(feature = "gzip")]
+    gzip_level: Option<u32>,
 }
 
 impl Publisher {
@@ -99,13 +201,54 @@ impl Publisher {
             ordering_senders.push(sender);
         }
 
+        let paused = Arc::new(PausedKeys::default());
+        let max_message_bytes = config.max_message_bytes;
+        #[cfg(feature = "gzip")]
+        let gzip_level = config.gzip_level;
         Self {
             sender,
             ordering_senders: Arc::new(ordering_senders),
-            tasks: Arc::new(Mutex::new(Tasks::new(fqtn.clone(), pubc.clone(), receivers, config))),
+            tasks: Arc::new(Mutex::new(Tasks::new(
+                fqtn.clone(),
+                pubc.clone(),
+                receivers,
+                config,
+                paused.clone(),
+            ))),
+            paused,
             fqtn,
             pubc,
+            max_message_bytes,
+            #[cfg(feature = "gzip")]
+            gzip_level,
+        }
+    }
+
+    /// encode compresses the data of the message if `PublisherConfig::gzip_level` is set.
+    fn encode(&self, message: PubsubMessage) -> PubsubMessage {
+        #[cfg(feature = "gzip")]
+        if let Some(level) = self.gzip_level {
+            return gzip(message, level);
+        }
+        message
+    }
+
+    /// check_size fails with `PubSubError::MessageTooLarge` if the encoded message is larger than
+    /// `PublisherConfig::max_message_bytes`.
+    fn check_size(&self, message: &PubsubMessage) -> Result<(), PubSubError> {
+        let size = message_size(message);
+        if size > self.max_message_bytes {
+            return Err(PubSubError::MessageTooLarge {
+                size,
+                max: self.max_message_bytes,
+            });
         }
+        Ok(())
+    }
+
+    /// resume_publish resumes the publish for the ordering key paused by a failed publish.
+    pub fn resume_publish(&self, ordering_key: &str) {
+        self.paused.resume(ordering_key);
     }
 
     /// publish publishes msg to the topic synchronously
@@ -114,6 +257,10 @@ impl Publisher {
         messages: Vec<PubsubMessage>,
         retry: Option<RetrySetting>,
     ) -> Result<Vec<String>, Status> {
+        let messages: Vec<PubsubMessage> = messages.into_iter().map(|v| self.encode(v)).collect();
+        for message in &messages {
+            self.check_size(message)?;
+        }
         self.pubc
             .publish(
                 PublishRequest {
@@ -131,7 +278,29 @@ impl Publisher {
     ///
     /// publish returns a non-nil Awaiter which will be ready when the
     /// message has been sent (or has failed to be sent) to the server.
+    /// A message larger than `PublisherConfig::max_message_bytes` fails with INVALID_ARGUMENT without being sent;
+    /// use `try_publish` to get the size as `PubSubError::MessageTooLarge`.
     pub async fn publish(&self, message: PubsubMessage) -> Awaiter {
+        let message = self.encode(message);
+        if let Err(e) = self.check_size(&message) {
+            return Awaiter::failed(e.into_status());
+        }
+        self.reserve(message).await
+    }
+
+    /// try_publish publishes the message like `publish`, but fails with `PubSubError::MessageTooLarge`
+    /// instead of returning the Awaiter if the message is larger than `PublisherConfig::max_message_bytes`.
+    pub async fn try_publish(&self, message: PubsubMessage) -> Result<Awaiter, PubSubError> {
+        let message = self.encode(message);
+        self.check_size(&message)?;
+        Ok(self.reserve(message).await)
+    }
+
+    /// reserve sends the encoded message to the worker of its ordering key.
+    async fn reserve(&self, message: PubsubMessage) -> Awaiter {
+        if self.paused.contains(&message.ordering_key) {
+            return Awaiter::failed(paused_error(&message.ordering_key));
+        }
         let (producer, consumer) = oneshot::channel();
         if message.ordering_key.is_empty() {
             let _ = self
@@ -148,10 +317,30 @@ impl Publisher {
         Awaiter::new(consumer)
     }
 
+    /// publish_json encodes the value as JSON into the data of a message and publishes it like `publish`.
+    /// To set the attributes or the ordering key, encode the data with `serde_json::to_vec` and use `publish`.
+    #[cfg(feature = "json")]
+    #[allow(clippy::useless_conversion)] // the data is Bytes with the bytes feature
+    pub async fn publish_json<T: serde::Serialize + ?Sized>(&self, value: &T) -> Result<Awaiter, serde_json::Error> {
+        let data = serde_json::to_vec(value)?;
+        let message = PubsubMessage {
+            data: data.into(),
+            ..Default::default()
+        };
+        Ok(self.publish(message).await)
+    }
+
     /// Publish a message to the topic asynchronously, from synchronous code.
     /// This method blocks until the message is sent to the publisher channel.
     /// The actual publishing to the server is done asynchronously.
     pub fn publish_blocking(&self, message: PubsubMessage) -> Awaiter {
+        if self.paused.contains(&message.ordering_key) {
+            return Awaiter::failed(paused_error(&message.ordering_key));
+        }
+        let message = self.encode(message);
+        if let Err(e) = self.check_size(&message) {
+            return Awaiter::failed(e.into_status());
+        }
         let (producer, consumer) = oneshot::channel();
         if message.ordering_key.is_empty() {
             let _ = self
@@ -174,6 +363,11 @@ impl Publisher {
         let mut awaiters = Vec::with_capacity(messages.len());
         let mut split_by_key = HashMap::<String, Vec<ReservedMessage>>::with_capacity(messages.len());
         for message in messages {
+            let message = self.encode(message);
+            if let Err(e) = self.check_size(&message) {
+                awaiters.push(Awaiter::failed(e.into_status()));
+                continue;
+            }
             let (producer, consumer) = oneshot::channel();
             awaiters.push(Awaiter::new(consumer));
             split_by_key
@@ -183,7 +377,11 @@ impl Publisher {
         }
 
         for e in split_by_key {
-            if e.0.is_empty() {
+            if self.paused.contains(&e.0) {
+                for reserved in e.1 {
+                    let _ = reserved.producer.send(Err(paused_error(&e.0)));
+                }
+            } else if e.0.is_empty() {
                 let _ = self.sender.send(Reserved::Multi(e.1)).await;
             } else {
                 let key = e.0.as_str().to_usize();
@@ -214,19 +412,11 @@ impl Tasks {
         pubc: PublisherClient,
         receivers: Vec<async_channel::Receiver<Reserved>>,
         config: PublisherConfig,
+        paused: Arc<PausedKeys>,
     ) -> Self {
         let tasks = receivers
             .into_iter()
-            .map(|receiver| {
-                Self::run_task(
-                    receiver,
-                    pubc.clone(),
-                    topic.clone(),
-                    config.retry_setting.clone(),
-                    config.flush_interval,
-                    config.bundle_size,
-                )
-            })
+            .map(|receiver| Self::run_task(receiver, pubc.clone(), topic.clone(), config.clone(), paused.clone()))
             .collect();
 
         Self { inner: Some(tasks) }
@@ -234,66 +424,119 @@ impl Tasks {
 
     fn run_task(
         receiver: Receiver<Reserved>,
-        mut client: PublisherClient,
+        client: PublisherClient,
         topic: String,
-        retry: Option<RetrySetting>,
-        flush_interval: Duration,
-        bundle_size: usize,
+        config: PublisherConfig,
+        paused: Arc<PausedKeys>,
     ) -> JoinHandle<()> {
+        let PublisherConfig {
+            flush_interval,
+            bundle_size,
+            bundle_byte_size,
+            retry_setting: retry,
+            ..
+        } = config;
         tokio::spawn(async move {
-            //TODO enable manage task by ordering_key
             let mut bundle = MessageBundle::new();
-            while !receiver.is_closed() {
-                let result = match timeout(flush_interval, &mut receiver.recv()).await {
-                    Ok(result) => result,
-                    //timed out
-                    Err(_e) => {
-                        if !bundle.is_empty() {
+            // The time to flush the bundle, set when the first message is added to it.
+            let mut deadline = None;
+            loop {
+                let result = match deadline {
+                    Some(at) => match timeout_at(at, receiver.recv()).await {
+                        Ok(result) => result,
+                        //timed out
+                        Err(_e) => {
                             tracing::trace!("elapsed: flush buffer : {}", topic);
-                            for value in bundle.key_by() {
-                                Self::flush(&mut client, topic.as_str(), value, retry.clone()).await;
-                            }
-                            bundle = MessageBundle::new();
+                            deadline = None;
+                            Self::flush_bundle(&client, topic.as_str(), bundle.take(), retry.clone(), &paused).await;
+                            continue;
                         }
-                        continue;
-                    }
+                    },
+                    None => receiver.recv().await,
                 };
-                match result {
-                    Ok(reserved) => {
-                        match reserved {
-                            Reserved::Single(message) => bundle.push(message),
-                            Reserved::Multi(messages) => bundle.extend(messages),
-                        }
-                        if bundle.len() >= bundle_size {
-                            tracing::trace!("bundle size max: {}", topic);
-                            for value in bundle.key_by() {
-                                Self::flush(&mut client, topic.as_str(), value, retry.clone()).await;
-                            }
-                            bundle = MessageBundle::new();
-                        }
-                    }
+                let messages = match result {
+                    Ok(Reserved::Single(message)) => vec![message],
+                    Ok(Reserved::Multi(messages)) => messages,
                     //closed
                     Err(_e) => break,
                 };
+                for message in messages {
+                    let size = message.message.encoded_len();
+                    if !bundle.is_empty() && bundle.bytes + size > bundle_byte_size {
+                        tracing::trace!("bundle byte size max: {}", topic);
+                        Self::flush_bundle(&client, topic.as_str(), bundle.take(), retry.clone(), &paused).await;
+                    }
+                    if bundle.is_empty() {
+                        deadline = Some(Instant::now() + flush_interval);
+                    }
+                    bundle.add(message, size);
+                    if bundle.bytes >= bundle_byte_size {
+                        tracing::trace!("bundle byte size max: {}", topic);
+                        Self::flush_bundle(&client, topic.as_str(), bundle.take(), retry.clone(), &paused).await;
+                    }
+                }
+                // The messages of publish_bulk are sent together up to the byte size.
+                if bundle.len() >= bundle_size {
+                    tracing::trace!("bundle size max: {}", topic);
+                    Self::flush_bundle(&client, topic.as_str(), bundle.take(), retry.clone(), &paused).await;
+                }
+                if bundle.is_empty() {
+                    deadline = None;
+                }
             }
 
             tracing::trace!("stop publisher : {}", topic);
             if !bundle.is_empty() {
                 tracing::trace!("flush rest buffer : {}", topic);
-                for value in bundle.key_by() {
-                    Self::flush(&mut client, topic.as_str(), value, retry.clone()).await;
-                }
+                Self::flush_bundle(&client, topic.as_str(), bundle.take(), retry.clone(), &paused).await;
             }
         })
     }
 
+    /// flush_bundle publishes the messages in the bundle for each ordering key concurrently.
+    async fn flush_bundle(
+        client: &PublisherClient,
+        topic: &str,
+        bundle: MessageBundle,
+        retry_setting: Option<RetrySetting>,
+        paused: &Arc<PausedKeys>,
+    ) {
+        let mut values = bundle.key_by();
+        if values.len() == 1 {
+            if let Some(value) = values.pop() {
+                Self::flush(client, topic, value, retry_setting, paused).await;
+            }
+            return;
+        }
+        let mut tasks = JoinSet::new();
+        for value in values {
+            let client = client.clone();
+            let topic = topic.to_string();
+            let retry_setting = retry_setting.clone();
+            let paused = paused.clone();
+            tasks.spawn(async move { Self::flush(&client, &topic, value, retry_setting, &paused).await });
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
     /// flush publishes the messages in buffer.
     async fn flush(
-        client: &mut PublisherClient,
+        client: &PublisherClient,
         topic: &str,
         bundle: Vec<ReservedMessage>,
         retry_setting: Option<RetrySetting>,
+        paused: &PausedKeys,
     ) {
+        let ordering_key = bundle
+            .first()
+            .map(|v| v.message.ordering_key.clone())
+            .unwrap_or_default();
+        if paused.contains(&ordering_key) {
+            for r in bundle {
+                let _ = r.producer.send(Err(paused_error(&ordering_key)));
+            }
+            return;
+        }
         let mut data = Vec::<PubsubMessage>::with_capacity(bundle.len());
         let mut callback = Vec::<oneshot::Sender<Result<String, Status>>>::with_capacity(bundle.len());
         bundle.into_iter().for_each(|r| {
@@ -320,6 +563,10 @@ impl Tasks {
                 }
             }
             Err(status) => {
+                if !ordering_key.is_empty() {
+                    tracing::error!("pause the ordering key {ordering_key} : status={}", status.code());
+                    paused.pause(&ordering_key);
+                }
                 for p in callback.into_iter() {
                     let code = status.code();
                     let status = Status::new(code, (*status.message()).to_string());
@@ -343,11 +590,25 @@ impl Tasks {
 
 struct MessageBundle {
     inner: Vec<ReservedMessage>,
+    /// total bytes of the encoded messages
+    bytes: usize,
 }
 
 impl MessageBundle {
     fn new() -> Self {
-        Self { inner: vec![] }
+        Self {
+            inner: vec![],
+            bytes: 0,
+        }
+    }
+
+    fn add(&mut self, message: ReservedMessage, size: usize) {
+        self.bytes += size;
+        self.inner.push(message);
+    }
+
+    fn take(&mut self) -> Self {
+        std::mem::replace(self, Self::new())
     }
 
     fn key_by(self) -> Vec<Vec<ReservedMessage>> {
@@ -387,7 +648,7 @@ impl DerefMut for MessageBundle {
 
 #[cfg(test)]
 mod tests {
-    use crate::publisher::{MessageBundle, ReservedMessage};
+    use crate::publisher::{message_size, MessageBundle, PausedKeys, ReservedMessage};
     use google_cloud_googleapis::pubsub::v1::PubsubMessage;
     use tokio::sync::oneshot;
 
@@ -402,6 +663,71 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_message_size() {
+        let message = PubsubMessage {
+            data: "abcd".into(),
+            attributes: [("key".to_string(), "value".to_string())].into(),
+            ordering_key: "ignored".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(message_size(&message), 12);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        use std::io::Read;
+
+        use crate::publisher::{gzip, CONTENT_ENCODING, GZIP};
+
+        let data = "abc".repeat(100);
+        let message = gzip(
+            PubsubMessage {
+                data: data.clone().into(),
+                ..Default::default()
+            },
+            6,
+        );
+        assert_eq!(GZIP, message.attributes[CONTENT_ENCODING]);
+        assert!(message.data.len() < data.len());
+        let mut inflated = String::new();
+        flate2::read::GzDecoder::new(&message.data[..])
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(data, inflated);
+
+        // The encoded message is not compressed twice.
+        let encoded = message.data.clone();
+        assert_eq!(encoded, gzip(message, 6).data);
+    }
+
+    #[test]
+    fn test_paused_keys() {
+        let paused = PausedKeys::default();
+        paused.pause("a");
+        assert!(paused.contains("a"));
+        assert!(!paused.contains("b"));
+        paused.resume("a");
+        assert!(!paused.contains("a"));
+        // The messages without the ordering key are never paused.
+        paused.pause("");
+        assert!(!paused.contains(""));
+    }
+
+    #[test]
+    fn test_message_bundle_bytes() {
+        let mut bundle = MessageBundle::new();
+        bundle.add(msg("a"), 3);
+        bundle.add(msg("b"), 5);
+        assert_eq!(8, bundle.bytes);
+        let taken = bundle.take();
+        assert_eq!(2, taken.len());
+        assert_eq!(8, taken.bytes);
+        assert!(bundle.is_empty());
+        assert_eq!(0, bundle.bytes);
+    }
+
     #[test]
     fn test_message_bundle_key_by() {
         let mut bundle = MessageBundle::new();
@@ -1,43 +1,86 @@
-use std::time::Duration;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use tokio::select;
-use tokio::task::JoinHandle;
-use tokio::time::sleep;
+use tokio::sync::{oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::sleep_until;
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 use google_cloud_gax::grpc::{Code, Status, Streaming};
-use google_cloud_gax::retry::RetrySetting;
+use google_cloud_gax::retry::{Retry, RetrySetting};
+use google_cloud_googleapis::pubsub::v1::streaming_pull_response::{
+    ModifyAckDeadlineConfirmation, SubscriptionProperties,
+};
 use google_cloud_googleapis::pubsub::v1::{
-    AcknowledgeRequest, ModifyAckDeadlineRequest, PubsubMessage, ReceivedMessage as InternalReceivedMessage,
-    StreamingPullResponse,
+    AcknowledgeRequest, ModifyAckDeadlineRequest, PubsubMessage, PullRequest, PullResponse,
+    ReceivedMessage as InternalReceivedMessage, StreamingPullRequest, StreamingPullResponse,
 };
 
 use crate::apiv1::default_retry_setting;
-use crate::apiv1::subscriber_client::{create_empty_streaming_pull_request, SubscriberClient};
+use crate::apiv1::subscriber_client::{create_empty_streaming_pull_request, ChannelSelection, SubscriberClient};
+use crate::error::PubSubError;
+use crate::publisher::Publisher;
+use crate::subscription::SubscriptionName;
 
-#[derive(Debug)]
-pub struct ReceivedMessage {
-    pub message: PubsubMessage,
+/// The error logged when the subscriber stops on PERMISSION_DENIED.
+const PERMISSION_DENIED_MESSAGE: &str =
+    "permission denied: check IAM roles (roles/pubsub.subscriber) on the subscription";
+
+/// AckHandle acks, nacks or extends the deadline of a received message without holding its payload.
+#[derive(Debug, Clone)]
+pub struct AckHandle {
     ack_id: String,
+    message_id: String,
     subscription: String,
     subscriber_client: SubscriberClient,
-    delivery_attempt: Option<usize>,
+    recent_acks: Option<Arc<RecentAcks>>,
+    latest_ack_ids: Option<Arc<LatestAckIdGuard>>,
+    control: Option<Arc<StreamControl>>,
+    idempotent_ack: bool,
+    nacked: Option<Arc<AtomicBool>>,
+    leases: Option<Arc<Leases>>,
+    in_flight: Option<Arc<InFlightGuard>>,
+    retry_settings: Option<Arc<AckRetrySettings>>,
+    aggregator: Option<Arc<AckAggregator>>,
+    ack_latency: Option<Arc<AckLatency>>,
+    observer: Option<Arc<dyn SubscriberObserver>>,
+    #[cfg(feature = "opentelemetry")]
+    telemetry: Option<Arc<Telemetry>>,
+    /// The clock of the subscriber, `SystemClock` if None.
+    clock: Option<Arc<dyn Clock>>,
+    received_at: tokio::time::Instant,
 }
 
-impl ReceivedMessage {
-    pub(crate) fn new(
-        subscription: String,
-        subc: SubscriberClient,
-        message: PubsubMessage,
-        ack_id: String,
-        delivery_attempt: Option<usize>,
-    ) -> Self {
+impl AckHandle {
+    pub(crate) fn new(subscription: String, subc: SubscriberClient, ack_id: String, message_id: String) -> Self {
         Self {
-            message,
             ack_id,
+            message_id,
             subscription,
             subscriber_client: subc,
-            delivery_attempt,
+            recent_acks: None,
+            latest_ack_ids: None,
+            control: None,
+            idempotent_ack: false,
+            nacked: None,
+            leases: None,
+            in_flight: None,
+            retry_settings: None,
+            aggregator: None,
+            ack_latency: None,
+            observer: None,
+            #[cfg(feature = "opentelemetry")]
+            telemetry: None,
+            clock: None,
+            received_at: SystemClock.now(),
         }
     }
 
@@ -45,32 +88,585 @@ impl ReceivedMessage {
         self.ack_id.as_str()
     }
 
-    pub async fn ack(&self) -> Result<(), Status> {
-        ack(
+    /// The clock of the subscriber that received the message.
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        self.clock.as_deref().unwrap_or(&SystemClock)
+    }
+
+    /// The fully qualified name of the subscription the message was received from.
+    pub fn subscription(&self) -> &str {
+        self.subscription.as_str()
+    }
+
+    /// The ack_id of the latest delivery of the message received by the same stream.
+    /// It differs from `ack_id` when the message was redelivered while this delivery was being handled.
+    pub fn latest_ack_id(&self) -> String {
+        match &self.latest_ack_ids {
+            Some(v) => v
+                .latest_ack_ids
+                .get(&self.message_id)
+                .unwrap_or_else(|| self.ack_id.clone()),
+            None => self.ack_id.clone(),
+        }
+    }
+
+    pub async fn ack(&self) -> Result<(), PubSubError> {
+        if let Some(recent_acks) = &self.recent_acks {
+            if recent_acks.contains(&self.ack_id) {
+                tracing::trace!("skip redundant ack : ack_id={}", self.ack_id);
+                return Ok(());
+            }
+        }
+        let ack_id = self.latest_ack_id();
+        let result = self.send_ack(ack_id.clone()).await;
+        let ack_id = match result {
+            Ok(()) => ack_id,
+            Err(e) if self.idempotent_ack && e.status().is_some_and(|v| is_invalid_ack_id(v, &ack_id)) => {
+                tracing::debug!("treat invalid ack_id as acked : msg_id={}", self.message_id);
+                ack_id
+            }
+            Err(e) => {
+                // The ack_id may have become stale by the redelivery during the request.
+                let latest = self.latest_ack_id();
+                if latest == ack_id {
+                    return Err(e);
+                }
+                tracing::debug!("retry ack with the latest ack_id : msg_id={}", self.message_id);
+                ack(
+                    &self.subscriber_client,
+                    &self.subscription,
+                    vec![latest.clone()],
+                    self.ack_retry_setting(),
+                    self.clock(),
+                )
+                .await?;
+                latest
+            }
+        };
+        self.acked(&ack_id);
+        Ok(())
+    }
+
+    /// ack_with_result acks the message and reports the outcome of its ack_id for exactly-once subscriptions,
+    /// in which the server rejects the individual ack_ids with the reason instead of failing the request.
+    /// Err is returned only if the request itself failed. The outcome is always `Success` for the other subscriptions.
+    pub async fn ack_with_result(&self) -> Result<AckOutcome, PubSubError> {
+        let ack_id = self.latest_ack_id();
+        let result = self.send_ack(ack_id.clone()).await;
+        let outcome = ack_outcomes(result, std::slice::from_ref(&ack_id))?
+            .remove(&ack_id)
+            .unwrap_or(AckOutcome::Success);
+        if outcome == AckOutcome::Success {
+            self.acked(&ack_id);
+        }
+        Ok(outcome)
+    }
+
+    /// into_ack is the same as `ack` but consumes the handle to move the ack_id into the request instead of
+    /// cloning it, for the paths acking many messages without using the handles afterwards.
+    /// Only the ack_id is moved: the subscription is still copied into each request unless
+    /// `SubscriberConfig::ack_batching` is set, with which a batch of acks shares a single copy.
+    /// It falls back to `ack` when the subscriber still needs the ack_id after the request, i.e. with
+    /// `ack_dedup_capacity`, `track_latest_ack_id`, `idempotent_ack` or the lease extension.
+    pub async fn into_ack(mut self) -> Result<(), PubSubError> {
+        if self.tracks_ack_id() {
+            return self.ack().await;
+        }
+        let ack_id = std::mem::take(&mut self.ack_id);
+        self.send_ack(ack_id).await?;
+        // None of the state using the ack_id is set.
+        self.acked("");
+        Ok(())
+    }
+
+    /// send_ack sends the ack_id with `SubscriberConfig::ack_batching` if set, or with a request of its own.
+    async fn send_ack(&self, ack_id: String) -> Result<(), PubSubError> {
+        let ack_id = match &self.aggregator {
+            Some(aggregator) => match aggregator.enqueue(ack_id) {
+                Ok(result) => {
+                    return result
+                        .await
+                        .unwrap_or_else(|_| Err(Status::cancelled("ack batching stopped").into()))
+                }
+                // The batching stopped with the subscriber.
+                Err(ack_id) => ack_id,
+            },
+            None => ack_id,
+        };
+        let request = ack(
             &self.subscriber_client,
-            self.subscription.to_string(),
-            vec![self.ack_id.to_string()],
+            &self.subscription,
+            vec![ack_id],
+            self.ack_retry_setting(),
+            self.clock(),
+        );
+        AckLatency::timed(self.ack_latency.as_deref(), self.clock(), request).await
+    }
+
+    /// batch_ack_id returns the ack_id sent by the batch functions: the latest ack_id of the message,
+    /// or None for an ack of a message already acked, which `ack` skips too.
+    fn batch_ack_id(&self, is_nack: bool) -> Option<String> {
+        if let Some(recent_acks) = self.recent_acks.as_ref().filter(|_| !is_nack) {
+            if recent_acks.contains(&self.ack_id) {
+                tracing::trace!("skip redundant ack : ack_id={}", self.ack_id);
+                return None;
+            }
+        }
+        Some(self.latest_ack_id())
+    }
+
+    /// Whether the ack_id is used after the ack or nack request.
+    fn tracks_ack_id(&self) -> bool {
+        self.recent_acks.is_some() || self.latest_ack_ids.is_some() || self.leases.is_some() || self.idempotent_ack
+    }
+
+    /// acked updates the state tracking the message after its ack_id was acked.
+    fn acked(&self, ack_id: &str) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.settled();
+        }
+        if let Some(guard) = &self.latest_ack_ids {
+            guard.latest_ack_ids.remove(&self.message_id, ack_id);
+        }
+        if let Some(recent_acks) = &self.recent_acks {
+            recent_acks.insert(self.ack_id.clone());
+        }
+        if let Some(leases) = &self.leases {
+            leases.remove(&self.ack_id);
+        }
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.acked(&self.subscription, self.clock().now().duration_since(self.received_at));
+        }
+        if let Some(observer) = &self.observer {
+            let latency = self.clock().now().duration_since(self.received_at);
+            observer.on_acked(&self.subscription, &self.message_id, latency);
+        }
+    }
+
+    fn ack_retry_setting(&self) -> Option<RetrySetting> {
+        self.retry_settings.as_ref().and_then(|v| v.ack.clone())
+    }
+
+    fn modify_ack_deadline_retry_setting(&self) -> Option<RetrySetting> {
+        self.retry_settings.as_ref().and_then(|v| v.modify_ack_deadline.clone())
+    }
+
+    pub async fn nack(&self) -> Result<(), PubSubError> {
+        let ack_id = self.latest_ack_id();
+        nack(
+            &self.subscriber_client,
+            &self.subscription,
+            vec![ack_id.clone()],
+            self.modify_ack_deadline_retry_setting(),
+            self.clock(),
         )
-        .await
+        .await?;
+        self.nacked(&ack_id);
+        Ok(())
     }
 
-    pub async fn nack(&self) -> Result<(), Status> {
+    /// into_nack is the same as `nack` but consumes the handle like `into_ack`.
+    pub async fn into_nack(mut self) -> Result<(), PubSubError> {
+        if self.tracks_ack_id() {
+            return self.nack().await;
+        }
+        let ack_id = std::mem::take(&mut self.ack_id);
         nack(
             &self.subscriber_client,
-            self.subscription.to_string(),
-            vec![self.ack_id.to_string()],
+            &self.subscription,
+            vec![ack_id],
+            self.modify_ack_deadline_retry_setting(),
+            self.clock(),
         )
-        .await
+        .await?;
+        self.nacked("");
+        Ok(())
+    }
+
+    /// nacked updates the state tracking the message after its ack_id was nacked.
+    fn nacked(&self, ack_id: &str) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.settled();
+        }
+        if let Some(guard) = &self.latest_ack_ids {
+            guard.latest_ack_ids.remove(&self.message_id, ack_id);
+        }
+        if let Some(nacked) = &self.nacked {
+            nacked.store(true, Ordering::Relaxed);
+        }
+        if let Some(leases) = &self.leases {
+            leases.remove(&self.ack_id);
+        }
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.nacked(&self.subscription, 1);
+        }
+        if let Some(observer) = &self.observer {
+            observer.on_nacked(&self.subscription, &self.message_id);
+        }
     }
 
-    pub async fn modify_ack_deadline(&self, ack_deadline_seconds: i32) -> Result<(), Status> {
+    /// Fails with `PubSubError::DeadlineOutOfRange` without sending the request unless `ack_deadline_seconds` is between 0 and 600.
+    pub async fn modify_ack_deadline(&self, ack_deadline_seconds: i32) -> Result<(), PubSubError> {
+        check_ack_deadline(ack_deadline_seconds)?;
+        if let Some(control) = &self.control {
+            control
+                .modify_ack_deadline(self.latest_ack_id(), ack_deadline_seconds)
+                .await?;
+            return Ok(());
+        }
         modify_ack_deadline(
             &self.subscriber_client,
-            self.subscription.to_string(),
-            vec![self.ack_id.to_string()],
+            &self.subscription,
+            vec![self.latest_ack_id()],
             ack_deadline_seconds,
+            self.modify_ack_deadline_retry_setting(),
+            self.clock(),
         )
-        .await
+        .await?;
+        Ok(())
+    }
+
+    /// modify_ack_deadline_with_expiry is the same as `modify_ack_deadline` but returns the time when the new
+    /// ack deadline expires, to schedule the next extension or to give up the message before it is redelivered.
+    /// The time is computed before the request, so the actual deadline is a little later.
+    pub async fn modify_ack_deadline_with_expiry(&self, ack_deadline_seconds: i32) -> Result<Instant, PubSubError> {
+        let expiry = self.clock().now().into_std() + Duration::from_secs(ack_deadline_seconds.max(0) as u64);
+        self.modify_ack_deadline(ack_deadline_seconds).await?;
+        Ok(expiry)
+    }
+
+    /// Extends the deadline of the message by `lease_seconds` and returns the `HandOff` to pass to
+    /// another subscriber instance, which takes over acking the message with `Subscription::take_over`.
+    ///
+    /// The handing off is racy and the caller must take care of the following:
+    /// * The lease is not extended any more after the hand off, even with `SubscriberConfig::lease_extension`. The message is redelivered, possibly to
+    ///   this instance, unless the taker acks it or extends its deadline before `leased_until`.
+    ///   The time spent passing the `HandOff` through the coordination channel shortens the lease.
+    /// * `leased_until` is the clock of this instance, so a clock skew between the instances makes it inaccurate.
+    /// * This handle must not be used after the hand off. A nack makes the message redelivered while
+    ///   the taker is still processing it.
+    /// * With exactly-once delivery a redelivery invalidates the handed off ack_id, so the taker fails to ack
+    ///   the message once the lease has expired.
+    ///
+    /// Fails with `PubSubError::DeadlineOutOfRange` without sending the request unless `lease_seconds` is between 0 and 600.
+    pub async fn hand_off(&self, lease_seconds: i32) -> Result<HandOff, PubSubError> {
+        check_ack_deadline(lease_seconds)?;
+        // Computed before the request so that the actual lease lasts at least until this time.
+        let leased_until = self.clock().system_now() + Duration::from_secs(lease_seconds as u64);
+        let ack_id = self.latest_ack_id();
+        modify_ack_deadline(
+            &self.subscriber_client,
+            &self.subscription,
+            vec![ack_id.clone()],
+            lease_seconds,
+            self.modify_ack_deadline_retry_setting(),
+            self.clock(),
+        )
+        .await?;
+        if let Some(guard) = &self.latest_ack_ids {
+            guard.latest_ack_ids.remove(&self.message_id, &ack_id);
+        }
+        if let Some(leases) = &self.leases {
+            leases.remove(&self.ack_id);
+        }
+        Ok(HandOff {
+            subscription: self.subscription.clone(),
+            ack_id,
+            message_id: self.message_id.clone(),
+            leased_until,
+        })
+    }
+}
+
+/// AckRetrySettings are the retry settings of the requests sent by the `AckHandle`s of a subscriber.
+#[derive(Debug)]
+pub(crate) struct AckRetrySettings {
+    ack: Option<RetrySetting>,
+    modify_ack_deadline: Option<RetrySetting>,
+}
+
+impl AsRef<AckHandle> for AckHandle {
+    fn as_ref(&self) -> &AckHandle {
+        self
+    }
+}
+
+/// Extends the ack deadline of the message to `ack_deadline_seconds` every half of it for exactly as long as
+/// `handler` is pending, and returns the output of the handler so that the caller can ack or nack the message.
+/// The extension in flight is abandoned when the handler completes. A failed extension is logged and retried
+/// at the next interval, so the message may be redelivered while the handler is still running.
+/// Fails with `PubSubError::DeadlineOutOfRange` without polling the handler unless `ack_deadline_seconds`
+/// is between 0 and 600.
+/// ```
+/// use google_cloud_pubsub::error::PubSubError;
+/// use google_cloud_pubsub::subscriber::{with_lease_extension, ReceivedMessage};
+///
+/// async fn run(message: ReceivedMessage) -> Result<(), PubSubError> {
+///     let ok = with_lease_extension(&message, 60, async {
+///         // long running task
+///         true
+///     })
+///     .await?;
+///     if ok { message.ack().await } else { message.nack().await }
+/// }
+/// ```
+pub async fn with_lease_extension<F: Future>(
+    message: impl AsRef<AckHandle>,
+    ack_deadline_seconds: i32,
+    handler: F,
+) -> Result<F::Output, PubSubError> {
+    check_ack_deadline(ack_deadline_seconds)?;
+    let handle = message.as_ref();
+    let interval = Duration::from_secs((ack_deadline_seconds / 2).max(1) as u64);
+    tokio::pin!(handler);
+    loop {
+        select! {
+            output = &mut handler => return Ok(output),
+            _ = handle.clock().sleep(interval) => {}
+        }
+        select! {
+            output = &mut handler => return Ok(output),
+            result = handle.modify_ack_deadline(ack_deadline_seconds) => {
+                if let Err(e) = result {
+                    tracing::warn!("failed to extend the lease : msg_id={} {:?}", handle.message_id, e);
+                }
+            }
+        }
+    }
+}
+
+/// HandOff is the lease of a received message passed to another subscriber instance by `AckHandle::hand_off`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandOff {
+    /// The fully qualified name of the subscription.
+    pub subscription: String,
+    pub ack_id: String,
+    pub message_id: String,
+    /// The time until which the message is leased to the taker.
+    pub leased_until: SystemTime,
+}
+
+impl HandOff {
+    /// The remaining time of the lease at the system time of `clock`, zero if it has expired.
+    /// Pass the `SubscriberConfig::clock` of the subscriber, or `SystemClock`.
+    pub fn remaining_lease(&self, clock: &dyn Clock) -> Duration {
+        self.leased_until
+            .duration_since(clock.system_now())
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// AckOutcome is the result of acking an ack_id of an exactly-once subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckOutcome {
+    Success,
+    /// The server rejected the ack_id permanently, typically because its deadline expired.
+    /// The message will be redelivered and retrying the ack doesn't help.
+    InvalidAckId,
+    /// The server failed to process the ack_id temporarily, so the ack can be retried.
+    /// The transient failures are already retried with the backoff of the ack retry setting before being reported.
+    TransientFailure,
+}
+
+/// The attribute in which the Pub/Sub client libraries propagate the W3C trace context.
+#[cfg(feature = "opentelemetry")]
+const DEFAULT_TRACE_CONTEXT_ATTRIBUTE: &str = "googclient_traceparent";
+#[cfg(feature = "opentelemetry")]
+const TRACEPARENT: &str = "traceparent";
+
+/// parse_traceparent parses the `version-trace_id-parent_id-flags` format of the W3C trace context.
+/// The fields appended by the future versions are ignored.
+#[cfg(feature = "opentelemetry")]
+fn parse_traceparent(value: &str) -> Option<opentelemetry::trace::SpanContext> {
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+    let is_hex = |v: &str, len: usize| v.len() == len && v.bytes().all(|b| b.is_ascii_hexdigit());
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if !is_hex(version, 2) || version.eq_ignore_ascii_case("ff") || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    let context = SpanContext::new(
+        TraceId::from_hex(trace_id).ok()?,
+        SpanId::from_hex(span_id).ok()?,
+        TraceFlags::new(flags) & TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    context.is_valid().then_some(context)
+}
+
+/// The range of `stream_ack_deadline_seconds` accepted by the server.
+pub(crate) const MIN_STREAM_ACK_DEADLINE_SECONDS: i32 = 10;
+pub(crate) const MAX_STREAM_ACK_DEADLINE_SECONDS: i32 = 600;
+
+/// `max_outstanding_messages` below this is warned unless `SubscriberConfig::low_concurrency` is set.
+const LOW_MAX_OUTSTANDING_MESSAGES: i64 = 10;
+
+/// The maximum number of ack_ids sent in a single Acknowledge or ModifyAckDeadline request.
+pub(crate) const MAX_ACK_IDS_PER_REQUEST: usize = 2000;
+
+/// The attribute in which the copies republished by `ReceivedMessage::forward_to`, `Client::move_to_dead_letter`
+/// and `Client::move_to_retry_topic` carry the message_id of the original message.
+pub const ORIGINAL_MESSAGE_ID_ATTRIBUTE: &str = "original_message_id";
+
+#[derive(Debug)]
+pub struct ReceivedMessage {
+    pub message: PubsubMessage,
+    handle: AckHandle,
+    delivery_attempt: Option<usize>,
+    internal: Option<Box<InternalReceivedMessage>>,
+    deadline_attribute: Option<Arc<str>>,
+    #[cfg(feature = "opentelemetry")]
+    trace_context_attribute: Option<Arc<str>>,
+}
+
+impl ReceivedMessage {
+    pub(crate) fn new(
+        subscription: String,
+        subc: SubscriberClient,
+        message: PubsubMessage,
+        ack_id: String,
+        delivery_attempt: Option<usize>,
+    ) -> Self {
+        let message_id = message.message_id.clone();
+        Self {
+            message,
+            handle: AckHandle::new(subscription, subc, ack_id, message_id),
+            delivery_attempt,
+            internal: None,
+            deadline_attribute: None,
+            #[cfg(feature = "opentelemetry")]
+            trace_context_attribute: None,
+        }
+    }
+
+    /// Creates the message retaining the original `InternalReceivedMessage` returned by the server.
+    pub(crate) fn with_internal(
+        subscription: String,
+        subc: SubscriberClient,
+        internal: InternalReceivedMessage,
+    ) -> Self {
+        let mut msg = Self::new(
+            subscription,
+            subc,
+            internal.message.clone().unwrap_or_default(),
+            internal.ack_id.clone(),
+            (internal.delivery_attempt > 0).then_some(internal.delivery_attempt as usize),
+        );
+        msg.internal = Some(Box::new(internal));
+        msg
+    }
+
+    pub fn ack_id(&self) -> &str {
+        self.handle.ack_id()
+    }
+
+    pub fn subscription(&self) -> &str {
+        self.handle.subscription()
+    }
+
+    pub async fn ack(&self) -> Result<(), PubSubError> {
+        self.handle.ack().await
+    }
+
+    /// See `AckHandle::ack_with_result`.
+    pub async fn ack_with_result(&self) -> Result<AckOutcome, PubSubError> {
+        self.handle.ack_with_result().await
+    }
+
+    pub async fn nack(&self) -> Result<(), PubSubError> {
+        self.handle.nack().await
+    }
+
+    /// See `AckHandle::into_ack`.
+    pub async fn into_ack(self) -> Result<(), PubSubError> {
+        self.handle.into_ack().await
+    }
+
+    /// See `AckHandle::into_nack`.
+    pub async fn into_nack(self) -> Result<(), PubSubError> {
+        self.handle.into_nack().await
+    }
+
+    pub async fn modify_ack_deadline(&self, ack_deadline_seconds: i32) -> Result<(), PubSubError> {
+        self.handle.modify_ack_deadline(ack_deadline_seconds).await
+    }
+
+    /// See `AckHandle::modify_ack_deadline_with_expiry`.
+    pub async fn modify_ack_deadline_with_expiry(&self, ack_deadline_seconds: i32) -> Result<Instant, PubSubError> {
+        self.handle.modify_ack_deadline_with_expiry(ack_deadline_seconds).await
+    }
+
+    /// See `AckHandle::hand_off`.
+    pub async fn hand_off(&self, lease_seconds: i32) -> Result<HandOff, PubSubError> {
+        self.handle.hand_off(lease_seconds).await
+    }
+
+    /// forward_to publishes a copy of the message with the publisher, e.g. to the dead letter topic of a subscription
+    /// without a dead letter policy, and acks the original message after the copy is published.
+    /// It returns the message_id of the copy. See `republish` for the attributes of the copy and the ack.
+    pub async fn forward_to(&self, publisher: &Publisher) -> Result<String, PubSubError> {
+        self.republish(None, |copy| async move { Ok(publisher.publish(copy).await.get().await?) })
+            .await
+    }
+
+    /// republish publishes a copy of the message with `publish` and acks the original message after the copy is
+    /// published. The copy carries the attributes of the message, overwritten by `attribute`, and the message_id
+    /// of the original message in `ORIGINAL_MESSAGE_ID_ATTRIBUTE` unless the message is already a copy.
+    /// The original message is not acked if the publish fails. The failure of the ack is only logged, because
+    /// returning an error for the published copy makes the caller retry and publish it again.
+    pub(crate) async fn republish<F, Fut>(
+        &self,
+        attribute: Option<(String, String)>,
+        publish: F,
+    ) -> Result<String, PubSubError>
+    where
+        F: FnOnce(PubsubMessage) -> Fut,
+        Fut: Future<Output = Result<String, PubSubError>>,
+    {
+        let mut attributes = self.message.attributes.clone();
+        attributes
+            .entry(ORIGINAL_MESSAGE_ID_ATTRIBUTE.to_string())
+            .or_insert_with(|| self.message.message_id.clone());
+        attributes.extend(attribute);
+        let copy = PubsubMessage {
+            data: self.message.data.clone(),
+            attributes,
+            ordering_key: self.message.ordering_key.clone(),
+            ..Default::default()
+        };
+        let message_id = publish(copy).await?;
+        if let Err(e) = self.ack().await {
+            tracing::warn!(
+                "failed to ack the message {} republished as {} : {:?}",
+                self.message.message_id,
+                message_id,
+                e
+            );
+        }
+        Ok(message_id)
+    }
+
+    /// Returns the flag set when the message is nacked through any clone of its handle.
+    pub(crate) fn watch_nack(&mut self) -> Arc<AtomicBool> {
+        self.handle.nacked.get_or_insert_with(Default::default).clone()
+    }
+
+    /// The handle used to ack the message.
+    pub fn handle(&self) -> &AckHandle {
+        &self.handle
+    }
+
+    /// Splits the message into its payload and the handle used to ack it.
+    pub fn into_parts(self) -> (PubsubMessage, AckHandle) {
+        (self.message, self.handle)
     }
 
     /// The approximate number of times that Cloud Pub/Sub has attempted to deliver
@@ -83,13 +679,189 @@ impl ReceivedMessage {
     pub fn delivery_attempt(&self) -> Option<usize> {
         self.delivery_attempt
     }
+
+    /// Whether the message has been delivered at least `max_attempts` times, i.e. it will be moved to
+    /// the dead letter topic if it is nacked again when `max_attempts` is the `max_delivery_attempts` of the
+    /// dead letter policy. Always false for the subscriptions without a dead letter policy,
+    /// since the server doesn't populate `delivery_attempt` for them.
+    pub fn is_near_dead_letter(&self, max_attempts: usize) -> bool {
+        self.delivery_attempt.is_some_and(|attempt| attempt >= max_attempts)
+    }
+
+    /// The number of bytes of the message data and the attribute keys and values.
+    pub fn size(&self) -> usize {
+        self.message.data.len() + attributes_size(&self.message.attributes)
+    }
+
+    /// The time at which the message was published, populated by the server.
+    /// Returns None if the field is not set.
+    pub fn publish_time(&self) -> Option<SystemTime> {
+        self.message.publish_time.and_then(|t| SystemTime::try_from(t).ok())
+    }
+
+    /// The same as `publish_time` but as the chrono `DateTime`.
+    #[cfg(feature = "chrono")]
+    pub fn publish_time_utc(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        let mut t = self.message.publish_time?;
+        t.normalize();
+        chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32)
+    }
+
+    /// The time by which the message must be processed, in the attribute named by
+    /// `SubscriberConfig::deadline_attribute` formatted in RFC 3339, e.g. "2024-01-01T00:00:00Z".
+    /// Returns None if the attribute is not configured or not set. A malformed value is logged and treated as None.
+    pub fn message_deadline(&self) -> Option<SystemTime> {
+        let value = self.message.attributes.get(self.deadline_attribute.as_deref()?)?;
+        let deadline = value
+            .parse::<prost_types::Timestamp>()
+            .ok()
+            .and_then(|t| SystemTime::try_from(t).ok());
+        if deadline.is_none() {
+            tracing::warn!("invalid deadline attribute {value} : msg_id={}", self.message.message_id);
+        }
+        deadline
+    }
+
+    /// The trace context of the publish span propagated by the producer in the W3C `traceparent` format,
+    /// so that the span of the handler can be its child. The attribute named by `SubscriberConfig::trace_context_attribute`
+    /// is read if set, otherwise `googclient_traceparent` and then `traceparent`.
+    /// Returns None if the attribute is missing or malformed.
+    #[cfg(feature = "opentelemetry")]
+    pub fn extract_trace_context(&self) -> Option<opentelemetry::trace::SpanContext> {
+        let attributes = &self.message.attributes;
+        let value = match self.trace_context_attribute.as_deref() {
+            Some(key) => attributes.get(key),
+            None => attributes
+                .get(DEFAULT_TRACE_CONTEXT_ATTRIBUTE)
+                .or_else(|| attributes.get(TRACEPARENT)),
+        }?;
+        let context = parse_traceparent(value);
+        if context.is_none() {
+            tracing::debug!("invalid trace context attribute {value} : msg_id={}", self.message.message_id);
+        }
+        context
+    }
+
+    /// The number of retries carried in the attribute `key` across the topics the message was moved through.
+    /// Unlike `delivery_attempt` it persists when the message is republished, e.g. by `Client::move_to_retry_topic`.
+    /// Returns 0 if the attribute is not set. A malformed value is logged and treated as 0.
+    pub fn retry_count_from_attr(&self, key: &str) -> u32 {
+        let Some(value) = self.message.attributes.get(key) else {
+            return 0;
+        };
+        value.parse().unwrap_or_else(|_| {
+            tracing::warn!("invalid retry count attribute {value} : msg_id={}", self.message.message_id);
+            0
+        })
+    }
+
+    /// The ordering key of the message, or None if the message was published without one.
+    /// When the subscription has message ordering enabled, the messages with the same key are delivered in order:
+    /// nacking one of them causes it and the subsequent messages with the key to be redelivered,
+    /// so they are blocked until it is acked.
+    pub fn ordering_key(&self) -> Option<&str> {
+        Some(self.message.ordering_key.as_str()).filter(|key| !key.is_empty())
+    }
+
+    /// The attributes set by the producer.
+    pub fn attributes(&self) -> &HashMap<String, String> {
+        &self.message.attributes
+    }
+
+    /// The value of the attribute `key`, or None if it is not set.
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.message.attributes.get(key).map(String::as_str)
+    }
+
+    /// attribute_ignore_ascii_case is the same as `attribute` but compares the keys case-insensitively
+    /// for the producers normalizing the keys inconsistently. The exact match is preferred, otherwise
+    /// which one of the keys differing only in case is returned is unspecified.
+    pub fn attribute_ignore_ascii_case(&self, key: &str) -> Option<&str> {
+        self.attribute(key).or_else(|| {
+            self.message
+                .attributes
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.as_str())
+        })
+    }
+
+    /// Decodes the data of the message as JSON.
+    /// Empty data fails with the EOF error of serde_json like any other malformed payload.
+    #[cfg(feature = "json")]
+    pub fn deserialize_json<T: serde::de::DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_slice(&self.message.data)
+    }
+
+    /// The data of the message inflated if the `content-encoding` attribute is `gzip`, as compressed by
+    /// a publisher with `PublisherConfig::gzip_level`. The data is returned as is without the attribute,
+    /// so the messages of the producers without the compression are read the same way.
+    /// An unsupported encoding or malformed data fails with `InvalidData`.
+    #[cfg(feature = "gzip")]
+    pub fn data_decompressed(&self) -> std::io::Result<std::borrow::Cow<'_, [u8]>> {
+        use std::io::Read;
+
+        use crate::publisher::{CONTENT_ENCODING, GZIP};
+
+        match self.attribute(CONTENT_ENCODING) {
+            None => Ok(std::borrow::Cow::Borrowed(&self.message.data[..])),
+            Some(GZIP) => {
+                let mut data = Vec::new();
+                flate2::read::GzDecoder::new(&self.message.data[..])
+                    .read_to_end(&mut data)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                Ok(std::borrow::Cow::Owned(data))
+            }
+            Some(encoding) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported {CONTENT_ENCODING}: {encoding}"),
+            )),
+        }
+    }
+
+    /// Returns true if the message has no data, such as a message that only carries attributes.
+    pub fn is_empty_payload(&self) -> bool {
+        self.message.data.is_empty()
+    }
+
+    /// Counts the message in `in_flight` until the consumer releases it.
+    pub(crate) fn track_in_flight(&mut self, in_flight: &Arc<InFlight>) {
+        self.handle.in_flight = Some(Arc::new(InFlightGuard::new(in_flight.clone(), self.ack_id())));
+    }
+
+    /// The original `google_cloud_googleapis::pubsub::v1::ReceivedMessage` returned by the server,
+    /// to access the fields not exposed by `ReceivedMessage`.
+    /// Returns None unless `SubscriberConfig::retain_internal_message` is enabled.
+    pub fn as_internal(&self) -> Option<&InternalReceivedMessage> {
+        self.internal.as_deref()
+    }
+}
+
+impl AsRef<AckHandle> for ReceivedMessage {
+    fn as_ref(&self) -> &AckHandle {
+        &self.handle
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SubscriberConfig {
     /// ping interval for Bi Directional Streaming
     pub ping_interval: Duration,
+    /// Randomizes each ping interval by up to this ratio of `ping_interval` in both directions,
+    /// e.g. 0.2 for ±20%, so that the pings of many subscribers are not synchronized.
+    /// The ratio is capped at 1.0. The pings are sent at the fixed interval if None.
+    pub ping_jitter: Option<f64>,
+    /// The retry setting of the streaming pull. `PubsubRetry` builds one from the defaults.
     pub retry_setting: Option<RetrySetting>,
+    /// The retry setting of the Acknowledge requests sent by `ReceivedMessage::ack`, e.g. to give up on
+    /// FAILED_PRECONDITION of exactly-once subscriptions. The default retry setting of the client is used if None.
+    pub ack_retry_setting: Option<RetrySetting>,
+    /// The retry setting of the ModifyAckDeadline requests sent by `ReceivedMessage::nack`, `modify_ack_deadline`
+    /// and `hand_off`. The default retry setting of the client is used if None.
+    pub modify_ack_deadline_retry_setting: Option<RetrySetting>,
+    /// The delay between the attempts to re-establish the streaming pull after it failed with a retryable code
+    /// or stalled for `stall_after_pings`. The consecutive stalls of the streams that received nothing grow the delay.
+    pub reconnect_backoff: ReconnectBackoff,
     /// It is important for exactly_once_delivery
     /// The ack deadline to use for the stream. This must be provided in
     /// the first request on the stream, but it can also be updated on subsequent
@@ -105,304 +877,5103 @@ pub struct SubscriberConfig {
     /// property can only be set on the initial StreamingPullRequest. If it is set
     /// on a subsequent request, the stream will be aborted with status
     /// `INVALID_ARGUMENT`.
+    /// The limits apply to each stream: with `streams` the client holds up to that many times the limits.
+    /// See `FlowControl::per_stream`.
     pub max_outstanding_messages: i64,
     pub max_outstanding_bytes: i64,
+    /// Opts into a `max_outstanding_messages` below 10, e.g. to process a few messages at a time with low latency.
+    /// Otherwise such a low value, which severely limits the throughput, is warned when the subscriber starts.
+    pub low_concurrency: bool,
+    /// The number of messages buffered locally before applying backpressure to the stream, which is the capacity
+    /// of the channel between the stream and the consumers. While `max_outstanding_messages` limits the messages
+    /// the server may have in flight to the subscriber, this limits the memory used by the messages not yet taken.
+    /// `SubscribeConfig::with_channel_capacity` and `ReceiveConfig::channel_capacity` take precedence if set.
+    /// If None, the buffer is bounded by `max_outstanding_messages` when it starts, and unbounded only if it is not positive.
+    pub prefetch_messages: Option<usize>,
+    /// Messages published more than `max_message_age` ago are acked and dropped
+    /// without being delivered. Messages without publish_time are always delivered.
+    pub max_message_age: Option<Duration>,
+    /// Messages exceeding the limits are rejected before being delivered. No limit by default.
+    pub attribute_limits: AttributeLimits,
+    /// When the subscriber is resumed after being paused for longer than this, the stream is
+    /// re-established with the current flow control settings instead of continuing the paused one,
+    /// because the server's view of the stream may be stale after a long pause.
+    /// The paused stream is always continued if None.
+    pub reconnect_after_pause: Option<Duration>,
+    /// Re-establishes the stream when nothing is received on it while this many pings are sent, i.e. for
+    /// `ping_interval` times this, which guards against the half-open connections that never fail.
+    /// The server sends nothing while no message is available or `max_outstanding_messages` is reached, so
+    /// an idle stream is also re-established: set it well above the expected gap between the messages.
+    /// Never if None.
+    pub stall_after_pings: Option<u32>,
+    /// Drains the backlog with the flow control limits of the backfill first, then re-establishes the stream
+    /// with `max_outstanding_messages` and `max_outstanding_bytes` for the live traffic.
+    pub backfill: Option<Backfill>,
+    /// How the stream chooses a channel from the connection pool. This matters when
+    /// multiple streams are opened, for example by `SubscribeConfig::with_enable_multiple_subscriber`.
+    pub channel_selection: ChannelSelection,
+    /// The number of streams opened by `Subscription::subscribe` and by `Subscription::receive` without
+    /// message ordering, each on a channel of the connection pool chosen by `channel_selection`.
+    /// The streams deliver the messages to the same queue, so opening multiple streams scales the throughput.
+    /// The flow control limits are per stream.
+    /// If None, `subscribe` opens one stream, or one per channel with `SubscribeConfig::with_enable_multiple_subscriber`,
+    /// and `receive` opens one per worker. `receive` with message ordering always opens one per worker
+    /// so that the messages of an ordering key are handled by a single worker.
+    pub streams: Option<usize>,
+    /// The number of recently acked ack_ids remembered by the subscriber in order to skip
+    /// redundant Acknowledge requests when a message is acked more than once. Disabled if None.
+    pub ack_dedup_capacity: Option<usize>,
+    /// Called once when the streaming pull task stops.
+    pub on_stopped: Option<Hook<StopReason>>,
+    /// Called with every response received on the stream before the messages are handled,
+    /// for diagnostics such as the batch sizes, the confirmations and the subscription properties.
+    pub on_response: Option<Hook<StreamingPullResponse>>,
+    /// Delays the delivery of each redelivered message, i.e. with `delivery_attempt` greater than 1, by a random time
+    /// up to this, so that the messages redelivered at once after a reconnect don't hit the handlers at the same time.
+    /// Only the redelivered message waits; the first deliveries and the stream are not held back by the delay.
+    /// The delay counts against the ack deadline, so keep it well below `stream_ack_deadline_seconds`.
+    /// The server only populates `delivery_attempt` for the subscriptions with a dead letter policy, so the other
+    /// messages are never delayed. No delay if None.
+    pub redelivery_jitter: Option<Duration>,
+    /// Rejects messages with empty data instead of delivering them to the handler.
+    /// Messages with empty data are delivered like any other message if None.
+    pub reject_empty_payload: Option<RejectPolicy>,
+    /// Acks, nacks and extends the deadline with the ack_id of the latest delivery of the message.
+    /// With exactly-once delivery the ack_id of a redelivered message invalidates the previous one,
+    /// so a handler still holding the previous delivery would fail to ack it.
+    pub track_latest_ack_id: bool,
+    /// Sends `modify_ack_deadline` of the received messages on the stream instead of the unary RPC.
+    /// With exactly-once delivery it waits for the confirmation returned on the stream.
+    pub stream_modify_ack_deadline: bool,
+    /// Treats the ack failing with an invalid ack_id as success for exactly-once subscriptions,
+    /// so that acking a message again, e.g. in a retry after an ambiguous failure, doesn't return an error.
+    /// The server doesn't distinguish an already acked ack_id from an expired one, so the ack of
+    /// an expired ack_id also succeeds even though the message will be redelivered.
+    pub idempotent_ack: bool,
+    /// Fails to subscribe with FAILED_PRECONDITION if the subscription has no dead letter policy,
+    /// because the server doesn't populate `ReceivedMessage::delivery_attempt` without it.
+    pub require_delivery_attempt: bool,
+    /// Messages delivered at least `max_delivery_attempts` times are passed to `on_near_dead_letter` instead of
+    /// the consumers, so that they can be handled before being moved to the dead letter topic.
+    /// See `ReceivedMessage::is_near_dead_letter`. Nothing is routed unless both are set.
+    pub max_delivery_attempts: Option<usize>,
+    pub on_near_dead_letter: Option<MessageRoute>,
+    /// The name of the attribute in which the producers set the time by which the message must be processed.
+    /// See `ReceivedMessage::message_deadline`.
+    pub deadline_attribute: Option<String>,
+    /// Messages past the deadline in `deadline_attribute` are acked and dropped without being delivered.
+    pub drop_past_deadline: bool,
+    /// Retains the original `InternalReceivedMessage` in the received message so that it can be
+    /// accessed by `ReceivedMessage::as_internal`. The message is copied, so it doubles the memory used.
+    pub retain_internal_message: bool,
+    /// Whether the acks or the nacks are sent first when both are pending, such as the acks of the completed
+    /// messages and the nacks of the outstanding messages on shutdown. See `MessageStream::dispose_with`.
+    pub shutdown_order: ShutdownOrder,
+    /// What to do with the received messages that can't be delivered because the subscriber is cancelled.
+    pub on_cancel_policy: CancelPolicy,
+    /// Extends the ack deadline of the delivered messages until they are acked or nacked,
+    /// so that a handler running longer than `stream_ack_deadline_seconds` doesn't get the message redelivered.
+    /// The deadlines are only extended by the ack deadline of the stream if None.
+    pub lease_extension: Option<LeaseConfig>,
+    /// Batches the acks of the delivered messages into a single Acknowledge request instead of sending one per
+    /// `ReceivedMessage::ack`, which cuts the requests of the busy subscriptions. The ack waits for the batch
+    /// to be sent and returns its result. The pending acks are sent when the subscriber stops.
+    pub ack_batching: Option<AckBatchConfig>,
+    /// Records the latency of the Acknowledge requests sent by `ReceivedMessage::ack` in a histogram, read with
+    /// `MessageStream::ack_latency_percentiles`, to spot the slow acks that make the messages redelivered.
+    pub record_ack_latency: bool,
+    /// Makes the streaming pull fail with the injected codes in order to test the reconnect logic.
+    #[cfg(feature = "fault-injection")]
+    pub fault_injector: Option<FaultInjector>,
+    /// Records the received, acked and nacked messages, the reconnects and the ack latency
+    /// as OpenTelemetry metrics with the meter. Nothing is recorded if None.
+    #[cfg(feature = "opentelemetry")]
+    pub meter: Option<opentelemetry::metrics::Meter>,
+    /// The name of the attribute from which `ReceivedMessage::extract_trace_context` reads the trace context.
+    /// `googclient_traceparent` and `traceparent` are read if None.
+    #[cfg(feature = "opentelemetry")]
+    pub trace_context_attribute: Option<String>,
+    /// Notified of the received, acked and nacked messages and the reconnects of the stream.
+    /// Use `MessageStream::stats` for the number of the messages waiting in the queue.
+    pub observer: Option<Arc<dyn SubscriberObserver>>,
+    /// The source of all the time read and waited for by the subscriber and the handles of its messages: the pings,
+    /// the reconnect backoff, the lease extension, the stall and pause timers, the age of the messages, the retries
+    /// and the batching of the acks, the ack latency and the grace periods of the `MessageStream`, e.g. to drive them
+    /// deterministically in tests. `SystemClock` if None.
+    pub clock: Option<Arc<dyn Clock>>,
 }
 
-impl Default for SubscriberConfig {
-    fn default() -> Self {
-        Self {
-            ping_interval: std::time::Duration::from_secs(10),
-            retry_setting: Some(default_retry_setting()),
-            stream_ack_deadline_seconds: 60,
-            max_outstanding_messages: 50,
-            max_outstanding_bytes: 1000 * 1000 * 1000,
-        }
-    }
+/// FaultPoint is the place where the `FaultInjector` makes the streaming pull fail.
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// Starting the streaming pull returns the error.
+    StreamingPull,
+    /// Reading the next response from the established stream returns the error.
+    /// The server may keep the messages delivered to the abandoned stream until their ack deadline.
+    Recv,
 }
 
-#[derive(Debug)]
-pub(crate) struct Subscriber {
-    pinger: Option<JoinHandle<()>>,
-    inner: Option<JoinHandle<()>>,
+/// FaultInjector holds the faults to be returned by the streaming pull.
+/// Each injected fault is returned once, in the order they were injected for the same point.
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<HashMap<FaultPoint, VecDeque<Code>>>>,
 }
 
-impl Subscriber {
-    pub fn start(
-        ctx: CancellationToken,
-        subscription: String,
-        client: SubscriberClient,
-        queue: async_channel::Sender<ReceivedMessage>,
-        config: SubscriberConfig,
-    ) -> Self {
-        let (ping_sender, ping_receiver) = async_channel::unbounded();
+#[cfg(feature = "fault-injection")]
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // ping request
-        let subscription_clone = subscription.to_string();
+    /// Queues a fault with the code at the point.
+    pub fn inject(&self, point: FaultPoint, code: Code) {
+        self.faults.lock().unwrap().entry(point).or_default().push_back(code);
+    }
 
-        let cancel_receiver = ctx.clone();
-        let pinger = tokio::spawn(async move {
-            loop {
-                select! {
+    /// The number of faults not yet returned at the point.
+    pub fn remaining(&self, point: FaultPoint) -> usize {
+        self.faults.lock().unwrap().get(&point).map_or(0, |v| v.len())
+    }
+
+    pub(crate) fn take(&self, point: FaultPoint) -> Option<Status> {
+        let code = self.faults.lock().unwrap().get_mut(&point)?.pop_front()?;
+        Some(Status::new(code, format!("injected fault at {point:?}")))
+    }
+}
+
+/// Hook is a callback stored in the `SubscriberConfig`.
+/// It is called synchronously from the subscriber task, so it must not block.
+pub struct Hook<T>(Arc<dyn Fn(&T) + Send + Sync>);
+
+impl<T> Hook<T> {
+    pub fn new(f: impl Fn(&T) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, v: &T) {
+        (self.0)(v)
+    }
+}
+
+impl<T> Clone for Hook<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> std::fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// SubscriberObserver is notified of the lifecycle events of the messages to emit custom metrics.
+/// The methods are called synchronously from the subscriber task and the ack/nack calls, so they must be
+/// cheap and must not block. All the methods do nothing by default.
+pub trait SubscriberObserver: Send + Sync {
+    /// Called when a message is received, before it is queued to the handler.
+    fn on_received(&self, _subscription: &str, _message_id: &str) {}
+    /// Called when a message is acked with the elapsed time since it was received, by the handler or by the
+    /// subscriber when it drops an expired or discarded message. It is called only after the ack succeeded.
+    fn on_acked(&self, _subscription: &str, _message_id: &str, _latency: Duration) {}
+    /// Called when a message is nacked by the handler or by the subscriber on the cancellation or rejection.
+    fn on_nacked(&self, _subscription: &str, _message_id: &str) {}
+    /// Called when the streaming pull is reconnected with the reason.
+    fn on_stream_reconnect(&self, _subscription: &str, _reason: &ReconnectReason) {}
+    /// Called after `on_stream_reconnect` with the number of the attempt, e.g. to expect the redelivery of
+    /// the messages delivered but not yet acked before the reconnect when deduplicating them.
+    fn on_stream_reconnected(&self, _subscription: &str, _event: &StreamReconnected) {}
+}
+
+impl std::fmt::Debug for dyn SubscriberObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SubscriberObserver")
+    }
+}
+
+/// Clock is the source of the time read and waited for by the subscriber.
+/// A test can advance a manual clock instead of sleeping, or pause the tokio clock with `tokio::time::pause`,
+/// which `SystemClock` follows.
+pub trait Clock: Send + Sync {
+    /// The monotonic time from which the deadlines and the elapsed times are computed.
+    fn now(&self) -> tokio::time::Instant;
+    /// The wall-clock time compared with the publish time and the deadline of the messages.
+    fn system_now(&self) -> SystemTime;
+    /// Completes when `now` reaches the deadline.
+    fn sleep_until(&self, deadline: tokio::time::Instant) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// SystemClock is the default `Clock`: the tokio clock and the system time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> tokio::time::Instant {
+        tokio::time::Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: tokio::time::Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(sleep_until(deadline))
+    }
+}
+
+/// MessageRoute takes over the messages diverted from the consumers by the `SubscriberConfig`.
+/// It is called synchronously from the subscriber task, so it must not block: pass the message to
+/// a channel or a spawned task to ack or nack it.
+pub struct MessageRoute(Arc<dyn Fn(ReceivedMessage) + Send + Sync>);
+
+impl MessageRoute {
+    pub fn new(f: impl Fn(ReceivedMessage) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, v: ReceivedMessage) {
+        (self.0)(v)
+    }
+}
+
+impl Clone for MessageRoute {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl std::fmt::Debug for MessageRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MessageRoute")
+    }
+}
+
+/// ReconnectReason is the reason why the streaming pull is re-established.
+#[derive(Debug, Clone)]
+pub enum ReconnectReason {
+    /// The stream or the request to start it failed with a retryable error.
+    Error(Status),
+    /// Nothing was received on the stream, or the request to start it was not responded,
+    /// for `SubscriberConfig::stall_after_pings` pings.
+    Stalled,
+    /// The stream is re-established with the current flow control, e.g. by `apply_config`,
+    /// after `reconnect_after_pause` or when the backlog of `backfill` is drained.
+    Refresh,
+}
+
+/// StreamReconnected is the event of `SubscriberObserver::on_stream_reconnected`.
+#[derive(Debug, Clone)]
+pub struct StreamReconnected {
+    /// The number of the consecutive failed or stalled streams, including the last one, since a stream was
+    /// established or received a response. 0 for `ReconnectReason::Refresh`.
+    pub attempt: u32,
+    /// The retryable error of the last stream, None if it stalled or was refreshed.
+    pub last_error: Option<Status>,
+}
+
+/// StopReason is the reason why the streaming pull task stopped.
+#[derive(Debug, Clone)]
+pub enum StopReason {
+    /// Stopped by the cancellation token or by the server closing the stream.
+    GracefulShutdown,
+    /// Stopped by a non-retryable error.
+    TerminalError(Status),
+    /// Stopped because the stream could not be re-established within the retry limit.
+    MaxReconnectsExceeded,
+    /// Stopped because all the receivers of the queue were dropped without the cancellation,
+    /// typically by a consumer that stopped reading the messages.
+    QueueClosed,
+}
+
+/// RejectPolicy decides what the subscriber does with a message it refuses to deliver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RejectPolicy {
+    /// Nack the message so that it is redelivered or forwarded to the dead letter topic.
+    #[default]
+    Nack,
+    /// Ack the message so that it is never redelivered.
+    Discard,
+}
+
+/// ShutdownOrder decides whether the pending acks or nacks are sent first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShutdownOrder {
+    /// Ack first to commit the completed work before the outstanding messages are redelivered,
+    /// which minimizes the redelivery of the messages already processed.
+    #[default]
+    AcksFirst,
+    /// Nack first so that the outstanding messages are redelivered to the other subscribers as soon as possible.
+    NacksFirst,
+}
+
+/// CancelPolicy decides what the subscriber does with the messages it can't deliver because it is cancelled
+/// or the queue is closed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CancelPolicy {
+    /// Nack the messages so that they are redelivered as soon as possible.
+    #[default]
+    NackImmediately,
+    /// Send nothing and let the messages be redelivered after their ack deadline.
+    /// This avoids a burst of ModifyAckDeadline requests when many subscribers are stopped at once, e.g. on deploy.
+    LetDeadlineExpire,
+    /// Extend the ack deadline of the messages by the seconds, which delays their redelivery.
+    ExtendDeadline(i32),
+}
+
+/// ReconnectBackoff is the exponential backoff of the reconnects of the streaming pull.
+/// The delay is reset once a stream is established.
+#[derive(Debug, Clone)]
+pub struct ReconnectBackoff {
+    /// The delay before the first reconnect, doubled on each consecutive failure.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Randomizes each delay by up to this ratio of itself in both directions, e.g. 0.2 for ±20%,
+    /// so that the subscribers disconnected at once don't reconnect at the same time.
+    pub jitter: Option<f64>,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: Some(0.2),
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    /// Returns the delay before the reconnect after `failures` consecutive failures.
+    fn delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_delay);
+        jittered(delay, self.jitter)
+    }
+}
+
+/// Backfill is the first phase of the subscriber draining the existing backlog with larger flow control limits.
+/// With `Subscription::receive` the number of messages handled concurrently is also bounded by the limits,
+/// so `ReceiveConfig::worker_count` should be sized for the backfill.
+#[derive(Debug, Clone)]
+pub struct Backfill {
+    /// `SubscriberConfig::max_outstanding_messages` used while draining the backlog.
+    pub max_outstanding_messages: i64,
+    /// `SubscriberConfig::max_outstanding_bytes` used while draining the backlog.
+    pub max_outstanding_bytes: i64,
+    /// The backlog is considered drained when at most `drained_messages` are received within a window.
+    pub window: Duration,
+    pub drained_messages: u64,
+}
+
+impl Default for Backfill {
+    fn default() -> Self {
+        Self {
+            max_outstanding_messages: 1000,
+            max_outstanding_bytes: 1000 * 1000 * 1000,
+            window: Duration::from_secs(10),
+            drained_messages: 0,
+        }
+    }
+}
+
+/// LeaseConfig is the lease management of `SubscriberConfig::lease_extension`.
+#[derive(Debug, Clone)]
+pub struct LeaseConfig {
+    /// How often the deadlines of the outstanding messages are extended by `stream_ack_deadline_seconds`.
+    /// It must be shorter than `stream_ack_deadline_seconds` to extend them before they expire.
+    pub extension_interval: Duration,
+    /// The messages outstanding for longer than this are not extended any more and are redelivered after
+    /// the ack deadline, so that a stuck handler doesn't hold a message forever.
+    pub max_lease_duration: Duration,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        Self {
+            extension_interval: Duration::from_secs(30),
+            max_lease_duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// AckBatchConfig is the batching of `SubscriberConfig::ack_batching`.
+#[derive(Debug, Clone)]
+pub struct AckBatchConfig {
+    /// The maximum number of ack_ids sent together, capped at 2000 ack_ids per request.
+    pub max_ack_batch: usize,
+    /// How long the first ack of a batch waits for the batch to fill before it is sent.
+    pub max_ack_latency: Duration,
+}
+
+impl Default for AckBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_ack_batch: 1000,
+            max_ack_latency: Duration::from_millis(100),
+        }
+    }
+}
+
+/// AttributeLimits protects handlers from messages with pathological attributes.
+#[derive(Debug, Clone, Default)]
+pub struct AttributeLimits {
+    /// The maximum number of attributes.
+    pub max_count: Option<usize>,
+    /// The maximum total bytes of the attribute keys and values.
+    pub max_bytes: Option<usize>,
+    pub policy: RejectPolicy,
+}
+
+impl AttributeLimits {
+    fn is_exceeded(&self, attributes: &HashMap<String, String>) -> bool {
+        if self.max_count.is_some_and(|max| attributes.len() > max) {
+            return true;
+        }
+        match self.max_bytes {
+            Some(max) => attributes_size(attributes) > max,
+            None => false,
+        }
+    }
+}
+
+impl SubscriberConfig {
+    /// Validates the fields that the server would reject with INVALID_ARGUMENT on the first request,
+    /// and the ones that would break the subscriber, returning `PubSubError::ConfigInvalid` with the name of the field.
+    /// It is called when subscribing and by `apply_config`, so calling it beforehand is only needed to check
+    /// the config up front, e.g. when loading it. The negative flow control limits, which the server treats
+    /// as no limit like 0, are warned.
+    pub fn validate(&self) -> Result<(), PubSubError> {
+        let range = MIN_STREAM_ACK_DEADLINE_SECONDS..=MAX_STREAM_ACK_DEADLINE_SECONDS;
+        if !range.contains(&self.stream_ack_deadline_seconds) {
+            return Err(PubSubError::ConfigInvalid(format!(
+                "stream_ack_deadline_seconds must be between {} and {} but was {}",
+                range.start(),
+                range.end(),
+                self.stream_ack_deadline_seconds
+            )));
+        }
+        if let Some(lease) = &self.lease_extension {
+            let deadline = Duration::from_secs(self.stream_ack_deadline_seconds as u64);
+            if lease.extension_interval.is_zero() || lease.extension_interval >= deadline {
+                return Err(PubSubError::ConfigInvalid(format!(
+                    "lease_extension.extension_interval must be shorter than stream_ack_deadline_seconds={} but was {:?}",
+                    self.stream_ack_deadline_seconds, lease.extension_interval
+                )));
+            }
+        }
+        if self.ping_interval.is_zero() {
+            return Err(PubSubError::ConfigInvalid("ping_interval must be positive".to_string()));
+        }
+        if self.stall_after_pings == Some(0) {
+            return Err(PubSubError::ConfigInvalid("stall_after_pings must be positive".to_string()));
+        }
+        if self.prefetch_messages == Some(0) {
+            return Err(PubSubError::ConfigInvalid("prefetch_messages must be positive".to_string()));
+        }
+        if self.streams == Some(0) {
+            return Err(PubSubError::ConfigInvalid("streams must be positive".to_string()));
+        }
+        if self.ack_batching.as_ref().is_some_and(|v| v.max_ack_batch == 0) {
+            return Err(PubSubError::ConfigInvalid(
+                "ack_batching.max_ack_batch must be positive".to_string(),
+            ));
+        }
+        if let CancelPolicy::ExtendDeadline(seconds) = self.on_cancel_policy {
+            if !(0..=MAX_STREAM_ACK_DEADLINE_SECONDS).contains(&seconds) {
+                return Err(PubSubError::ConfigInvalid(format!(
+                    "on_cancel_policy must extend the deadline between 0 and {MAX_STREAM_ACK_DEADLINE_SECONDS} seconds but was {seconds}"
+                )));
+            }
+        }
+        if self.max_outstanding_messages < 0 {
+            tracing::warn!(
+                "max_outstanding_messages={} is treated as no limit: set 0 for no limit",
+                self.max_outstanding_messages
+            );
+        }
+        if self.max_outstanding_bytes < 0 {
+            tracing::warn!(
+                "max_outstanding_bytes={} is treated as no limit: set 0 for no limit",
+                self.max_outstanding_bytes
+            );
+        }
+        Ok(())
+    }
+
+    /// The time without any response after which the stream is re-established. See `stall_after_pings`.
+    fn stall_timeout(&self) -> Option<Duration> {
+        self.stall_after_pings.map(|v| self.ping_interval * v)
+    }
+
+    /// The capacity of the queue between the stream and the consumers, None if unbounded.
+    /// See `prefetch_messages`.
+    pub(crate) fn queue_capacity(&self) -> Option<usize> {
+        self.prefetch_messages
+            .or_else(|| (self.max_outstanding_messages > 0).then_some(self.max_outstanding_messages as usize))
+    }
+
+    /// Warns the `max_outstanding_messages` low enough to be an accidental misconfiguration and returns true if warned.
+    /// The value <= 0 means no limit.
+    pub(crate) fn warn_low_concurrency(&self, subscription: &str) -> bool {
+        let low = !self.low_concurrency && (1..LOW_MAX_OUTSTANDING_MESSAGES).contains(&self.max_outstanding_messages);
+        if low {
+            tracing::warn!(
+                "max_outstanding_messages={} severely limits the throughput: set low_concurrency to opt in : {}",
+                self.max_outstanding_messages,
+                subscription
+            );
+        }
+        low
+    }
+}
+
+/// Returns the clear error if the status is the failure to decode a response larger than the decoding limit
+/// of the channel. This is distinguished from the transport errors because reconnecting doesn't help:
+/// the messages in the response are not even decoded, so they are redelivered after the ack deadline and fail again.
+fn decode_limit_exceeded(e: &Status) -> Option<Status> {
+    if e.code() != Code::OutOfRange || !e.message().starts_with("Error, decoded message length too large") {
+        return None;
+    }
+    Some(Status::out_of_range(format!(
+        "streaming pull response exceeded the decoding limit: reduce max_outstanding_bytes or the size of the messages : {}",
+        e.message()
+    )))
+}
+
+fn attributes_size(attributes: &HashMap<String, String>) -> usize {
+    attributes.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
+impl Default for SubscriberConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: std::time::Duration::from_secs(10),
+            ping_jitter: None,
+            retry_setting: Some(default_retry_setting()),
+            ack_retry_setting: None,
+            modify_ack_deadline_retry_setting: None,
+            reconnect_backoff: ReconnectBackoff::default(),
+            stream_ack_deadline_seconds: 60,
+            max_outstanding_messages: 50,
+            max_outstanding_bytes: 1000 * 1000 * 1000,
+            low_concurrency: false,
+            prefetch_messages: None,
+            max_message_age: None,
+            attribute_limits: AttributeLimits::default(),
+            reconnect_after_pause: None,
+            stall_after_pings: None,
+            backfill: None,
+            channel_selection: ChannelSelection::default(),
+            streams: None,
+            ack_dedup_capacity: None,
+            on_stopped: None,
+            on_response: None,
+            redelivery_jitter: None,
+            reject_empty_payload: None,
+            track_latest_ack_id: false,
+            stream_modify_ack_deadline: false,
+            idempotent_ack: false,
+            require_delivery_attempt: false,
+            max_delivery_attempts: None,
+            on_near_dead_letter: None,
+            deadline_attribute: None,
+            drop_past_deadline: false,
+            retain_internal_message: false,
+            shutdown_order: ShutdownOrder::default(),
+            on_cancel_policy: CancelPolicy::default(),
+            lease_extension: None,
+            ack_batching: None,
+            record_ack_latency: false,
+            #[cfg(feature = "fault-injection")]
+            fault_injector: None,
+            #[cfg(feature = "opentelemetry")]
+            meter: None,
+            #[cfg(feature = "opentelemetry")]
+            trace_context_attribute: None,
+            observer: None,
+            clock: None,
+        }
+    }
+}
+
+/// SubscriberStats is a snapshot of the counters collected by the subscriber.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubscriberStats {
+    /// The number of messages acked and dropped because they exceeded `max_message_age`
+    /// or were past the deadline with `drop_past_deadline`.
+    pub expired_messages: u64,
+    /// The number of messages rejected because they exceeded the `attribute_limits`
+    /// or had an empty payload with `reject_empty_payload`.
+    pub rejected_messages: u64,
+    /// The number of messages received from the server.
+    pub received_messages: u64,
+    /// The total bytes of the data and attributes of the received messages.
+    pub received_bytes: u64,
+    /// The number of messages handed to the queue of the consumers.
+    pub delivered_messages: u64,
+    /// The number of messages nacked because the subscriber was cancelled or the queue was closed.
+    /// See `SubscriberConfig::on_cancel_policy`.
+    pub cancel_nacked_messages: u64,
+    /// The number of times the stream was re-established.
+    pub reconnects: u64,
+    /// The time elapsed since the subscriber started.
+    pub uptime: Duration,
+    /// The number of messages received from the server but not yet taken by the consumer.
+    /// A growing depth indicates that the consumers are not keeping up.
+    pub queue_depth: u64,
+}
+
+impl SubscriberStats {
+    /// The average number of bytes received per second since the subscriber started.
+    pub fn received_bytes_per_sec(&self) -> f64 {
+        let secs = self.uptime.as_secs_f64();
+        if secs > 0.0 {
+            self.received_bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// recommend_flow_control suggests the flow control settings that keep the messages outstanding to the
+    /// subscriber within the memory budget, based on the average size of the messages received so far.
+    /// `max_outstanding_bytes` bounds the memory, and `max_outstanding_messages` is sized so that it doesn't limit
+    /// the delivery of the messages of the average size before the bytes do.
+    /// Returns None until a message is received.
+    pub fn recommend_flow_control(&self, target_memory: usize) -> Option<FlowControl> {
+        if self.received_messages == 0 {
+            return None;
+        }
+        let average_size = (self.received_bytes / self.received_messages).max(1);
+        let target_memory = i64::try_from(target_memory).unwrap_or(i64::MAX);
+        Some(FlowControl {
+            max_outstanding_messages: (target_memory / average_size as i64).max(1),
+            max_outstanding_bytes: target_memory,
+        })
+    }
+}
+
+/// FlowControl is the pair of the flow control settings of `SubscriberConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowControl {
+    pub max_outstanding_messages: i64,
+    pub max_outstanding_bytes: i64,
+}
+
+impl FlowControl {
+    /// Sets the flow control to the config, e.g. to pass it to `MessageStream::apply_config`.
+    pub fn apply_to(&self, config: &mut SubscriberConfig) {
+        config.max_outstanding_messages = self.max_outstanding_messages;
+        config.max_outstanding_bytes = self.max_outstanding_bytes;
+    }
+
+    /// per_stream divides the limits across the streams, rounding up so that each stream can receive a message.
+    /// The limits of zero or less mean no limit and are kept as is.
+    pub fn per_stream(&self, streams: usize) -> Self {
+        let streams = i64::try_from(streams.max(1)).unwrap_or(i64::MAX);
+        let divide = |v: i64| {
+            if v > 0 {
+                v / streams + i64::from(v % streams != 0)
+            } else {
+                v
+            }
+        };
+        Self {
+            max_outstanding_messages: divide(self.max_outstanding_messages),
+            max_outstanding_bytes: divide(self.max_outstanding_bytes),
+        }
+    }
+}
+
+impl std::iter::Sum for SubscriberStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |acc, v| Self {
+            expired_messages: acc.expired_messages + v.expired_messages,
+            rejected_messages: acc.rejected_messages + v.rejected_messages,
+            received_messages: acc.received_messages + v.received_messages,
+            received_bytes: acc.received_bytes + v.received_bytes,
+            delivered_messages: acc.delivered_messages + v.delivered_messages,
+            cancel_nacked_messages: acc.cancel_nacked_messages + v.cancel_nacked_messages,
+            reconnects: acc.reconnects + v.reconnects,
+            uptime: acc.uptime.max(v.uptime),
+            queue_depth: acc.queue_depth + v.queue_depth,
+        })
+    }
+}
+
+/// The upper bounds of the buckets of the ack latency histogram.
+/// The latencies above the last bound are counted in the last bucket.
+const ACK_LATENCY_BOUNDS: [Duration; 14] = [
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+];
+
+/// AckLatencyPercentiles are the percentiles of the latency of the Acknowledge requests recorded with
+/// `SubscriberConfig::record_ack_latency`. Each percentile is the upper bound of the histogram bucket
+/// containing it, between 1ms and 30s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AckLatencyPercentiles {
+    /// The number of the recorded requests.
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+/// AckLatency is the histogram of the latency of the Acknowledge requests.
+#[derive(Debug, Default)]
+pub(crate) struct AckLatency {
+    counts: [AtomicU64; ACK_LATENCY_BOUNDS.len()],
+}
+
+impl AckLatency {
+    fn record(&self, latency: Duration) {
+        let bucket = ACK_LATENCY_BOUNDS
+            .iter()
+            .position(|v| latency <= *v)
+            .unwrap_or(ACK_LATENCY_BOUNDS.len() - 1);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// timed records the latency of the ack if the histogram is enabled.
+    async fn timed<T>(latency: Option<&Self>, clock: &dyn Clock, ack: impl Future<Output = T>) -> T {
+        let start = clock.now();
+        let result = ack.await;
+        if let Some(latency) = latency {
+            latency.record(clock.now().duration_since(start));
+        }
+        result
+    }
+
+    /// percentiles merges the histograms, e.g. of the streams of a `MessageStream`.
+    /// Returns None if nothing is recorded.
+    pub(crate) fn percentiles<'a>(histograms: impl IntoIterator<Item = &'a Self>) -> Option<AckLatencyPercentiles> {
+        let mut counts = [0; ACK_LATENCY_BOUNDS.len()];
+        for histogram in histograms {
+            for (count, v) in counts.iter_mut().zip(&histogram.counts) {
+                *count += v.load(Ordering::Relaxed);
+            }
+        }
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let percentile = |p: f64| {
+            let rank = ((total as f64 * p).ceil() as u64).max(1);
+            let mut seen = 0;
+            ACK_LATENCY_BOUNDS
+                .iter()
+                .zip(counts)
+                .find(|(_, count)| {
+                    seen += count;
+                    seen >= rank
+                })
+                .map_or(ACK_LATENCY_BOUNDS[ACK_LATENCY_BOUNDS.len() - 1], |(bound, _)| *bound)
+        };
+        Some(AckLatencyPercentiles {
+            count: total,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Counters {
+    started_at: tokio::time::Instant,
+    expired_messages: AtomicU64,
+    rejected_messages: AtomicU64,
+    received_messages: AtomicU64,
+    received_bytes: AtomicU64,
+    delivered_messages: AtomicU64,
+    cancel_nacked_messages: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl Counters {
+    fn new(started_at: tokio::time::Instant) -> Self {
+        Self {
+            started_at,
+            expired_messages: AtomicU64::default(),
+            rejected_messages: AtomicU64::default(),
+            received_messages: AtomicU64::default(),
+            received_bytes: AtomicU64::default(),
+            delivered_messages: AtomicU64::default(),
+            cancel_nacked_messages: AtomicU64::default(),
+            reconnects: AtomicU64::default(),
+        }
+    }
+}
+
+impl Counters {
+    fn snapshot(&self, now: tokio::time::Instant) -> SubscriberStats {
+        SubscriberStats {
+            expired_messages: self.expired_messages.load(Ordering::Relaxed),
+            rejected_messages: self.rejected_messages.load(Ordering::Relaxed),
+            received_messages: self.received_messages.load(Ordering::Relaxed),
+            received_bytes: self.received_bytes.load(Ordering::Relaxed),
+            delivered_messages: self.delivered_messages.load(Ordering::Relaxed),
+            cancel_nacked_messages: self.cancel_nacked_messages.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            uptime: now.duration_since(self.started_at),
+            queue_depth: 0,
+        }
+    }
+}
+
+/// RecentAcks remembers a bounded number of the latest acked ack_ids.
+#[derive(Debug)]
+pub(crate) struct RecentAcks {
+    capacity: usize,
+    inner: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl RecentAcks {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new((HashSet::with_capacity(capacity), VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    fn contains(&self, ack_id: &str) -> bool {
+        self.inner.lock().unwrap().0.contains(ack_id)
+    }
+
+    fn insert(&self, ack_id: String) {
+        let mut lock = self.inner.lock().unwrap();
+        let (ids, order) = &mut *lock;
+        if self.capacity == 0 || !ids.insert(ack_id.clone()) {
+            return;
+        }
+        order.push_back(ack_id);
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// LatestAckIds maps the message_id of the messages being handled to the ack_id of their latest delivery.
+/// The entries are removed when the message is settled or its handles are dropped, see `LatestAckIdGuard`.
+#[derive(Debug, Default)]
+pub(crate) struct LatestAckIds {
+    inner: Mutex<HashMap<String, String>>,
+}
+
+impl LatestAckIds {
+    fn get(&self, message_id: &str) -> Option<String> {
+        self.inner.lock().unwrap().get(message_id).cloned()
+    }
+
+    fn insert(&self, message_id: String, ack_id: String) {
+        self.inner.lock().unwrap().insert(message_id, ack_id);
+    }
+
+    /// Removes the entry unless it was replaced by a newer delivery.
+    fn remove(&self, message_id: &str, ack_id: &str) {
+        let mut lock = self.inner.lock().unwrap();
+        if lock.get(message_id).is_some_and(|v| v == ack_id) {
+            lock.remove(message_id);
+        }
+    }
+}
+
+/// LatestAckIdGuard tracks the delivery of a message in `LatestAckIds` until all the clones of its handle are dropped,
+/// so that the messages dropped without being acked or nacked are not tracked forever.
+#[derive(Debug)]
+pub(crate) struct LatestAckIdGuard {
+    latest_ack_ids: Arc<LatestAckIds>,
+    message_id: String,
+    ack_id: String,
+}
+
+impl LatestAckIdGuard {
+    fn new(latest_ack_ids: Arc<LatestAckIds>, message_id: &str, ack_id: &str) -> Self {
+        latest_ack_ids.insert(message_id.to_string(), ack_id.to_string());
+        Self {
+            latest_ack_ids,
+            message_id: message_id.to_string(),
+            ack_id: ack_id.to_string(),
+        }
+    }
+}
+
+impl Drop for LatestAckIdGuard {
+    fn drop(&mut self) {
+        self.latest_ack_ids.remove(&self.message_id, &self.ack_id);
+    }
+}
+
+/// Leases holds the ack_ids of the outstanding messages extended by `SubscriberConfig::lease_extension`
+/// and the time they were delivered.
+#[derive(Debug, Default)]
+pub(crate) struct Leases {
+    inner: Mutex<HashMap<String, tokio::time::Instant>>,
+}
+
+impl Leases {
+    fn insert(&self, ack_id: String, now: tokio::time::Instant) {
+        self.inner.lock().unwrap().insert(ack_id, now);
+    }
+
+    fn remove(&self, ack_id: &str) {
+        self.inner.lock().unwrap().remove(ack_id);
+    }
+
+    /// Returns the ack_ids to extend and forgets the ones leased for longer than `max_lease_duration`.
+    fn extendable(&self, max_lease_duration: Duration, now: tokio::time::Instant) -> Vec<String> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.retain(|ack_id, leased_at| {
+            let keep = now.duration_since(*leased_at) < max_lease_duration;
+            if !keep {
+                tracing::debug!("max lease duration exceeded -> so stop extending : ack_id={ack_id}");
+            }
+            keep
+        });
+        lock.keys().cloned().collect()
+    }
+}
+
+/// InFlight tracks the messages read from a `MessageStream` and not yet released by the consumer.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    /// The number of the messages not yet released.
+    pub(crate) count: watch::Sender<usize>,
+    /// The ack_ids of the messages neither released, acked nor nacked.
+    unsettled: Mutex<HashSet<String>>,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self {
+            count: watch::channel(0).0,
+            unsettled: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl InFlight {
+    pub(crate) fn unsettled_ack_ids(&self) -> Vec<String> {
+        self.unsettled.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// InFlightGuard counts a message as in flight until all the clones of its handle are dropped,
+/// i.e. until the consumer has finished with it.
+#[derive(Debug)]
+pub(crate) struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+    ack_id: String,
+}
+
+impl InFlightGuard {
+    fn new(in_flight: Arc<InFlight>, ack_id: &str) -> Self {
+        in_flight.count.send_modify(|v| *v += 1);
+        in_flight.unsettled.lock().unwrap().insert(ack_id.to_string());
+        Self {
+            in_flight,
+            ack_id: ack_id.to_string(),
+        }
+    }
+
+    /// settled is called when the message is acked or nacked, which doesn't release it.
+    fn settled(&self) {
+        self.in_flight.unsettled.lock().unwrap().remove(&self.ack_id);
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.settled();
+        self.in_flight.count.send_modify(|v| *v -= 1);
+    }
+}
+
+/// The maximum time to wait for the confirmation of the modify_ack_deadline sent on the stream.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Confirmation = oneshot::Sender<Result<(), Status>>;
+
+/// StreamControl sends the modify_ack_deadline requests on the stream and routes the
+/// confirmations received by the stream to the waiting requests.
+#[derive(Debug)]
+pub(crate) struct StreamControl {
+    sender: async_channel::Sender<StreamingPullRequest>,
+    receiver: async_channel::Receiver<StreamingPullRequest>,
+    /// The confirmations are only returned for the exactly-once delivery subscriptions.
+    exactly_once: AtomicBool,
+    /// True while a stream is open, false while the subscriber reconnects.
+    connected: AtomicBool,
+    pending: Mutex<HashMap<String, Confirmation>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl StreamControl {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        let (sender, receiver) = async_channel::unbounded();
+        Self {
+            sender,
+            receiver,
+            exactly_once: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            pending: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    async fn modify_ack_deadline(&self, ack_id: String, ack_deadline_seconds: i32) -> Result<(), PubSubError> {
+        let mut results = self.modify_ack_deadlines(vec![ack_id], ack_deadline_seconds).await;
+        results.pop().map_or(Ok(()), |(_, result)| result)
+    }
+
+    /// modify_ack_deadlines sends the ack_ids in a single request and returns the result of each ack_id.
+    async fn modify_ack_deadlines(
+        &self,
+        ack_ids: Vec<String>,
+        ack_deadline_seconds: i32,
+    ) -> Vec<(String, Result<(), PubSubError>)> {
+        let mut request = create_empty_streaming_pull_request();
+        request.modify_deadline_ack_ids = ack_ids.clone();
+        request.modify_deadline_seconds = vec![ack_deadline_seconds; ack_ids.len()];
+        if !self.exactly_once.load(Ordering::Relaxed) {
+            let result = self.send(request).await;
+            return ack_ids
+                .into_iter()
+                .map(|ack_id| (ack_id, result.clone().map_err(PubSubError::from)))
+                .collect();
+        }
+        let receivers: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            ack_ids
+                .iter()
+                .map(|ack_id| {
+                    let (tx, rx) = oneshot::channel();
+                    pending.insert(ack_id.clone(), tx);
+                    rx
+                })
+                .collect()
+        };
+        if let Err(e) = self.send(request).await {
+            // Nothing will confirm the ack_ids.
+            let mut pending = self.pending.lock().unwrap();
+            for ack_id in &ack_ids {
+                pending.remove(ack_id);
+            }
+            return ack_ids
+                .into_iter()
+                .map(|ack_id| (ack_id, Err(e.clone().into())))
+                .collect();
+        }
+        let deadline = self.clock.now() + CONFIRMATION_TIMEOUT;
+        let mut results = Vec::with_capacity(ack_ids.len());
+        for (ack_id, rx) in ack_ids.into_iter().zip(receivers) {
+            let result = select! {
+                result = rx => match result {
+                    Ok(result) => result.map_err(PubSubError::from),
+                    Err(_) => Err(Status::aborted("modify_ack_deadline was superseded by another request").into()),
+                },
+                _ = self.clock.sleep_until(deadline) => {
+                    self.pending.lock().unwrap().remove(&ack_id);
+                    Err(Status::deadline_exceeded("modify_ack_deadline was not confirmed").into())
+                }
+            };
+            results.push((ack_id, result));
+        }
+        results
+    }
+
+    async fn send(&self, request: StreamingPullRequest) -> Result<(), Status> {
+        self.sender
+            .send(request)
+            .await
+            .map_err(|_| Status::cancelled("subscriber is stopped"))
+    }
+
+    fn confirm(&self, confirmation: ModifyAckDeadlineConfirmation) {
+        let mut pending = self.pending.lock().unwrap();
+        let mut complete = |ack_ids: Vec<String>, result: Result<(), Status>| {
+            for ack_id in ack_ids {
+                if let Some(tx) = pending.remove(&ack_id) {
+                    let _ = tx.send(result.clone());
+                }
+            }
+        };
+        complete(confirmation.ack_ids, Ok(()));
+        complete(confirmation.invalid_ack_ids, Err(Status::failed_precondition("invalid ack_id")));
+        complete(
+            confirmation.temporary_failed_ack_ids,
+            Err(Status::unavailable("temporary failure")),
+        );
+    }
+}
+
+/// State shared between the Subscriber and its streaming pull task.
+#[derive(Debug)]
+pub(crate) struct State {
+    counters: Counters,
+    terminal_error: Mutex<Option<Status>>,
+    /// The time when the subscriber was paused, None while running.
+    paused: watch::Sender<Option<tokio::time::Instant>>,
+    /// True until the backlog is drained if `SubscriberConfig::backfill` is set.
+    backfilling: AtomicBool,
+    /// The settings of the next streaming pull, replaced by `Subscriber::apply_config`.
+    settings: watch::Sender<StreamSettings>,
+    recent_acks: Option<Arc<RecentAcks>>,
+    latest_ack_ids: Option<Arc<LatestAckIds>>,
+    leases: Option<Arc<Leases>>,
+    /// Becomes true when the streaming pull task stops.
+    stopped: watch::Sender<bool>,
+    /// The latest properties of the subscription sent by the server on the stream.
+    properties: Mutex<Option<SubscriptionProperties>>,
+    control: Option<Arc<StreamControl>>,
+    deadline_attribute: Option<Arc<str>>,
+    retry_settings: Option<Arc<AckRetrySettings>>,
+    aggregator: Option<Arc<AckAggregator>>,
+    ack_latency: Option<Arc<AckLatency>>,
+    /// The tasks delivering the redeliveries delayed by `SubscriberConfig::redelivery_jitter`.
+    /// Each of them holds a sender of the queue until it settles its message.
+    delayed: Mutex<JoinSet<()>>,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "opentelemetry")]
+    telemetry: Option<Arc<Telemetry>>,
+    #[cfg(feature = "opentelemetry")]
+    trace_context_attribute: Option<Arc<str>>,
+}
+
+impl State {
+    fn new(config: &SubscriberConfig) -> Self {
+        let clock = config.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+        Self {
+            counters: Counters::new(clock.now()),
+            terminal_error: Mutex::new(None),
+            paused: watch::channel(None).0,
+            backfilling: AtomicBool::new(config.backfill.is_some()),
+            settings: watch::channel(StreamSettings::from(config)).0,
+            recent_acks: config.ack_dedup_capacity.map(|v| Arc::new(RecentAcks::new(v))),
+            latest_ack_ids: config.track_latest_ack_id.then(Default::default),
+            leases: config.lease_extension.as_ref().map(|_| Default::default()),
+            stopped: watch::channel(false).0,
+            properties: Mutex::new(None),
+            control: config
+                .stream_modify_ack_deadline
+                .then(|| Arc::new(StreamControl::new(clock.clone()))),
+            deadline_attribute: config.deadline_attribute.as_deref().map(Arc::from),
+            retry_settings: (config.ack_retry_setting.is_some() || config.modify_ack_deadline_retry_setting.is_some())
+                .then(|| {
+                    Arc::new(AckRetrySettings {
+                        ack: config.ack_retry_setting.clone(),
+                        modify_ack_deadline: config.modify_ack_deadline_retry_setting.clone(),
+                    })
+                }),
+            aggregator: None,
+            ack_latency: config.record_ack_latency.then(Default::default),
+            delayed: Mutex::new(JoinSet::new()),
+            clock,
+            #[cfg(feature = "opentelemetry")]
+            telemetry: config.meter.as_ref().map(|v| Arc::new(Telemetry::new(v))),
+            #[cfg(feature = "opentelemetry")]
+            trace_context_attribute: config.trace_context_attribute.as_deref().map(Arc::from),
+        }
+    }
+
+    /// spawn_delayed runs the delivery of a delayed redelivery, dropping the results of the finished ones.
+    fn spawn_delayed(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut delayed = self.delayed.lock().unwrap();
+        while delayed.try_join_next().is_some() {}
+        delayed.spawn(task);
+    }
+
+    /// join_delayed waits until the delayed redeliveries are delivered or settled with the `CancelPolicy`.
+    async fn join_delayed(&self) {
+        let mut delayed = std::mem::take(&mut *self.delayed.lock().unwrap());
+        while delayed.join_next().await.is_some() {}
+    }
+}
+
+/// StreamSettings are the flow control settings only accepted on the initial StreamingPullRequest.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StreamSettings {
+    stream_ack_deadline_seconds: i32,
+    max_outstanding_messages: i64,
+    max_outstanding_bytes: i64,
+}
+
+impl From<&SubscriberConfig> for StreamSettings {
+    fn from(config: &SubscriberConfig) -> Self {
+        Self {
+            stream_ack_deadline_seconds: config.stream_ack_deadline_seconds,
+            max_outstanding_messages: config.max_outstanding_messages,
+            max_outstanding_bytes: config.max_outstanding_bytes,
+        }
+    }
+}
+
+/// Telemetry holds the OpenTelemetry instruments recording the subscriber events.
+#[cfg(feature = "opentelemetry")]
+#[derive(Debug)]
+pub(crate) struct Telemetry {
+    received: opentelemetry::metrics::Counter<u64>,
+    acked: opentelemetry::metrics::Counter<u64>,
+    nacked: opentelemetry::metrics::Counter<u64>,
+    reconnects: opentelemetry::metrics::Counter<u64>,
+    ack_latency: opentelemetry::metrics::Histogram<f64>,
+}
+
+#[cfg(feature = "opentelemetry")]
+impl Telemetry {
+    fn new(meter: &opentelemetry::metrics::Meter) -> Self {
+        Self {
+            received: meter
+                .u64_counter("pubsub.subscriber.received_messages")
+                .with_description("The number of messages received from the server.")
+                .build(),
+            acked: meter
+                .u64_counter("pubsub.subscriber.acked_messages")
+                .with_description("The number of messages acked.")
+                .build(),
+            nacked: meter
+                .u64_counter("pubsub.subscriber.nacked_messages")
+                .with_description("The number of messages nacked.")
+                .build(),
+            reconnects: meter
+                .u64_counter("pubsub.subscriber.reconnects")
+                .with_description("The number of times the streaming pull was re-established.")
+                .build(),
+            ack_latency: meter
+                .f64_histogram("pubsub.subscriber.ack_latency")
+                .with_description("The time from the receipt of a message to its ack.")
+                .with_unit("s")
+                .build(),
+        }
+    }
+
+    fn attributes(subscription: &str) -> [opentelemetry::KeyValue; 1] {
+        [opentelemetry::KeyValue::new("subscription", subscription.to_string())]
+    }
+
+    fn received(&self, subscription: &str, count: u64) {
+        self.received.add(count, &Self::attributes(subscription));
+    }
+
+    fn acked(&self, subscription: &str, latency: Duration) {
+        let attributes = Self::attributes(subscription);
+        self.acked.add(1, &attributes);
+        self.ack_latency.record(latency.as_secs_f64(), &attributes);
+    }
+
+    fn nacked(&self, subscription: &str, count: u64) {
+        self.nacked.add(count, &Self::attributes(subscription));
+    }
+
+    fn reconnected(&self, subscription: &str) {
+        self.reconnects.add(1, &Self::attributes(subscription));
+    }
+}
+
+/// The minimum interval between the logs of the repeated reconnect failures.
+const RECONNECT_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// LogThrottle limits a repeated log to once per interval and counts the suppressed ones.
+struct LogThrottle {
+    interval: Duration,
+    last: Option<tokio::time::Instant>,
+    suppressed: u64,
+}
+
+impl LogThrottle {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns the number of logs suppressed since the last one if the log should be emitted now.
+    fn check(&mut self, now: tokio::time::Instant) -> Option<u64> {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.interval => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+        self.suppressed = 0;
+    }
+}
+
+/// StreamEnd is the reason why a stream finished without error.
+enum StreamEnd {
+    Closed,
+    Reconnect(ReconnectReason),
+    /// Nothing was received for `stall_after_pings`. `responded` is true if the stream received anything before.
+    Stalled {
+        responded: bool,
+    },
+    /// The queue was closed while running, so the messages can't be delivered anymore.
+    QueueClosed,
+}
+
+/// BackfillWindow counts the messages received within a window of the `Backfill`.
+struct BackfillWindow {
+    deadline: tokio::time::Instant,
+    received: u64,
+}
+
+impl BackfillWindow {
+    fn new(backfill: Option<&Backfill>, now: tokio::time::Instant) -> Self {
+        Self {
+            deadline: now + backfill.map_or(Duration::ZERO, |v| v.window),
+            received: 0,
+        }
+    }
+}
+
+/// SubscriberBuilder configures and starts a `Subscriber` of a subscription. The config defaults to
+/// `SubscriberConfig::default()` and the cancellation token to a new one; the queue is required.
+/// ```
+/// use google_cloud_pubsub::apiv1::subscriber_client::SubscriberClient;
+/// use google_cloud_pubsub::error::PubSubError;
+/// use google_cloud_pubsub::subscriber::{Subscriber, SubscriberConfig};
+/// use google_cloud_pubsub::subscription::SubscriptionName;
+/// use tokio_util::sync::CancellationToken;
+///
+/// async fn run(client: SubscriberClient) -> Result<(), PubSubError> {
+///     let (queue, receiver) = async_channel::unbounded();
+///     let cancel = CancellationToken::new();
+///     let mut subscriber = Subscriber::builder(client, SubscriptionName::new("project", "subscription")?)
+///         .config(SubscriberConfig::default())
+///         .cancellation_token(cancel.clone())
+///         .queue(queue)
+///         .start()?;
+///     while let Ok(message) = receiver.recv().await {
+///         message.ack().await?;
+///     }
+///     cancel.cancel();
+///     subscriber.done().await;
+///     Ok(())
+/// }
+/// ```
+pub struct SubscriberBuilder {
+    client: SubscriberClient,
+    subscription: SubscriptionName,
+    config: SubscriberConfig,
+    cancel: CancellationToken,
+    queue: Option<async_channel::Sender<ReceivedMessage>>,
+}
+
+impl SubscriberBuilder {
+    pub fn new(client: SubscriberClient, subscription: impl Into<SubscriptionName>) -> Self {
+        Self {
+            client,
+            subscription: subscription.into(),
+            config: SubscriberConfig::default(),
+            cancel: CancellationToken::new(),
+            queue: None,
+        }
+    }
+
+    /// config sets the config of the subscriber.
+    pub fn config(mut self, config: SubscriberConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// cancellation_token sets the token that stops the subscriber when cancelled.
+    pub fn cancellation_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// queue sets the queue the received messages are delivered to.
+    pub fn queue(mut self, queue: async_channel::Sender<ReceivedMessage>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// start spawns the tasks of the subscriber. It fails with `PubSubError::ConfigInvalid` if the queue is not set.
+    pub fn start(self) -> Result<Subscriber, PubSubError> {
+        let queue = self
+            .queue
+            .ok_or_else(|| PubSubError::ConfigInvalid("the queue of the subscriber is not set".to_string()))?;
+        Ok(Subscriber::start(
+            self.cancel,
+            self.subscription.to_string(),
+            self.client,
+            queue,
+            self.config,
+        ))
+    }
+}
+
+/// Subscriber runs a streaming pull of a subscription and delivers the received messages to its queue.
+/// Start it with `Subscriber::builder`, or let `Subscription::subscribe` and `Subscription::receive` start it.
+#[derive(Debug)]
+pub struct Subscriber {
+    pinger: Option<JoinHandle<()>>,
+    leaser: Option<JoinHandle<()>>,
+    aggregator: Option<JoinHandle<()>>,
+    inner: Option<JoinHandle<()>>,
+    state: Arc<State>,
+}
+
+impl Subscriber {
+    pub fn builder(client: SubscriberClient, subscription: impl Into<SubscriptionName>) -> SubscriberBuilder {
+        SubscriberBuilder::new(client, subscription)
+    }
+
+    fn start(
+        ctx: CancellationToken,
+        subscription: String,
+        client: SubscriberClient,
+        queue: async_channel::Sender<ReceivedMessage>,
+        config: SubscriberConfig,
+    ) -> Self {
+        let (ping_sender, ping_receiver) = async_channel::unbounded();
+
+        // ping request
+        let subscription_clone = subscription.to_string();
+
+        let cancel_receiver = ctx.clone();
+        let mut state = State::new(&config);
+        let aggregator = config.ack_batching.clone().map(|batching| {
+            let (aggregator, task) = AckAggregator::start(
+                client.clone(),
+                subscription.to_string(),
+                batching,
+                config.ack_retry_setting.clone(),
+                state.ack_latency.clone(),
+                state.clock.clone(),
+            );
+            state.aggregator = Some(Arc::new(aggregator));
+            task
+        });
+        let state = Arc::new(state);
+        let state_for_inner = state.clone();
+        let clock = state.clock.clone();
+        let pinger = tokio::spawn(async move {
+            loop {
+                select! {
                     _ = ctx.cancelled() => {
                         ping_sender.close();
                         break;
                     }
-                    _ = sleep(config.ping_interval) => {
-                        let _ = ping_sender.send(true).await;
+                    _ = clock.sleep(jittered(config.ping_interval, config.ping_jitter)) => {
+                        // The channel is closed when the streaming pull task stops without the cancellation.
+                        if ping_sender.send(true).await.is_err() {
+                            tracing::debug!("ping channel closed -> so stop pinger : {}", subscription_clone);
+                            break;
+                        }
+                    }
+                }
+            }
+            tracing::trace!("stop pinger : {}", subscription_clone);
+        });
+
+        let leaser = config.lease_extension.clone().map(|lease| {
+            let ctx = cancel_receiver.clone();
+            let state = state.clone();
+            let client = client.clone();
+            let subscription = subscription.to_string();
+            let retry = config.modify_ack_deadline_retry_setting.clone();
+            tokio::spawn(async move {
+                let mut stopped = state.stopped.subscribe();
+                loop {
+                    select! {
+                        _ = ctx.cancelled() => break,
+                        _ = stopped.wait_for(|v| *v) => break,
+                        _ = state.clock.sleep(lease.extension_interval) => {}
+                    }
+                    let ack_deadline_seconds = state.settings.borrow().stream_ack_deadline_seconds;
+                    if let Some(leases) = &state.leases {
+                        let ack_ids = leases.extendable(lease.max_lease_duration, state.clock.now());
+                        // The stream is preferred while open, the requests queued during the reconnect would
+                        // arrive too late to extend the leases.
+                        let control = state.control.as_deref().filter(|v| v.connected.load(Ordering::Relaxed));
+                        extend_leases(
+                            &client,
+                            &subscription,
+                            leases,
+                            ack_ids,
+                            ack_deadline_seconds,
+                            control,
+                            retry.clone(),
+                            state.clock.as_ref(),
+                        )
+                        .await;
+                    }
+                }
+                tracing::trace!("stop leaser : {}", subscription);
+            })
+        });
+
+        let inner = tokio::spawn(async move {
+            let mut cancel_retry = 0;
+            // The number of consecutive failures to establish or keep the stream.
+            let mut failures = 0;
+            let mut reconnect_log = LogThrottle::new(RECONNECT_LOG_INTERVAL);
+            // The number of consecutive stalls, reset when a stream receives a response.
+            let mut stalls = 0;
+            tracing::trace!("start subscriber: {}", subscription);
+            let retryable_codes = match &config.retry_setting {
+                Some(v) => v.codes.clone(),
+                None => default_retry_setting().codes,
+            };
+            let mut reconnect = None;
+            let reason = loop {
+                if let Some(reason) = reconnect.take() {
+                    state_for_inner.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+                    #[cfg(feature = "opentelemetry")]
+                    if let Some(telemetry) = &state_for_inner.telemetry {
+                        telemetry.reconnected(&subscription);
+                    }
+                    if let Some(observer) = &config.observer {
+                        observer.on_stream_reconnect(&subscription, &reason);
+                        let event = match reason {
+                            ReconnectReason::Error(e) => StreamReconnected {
+                                attempt: failures,
+                                last_error: Some(e),
+                            },
+                            ReconnectReason::Stalled => StreamReconnected {
+                                attempt: stalls,
+                                last_error: None,
+                            },
+                            ReconnectReason::Refresh => StreamReconnected {
+                                attempt: 0,
+                                last_error: None,
+                            },
+                        };
+                        observer.on_stream_reconnected(&subscription, &event);
+                    }
+                }
+                let settings = state_for_inner.settings.borrow().clone();
+                let mut request = create_empty_streaming_pull_request();
+                request.subscription = subscription.to_string();
+                request.stream_ack_deadline_seconds = settings.stream_ack_deadline_seconds;
+                match config
+                    .backfill
+                    .as_ref()
+                    .filter(|_| state_for_inner.backfilling.load(Ordering::Relaxed))
+                {
+                    Some(backfill) => {
+                        request.max_outstanding_messages = backfill.max_outstanding_messages;
+                        request.max_outstanding_bytes = backfill.max_outstanding_bytes;
+                    }
+                    None => {
+                        request.max_outstanding_messages = settings.max_outstanding_messages;
+                        request.max_outstanding_bytes = settings.max_outstanding_bytes;
+                    }
+                }
+
+                // The channel is reserved until the stream is closed.
+                let channel = client.reserve_streaming_channel(config.channel_selection);
+                // Ends the request stream when the stream is abandoned by the reconnect or the stop.
+                let closed = CancellationToken::new();
+                let _close = closed.clone().drop_guard();
+                #[cfg(feature = "fault-injection")]
+                let fault = config
+                    .fault_injector
+                    .as_ref()
+                    .and_then(|v| v.take(FaultPoint::StreamingPull));
+                #[cfg(not(feature = "fault-injection"))]
+                let fault: Option<Status> = None;
+                let response = match fault {
+                    Some(fault) => Err(fault),
+                    None => {
+                        let start = client.streaming_pull_on(
+                            channel.as_ref(),
+                            request,
+                            ping_receiver.clone(),
+                            state_for_inner.control.as_ref().map(|v| v.receiver.clone()),
+                            closed.clone(),
+                            config.retry_setting.clone(),
+                        );
+                        // The server may not respond to the request on a half-open connection either.
+                        match config.stall_timeout() {
+                            Some(timeout) => match select! {
+                                response = start => Some(response),
+                                _ = state_for_inner.clock.sleep(timeout) => None,
+                            } {
+                                Some(response) => response,
+                                None => {
+                                    tracing::warn!(
+                                        "no response for {:?} -> so restart the stalled stream : {}",
+                                        timeout,
+                                        subscription
+                                    );
+                                    reconnect = Some(ReconnectReason::Stalled);
+                                    stalls += 1;
+                                    if !wait_reconnect(
+                                        &config.reconnect_backoff,
+                                        stalls,
+                                        state_for_inner.clock.as_ref(),
+                                        &cancel_receiver,
+                                    )
+                                    .await
+                                    {
+                                        break StopReason::GracefulShutdown;
+                                    }
+                                    continue;
+                                }
+                            },
+                            None => start.await,
+                        }
+                    }
+                };
+
+                let stream = match response {
+                    Ok(r) => {
+                        reconnect_log.reset();
+                        cancel_retry = 0;
+                        failures = 0;
+                        r.into_inner()
+                    }
+                    Err(e) => {
+                        if e.code() == Code::Cancelled {
+                            if cancel_retry < 5 {
+                                cancel_retry += 1;
+                                if let Some(suppressed) = reconnect_log.check(state_for_inner.clock.now()) {
+                                    tracing::warn!(
+                                        "failed to start streaming: will reconnect {:?} : {} (suppressed {} similar logs)",
+                                        e,
+                                        subscription,
+                                        suppressed
+                                    );
+                                }
+                                reconnect = Some(ReconnectReason::Error(e.clone()));
+                                failures += 1;
+                                if !wait_reconnect(
+                                    &config.reconnect_backoff,
+                                    failures,
+                                    state_for_inner.clock.as_ref(),
+                                    &cancel_receiver,
+                                )
+                                .await
+                                {
+                                    break StopReason::GracefulShutdown;
+                                }
+                                continue;
+                            }
+                            tracing::trace!("stop subscriber : {}", subscription);
+                            if cancel_receiver.is_cancelled() {
+                                break StopReason::GracefulShutdown;
+                            }
+                            *state_for_inner.terminal_error.lock().unwrap() = Some(e);
+                            break StopReason::MaxReconnectsExceeded;
+                        } else if e.code() == Code::PermissionDenied {
+                            // Not retried even if it is in the retry codes: it only recovers after the IAM policy
+                            // of the subscription is fixed.
+                            tracing::error!("{PERMISSION_DENIED_MESSAGE}: will stop {:?} : {}", e, subscription);
+                            break StopReason::TerminalError(e);
+                        } else if is_token_error(&e) {
+                            // The streams of the client reconnect together to share a single token refresh.
+                            if let Some(suppressed) = reconnect_log.check(state_for_inner.clock.now()) {
+                                tracing::warn!(
+                                    "failed to get the token: will reconnect {:?} : {} (suppressed {} similar logs)",
+                                    e,
+                                    subscription,
+                                    suppressed
+                                );
+                            }
+                            reconnect = Some(ReconnectReason::Error(e.clone()));
+                            failures += 1;
+                            let clock = state_for_inner.clock.as_ref();
+                            let retry_at = client.token_retry_at(clock.now(), config.reconnect_backoff.delay(failures));
+                            select! {
+                                _ = clock.sleep_until(retry_at) => continue,
+                                _ = cancel_receiver.cancelled() => break StopReason::GracefulShutdown,
+                            }
+                        } else if retryable_codes.contains(&e.code()) {
+                            if let Some(suppressed) = reconnect_log.check(state_for_inner.clock.now()) {
+                                tracing::warn!(
+                                    "failed to start streaming: will reconnect {:?} : {} (suppressed {} similar logs)",
+                                    e,
+                                    subscription,
+                                    suppressed
+                                );
+                            }
+                            reconnect = Some(ReconnectReason::Error(e.clone()));
+                            failures += 1;
+                            if !wait_reconnect(
+                                &config.reconnect_backoff,
+                                failures,
+                                state_for_inner.clock.as_ref(),
+                                &cancel_receiver,
+                            )
+                            .await
+                            {
+                                break StopReason::GracefulShutdown;
+                            }
+                            continue;
+                        } else {
+                            tracing::error!("failed to start streaming: will stop {:?} : {}", e, subscription);
+                            break StopReason::TerminalError(e);
+                        }
+                    }
+                };
+                let set_connected = |connected: bool| {
+                    if let Some(control) = &state_for_inner.control {
+                        control.connected.store(connected, Ordering::Relaxed);
+                    }
+                };
+                set_connected(true);
+                let end = Self::recv(
+                    client.clone(),
+                    stream,
+                    subscription.as_str(),
+                    cancel_receiver.clone(),
+                    &queue,
+                    &config,
+                    &state_for_inner,
+                )
+                .instrument(tracing::info_span!("streaming_pull", subscription = %subscription))
+                .await;
+                set_connected(false);
+                match end {
+                    Ok(StreamEnd::Closed) => break StopReason::GracefulShutdown,
+                    Ok(StreamEnd::QueueClosed) => break StopReason::QueueClosed,
+                    Ok(StreamEnd::Reconnect(reason)) => {
+                        tracing::trace!("reconnect with the current flow control : {}", subscription);
+                        reconnect = Some(reason);
+                        continue;
+                    }
+                    Ok(StreamEnd::Stalled { responded }) => {
+                        reconnect = Some(ReconnectReason::Stalled);
+                        if responded {
+                            stalls = 0;
+                        }
+                        stalls += 1;
+                        if !wait_reconnect(
+                            &config.reconnect_backoff,
+                            stalls,
+                            state_for_inner.clock.as_ref(),
+                            &cancel_receiver,
+                        )
+                        .await
+                        {
+                            break StopReason::GracefulShutdown;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        // Not reconnected even if it is in the retry codes.
+                        if e.code() == Code::PermissionDenied {
+                            tracing::error!(
+                                "{PERMISSION_DENIED_MESSAGE}: terminated subscriber streaming {:?} : {}",
+                                e,
+                                subscription
+                            );
+                            break StopReason::TerminalError(e);
+                        } else if let Some(e) = decode_limit_exceeded(&e) {
+                            tracing::error!("terminated subscriber streaming {} : {}", e.message(), subscription);
+                            break StopReason::TerminalError(e);
+                        } else if retryable_codes.contains(&e.code()) {
+                            tracing::trace!("reconnect - '{:?}' : {} ", e, subscription);
+                            reconnect = Some(ReconnectReason::Error(e.clone()));
+                            failures += 1;
+                            if !wait_reconnect(
+                                &config.reconnect_backoff,
+                                failures,
+                                state_for_inner.clock.as_ref(),
+                                &cancel_receiver,
+                            )
+                            .await
+                            {
+                                break StopReason::GracefulShutdown;
+                            }
+                            continue;
+                        } else {
+                            tracing::error!("terminated subscriber streaming with error {:?} : {}", e, subscription);
+                            break StopReason::TerminalError(e);
+                        }
+                    }
+                }
+            };
+            if let StopReason::TerminalError(e) = &reason {
+                *state_for_inner.terminal_error.lock().unwrap() = Some(e.clone());
+            }
+            if let Some(on_stopped) = &config.on_stopped {
+                on_stopped.call(&reason);
+            }
+            // The request streams may still hold the receiver, so close it to stop the pinger.
+            ping_receiver.close();
+            state_for_inner.stopped.send_replace(true);
+            // streaming request is closed when the ping_sender closed.
+            tracing::trace!("stop subscriber in streaming: {}", subscription);
+        });
+        Self {
+            pinger: Some(pinger),
+            leaser,
+            aggregator,
+            inner: Some(inner),
+            state,
+        }
+    }
+
+    async fn recv(
+        client: SubscriberClient,
+        mut stream: Streaming<StreamingPullResponse>,
+        subscription: &str,
+        cancel: CancellationToken,
+        queue: &async_channel::Sender<ReceivedMessage>,
+        config: &SubscriberConfig,
+        state: &Arc<State>,
+    ) -> Result<StreamEnd, Status> {
+        tracing::trace!("start streaming");
+        let mut paused = state.paused.subscribe();
+        let mut settings = state.settings.subscribe();
+        let backfill = config
+            .backfill
+            .as_ref()
+            .filter(|_| state.backfilling.load(Ordering::Relaxed));
+        let mut window = BackfillWindow::new(backfill, state.clock.now());
+        let stall_timeout = config.stall_timeout();
+        let mut last_received = state.clock.now();
+        let mut responded = false;
+        loop {
+            let paused_at = *paused.borrow_and_update();
+            if let Some(paused_at) = paused_at {
+                // Stop reading the stream so that the server's flow control stops the delivery.
+                tracing::trace!("pause streaming");
+                select! {
+                    _ = cancel.cancelled() => {
+                        close_queue(queue, subscription, state).await;
+                        return Ok(StreamEnd::Closed);
+                    }
+                    _ = paused.changed() => {}
+                }
+                let resumed = paused.borrow().is_none();
+                if resumed
+                    && config
+                        .reconnect_after_pause
+                        .is_some_and(|v| state.clock.now().duration_since(paused_at) >= v)
+                {
+                    return Ok(StreamEnd::Reconnect(ReconnectReason::Refresh));
+                }
+                last_received = state.clock.now();
+                // Nothing is received while paused, so it must not be counted as drained.
+                window = BackfillWindow::new(backfill, state.clock.now());
+                continue;
+            }
+            #[cfg(feature = "fault-injection")]
+            if let Some(fault) = config.fault_injector.as_ref().and_then(|v| v.take(FaultPoint::Recv)) {
+                return Err(fault);
+            }
+            select! {
+                _ = cancel.cancelled() => {
+                    close_queue(queue, subscription, state).await;
+                    return Ok(StreamEnd::Closed);
+                }
+                _ = paused.changed() => {}
+                _ = settings.changed() => {
+                    tracing::debug!("flow control changed -> so reconnect");
+                    return Ok(StreamEnd::Reconnect(ReconnectReason::Refresh));
+                }
+                _ = state.clock.sleep_until(window.deadline), if backfill.is_some() => {
+                    if window.received <= backfill.map_or(0, |v| v.drained_messages) {
+                        tracing::info!("backlog drained -> so reconnect for the live traffic");
+                        state.backfilling.store(false, Ordering::Relaxed);
+                        return Ok(StreamEnd::Reconnect(ReconnectReason::Refresh));
+                    }
+                    window = BackfillWindow::new(backfill, state.clock.now());
+                }
+                _ = state.clock.sleep_until(last_received + stall_timeout.unwrap_or_default()), if stall_timeout.is_some() => {
+                    tracing::warn!(?stall_timeout, "nothing received -> so reconnect the stalled stream");
+                    return Ok(StreamEnd::Stalled { responded });
+                }
+                maybe = stream.message() => {
+                    let message = maybe?;
+                    let mut message = match message {
+                        Some(m) => m,
+                        None => return Ok(StreamEnd::Closed)
+                    };
+                    responded = true;
+                    if let Some(on_response) = &config.on_response {
+                        on_response.call(&message);
+                    }
+                    if let Some(properties) = message.subscription_properties {
+                        if let Some(control) = &state.control {
+                            control.exactly_once.store(properties.exactly_once_delivery_enabled, Ordering::Relaxed);
+                        }
+                        *state.properties.lock().unwrap() = Some(properties);
+                    }
+                    if let Some(control) = &state.control {
+                        if let Some(confirmation) = message.modify_ack_deadline_confirmation.take() {
+                            control.confirm(confirmation);
+                        }
+                    }
+                    window.received += message.received_messages.len() as u64;
+                    let _ = handle_message(
+                        &cancel,
+                        queue,
+                        &client,
+                        subscription,
+                        message.received_messages,
+                        config,
+                        state,
+                    )
+                    .await;
+                    if queue.is_closed() && !cancel.is_cancelled() {
+                        tracing::error!("queue is closed while running -> so stop");
+                        return Ok(StreamEnd::QueueClosed);
+                    }
+                    // Waiting for the queue is not the silence of the stream.
+                    last_received = state.clock.now();
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> SubscriberStats {
+        self.state.counters.snapshot(self.state.clock.now())
+    }
+
+    /// subscription_properties returns the properties of the subscription sent by the server on the stream,
+    /// e.g. to assert that exactly-once delivery is enabled when the processing relies on it.
+    /// None until the server sends them in a response, typically the first one of the stream.
+    pub fn subscription_properties(&self) -> Option<SubscriptionProperties> {
+        *self.state.properties.lock().unwrap()
+    }
+
+    /// The histogram of the ack latency if `SubscriberConfig::record_ack_latency` is set.
+    pub(crate) fn ack_latency(&self) -> Option<&AckLatency> {
+        self.state.ack_latency.as_deref()
+    }
+
+    /// is_backfilling reports whether the subscriber is still draining the backlog with `SubscriberConfig::backfill`.
+    pub fn is_backfilling(&self) -> bool {
+        self.state.backfilling.load(Ordering::Relaxed)
+    }
+
+    /// is_running reports whether the streaming pull task is still alive.
+    /// It returns false once the task has stopped by cancellation, by a non-retryable error or by a panic.
+    pub fn is_running(&self) -> bool {
+        self.inner.as_ref().is_some_and(|v| !v.is_finished())
+    }
+
+    /// terminal_error returns the error that stopped the streaming pull task, if any.
+    pub fn terminal_error(&self) -> Option<Status> {
+        self.state.terminal_error.lock().unwrap().clone()
+    }
+
+    /// stopped returns a future that resolves when the streaming pull task stops.
+    /// It resolves to the terminal error unless the task stopped by the cancellation.
+    pub(crate) fn stopped(&self) -> impl Future<Output = Result<(), Status>> + Send + 'static {
+        let state = self.state.clone();
+        async move {
+            let _ = state.stopped.subscribe().wait_for(|v| *v).await;
+            let error = state.terminal_error.lock().unwrap().clone();
+            error.map_or(Ok(()), Err)
+        }
+    }
+
+    /// pause stops reading messages from the stream without closing it.
+    /// The server stops delivering messages when its flow control limits are reached, and the
+    /// stream is kept alive by the ping.
+    pub fn pause(&self) {
+        self.state.paused.send_if_modified(|v| {
+            if v.is_none() {
+                *v = Some(self.state.clock.now());
+                return true;
+            }
+            false
+        });
+    }
+
+    /// resume restarts reading messages.
+    /// See `SubscriberConfig::reconnect_after_pause` for long pauses.
+    pub fn resume(&self) {
+        self.state.paused.send_if_modified(|v| v.take().is_some());
+    }
+
+    /// apply_config reconnects the streaming pull if the flow control of the config differs from the current one.
+    pub fn apply_config(&self, config: &SubscriberConfig) -> Result<(), PubSubError> {
+        config.validate()?;
+        self.state.settings.send_if_modified(|v| {
+            let settings = StreamSettings::from(config);
+            if *v == settings {
+                return false;
+            }
+            *v = settings;
+            true
+        });
+        Ok(())
+    }
+
+    /// done waits for the tasks of the subscriber to stop after its cancellation token is cancelled.
+    pub async fn done(&mut self) {
+        if let Some(v) = self.pinger.take() {
+            let _ = v.await;
+        }
+        if let Some(v) = self.leaser.take() {
+            let _ = v.await;
+        }
+        if let Some(v) = self.inner.take() {
+            let _ = v.await;
+        }
+        // The delayed redeliveries are left running if the streaming pull stopped without the cancellation.
+        self.state.join_delayed().await;
+        // The acks sent after this are not batched.
+        if let Some(v) = self.aggregator.take() {
+            if let Some(aggregator) = &self.state.aggregator {
+                aggregator.close();
+            }
+            let _ = v.await;
+        }
+    }
+}
+
+async fn handle_message(
+    cancel: &CancellationToken,
+    queue: &async_channel::Sender<ReceivedMessage>,
+    client: &SubscriberClient,
+    subscription: &str,
+    messages: Vec<InternalReceivedMessage>,
+    config: &SubscriberConfig,
+    state: &Arc<State>,
+) -> usize {
+    let counters = &state.counters;
+    let mut nack_targets = vec![];
+    let mut ack_targets = vec![];
+    // The message_ids of `ack_targets`, reported to the observer once the ack succeeded.
+    let mut acked_ids = vec![];
+    let mut extend_targets = vec![];
+    let now = state.clock.system_now();
+    // The redeliveries are spread from the time the response was received.
+    let received_at = state.clock.now();
+    let notify_nacked = |message_id: &str| {
+        if let Some(observer) = &config.observer {
+            observer.on_nacked(subscription, message_id);
+        }
+    };
+    // The messages acked and dropped without reaching the handler.
+    let notify_acked = |message_id: &str| {
+        if let Some(observer) = &config.observer {
+            observer.on_acked(subscription, message_id, state.clock.now().duration_since(received_at));
+        }
+    };
+    for received_message in messages {
+        let internal = config.retain_internal_message.then(|| received_message.clone());
+        if let Some(message) = received_message.message {
+            let id = message.message_id.clone();
+            tracing::debug!(msg_id = %id, ack_id = %received_message.ack_id, "message received");
+            let mut msg = match internal {
+                Some(internal) => ReceivedMessage::with_internal(subscription.to_string(), client.clone(), internal),
+                None => ReceivedMessage::new(
+                    subscription.to_string(),
+                    client.clone(),
+                    message,
+                    received_message.ack_id.clone(),
+                    (received_message.delivery_attempt > 0).then_some(received_message.delivery_attempt as usize),
+                ),
+            };
+            msg.handle.recent_acks = state.recent_acks.clone();
+            msg.handle.aggregator = state.aggregator.clone();
+            msg.handle.ack_latency = state.ack_latency.clone();
+            msg.handle.control = state.control.clone();
+            msg.handle.idempotent_ack = config.idempotent_ack;
+            msg.handle.leases = state.leases.clone();
+            msg.handle.retry_settings = state.retry_settings.clone();
+            msg.handle.clock = Some(state.clock.clone());
+            msg.handle.received_at = received_at;
+            msg.deadline_attribute = state.deadline_attribute.clone();
+            #[cfg(feature = "opentelemetry")]
+            {
+                msg.trace_context_attribute = state.trace_context_attribute.clone();
+            }
+            #[cfg(feature = "opentelemetry")]
+            if let Some(telemetry) = &state.telemetry {
+                msg.handle.telemetry = Some(telemetry.clone());
+                telemetry.received(subscription, 1);
+            }
+            if let Some(observer) = &config.observer {
+                msg.handle.observer = Some(observer.clone());
+                observer.on_received(subscription, &id);
+            }
+            counters.received_messages.fetch_add(1, Ordering::Relaxed);
+            counters.received_bytes.fetch_add(msg.size() as u64, Ordering::Relaxed);
+            if let Some(max_age) = config.max_message_age {
+                if is_expired(msg.publish_time(), max_age, now) {
+                    tracing::debug!(msg_id = %id, ack_id = %received_message.ack_id, "message expired -> so ack and drop");
+                    counters.expired_messages.fetch_add(1, Ordering::Relaxed);
+                    acked_ids.push(id);
+                    ack_targets.push(received_message.ack_id);
+                    continue;
+                }
+            }
+            if config.drop_past_deadline && msg.message_deadline().is_some_and(|deadline| deadline < now) {
+                tracing::debug!(msg_id = %id, ack_id = %received_message.ack_id, "message past deadline -> so ack and drop");
+                counters.expired_messages.fetch_add(1, Ordering::Relaxed);
+                acked_ids.push(id);
+                ack_targets.push(received_message.ack_id);
+                continue;
+            }
+            if config.attribute_limits.is_exceeded(&msg.message.attributes) {
+                tracing::warn!(msg_id = %id, ack_id = %received_message.ack_id, "attribute limits exceeded -> so reject");
+                counters.rejected_messages.fetch_add(1, Ordering::Relaxed);
+                match config.attribute_limits.policy {
+                    RejectPolicy::Nack => {
+                        notify_nacked(&id);
+                        nack_targets.push(received_message.ack_id)
+                    }
+                    RejectPolicy::Discard => {
+                        acked_ids.push(id);
+                        ack_targets.push(received_message.ack_id)
+                    }
+                }
+                continue;
+            }
+            if let Some(policy) = config.reject_empty_payload.filter(|_| msg.is_empty_payload()) {
+                tracing::debug!(msg_id = %id, ack_id = %received_message.ack_id, "empty payload -> so reject");
+                counters.rejected_messages.fetch_add(1, Ordering::Relaxed);
+                match policy {
+                    RejectPolicy::Nack => {
+                        notify_nacked(&id);
+                        nack_targets.push(received_message.ack_id)
+                    }
+                    RejectPolicy::Discard => {
+                        acked_ids.push(id);
+                        ack_targets.push(received_message.ack_id)
+                    }
+                }
+                continue;
+            }
+            msg.handle.latest_ack_ids = state
+                .latest_ack_ids
+                .clone()
+                .map(|v| Arc::new(LatestAckIdGuard::new(v, &id, &received_message.ack_id)));
+            if let Some(leases) = &state.leases {
+                leases.insert(received_message.ack_id.clone(), state.clock.now());
+            }
+            if let Some((max_attempts, route)) = config.max_delivery_attempts.zip(config.on_near_dead_letter.as_ref()) {
+                if msg.is_near_dead_letter(max_attempts) {
+                    tracing::debug!(msg_id = %id, ack_id = %received_message.ack_id, "near dead letter -> so route");
+                    route.call(msg);
+                    continue;
+                }
+            }
+            let delay = config
+                .redelivery_jitter
+                .filter(|_| msg.delivery_attempt().is_some_and(|v| v > 1));
+            if let Some(jitter) = delay {
+                // Delivered by a task of its own so that the following messages and the stream are not blocked.
+                let deadline = received_at + jitter.mul_f64(random());
+                state.spawn_delayed(deliver_delayed(
+                    deadline,
+                    msg,
+                    cancel.clone(),
+                    queue.clone(),
+                    client.clone(),
+                    subscription.to_string(),
+                    config.on_cancel_policy,
+                    config.modify_ack_deadline_retry_setting.clone(),
+                    state.clone(),
+                ));
+                continue;
+            }
+            let should_nack = select! {
+                result = queue.send(msg) => result.is_err(),
+                _ = cancel.cancelled() => true
+            };
+            if !should_nack {
+                counters.delivered_messages.fetch_add(1, Ordering::Relaxed);
+            } else {
+                if cancel.is_cancelled() {
+                    tracing::info!(msg_id = %id, ack_id = %received_message.ack_id, policy = ?config.on_cancel_policy, "cancelled");
+                } else {
+                    // The consumer dropped the receivers without the cancellation.
+                    tracing::error!(
+                        msg_id = %id,
+                        ack_id = %received_message.ack_id,
+                        policy = ?config.on_cancel_policy,
+                        "queue is closed while running"
+                    );
+                }
+                if let Some(leases) = &state.leases {
+                    leases.remove(&received_message.ack_id);
+                }
+                match config.on_cancel_policy {
+                    CancelPolicy::NackImmediately => {
+                        counters.cancel_nacked_messages.fetch_add(1, Ordering::Relaxed);
+                        notify_nacked(&id);
+                        nack_targets.push(received_message.ack_id);
+                    }
+                    CancelPolicy::LetDeadlineExpire => {}
+                    CancelPolicy::ExtendDeadline(_) => extend_targets.push(received_message.ack_id),
+                }
+            }
+        }
+    }
+    let size = nack_targets.len();
+    let acks = async {
+        let retry = config.ack_retry_setting.clone();
+        match ack(client, subscription, ack_targets, retry, state.clock.as_ref()).await {
+            Ok(()) => acked_ids.iter().for_each(|id| notify_acked(id)),
+            // The messages are not reported as acked since they will be redelivered.
+            Err(err) => tracing::error!(
+                "failed to ack dropped messages {err}. The messages will be redelivered after the ack deadline."
+            ),
+        }
+    };
+    let nacks = async {
+        if size == 0 {
+            return;
+        }
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &state.telemetry {
+            telemetry.nacked(subscription, size as u64);
+        }
+        // Nack immediately although the queue is closed only when the cancellation token is closed.
+        let retry = config.modify_ack_deadline_retry_setting.clone();
+        if let Err(err) = nack(client, subscription, nack_targets, retry, state.clock.as_ref()).await {
+            tracing::error!(
+                "failed to nack immediately {err}. The messages will be redelivered after the ack deadline."
+            );
+        }
+    };
+    let extends = async {
+        let CancelPolicy::ExtendDeadline(seconds) = config.on_cancel_policy else {
+            return;
+        };
+        if extend_targets.is_empty() {
+            return;
+        }
+        let retry = config.modify_ack_deadline_retry_setting.clone();
+        let clock = state.clock.as_ref();
+        if let Err(err) = modify_ack_deadline(client, subscription, extend_targets, seconds, retry, clock).await {
+            tracing::error!("failed to extend the deadline of undelivered messages {err}.");
+        }
+    };
+    match config.shutdown_order {
+        ShutdownOrder::AcksFirst => {
+            acks.await;
+            nacks.await;
+            extends.await;
+        }
+        ShutdownOrder::NacksFirst => {
+            nacks.await;
+            extends.await;
+            acks.await;
+        }
+    }
+    size
+}
+
+/// deliver_delayed sends the redelivered message to the queue at `deadline` for `SubscriberConfig::redelivery_jitter`.
+/// The message is settled with the `CancelPolicy` like the other undelivered messages if the subscriber is cancelled
+/// or the queue is closed before it is delivered.
+#[allow(clippy::too_many_arguments)]
+async fn deliver_delayed(
+    deadline: tokio::time::Instant,
+    msg: ReceivedMessage,
+    cancel: CancellationToken,
+    queue: async_channel::Sender<ReceivedMessage>,
+    client: SubscriberClient,
+    subscription: String,
+    policy: CancelPolicy,
+    retry: Option<RetrySetting>,
+    state: Arc<State>,
+) {
+    let observer = msg.handle.observer.clone();
+    let (id, ack_id) = (msg.message.message_id.clone(), msg.ack_id().to_string());
+    let delivered = select! {
+        _ = state.clock.sleep_until(deadline) => select! {
+            result = queue.send(msg) => result.is_ok(),
+            _ = cancel.cancelled() => false
+        },
+        _ = cancel.cancelled() => false
+    };
+    if delivered {
+        state.counters.delivered_messages.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    tracing::info!(msg_id = %id, ack_id = %ack_id, ?policy, "not delivered after the redelivery delay");
+    if let Some(leases) = &state.leases {
+        leases.remove(&ack_id);
+    }
+    match policy {
+        CancelPolicy::NackImmediately => {
+            state.counters.cancel_nacked_messages.fetch_add(1, Ordering::Relaxed);
+            if let Some(observer) = &observer {
+                observer.on_nacked(&subscription, &id);
+            }
+            #[cfg(feature = "opentelemetry")]
+            if let Some(telemetry) = &state.telemetry {
+                telemetry.nacked(&subscription, 1);
+            }
+            if let Err(err) = nack(&client, &subscription, vec![ack_id], retry, state.clock.as_ref()).await {
+                tracing::error!(
+                    "failed to nack immediately {err}. The message will be redelivered after the ack deadline."
+                );
+            }
+        }
+        CancelPolicy::LetDeadlineExpire => {}
+        CancelPolicy::ExtendDeadline(seconds) => {
+            let clock = state.clock.as_ref();
+            if let Err(err) = modify_ack_deadline(&client, &subscription, vec![ack_id], seconds, retry, clock).await {
+                tracing::error!("failed to extend the deadline of the undelivered message {err}.");
+            }
+        }
+    }
+}
+
+/// extend_leases extends the deadline of the outstanding messages by `ack_deadline_seconds`.
+#[allow(clippy::too_many_arguments)]
+async fn extend_leases(
+    client: &SubscriberClient,
+    subscription: &str,
+    leases: &Leases,
+    ack_ids: Vec<String>,
+    ack_deadline_seconds: i32,
+    control: Option<&StreamControl>,
+    retry: Option<RetrySetting>,
+    clock: &dyn Clock,
+) {
+    for chunk in ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+        let outcomes = match control {
+            Some(control) => Ok(control
+                .modify_ack_deadlines(chunk.to_vec(), ack_deadline_seconds)
+                .await
+                .into_iter()
+                .map(|(ack_id, result)| {
+                    let outcome = match result {
+                        Ok(()) => AckOutcome::Success,
+                        Err(e) if e.code() == Code::FailedPrecondition => AckOutcome::InvalidAckId,
+                        Err(_) => AckOutcome::TransientFailure,
+                    };
+                    (ack_id, outcome)
+                })
+                .collect()),
+            None => ack_outcomes(
+                modify_ack_deadline(client, subscription, chunk.to_vec(), ack_deadline_seconds, retry.clone(), clock)
+                    .await,
+                chunk,
+            ),
+        };
+        let outcomes: HashMap<String, AckOutcome> = match outcomes {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!(
+                    "failed to extend the leases of {} messages {:?} : {}",
+                    chunk.len(),
+                    e,
+                    subscription
+                );
+                continue;
+            }
+        };
+        let mut transient = 0;
+        for (ack_id, outcome) in outcomes {
+            match outcome {
+                AckOutcome::Success => {}
+                AckOutcome::TransientFailure => transient += 1,
+                AckOutcome::InvalidAckId => {
+                    // The message will be redelivered, so the lease can't be extended anymore.
+                    tracing::warn!("stop extending the lease of the invalid ack_id {} : {}", ack_id, subscription);
+                    leases.remove(&ack_id);
+                }
+            }
+        }
+        if transient > 0 {
+            tracing::warn!(
+                "failed to extend the leases of {} messages temporarily : {}",
+                transient,
+                subscription
+            );
+        }
+    }
+}
+
+/// is_token_error returns whether the request failed before it was sent because the token could not be obtained.
+/// The status is returned by the auth interceptor of the connection, and the refresh is retried on the reconnect
+/// even if UNAUTHENTICATED is not in the retry codes.
+fn is_token_error(status: &Status) -> bool {
+    status.code() == Code::Unauthenticated && status.message().starts_with("token error")
+}
+
+/// wait_reconnect waits for the backoff before the reconnect and returns false if cancelled meanwhile.
+async fn wait_reconnect(
+    backoff: &ReconnectBackoff,
+    failures: u32,
+    clock: &dyn Clock,
+    cancel: &CancellationToken,
+) -> bool {
+    select! {
+        _ = clock.sleep(backoff.delay(failures)) => true,
+        _ = cancel.cancelled() => false,
+    }
+}
+
+/// close_queue closes the queue on the cancellation unless other subscribers share it.
+/// A shared queue is closed when the last sender is dropped, so that the cancellation of one subscriber
+/// doesn't fail the sends of the others and make them nack the messages.
+/// The delayed redeliveries are settled first so that their senders are not taken for other subscribers.
+async fn close_queue(queue: &async_channel::Sender<ReceivedMessage>, subscription: &str, state: &State) {
+    state.join_delayed().await;
+    if queue.sender_count() == 1 {
+        queue.close();
+    } else {
+        tracing::trace!("queue is shared -> so leave it open : {}", subscription);
+    }
+}
+
+fn is_expired(publish_time: Option<SystemTime>, max_age: Duration, now: SystemTime) -> bool {
+    match publish_time {
+        Some(publish_time) => now.duration_since(publish_time).is_ok_and(|age| age > max_age),
+        None => false,
+    }
+}
+
+/// The reason of the ErrorInfo returned by the failed acks and modacks for exactly-once subscriptions.
+const EXACTLY_ONCE_ACK_ID_FAILURE: &str = "EXACTLY_ONCE_ACKID_FAILURE";
+const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// google.rpc.ErrorInfo in the details of the status.
+#[derive(Clone, PartialEq, prost::Message)]
+struct ErrorInfo {
+    #[prost(string, tag = "1")]
+    reason: String,
+    #[prost(string, tag = "2")]
+    domain: String,
+    /// The failure of each ack_id for EXACTLY_ONCE_ACKID_FAILURE.
+    #[prost(map = "string, string", tag = "3")]
+    metadata: HashMap<String, String>,
+}
+
+/// Returns the failures of the ack_ids reported in the status details for exactly-once subscriptions.
+fn ack_id_failures(status: &Status) -> Option<HashMap<String, String>> {
+    use prost::Message;
+    let details = google_cloud_googleapis::rpc::Status::decode(status.details()).ok()?;
+    details
+        .details
+        .iter()
+        .filter(|v| v.type_url == ERROR_INFO_TYPE_URL)
+        .filter_map(|v| ErrorInfo::decode(&v.value[..]).ok())
+        .find(|v| v.reason == EXACTLY_ONCE_ACK_ID_FAILURE)
+        .map(|v| v.metadata)
+}
+
+/// Returns the failure of the ack_id reported in the status details for exactly-once subscriptions.
+fn ack_id_failure(status: &Status, ack_id: &str) -> Option<String> {
+    ack_id_failures(status)?.remove(ack_id)
+}
+
+/// Maps the result of the ack request to the outcome of each ack_id.
+/// The ack_ids not reported in the failures of an exactly-once subscription succeeded.
+fn ack_outcomes(
+    result: Result<(), PubSubError>,
+    ack_ids: &[String],
+) -> Result<HashMap<String, AckOutcome>, PubSubError> {
+    let failures = match result {
+        Ok(()) => HashMap::new(),
+        Err(e) => e.status().and_then(ack_id_failures).ok_or(e)?,
+    };
+    Ok(ack_ids
+        .iter()
+        .map(|ack_id| {
+            let outcome = match failures.get(ack_id) {
+                None => AckOutcome::Success,
+                Some(v) if v.starts_with("TRANSIENT_FAILURE") => AckOutcome::TransientFailure,
+                Some(_) => AckOutcome::InvalidAckId,
+            };
+            (ack_id.clone(), outcome)
+        })
+        .collect())
+}
+
+/// Returns the status reporting the failures of the ack_ids like the one of a single request.
+fn with_ack_id_failures(status: &Status, failures: HashMap<String, String>) -> Status {
+    use prost::Message;
+    let error_info = ErrorInfo {
+        reason: EXACTLY_ONCE_ACK_ID_FAILURE.to_string(),
+        domain: "pubsub.googleapis.com".to_string(),
+        metadata: failures,
+    };
+    let details = google_cloud_googleapis::rpc::Status {
+        code: status.code() as i32,
+        message: status.message().to_string(),
+        details: vec![prost_types::Any {
+            type_url: ERROR_INFO_TYPE_URL.to_string(),
+            value: error_info.encode_to_vec(),
+        }],
+    };
+    Status::with_details(status.code(), status.message(), details.encode_to_vec().into())
+}
+
+/// retry_transient_ack_ids sends the ack_ids and resends the ones reported as TRANSIENT_FAILURE by an exactly-once
+/// subscription with the backoff of the retry setting, since the server fails them individually instead of
+/// failing the request. The permanent failures are not retried.
+/// The error reports the remaining failures of all the attempts like the one of a single request,
+/// with the status of the retry if it failed as a whole.
+/// It is a single request for the other subscriptions, which never report the failures of the ack_ids.
+async fn retry_transient_ack_ids<F, Fut>(
+    ack_ids: Vec<String>,
+    retry: Option<RetrySetting>,
+    clock: &dyn Clock,
+    send: F,
+) -> Result<(), Status>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<(), Status>>,
+{
+    let mut backoff = retry.unwrap_or_default().strategy();
+    let mut failures = HashMap::new();
+    let mut pending = ack_ids;
+    // The failures of the ack_ids being retried.
+    let mut retried = HashMap::new();
+    let mut last = None;
+    loop {
+        let e = match send(pending).await {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        let Some(reported) = ack_id_failures(&e) else {
+            if retried.is_empty() {
+                return Err(e);
+            }
+            // A retry failed as a whole: the retried ack_ids keep their transient failures, and the others keep
+            // the results of the earlier attempts.
+            failures.extend(retried);
+            return Err(with_ack_id_failures(&e, failures));
+        };
+        pending = reported
+            .iter()
+            .filter(|(_, v)| v.starts_with("TRANSIENT_FAILURE"))
+            .map(|(k, _)| k.clone())
+            .collect();
+        failures.extend(reported);
+        last = Some(e);
+        let Some(delay) = backoff.next().filter(|_| !pending.is_empty()) else {
+            break;
+        };
+        tracing::debug!("retry the transient failures of {} ack_ids after {delay:?}", pending.len());
+        clock.sleep(delay).await;
+        retried = pending.iter().filter_map(|v| failures.remove_entry(v)).collect();
+    }
+    match last {
+        Some(last) if !failures.is_empty() => Err(with_ack_id_failures(&last, failures)),
+        _ => Ok(()),
+    }
+}
+
+fn is_invalid_ack_id(status: &Status, ack_id: &str) -> bool {
+    ack_id_failure(status, ack_id).is_some_and(|v| v == "PERMANENT_FAILURE_INVALID_ACK_ID")
+}
+
+/// check_ack_deadline fails with `PubSubError::DeadlineOutOfRange` unless the deadline is between 0 and 600 seconds.
+pub(crate) fn check_ack_deadline(ack_deadline_seconds: i32) -> Result<(), PubSubError> {
+    if !(0..=MAX_STREAM_ACK_DEADLINE_SECONDS).contains(&ack_deadline_seconds) {
+        return Err(PubSubError::DeadlineOutOfRange(ack_deadline_seconds));
+    }
+    Ok(())
+}
+
+/// Returns the interval randomized by up to `jitter` of itself in both directions.
+fn jittered(interval: Duration, jitter: Option<f64>) -> Duration {
+    let jitter = match jitter {
+        Some(jitter) if jitter > 0.0 => jitter.min(1.0),
+        _ => return interval,
+    };
+    interval.mul_f64(1.0 + jitter * (2.0 * random() - 1.0))
+}
+
+/// Returns a random number between 0.0 and 1.0.
+fn random() -> f64 {
+    // A RandomState is seeded with different keys each time.
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+/// modify_ack_deadline sends the ack_ids in chunks of `MAX_ACK_IDS_PER_REQUEST` one after another.
+/// All the chunks are sent even if some of them fail, and the failures are merged like the result of a single request.
+pub(crate) async fn modify_ack_deadline(
+    subscriber_client: &SubscriberClient,
+    subscription: &str,
+    ack_ids: Vec<String>,
+    ack_deadline_seconds: i32,
+    retry: Option<RetrySetting>,
+    clock: &dyn Clock,
+) -> Result<(), PubSubError> {
+    if ack_ids.is_empty() {
+        return Ok(());
+    }
+    let mut results = Vec::with_capacity(ack_ids.len().div_ceil(MAX_ACK_IDS_PER_REQUEST));
+    for chunk in ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+        let result = retry_transient_ack_ids(chunk.to_vec(), retry.clone(), clock, |ack_ids| {
+            let req = ModifyAckDeadlineRequest {
+                subscription: subscription.to_string(),
+                ack_deadline_seconds,
+                ack_ids,
+            };
+            let retry = retry.clone();
+            async move {
+                subscriber_client
+                    .modify_ack_deadline(req, retry)
+                    .await
+                    .map(|e| e.into_inner())
+            }
+        })
+        .await;
+        results.push(result);
+    }
+    merge_ack_results(results)
+}
+
+/// merge_ack_results merges the results of the chunks of a request.
+/// The failures of the ack_ids reported by the chunks are merged into the first error, unless a chunk failed
+/// without them, in which case that error is returned as the whole request failed.
+fn merge_ack_results(results: Vec<Result<(), Status>>) -> Result<(), PubSubError> {
+    let mut first = None;
+    let mut failures = HashMap::new();
+    for e in results.into_iter().filter_map(Result::err) {
+        match ack_id_failures(&e) {
+            Some(reported) => {
+                failures.extend(reported);
+                first.get_or_insert(e);
+            }
+            None => return Err(e.into()),
+        }
+    }
+    match first {
+        Some(first) => Err(with_ack_id_failures(&first, failures).into()),
+        None => Ok(()),
+    }
+}
+
+pub(crate) async fn nack(
+    subscriber_client: &SubscriberClient,
+    subscription: &str,
+    ack_ids: Vec<String>,
+    retry: Option<RetrySetting>,
+    clock: &dyn Clock,
+) -> Result<(), PubSubError> {
+    modify_ack_deadline(subscriber_client, subscription, ack_ids, 0, retry, clock).await
+}
+
+pub(crate) async fn ack(
+    subscriber_client: &SubscriberClient,
+    subscription: &str,
+    ack_ids: Vec<String>,
+    retry: Option<RetrySetting>,
+    clock: &dyn Clock,
+) -> Result<(), PubSubError> {
+    if ack_ids.is_empty() {
+        return Ok(());
+    }
+    retry_transient_ack_ids(ack_ids, retry.clone(), clock, |ack_ids| {
+        let req = AcknowledgeRequest {
+            subscription: subscription.to_string(),
+            ack_ids,
+        };
+        let retry = retry.clone();
+        async move { subscriber_client.acknowledge(req, retry).await.map(|e| e.into_inner()) }
+    })
+    .await
+    .map_err(PubSubError::from)
+}
+
+/// PendingAck is an ack_id waiting for the batch and the sender of its result.
+type PendingAck = (String, oneshot::Sender<Result<(), PubSubError>>);
+
+/// AckAggregator sends the acks of `SubscriberConfig::ack_batching` from a background task.
+#[derive(Debug)]
+pub(crate) struct AckAggregator {
+    sender: async_channel::Sender<PendingAck>,
+}
+
+impl AckAggregator {
+    fn start(
+        client: SubscriberClient,
+        subscription: String,
+        batching: AckBatchConfig,
+        retry: Option<RetrySetting>,
+        ack_latency: Option<Arc<AckLatency>>,
+        clock: Arc<dyn Clock>,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = async_channel::unbounded::<PendingAck>();
+        let max_ack_batch = batching.max_ack_batch.clamp(1, MAX_ACK_IDS_PER_REQUEST);
+        let task = tokio::spawn(async move {
+            // The queued acks are still received after the channel is closed.
+            while let Ok(first) = receiver.recv().await {
+                let mut flush = clock.sleep(batching.max_ack_latency);
+                let mut batch = vec![first];
+                while batch.len() < max_ack_batch {
+                    select! {
+                        pending = receiver.recv() => match pending {
+                            Ok(pending) => batch.push(pending),
+                            // Closed.
+                            Err(_) => break,
+                        },
+                        _ = &mut flush => break,
+                    }
+                }
+                let (ack_ids, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+                tracing::trace!("ack {} ack_ids in batch : {}", ack_ids.len(), subscription);
+                let request = ack(&client, &subscription, ack_ids.clone(), retry.clone(), clock.as_ref());
+                let result = AckLatency::timed(ack_latency.as_deref(), clock.as_ref(), request).await;
+                for (result, sender) in split_ack_result(result, ack_ids).into_iter().zip(senders) {
+                    let _ = sender.send(result);
+                }
+            }
+            tracing::trace!("stop ack batching : {}", subscription);
+        });
+        (Self { sender }, task)
+    }
+
+    /// enqueue returns the receiver of the result, or the ack_id back if the batching stopped.
+    fn enqueue(&self, ack_id: String) -> Result<oneshot::Receiver<Result<(), PubSubError>>, String> {
+        let (tx, rx) = oneshot::channel();
+        match self.sender.try_send((ack_id, tx)) {
+            Ok(()) => Ok(rx),
+            Err(e) => Err(e.into_inner().0),
+        }
+    }
+
+    /// close stops accepting the acks, and the task stops after sending the pending ones.
+    fn close(&self) {
+        self.sender.close();
+    }
+}
+
+/// split_ack_result returns the result of each ack_id of the request.
+/// The ack_ids failed individually by an exactly-once subscription get the error with their own failure,
+/// and the others succeed. Every ack_id gets the error if the request itself failed.
+fn split_ack_result(result: Result<(), PubSubError>, ack_ids: Vec<String>) -> Vec<Result<(), PubSubError>> {
+    let Err(e) = result else {
+        return ack_ids.iter().map(|_| Ok(())).collect();
+    };
+    let e = e.into_status();
+    let Some(mut failures) = ack_id_failures(&e) else {
+        return ack_ids.iter().map(|_| Err(e.clone().into())).collect();
+    };
+    ack_ids
+        .into_iter()
+        .map(|ack_id| match failures.remove(&ack_id) {
+            Some(reason) => Err(with_ack_id_failures(&e, HashMap::from([(ack_id, reason)])).into()),
+            None => Ok(()),
+        })
+        .collect()
+}
+
+/// MultiAckBatcher collects the ack_ids of messages received from any number of subscriptions.
+/// An `AcknowledgeRequest` is scoped to a single subscription, so `flush` sends one request per
+/// subscription (chunked by `MAX_ACK_IDS_PER_REQUEST`) and runs them concurrently.
+#[derive(Debug, Default)]
+pub struct MultiAckBatcher {
+    pending: HashMap<String, AckGroup>,
+    /// The handles of the pending ack_ids with the ack_id sent for them, tracked as acked once their request succeeds.
+    handles: Vec<(AckHandle, String)>,
+}
+
+/// AckGroup is the ack_ids of a subscription sent together, with the retry settings of the subscriber
+/// that received them.
+#[derive(Debug)]
+struct AckGroup {
+    client: SubscriberClient,
+    retry_settings: Option<Arc<AckRetrySettings>>,
+    clock: Option<Arc<dyn Clock>>,
+    ack_ids: Vec<String>,
+}
+
+impl AckGroup {
+    fn new(handle: &AckHandle) -> Self {
+        Self {
+            client: handle.subscriber_client.clone(),
+            retry_settings: handle.retry_settings.clone(),
+            clock: handle.clock.clone(),
+            ack_ids: vec![],
+        }
+    }
+
+    fn retry(&self, is_nack: bool) -> Option<RetrySetting> {
+        self.retry_settings.as_ref().and_then(|v| {
+            if is_nack {
+                v.modify_ack_deadline.clone()
+            } else {
+                v.ack.clone()
+            }
+        })
+    }
+}
+
+impl MultiAckBatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// push registers the message to be acknowledged on the next flush.
+    /// The latest ack_id of the message is sent, and nothing is sent if the message was already acked.
+    pub fn push(&mut self, message: &ReceivedMessage) {
+        let handle = &message.handle;
+        let Some(ack_id) = handle.batch_ack_id(false) else {
+            return;
+        };
+        self.pending
+            .entry(handle.subscription.clone())
+            .or_insert_with(|| AckGroup::new(handle))
+            .ack_ids
+            .push(ack_id.clone());
+        self.handles.push((handle.clone(), ack_id));
+    }
+
+    /// len returns the number of ack_ids waiting to be flushed.
+    pub fn len(&self) -> usize {
+        self.pending.values().map(|v| v.ack_ids.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// flush acknowledges all the pending ack_ids.
+    /// All the requests are sent even if some of them fail, and the first error is returned.
+    /// The messages of the successful requests are tracked as acked like `ack_all`.
+    pub async fn flush(&mut self) -> Result<(), PubSubError> {
+        let (acked, result) = send_in_batch(self.pending.drain(), false).await;
+        let handles = std::mem::take(&mut self.handles);
+        settle_succeeded(handles.iter().map(|(h, v)| (h, v.as_str())), &acked, AckHandle::acked);
+        result
+    }
+}
+
+/// ack_all acknowledges the messages with a single `AcknowledgeRequest` per subscription
+/// (chunked by `MAX_ACK_IDS_PER_REQUEST`) instead of one request per message.
+/// Like `AckHandle::ack`, the latest ack_id of each message is sent and the messages already acked are skipped.
+/// Duplicated ack_ids are sent only once. All the requests are sent even if some of them fail, and the first error is returned.
+/// The messages of the successful requests are tracked as acked like `AckHandle::ack` even if other requests fail.
+pub async fn ack_all(messages: &[ReceivedMessage]) -> Result<(), PubSubError> {
+    let (grouped, sent) = group_ack_ids(messages, false);
+    let (acked, result) = send_in_batch(grouped, false).await;
+    settle_succeeded(sent.iter().map(|(h, v)| (*h, v.as_str())), &acked, AckHandle::acked);
+    result
+}
+
+/// nack_all is the `nack` counterpart of `ack_all`: it sends a single `ModifyAckDeadlineRequest`
+/// with a zero deadline per subscription, so the messages are redelivered.
+/// The messages of the successful requests are tracked as nacked like `AckHandle::nack` even if other requests fail.
+pub async fn nack_all(messages: &[ReceivedMessage]) -> Result<(), PubSubError> {
+    let (grouped, sent) = group_ack_ids(messages, true);
+    let (nacked, result) = send_in_batch(grouped, true).await;
+    settle_succeeded(sent.iter().map(|(h, v)| (*h, v.as_str())), &nacked, AckHandle::nacked);
+    result
+}
+
+/// settle_succeeded calls `settle` once per subscription and ack_id pair of the handles that succeeded in `send_in_batch`,
+/// with the ack_id sent for the handle.
+fn settle_succeeded<'a>(
+    sent: impl IntoIterator<Item = (&'a AckHandle, &'a str)>,
+    succeeded: &HashSet<(String, String)>,
+    settle: impl Fn(&AckHandle, &str),
+) {
+    let mut settled = HashSet::new();
+    for (handle, ack_id) in sent {
+        let key = (handle.subscription.clone(), ack_id.to_string());
+        if succeeded.contains(&key) && settled.insert(key) {
+            settle(handle, ack_id);
+        }
+    }
+}
+
+/// ack_all_with_result is the batch variant of `AckHandle::ack_with_result`.
+/// It acks the messages like `ack_all` and returns the outcome of each message keyed by `ReceivedMessage::ack_id`,
+/// which is the outcome of the latest ack_id sent for the message. The messages already acked are `Success`.
+/// All the requests are sent even if some of them fail, and the first error of the requests is returned.
+pub async fn ack_all_with_result(messages: &[ReceivedMessage]) -> Result<HashMap<String, AckOutcome>, PubSubError> {
+    let (grouped, sent) = group_ack_ids(messages, false);
+    let mut tasks = JoinSet::new();
+    for (subscription, group) in grouped {
+        let retry = group.retry(false);
+        for chunk in group.ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+            let client = group.client.clone();
+            let subscription = subscription.clone();
+            let chunk = chunk.to_vec();
+            let retry = retry.clone();
+            let clock = group.clock.clone();
+            tasks.spawn(async move {
+                let clock = clock.as_deref().unwrap_or(&SystemClock);
+                let result = ack(&client, &subscription, chunk.clone(), retry, clock).await;
+                ack_outcomes(result, &chunk)
+            });
+        }
+    }
+    let mut outcomes = HashMap::new();
+    let mut result = Ok(());
+    while let Some(joined) = tasks.join_next().await {
+        let e = match joined {
+            Ok(Ok(v)) => {
+                outcomes.extend(v);
+                continue;
+            }
+            Ok(Err(e)) => e,
+            Err(e) => Status::internal(e.to_string()).into(),
+        };
+        tracing::error!("failed to ack in batch {:?}", e);
+        if result.is_ok() {
+            result = Err(e);
+        }
+    }
+    let mut results = HashMap::with_capacity(messages.len());
+    for message in messages {
+        results.insert(message.handle.ack_id.clone(), AckOutcome::Success);
+    }
+    for (handle, ack_id) in sent {
+        match outcomes.get(&ack_id) {
+            Some(AckOutcome::Success) => handle.acked(&ack_id),
+            Some(outcome) => {
+                results.insert(handle.ack_id.clone(), *outcome);
+            }
+            // The request of the ack_id failed.
+            None => {
+                results.remove(&handle.ack_id);
+            }
+        }
+    }
+    result.map(|_| results)
+}
+
+/// pull_batch pulls up to `max_messages` messages of the subscription with a single Pull request, for the batch
+/// workloads that don't need the streaming pull. It returns an empty list if no message is available within `timeout`.
+/// The timeout is the deadline of the Pull request itself, so the server doesn't lease messages to a request the
+/// client has given up on. The failures with the codes of `retry` are retried until the timeout.
+/// No background task is spawned, so the messages must be acked within the ack deadline of the subscription.
+pub async fn pull_batch(
+    client: &SubscriberClient,
+    subscription: &str,
+    max_messages: i32,
+    timeout: Duration,
+    retry: Option<RetrySetting>,
+) -> Result<Vec<ReceivedMessage>, PubSubError> {
+    pull_within(client, subscription, max_messages, timeout, retry, &SystemClock).await
+}
+
+/// pull_within is `pull_batch` with the time of the timeout and the retry backoff read from `clock`.
+pub(crate) async fn pull_within(
+    client: &SubscriberClient,
+    subscription: &str,
+    max_messages: i32,
+    timeout: Duration,
+    retry: Option<RetrySetting>,
+    clock: &dyn Clock,
+) -> Result<Vec<ReceivedMessage>, PubSubError> {
+    let deadline = clock.now() + timeout;
+    let retry = retry.unwrap_or_default();
+    let mut backoff = retry.strategy();
+    #[allow(deprecated)]
+    let req = PullRequest {
+        subscription: subscription.to_string(),
+        return_immediately: false,
+        max_messages,
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(clock.now());
+        if remaining.is_zero() {
+            return Ok(vec![]);
+        }
+        let status = match client.pull_with_timeout(req.clone(), remaining).await {
+            Ok(response) => return Ok(received_messages(client, subscription, response.into_inner())),
+            Err(status) if status.code() == Code::DeadlineExceeded => return Ok(vec![]),
+            // tonic reports the deadline elapsed on the client side as CANCELLED. The other cancellations, e.g. by
+            // the server or the connection, are retried or returned like the other errors.
+            Err(status) if status.code() == Code::Cancelled && clock.now() >= deadline => return Ok(vec![]),
+            Err(status) => status,
+        };
+        let Some(delay) = backoff.next().filter(|_| retry.codes.contains(&status.code())) else {
+            return Err(status.into());
+        };
+        tracing::debug!("retry the pull after {delay:?}: {status}");
+        clock.sleep_until(deadline.min(clock.now() + delay)).await;
+    }
+}
+
+pub(crate) async fn pull(
+    client: &SubscriberClient,
+    subscription: String,
+    max_messages: i32,
+    retry: Option<RetrySetting>,
+) -> Result<Vec<ReceivedMessage>, Status> {
+    #[allow(deprecated)]
+    let req = PullRequest {
+        subscription: subscription.clone(),
+        return_immediately: false,
+        max_messages,
+    };
+    let response = client.pull(req, retry).await?.into_inner();
+    Ok(received_messages(client, &subscription, response))
+}
+
+fn received_messages(client: &SubscriberClient, subscription: &str, response: PullResponse) -> Vec<ReceivedMessage> {
+    response
+        .received_messages
+        .into_iter()
+        .filter_map(|m| {
+            let delivery_attempt = (m.delivery_attempt > 0).then_some(m.delivery_attempt as usize);
+            let message = m.message?;
+            Some(ReceivedMessage::new(
+                subscription.to_string(),
+                client.clone(),
+                message,
+                m.ack_id,
+                delivery_attempt,
+            ))
+        })
+        .collect()
+}
+
+/// group_ack_ids groups the ack_ids to send for the messages by subscription, and returns the ack_id sent for each handle.
+/// See `AckHandle::batch_ack_id` for the ack_id sent.
+fn group_ack_ids(
+    messages: &[ReceivedMessage],
+    is_nack: bool,
+) -> (HashMap<String, AckGroup>, Vec<(&AckHandle, String)>) {
+    let mut grouped: HashMap<String, AckGroup> = HashMap::new();
+    let mut sent = Vec::with_capacity(messages.len());
+    let mut seen = HashSet::new();
+    for message in messages {
+        let handle = &message.handle;
+        let Some(ack_id) = handle.batch_ack_id(is_nack) else {
+            continue;
+        };
+        if seen.insert((handle.subscription.as_str(), ack_id.clone())) {
+            grouped
+                .entry(handle.subscription.clone())
+                .or_insert_with(|| AckGroup::new(handle))
+                .ack_ids
+                .push(ack_id.clone());
+        }
+        sent.push((handle, ack_id));
+    }
+    (grouped, sent)
+}
+
+/// send_in_batch returns the subscription and ack_id pairs of the successful requests and the first error.
+async fn send_in_batch(
+    pending: impl IntoIterator<Item = (String, AckGroup)>,
+    is_nack: bool,
+) -> (HashSet<(String, String)>, Result<(), PubSubError>) {
+    let mut tasks = JoinSet::new();
+    for (subscription, group) in pending {
+        let retry = group.retry(is_nack);
+        for chunk in group.ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+            let client = group.client.clone();
+            let subscription = subscription.clone();
+            let chunk = chunk.to_vec();
+            let retry = retry.clone();
+            let clock = group.clock.clone();
+            tasks.spawn(async move {
+                let clock = clock.as_deref().unwrap_or(&SystemClock);
+                let result = if is_nack {
+                    nack(&client, &subscription, chunk.clone(), retry, clock).await
+                } else {
+                    ack(&client, &subscription, chunk.clone(), retry, clock).await
+                };
+                result.map(|_| (subscription, chunk))
+            });
+        }
+    }
+    let mut succeeded = HashSet::new();
+    let mut result = Ok(());
+    while let Some(joined) = tasks.join_next().await {
+        let e = match joined {
+            Ok(Ok((subscription, chunk))) => {
+                succeeded.extend(chunk.into_iter().map(|ack_id| (subscription.clone(), ack_id)));
+                continue;
+            }
+            Ok(Err(e)) => e,
+            Err(e) => Status::internal(e.to_string()).into(),
+        };
+        tracing::error!("failed to {} in batch {:?}", if is_nack { "nack" } else { "ack" }, e);
+        if result.is_ok() {
+            result = Err(e);
+        }
+    }
+    (succeeded, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use serial_test::serial;
+    use tokio::select;
+    use tokio::time::Instant;
+    use tokio_util::sync::CancellationToken;
+    use uuid::Uuid;
+
+    use google_cloud_gax::conn::{ConnectionOptions, Environment};
+    use google_cloud_gax::grpc::{Code, Status};
+    use google_cloud_gax::retry::RetrySetting;
+    use google_cloud_googleapis::pubsub::v1::streaming_pull_response::ModifyAckDeadlineConfirmation;
+    use google_cloud_googleapis::pubsub::v1::{
+        AcknowledgeRequest, DeleteSubscriptionRequest, PublishRequest, PubsubMessage, PullRequest,
+        ReceivedMessage as InternalReceivedMessage, Subscription,
+    };
+
+    use crate::apiv1::conn_pool::ConnectionManager;
+    use crate::apiv1::publisher_client::PublisherClient;
+    use crate::apiv1::subscriber_client::tests::empty_client;
+    use crate::apiv1::subscriber_client::SubscriberClient;
+    use crate::error::PubSubError;
+    use crate::subscriber::{
+        close_queue, decode_limit_exceeded, handle_message, is_expired, jittered, merge_ack_results, wait_reconnect,
+        AckBatchConfig, AttributeLimits, CancelPolicy, Clock, FlowControl, HandOff, Hook, LatestAckIdGuard,
+        LatestAckIds, LeaseConfig, Leases, LogThrottle, MessageRoute, ReceivedMessage, RecentAcks, ReconnectBackoff,
+        RejectPolicy, State, StopReason, StreamControl, Subscriber, SubscriberConfig, SubscriberObserver,
+        SubscriberStats, SystemClock,
+    };
+    use crate::subscription::SubscriptionName;
+
+    #[ctor::ctor]
+    fn init() {
+        let _ = tracing_subscriber::fmt().try_init();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_handle_message_immediately_nack() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let pubc = PublisherClient::new(cm().await);
+
+        pubc.publish(
+            PublishRequest {
+                topic: "projects/local-project/topics/test-topic1".to_string(),
+                messages: vec![PubsubMessage {
+                    data: "hoge".into(),
+                    ..Default::default()
+                }],
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        let subscription = "projects/local-project/subscriptions/test-subscription1";
+        let response = subc
+            .pull(
+                PullRequest {
+                    subscription: subscription.to_string(),
+                    max_messages: 1,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .into_inner();
+
+        let messages = response.received_messages;
+        let (queue, _) = async_channel::unbounded();
+        queue.close();
+        let nack_size = handle_message(
+            &CancellationToken::new(),
+            &queue,
+            &subc,
+            subscription,
+            messages,
+            &SubscriberConfig::default(),
+            &Arc::new(State::new(&SubscriberConfig::default())),
+        )
+        .await;
+        assert_eq!(1, nack_size);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handle_message_on_cancel_policy() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let pubc = PublisherClient::new(cm().await);
+
+        let topic = "projects/local-project/topics/test-topic1";
+        let subscription = format!("projects/local-project/subscriptions/s{}", Uuid::new_v4());
+        subc.create_subscription(
+            Subscription {
+                name: subscription.clone(),
+                topic: topic.to_string(),
+                ack_deadline_seconds: 10,
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        let pull = || async {
+            subc.pull(
+                PullRequest {
+                    subscription: subscription.clone(),
+                    max_messages: 1,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .received_messages
+        };
+
+        for (policy, nacked) in [
+            (CancelPolicy::LetDeadlineExpire, 0),
+            (CancelPolicy::ExtendDeadline(60), 0),
+            (CancelPolicy::NackImmediately, 1),
+        ] {
+            pubc.publish(
+                PublishRequest {
+                    topic: topic.to_string(),
+                    messages: vec![PubsubMessage {
+                        data: "hoge".into(),
+                        ..Default::default()
+                    }],
+                },
+                None,
+            )
+            .await
+            .unwrap();
+            let messages = pull().await;
+            let handled = messages[0].message.as_ref().unwrap().message_id.clone();
+            let (queue, _) = async_channel::unbounded();
+            queue.close();
+            let config = SubscriberConfig {
+                on_cancel_policy: policy,
+                ..Default::default()
+            };
+            let state = Arc::new(State::new(&config));
+            let nack_size = handle_message(
+                &CancellationToken::new(),
+                &queue,
+                &subc,
+                &subscription,
+                messages,
+                &config,
+                &state,
+            )
+            .await;
+            assert_eq!(nacked, nack_size, "{policy:?}");
+            let stats = state.counters.snapshot(state.clock.now());
+            assert_eq!(nacked as u64, stats.cancel_nacked_messages, "{policy:?}");
+            assert_eq!(0, stats.delivered_messages);
+            // The messages handled in the previous iterations may be redelivered meanwhile.
+            let wait = Duration::from_secs(if nacked == 1 { 20 } else { 3 });
+            let redelivered = tokio::time::timeout(wait, async {
+                loop {
+                    for m in pull().await {
+                        subc.acknowledge(
+                            AcknowledgeRequest {
+                                subscription: subscription.clone(),
+                                ack_ids: vec![m.ack_id],
+                            },
+                            None,
+                        )
+                        .await
+                        .unwrap();
+                        if m.message.unwrap().message_id == handled {
+                            return;
+                        }
                     }
                 }
+            })
+            .await;
+            // Not redelivered until the deadline of the subscription or the extended one unless nacked.
+            assert_eq!(nacked == 1, redelivered.is_ok(), "{policy:?}");
+        }
+
+        subc.delete_subscription(DeleteSubscriptionRequest { subscription }, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handle_message_reject_empty_payload() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let pubc = PublisherClient::new(cm().await);
+
+        // Use a new subscription so that messages left by the other tests are not pulled.
+        let topic = "projects/local-project/topics/test-topic1";
+        let subscription = format!("projects/local-project/subscriptions/s{}", Uuid::new_v4());
+        subc.create_subscription(
+            Subscription {
+                name: subscription.clone(),
+                topic: topic.to_string(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        pubc.publish(
+            PublishRequest {
+                topic: topic.to_string(),
+                messages: vec![PubsubMessage {
+                    attributes: HashMap::from([("key".to_string(), "value".to_string())]),
+                    ..Default::default()
+                }],
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        let response = subc
+            .pull(
+                PullRequest {
+                    subscription: subscription.clone(),
+                    max_messages: 1,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .into_inner();
+
+        let messages = response.received_messages;
+        let (queue, receiver) = async_channel::unbounded();
+        let config = SubscriberConfig {
+            reject_empty_payload: Some(RejectPolicy::Discard),
+            ..Default::default()
+        };
+        let state = Arc::new(State::new(&config));
+        let nack_size = handle_message(
+            &CancellationToken::new(),
+            &queue,
+            &subc,
+            &subscription,
+            messages,
+            &config,
+            &state,
+        )
+        .await;
+        assert_eq!(0, nack_size);
+        assert!(receiver.is_empty());
+        assert_eq!(1, state.counters.snapshot(state.clock.now()).rejected_messages);
+
+        subc.delete_subscription(DeleteSubscriptionRequest { subscription }, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handle_message_retain_internal_message() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let messages = || {
+            vec![InternalReceivedMessage {
+                ack_id: "ack1".to_string(),
+                message: Some(PubsubMessage {
+                    data: "hoge".into(),
+                    message_id: "msg1".to_string(),
+                    ..Default::default()
+                }),
+                delivery_attempt: 3,
+            }]
+        };
+        let subscription = "projects/local-project/subscriptions/test-subscription1";
+
+        for retain_internal_message in [false, true] {
+            let (queue, receiver) = async_channel::unbounded();
+            let config = SubscriberConfig {
+                retain_internal_message,
+                ..Default::default()
+            };
+            let nack_size = handle_message(
+                &CancellationToken::new(),
+                &queue,
+                &subc,
+                subscription,
+                messages(),
+                &config,
+                &Arc::new(State::new(&config)),
+            )
+            .await;
+            assert_eq!(0, nack_size);
+            let msg = receiver.recv().await.unwrap();
+            assert_eq!("ack1", msg.ack_id());
+            assert_eq!(Some(3), msg.delivery_attempt());
+            if retain_internal_message {
+                assert_eq!(Some(&messages()[0]), msg.as_internal());
+            } else {
+                assert!(msg.as_internal().is_none());
             }
-            tracing::trace!("stop pinger : {}", subscription_clone);
-        });
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handle_message_near_dead_letter() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let messages = [("msg1", 0), ("msg2", 4), ("msg3", 5), ("msg4", 6)]
+            .into_iter()
+            .map(|(message_id, delivery_attempt)| InternalReceivedMessage {
+                ack_id: format!("ack_{message_id}"),
+                message: Some(PubsubMessage {
+                    message_id: message_id.to_string(),
+                    ..Default::default()
+                }),
+                delivery_attempt,
+            })
+            .collect();
+        let (queue, receiver) = async_channel::unbounded();
+        let (route_sender, route_receiver) = std::sync::mpsc::channel();
+        let config = SubscriberConfig {
+            max_delivery_attempts: Some(5),
+            on_near_dead_letter: Some(MessageRoute::new(move |msg| route_sender.send(msg).unwrap())),
+            ..Default::default()
+        };
+        let subscription = "projects/local-project/subscriptions/test-subscription1";
+        handle_message(
+            &CancellationToken::new(),
+            &queue,
+            &subc,
+            subscription,
+            messages,
+            &config,
+            &Arc::new(State::new(&config)),
+        )
+        .await;
+
+        let queued: Vec<ReceivedMessage> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(
+            vec!["msg1", "msg2"],
+            queued.iter().map(|v| v.message.message_id.as_str()).collect::<Vec<_>>()
+        );
+        assert!(!queued[0].is_near_dead_letter(1));
+        assert!(!queued[1].is_near_dead_letter(5));
+        let routed: Vec<ReceivedMessage> = route_receiver.try_iter().collect();
+        assert_eq!(
+            vec!["msg3", "msg4"],
+            routed.iter().map(|v| v.message.message_id.as_str()).collect::<Vec<_>>()
+        );
+        assert!(routed.iter().all(|v| v.is_near_dead_letter(5)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_handle_message_redelivery_jitter() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let messages = [("msg1", 0), ("msg2", 1), ("msg3", 2)]
+            .into_iter()
+            .map(|(message_id, delivery_attempt)| InternalReceivedMessage {
+                ack_id: format!("ack_{message_id}"),
+                message: Some(PubsubMessage {
+                    message_id: message_id.to_string(),
+                    ..Default::default()
+                }),
+                delivery_attempt,
+            })
+            .collect();
+        let (queue, receiver) = async_channel::unbounded();
+        let config = SubscriberConfig {
+            redelivery_jitter: Some(Duration::from_secs(24 * 60 * 60)),
+            ..Default::default()
+        };
+        let cancel = CancellationToken::new();
+        let state = Arc::new(State::new(&config));
+        let subscription = "projects/local-project/subscriptions/test-subscription1";
+        // The delayed redelivery doesn't block the handling of the response.
+        let nacked = tokio::time::timeout(
+            Duration::from_secs(1),
+            handle_message(&cancel, &queue, &subc, subscription, messages, &config, &state),
+        )
+        .await
+        .unwrap();
+        assert_eq!(0, nacked);
+
+        // The first deliveries are not delayed.
+        for message_id in ["msg1", "msg2"] {
+            let message = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(message_id, message.message.message_id);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(receiver.is_empty());
+
+        // The delayed redelivery is nacked on the cancellation before the queue is closed.
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), close_queue(&queue, subscription, &state))
+            .await
+            .unwrap();
+        assert_eq!(1, state.counters.cancel_nacked_messages.load(Ordering::Relaxed));
+        assert!(queue.is_closed());
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handle_message_retry_settings() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let messages = || {
+            vec![InternalReceivedMessage {
+                ack_id: "ack1".to_string(),
+                message: Some(PubsubMessage {
+                    message_id: "msg1".to_string(),
+                    ..Default::default()
+                }),
+                delivery_attempt: 0,
+            }]
+        };
+        let subscription = "projects/local-project/subscriptions/test-subscription1";
+        let ack_retry_setting = RetrySetting {
+            codes: vec![Code::Unavailable],
+            ..Default::default()
+        };
+        for (ack, modify_ack_deadline) in [(None, None), (Some(ack_retry_setting), None)] {
+            let (queue, receiver) = async_channel::unbounded();
+            let config = SubscriberConfig {
+                ack_retry_setting: ack,
+                modify_ack_deadline_retry_setting: modify_ack_deadline,
+                ..Default::default()
+            };
+            handle_message(
+                &CancellationToken::new(),
+                &queue,
+                &subc,
+                subscription,
+                messages(),
+                &config,
+                &Arc::new(State::new(&config)),
+            )
+            .await;
+            let handle = receiver.recv().await.unwrap().handle;
+            assert_eq!(
+                config.ack_retry_setting.map(|v| v.codes),
+                handle.ack_retry_setting().map(|v| v.codes)
+            );
+            assert!(handle.modify_ack_deadline_retry_setting().is_none());
+        }
+    }
 
-        let inner = tokio::spawn(async move {
-            let mut cancel_retry = 0;
-            tracing::trace!("start subscriber: {}", subscription);
-            let retryable_codes = match &config.retry_setting {
-                Some(v) => v.codes.clone(),
-                None => default_retry_setting().codes,
+    #[tokio::test]
+    async fn test_publish_time() {
+        let subc = empty_client().await;
+        let message = |publish_time| {
+            let message = PubsubMessage {
+                publish_time,
+                ..Default::default()
             };
-            loop {
-                let mut request = create_empty_streaming_pull_request();
-                request.subscription = subscription.to_string();
-                request.stream_ack_deadline_seconds = config.stream_ack_deadline_seconds;
-                request.max_outstanding_messages = config.max_outstanding_messages;
-                request.max_outstanding_bytes = config.max_outstanding_bytes;
+            ReceivedMessage::new("s".to_string(), subc.clone(), message, "a".to_string(), None)
+        };
+        let msg = message(Some(prost_types::Timestamp {
+            seconds: 1_700_000_000,
+            nanos: 123_456_789,
+        }));
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            msg.publish_time()
+        );
+        assert_eq!(None, message(None).publish_time());
 
-                let response = client
-                    .streaming_pull(request, ping_receiver.clone(), config.retry_setting.clone())
-                    .await;
+        #[cfg(feature = "chrono")]
+        {
+            let utc = msg.publish_time_utc().unwrap();
+            assert_eq!(1_700_000_000, utc.timestamp());
+            assert_eq!(123_456_789, utc.timestamp_subsec_nanos());
+            assert_eq!(None, message(None).publish_time_utc());
+            // not normalized
+            let msg = message(Some(prost_types::Timestamp { seconds: 1, nanos: -1 }));
+            assert_eq!(999_999_999, msg.publish_time_utc().unwrap().timestamp_subsec_nanos());
+        }
+    }
 
-                let stream = match response {
-                    Ok(r) => r.into_inner(),
-                    Err(e) => {
-                        if e.code() == Code::Cancelled {
-                            if cancel_retry < 5 {
-                                cancel_retry += 1;
-                                tracing::warn!("failed to start streaming: will reconnect {:?} : {}", e, subscription);
-                                tokio::time::sleep(Duration::from_millis(1000)).await;
-                                continue;
-                            }
-                            tracing::trace!("stop subscriber : {}", subscription);
-                            break;
-                        } else if retryable_codes.contains(&e.code()) {
-                            tracing::warn!("failed to start streaming: will reconnect {:?} : {}", e, subscription);
-                            continue;
-                        } else {
-                            tracing::error!("failed to start streaming: will stop {:?} : {}", e, subscription);
-                            break;
-                        }
-                    }
+    #[tokio::test]
+    async fn test_attribute() {
+        let subc = empty_client().await;
+        let message = PubsubMessage {
+            attributes: HashMap::from([
+                ("Content-Type".to_string(), "json".to_string()),
+                ("content-type".to_string(), "text".to_string()),
+                ("X-Trace".to_string(), "abc".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let msg = ReceivedMessage::new("s".to_string(), subc, message, "a".to_string(), None);
+        assert_eq!(3, msg.attributes().len());
+        assert_eq!(Some("json"), msg.attribute("Content-Type"));
+        assert_eq!(None, msg.attribute("x-trace"));
+        assert_eq!(Some("text"), msg.attribute_ignore_ascii_case("content-type"));
+        assert_eq!(Some("abc"), msg.attribute_ignore_ascii_case("x-trace"));
+        assert_eq!(None, msg.attribute_ignore_ascii_case("x-span"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subscriber_builder_requires_queue() {
+        let cm = || async {
+            ConnectionManager::new(
+                1,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let subscription = SubscriptionName::new("local-project", "test-subscription1").unwrap();
+        let err = Subscriber::builder(subc.clone(), subscription.clone())
+            .config(SubscriberConfig::default())
+            .start()
+            .unwrap_err();
+        assert!(matches!(err, PubSubError::ConfigInvalid(_)), "{err:?}");
+
+        let (queue, _receiver) = async_channel::unbounded();
+        let cancel = CancellationToken::new();
+        let mut subscriber = Subscriber::builder(subc, subscription)
+            .cancellation_token(cancel.clone())
+            .queue(queue)
+            .start()
+            .unwrap();
+        cancel.cancel();
+        subscriber.done().await;
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_pinger_stops_when_stream_stopped() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let (queue, _receiver) = async_channel::unbounded();
+        let config = SubscriberConfig {
+            ping_interval: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let subscription = SubscriptionName::new("local-project", &format!("s{}", Uuid::new_v4())).unwrap();
+        let mut subscriber = Subscriber::builder(subc, subscription)
+            .queue(queue)
+            .config(config)
+            .start()
+            .unwrap();
+
+        // The subscription does not exist, so the streaming pull task stops without the cancellation.
+        assert_eq!(Code::NotFound, subscriber.stopped().await.unwrap_err().code());
+        let pinger = subscriber.pinger.take().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), pinger)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_stop_when_queue_receivers_dropped() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let pubc = PublisherClient::new(cm().await);
+        let topic = "projects/local-project/topics/test-topic1";
+        let subscription = format!("projects/local-project/subscriptions/s{}", Uuid::new_v4());
+        subc.create_subscription(
+            Subscription {
+                name: subscription.clone(),
+                topic: topic.to_string(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        let (queue, receiver) = async_channel::unbounded();
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let reasons_clone = reasons.clone();
+        let config = SubscriberConfig {
+            on_stopped: Some(Hook::new(move |reason: &StopReason| {
+                reasons_clone.lock().unwrap().push(format!("{reason:?}"));
+            })),
+            ..Default::default()
+        };
+        let mut subscriber = Subscriber::builder(subc.clone(), SubscriptionName::parse(&subscription).unwrap())
+            .queue(queue)
+            .config(config)
+            .start()
+            .unwrap();
+        // The consumer is gone without cancelling the subscriber.
+        drop(receiver);
+        let stopped = subscriber.stopped();
+        let publish = async {
+            loop {
+                let request = PublishRequest {
+                    topic: topic.to_string(),
+                    messages: vec![PubsubMessage {
+                        data: "hoge".into(),
+                        ..Default::default()
+                    }],
                 };
-                match Self::recv(
-                    client.clone(),
-                    stream,
-                    subscription.as_str(),
-                    cancel_receiver.clone(),
-                    queue.clone(),
-                )
-                .await
-                {
-                    Ok(_) => break,
-                    Err(e) => {
-                        if retryable_codes.contains(&e.code()) {
-                            tracing::trace!("reconnect - '{:?}' : {} ", e, subscription);
-                            continue;
-                        } else {
-                            tracing::error!("terminated subscriber streaming with error {:?} : {}", e, subscription);
-                            break;
-                        }
-                    }
-                }
+                pubc.publish(request, None).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(1)).await;
             }
-            // streaming request is closed when the ping_sender closed.
-            tracing::trace!("stop subscriber in streaming: {}", subscription);
+        };
+        tokio::time::timeout(Duration::from_secs(30), async {
+            select! {
+                result = stopped => result.unwrap(),
+                _ = publish => {}
+            }
+        })
+        .await
+        .unwrap();
+        subscriber.done().await;
+        assert_eq!(*reasons.lock().unwrap(), vec!["QueueClosed".to_string()]);
+
+        subc.delete_subscription(DeleteSubscriptionRequest { subscription }, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_shared_queue_is_not_closed_by_cancellation() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let (queue, receiver) = async_channel::unbounded();
+        let subscription = SubscriptionName::new("local-project", "test-subscription1").unwrap();
+        let start = |cancel: CancellationToken| {
+            Subscriber::builder(subc.clone(), subscription.clone())
+                .cancellation_token(cancel)
+                .queue(queue.clone())
+                .start()
+                .unwrap()
+        };
+        let (cancel1, cancel2) = (CancellationToken::new(), CancellationToken::new());
+        let mut subscriber1 = start(cancel1.clone());
+        let mut subscriber2 = start(cancel2.clone());
+        drop(queue);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // The other subscriber can still deliver to the queue.
+        cancel1.cancel();
+        subscriber1.done().await;
+        assert!(!receiver.is_closed());
+        assert!(subscriber2.is_running());
+
+        // The queue is closed when the last subscriber stops.
+        cancel2.cancel();
+        subscriber2.done().await;
+        assert!(receiver.is_closed());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handle_message_drop_past_deadline() {
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let pubc = PublisherClient::new(cm().await);
+        let subscription = "projects/local-project/subscriptions/test-subscription1";
+
+        // The dropped message is acked, so it needs the ack_id of a delivered message.
+        pubc.publish(
+            PublishRequest {
+                topic: "projects/local-project/topics/test-topic1".to_string(),
+                messages: vec![PubsubMessage {
+                    data: "hoge".into(),
+                    ..Default::default()
+                }],
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        let delivered = subc
+            .pull(
+                PullRequest {
+                    subscription: subscription.to_string(),
+                    max_messages: 1,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .received_messages;
+        let message = |id: &str, deadline: Option<&str>| InternalReceivedMessage {
+            ack_id: match id {
+                "past" => delivered[0].ack_id.clone(),
+                _ => format!("ack-{id}"),
+            },
+            message: Some(PubsubMessage {
+                data: "hoge".into(),
+                message_id: id.to_string(),
+                attributes: deadline
+                    .map(|v| HashMap::from([("deadline".to_string(), v.to_string())]))
+                    .unwrap_or_default(),
+                ..Default::default()
+            }),
+            delivery_attempt: 0,
+        };
+        let messages = vec![
+            message("past", Some("2000-01-01T00:00:00Z")),
+            message("future", Some("2999-01-01T00:00:00Z")),
+            message("invalid", Some("tomorrow")),
+            message("none", None),
+        ];
+        #[derive(Default)]
+        struct AckedObserver(Mutex<Vec<String>>);
+        impl SubscriberObserver for AckedObserver {
+            fn on_acked(&self, _subscription: &str, message_id: &str, _latency: Duration) {
+                self.0.lock().unwrap().push(message_id.to_string());
+            }
+        }
+        let observer = Arc::new(AckedObserver::default());
+        let (queue, receiver) = async_channel::unbounded();
+        let config = SubscriberConfig {
+            deadline_attribute: Some("deadline".to_string()),
+            drop_past_deadline: true,
+            observer: Some(observer.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(State::new(&config));
+        handle_message(
+            &CancellationToken::new(),
+            &queue,
+            &subc,
+            subscription,
+            messages,
+            &config,
+            &state,
+        )
+        .await;
+        assert_eq!(1, state.counters.snapshot(state.clock.now()).expired_messages);
+        assert_eq!(3, state.counters.snapshot(state.clock.now()).delivered_messages);
+        // The dropped message is reported as acked so that the lifecycle events balance.
+        assert_eq!(vec!["past".to_string()], *observer.0.lock().unwrap());
+
+        let future = receiver.recv().await.unwrap();
+        assert_eq!("future", future.message.message_id);
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(32472144000)),
+            future.message_deadline()
+        );
+        assert_eq!(None, receiver.recv().await.unwrap().message_deadline());
+        assert_eq!(None, receiver.recv().await.unwrap().message_deadline());
+        assert!(receiver.is_empty());
+    }
+
+    /// ManualClock only moves when advanced, at a fixed system time.
+    struct ManualClock {
+        start: Instant,
+        elapsed: tokio::sync::watch::Sender<Duration>,
+        system_now: SystemTime,
+    }
+
+    impl ManualClock {
+        fn new(system_now: SystemTime) -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: tokio::sync::watch::channel(Duration::ZERO).0,
+                system_now,
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.elapsed.send_modify(|v| *v += duration);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.borrow()
+        }
+
+        fn system_now(&self) -> SystemTime {
+            self.system_now
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+            let start = self.start;
+            let mut elapsed = self.elapsed.subscribe();
+            Box::pin(async move {
+                let _ = elapsed.wait_for(|v| start + *v >= deadline).await;
+            })
+        }
+    }
+
+    #[test]
+    fn test_hand_off_remaining_lease() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        let hand_off = |leased_until: u64| HandOff {
+            subscription: "projects/local-project/subscriptions/test-subscription1".to_string(),
+            ack_id: "ack".to_string(),
+            message_id: "msg".to_string(),
+            leased_until: SystemTime::UNIX_EPOCH + Duration::from_secs(leased_until),
+        };
+        assert_eq!(Duration::from_secs(60), hand_off(1060).remaining_lease(&clock));
+        assert_eq!(Duration::ZERO, hand_off(1000).remaining_lease(&clock));
+        assert_eq!(Duration::ZERO, hand_off(940).remaining_lease(&clock));
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_ack_ids_clock() {
+        use crate::subscriber::{retry_transient_ack_ids, with_ack_id_failures};
+
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let retry = Some(RetrySetting {
+            from_millis: 60_000,
+            max_delay: Some(Duration::from_secs(60)),
+            take: 1,
+            ..Default::default()
+        });
+        let sent = Arc::new(Mutex::new(0));
+        let task = {
+            let (clock, sent) = (clock.clone(), sent.clone());
+            tokio::spawn(async move {
+                retry_transient_ack_ids(vec!["a1".to_string()], retry, clock.as_ref(), |_| {
+                    let mut sent = sent.lock().unwrap();
+                    *sent += 1;
+                    let result = match *sent {
+                        1 => Err(with_ack_id_failures(
+                            &Status::invalid_argument("invalid ack ids"),
+                            HashMap::from([("a1".to_string(), "TRANSIENT_FAILURE_UNORDERED_ACK_ID".to_string())]),
+                        )),
+                        _ => Ok(()),
+                    };
+                    async move { result }
+                })
+                .await
+            })
+        };
+
+        // The retry waits for the clock, not for the wall-clock time.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*sent.lock().unwrap(), 1);
+        clock.advance(Duration::from_secs(60));
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(*sent.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subscriber_clock() {
+        // 3000-01-01T00:00:00Z
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(32503680000)));
+
+        // The backoff waits for the clock, not for the wall-clock time.
+        let backoff = ReconnectBackoff {
+            base_delay: Duration::from_secs(3600),
+            max_delay: Duration::from_secs(3600),
+            jitter: None,
+        };
+        let waiting = {
+            let clock = clock.clone();
+            tokio::spawn(async move { wait_reconnect(&backoff, 1, clock.as_ref(), &CancellationToken::new()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        clock.advance(Duration::from_secs(3599));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        clock.advance(Duration::from_secs(1));
+        assert!(tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap());
+
+        let config = SubscriberConfig {
+            deadline_attribute: Some("deadline".to_string()),
+            drop_past_deadline: true,
+            clock: Some(clock.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(State::new(&config));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(Duration::from_secs(60), state.counters.snapshot(state.clock.now()).uptime);
+
+        // The deadlines are compared with the system time of the clock.
+        let cm = || async {
+            ConnectionManager::new(
+                4,
+                "",
+                &Environment::Emulator("localhost:8681".to_string()),
+                &ConnectionOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let subc = SubscriberClient::new(cm().await, cm().await);
+        let (queue, receiver) = async_channel::unbounded();
+        let message = InternalReceivedMessage {
+            ack_id: "ack-future".to_string(),
+            message: Some(PubsubMessage {
+                data: "hoge".into(),
+                message_id: "future".to_string(),
+                attributes: HashMap::from([("deadline".to_string(), "2999-01-01T00:00:00Z".to_string())]),
+                ..Default::default()
+            }),
+            delivery_attempt: 0,
+        };
+        handle_message(
+            &CancellationToken::new(),
+            &queue,
+            &subc,
+            "projects/local-project/subscriptions/test-subscription1",
+            vec![message],
+            &config,
+            &state,
+        )
+        .await;
+        assert_eq!(1, state.counters.snapshot(state.clock.now()).expired_messages);
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn test_ack_id_failure() {
+        use prost::Message;
+
+        use crate::subscriber::{ack_id_failure, is_invalid_ack_id, ErrorInfo};
+
+        let error_info = ErrorInfo {
+            reason: "EXACTLY_ONCE_ACKID_FAILURE".to_string(),
+            domain: "pubsub.googleapis.com".to_string(),
+            metadata: HashMap::from([
+                ("ack1".to_string(), "PERMANENT_FAILURE_INVALID_ACK_ID".to_string()),
+                ("ack2".to_string(), "TRANSIENT_FAILURE_UNORDERED_ACK_ID".to_string()),
+            ]),
+        };
+        let details = google_cloud_googleapis::rpc::Status {
+            code: Code::InvalidArgument as i32,
+            message: "invalid ack ids".to_string(),
+            details: vec![prost_types::Any {
+                type_url: "type.googleapis.com/google.rpc.ErrorInfo".to_string(),
+                value: error_info.encode_to_vec(),
+            }],
+        };
+        let status = Status::with_details(Code::InvalidArgument, "invalid ack ids", details.encode_to_vec().into());
+        assert!(is_invalid_ack_id(&status, "ack1"));
+        assert!(!is_invalid_ack_id(&status, "ack2"));
+        assert_eq!(
+            Some("TRANSIENT_FAILURE_UNORDERED_ACK_ID".to_string()),
+            ack_id_failure(&status, "ack2")
+        );
+        assert_eq!(None, ack_id_failure(&status, "ack3"));
+        assert!(!is_invalid_ack_id(&Status::invalid_argument("no details"), "ack1"));
+    }
+
+    #[test]
+    fn test_ack_outcomes() {
+        use prost::Message;
+
+        use crate::subscriber::{ack_outcomes, AckOutcome, ErrorInfo};
+
+        let ack_ids: Vec<String> = ["ack1", "ack2", "ack3"].iter().map(|v| v.to_string()).collect();
+        let outcomes = ack_outcomes(Ok(()), &ack_ids).unwrap();
+        assert!(outcomes.values().all(|v| *v == AckOutcome::Success));
+
+        let error_info = ErrorInfo {
+            reason: "EXACTLY_ONCE_ACKID_FAILURE".to_string(),
+            domain: "pubsub.googleapis.com".to_string(),
+            metadata: HashMap::from([
+                ("ack1".to_string(), "PERMANENT_FAILURE_INVALID_ACK_ID".to_string()),
+                ("ack2".to_string(), "TRANSIENT_FAILURE_UNORDERED_ACK_ID".to_string()),
+            ]),
+        };
+        let details = google_cloud_googleapis::rpc::Status {
+            code: Code::InvalidArgument as i32,
+            message: "invalid ack ids".to_string(),
+            details: vec![prost_types::Any {
+                type_url: "type.googleapis.com/google.rpc.ErrorInfo".to_string(),
+                value: error_info.encode_to_vec(),
+            }],
+        };
+        let status = Status::with_details(Code::InvalidArgument, "invalid ack ids", details.encode_to_vec().into());
+        let outcomes = ack_outcomes(Err(status.into()), &ack_ids).unwrap();
+        assert_eq!(outcomes["ack1"], AckOutcome::InvalidAckId);
+        assert_eq!(outcomes["ack2"], AckOutcome::TransientFailure);
+        assert_eq!(outcomes["ack3"], AckOutcome::Success);
+
+        // The failure of the request is returned as is.
+        let err = ack_outcomes(Err(Status::unavailable("unavailable").into()), &ack_ids).unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+    }
+
+    #[test]
+    fn test_merge_ack_results() {
+        use crate::subscriber::{ack_id_failures, with_ack_id_failures};
+
+        assert!(merge_ack_results(vec![Ok(()), Ok(())]).is_ok());
+
+        let failed = |ack_id: &str| {
+            with_ack_id_failures(
+                &Status::invalid_argument("invalid ack ids"),
+                HashMap::from([(ack_id.to_string(), "PERMANENT_FAILURE_INVALID_ACK_ID".to_string())]),
+            )
+        };
+        let err = merge_ack_results(vec![Err(failed("ack1")), Ok(()), Err(failed("ack3"))]).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        let mut failures: Vec<String> = ack_id_failures(err.status().unwrap()).unwrap().into_keys().collect();
+        failures.sort();
+        assert_eq!(failures, vec!["ack1", "ack3"]);
+
+        // A chunk failed as a whole fails the request.
+        let err = merge_ack_results(vec![Err(failed("ack1")), Err(Status::unavailable("unavailable"))]).unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        assert!(ack_id_failures(err.status().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_ack_ids() {
+        use std::sync::Mutex;
+
+        use crate::subscriber::{ack_id_failures, retry_transient_ack_ids, with_ack_id_failures};
+
+        let failure = |failures: &[(&str, &str)]| {
+            let failures = failures.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            with_ack_id_failures(&Status::invalid_argument("invalid ack ids"), failures)
+        };
+        let retry = Some(RetrySetting {
+            from_millis: 1,
+            max_delay: Some(Duration::from_millis(10)),
+            take: 3,
+            ..Default::default()
         });
-        Self {
-            pinger: Some(pinger),
-            inner: Some(inner),
+        let ack_ids = || vec!["a1".to_string(), "a2".to_string(), "a3".to_string()];
+
+        // Only the transient failures are retried until they succeed.
+        let sent = Mutex::new(vec![]);
+        let err = retry_transient_ack_ids(ack_ids(), retry.clone(), &SystemClock, |ack_ids| {
+            let mut sent = sent.lock().unwrap();
+            sent.push(ack_ids);
+            let result = match sent.len() {
+                1 => Err(failure(&[
+                    ("a1", "PERMANENT_FAILURE_INVALID_ACK_ID"),
+                    ("a2", "TRANSIENT_FAILURE_UNORDERED_ACK_ID"),
+                ])),
+                _ => Ok(()),
+            };
+            async move { result }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(*sent.lock().unwrap(), vec![ack_ids(), vec!["a2".to_string()]]);
+        let failures = ack_id_failures(&err).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures["a1"], "PERMANENT_FAILURE_INVALID_ACK_ID");
+
+        // The transient failures are reported after the retries are exhausted.
+        let sent = Mutex::new(0);
+        let err = retry_transient_ack_ids(ack_ids(), retry.clone(), &SystemClock, |_| {
+            *sent.lock().unwrap() += 1;
+            let result = Err(failure(&[("a3", "TRANSIENT_FAILURE_UNORDERED_ACK_ID")]));
+            async move { result }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(*sent.lock().unwrap(), 4);
+        assert_eq!(ack_id_failures(&err).unwrap()["a3"], "TRANSIENT_FAILURE_UNORDERED_ACK_ID");
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        // The failure of the request is returned as is without the retry.
+        let sent = Mutex::new(0);
+        let err = retry_transient_ack_ids(ack_ids(), retry.clone(), &SystemClock, |_| {
+            *sent.lock().unwrap() += 1;
+            async move { Err(Status::unavailable("unavailable")) }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(*sent.lock().unwrap(), 1);
+        assert_eq!(err.code(), Code::Unavailable);
+        assert!(ack_id_failures(&err).is_none());
+
+        // The failure of a retry keeps the results of the earlier attempts.
+        let sent = Mutex::new(0);
+        let err = retry_transient_ack_ids(ack_ids(), retry, &SystemClock, |_| {
+            let mut sent = sent.lock().unwrap();
+            *sent += 1;
+            let result = match *sent {
+                1 => Err(failure(&[
+                    ("a1", "PERMANENT_FAILURE_INVALID_ACK_ID"),
+                    ("a2", "TRANSIENT_FAILURE_UNORDERED_ACK_ID"),
+                ])),
+                _ => Err(Status::unavailable("unavailable")),
+            };
+            async move { result }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(*sent.lock().unwrap(), 2);
+        assert_eq!(err.code(), Code::Unavailable);
+        let failures = ack_id_failures(&err).unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures["a1"], "PERMANENT_FAILURE_INVALID_ACK_ID");
+        assert_eq!(failures["a2"], "TRANSIENT_FAILURE_UNORDERED_ACK_ID");
+    }
+
+    #[test]
+    fn test_ack_latency_percentiles() {
+        use crate::subscriber::{AckLatency, AckLatencyPercentiles};
+
+        let latency = AckLatency::default();
+        assert_eq!(None, AckLatency::percentiles([&latency]));
+        for millis in [3, 4, 8, 9, 15, 30, 40, 45, 70, 150] {
+            latency.record(Duration::from_millis(millis));
         }
+        assert_eq!(
+            Some(AckLatencyPercentiles {
+                count: 10,
+                p50: Duration::from_millis(20),
+                p90: Duration::from_millis(100),
+                p99: Duration::from_millis(200),
+            }),
+            AckLatency::percentiles([&latency])
+        );
+
+        // The histograms are merged, and the latencies above the last bound are counted in the last bucket.
+        let slow = AckLatency::default();
+        for _ in 0..90 {
+            slow.record(Duration::from_secs(60));
+        }
+        let merged = AckLatency::percentiles([&latency, &slow]).unwrap();
+        assert_eq!(merged.count, 100);
+        assert_eq!(merged.p50, Duration::from_secs(30));
+        assert_eq!(merged.p99, Duration::from_secs(30));
     }
 
-    async fn recv(
-        client: SubscriberClient,
-        mut stream: Streaming<StreamingPullResponse>,
-        subscription: &str,
-        cancel: CancellationToken,
-        queue: async_channel::Sender<ReceivedMessage>,
-    ) -> Result<(), Status> {
-        tracing::trace!("start streaming: {}", subscription);
-        loop {
-            select! {
-                _ = cancel.cancelled() => {
-                    queue.close();
-                    return Ok(());
-                }
-                maybe = stream.message() => {
-                    let message = maybe?;
-                    let message = match message {
-                        Some(m) => m,
-                        None => return Ok(())
-                    };
-                    let _ = handle_message(&cancel, &queue, &client, subscription, message.received_messages).await;
-                }
-            }
+    #[test]
+    fn test_split_ack_result() {
+        use crate::subscriber::{ack_id_failures, split_ack_result, with_ack_id_failures};
+
+        let ack_ids = || vec!["a1".to_string(), "a2".to_string()];
+        assert!(split_ack_result(Ok(()), ack_ids()).iter().all(|v| v.is_ok()));
+
+        let results = split_ack_result(Err(Status::unavailable("unavailable").into()), ack_ids());
+        assert!(results
+            .iter()
+            .all(|v| v.as_ref().unwrap_err().code() == Code::Unavailable));
+
+        // Only the failed ack_id gets the error with its own failure.
+        let failures = HashMap::from([("a2".to_string(), "PERMANENT_FAILURE_INVALID_ACK_ID".to_string())]);
+        let err = with_ack_id_failures(&Status::invalid_argument("invalid ack ids"), failures);
+        let mut results = split_ack_result(Err(err.into()), ack_ids());
+        let err = results.pop().unwrap().unwrap_err();
+        assert_eq!(
+            ack_id_failures(err.status().unwrap()).unwrap()["a2"],
+            "PERMANENT_FAILURE_INVALID_ACK_ID"
+        );
+        assert!(results.pop().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_jittered() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(interval, jittered(interval, None));
+        assert_eq!(interval, jittered(interval, Some(0.0)));
+        let intervals: HashSet<Duration> = (0..100).map(|_| jittered(interval, Some(0.2))).collect();
+        assert!(intervals.len() > 1);
+        for v in intervals {
+            assert!(v >= Duration::from_secs(8) && v <= Duration::from_secs(12), "{v:?}");
         }
+        assert!(jittered(interval, Some(2.0)) <= Duration::from_secs(20));
     }
 
-    pub async fn done(&mut self) {
-        if let Some(v) = self.pinger.take() {
-            let _ = v.await;
+    #[test]
+    fn test_is_expired() {
+        let now = SystemTime::now();
+        let max_age = Duration::from_secs(60);
+        assert!(!is_expired(None, max_age, now));
+        assert!(!is_expired(Some(now - Duration::from_secs(59)), max_age, now));
+        assert!(is_expired(Some(now - Duration::from_secs(61)), max_age, now));
+        // publish_time in the future due to clock skew
+        assert!(!is_expired(Some(now + Duration::from_secs(61)), max_age, now));
+    }
+
+    #[test]
+    fn test_recent_acks() {
+        let recent_acks = RecentAcks::new(2);
+        recent_acks.insert("a".to_string());
+        recent_acks.insert("b".to_string());
+        recent_acks.insert("a".to_string());
+        assert!(recent_acks.contains("a"));
+        assert!(recent_acks.contains("b"));
+        recent_acks.insert("c".to_string());
+        assert!(!recent_acks.contains("a"));
+        assert!(recent_acks.contains("b"));
+        assert!(recent_acks.contains("c"));
+
+        let disabled = RecentAcks::new(0);
+        disabled.insert("a".to_string());
+        assert!(!disabled.contains("a"));
+    }
+
+    #[test]
+    fn test_attribute_limits() {
+        let attributes = HashMap::from([
+            ("key1".to_string(), "value1".to_string()),
+            ("k2".to_string(), "v2".to_string()),
+        ]);
+        assert!(!AttributeLimits::default().is_exceeded(&attributes));
+
+        let limits = AttributeLimits {
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert!(!limits.is_exceeded(&attributes));
+        let limits = AttributeLimits {
+            max_count: Some(1),
+            ..Default::default()
+        };
+        assert!(limits.is_exceeded(&attributes));
+
+        let limits = AttributeLimits {
+            max_bytes: Some(14),
+            ..Default::default()
+        };
+        assert!(!limits.is_exceeded(&attributes));
+        let limits = AttributeLimits {
+            max_bytes: Some(13),
+            ..Default::default()
+        };
+        assert!(limits.is_exceeded(&attributes));
+    }
+
+    #[test]
+    fn test_is_token_error() {
+        use crate::subscriber::is_token_error;
+
+        assert!(is_token_error(&Status::unauthenticated("token error: InvalidToken")));
+        assert!(!is_token_error(&Status::unauthenticated(
+            "request had invalid authentication credentials"
+        )));
+        assert!(!is_token_error(&Status::unavailable("token error: InvalidToken")));
+    }
+
+    #[test]
+    fn test_validate_stream_ack_deadline_seconds() {
+        for v in [10, 60, 600] {
+            let config = SubscriberConfig {
+                stream_ack_deadline_seconds: v,
+                ..Default::default()
+            };
+            assert!(config.validate().is_ok());
         }
-        if let Some(v) = self.inner.take() {
-            let _ = v.await;
+        for v in [0, 9, 601] {
+            let config = SubscriberConfig {
+                stream_ack_deadline_seconds: v,
+                ..Default::default()
+            };
+            let err = config.validate().unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+            assert!(err.to_string().contains("stream_ack_deadline_seconds"), "{err}");
         }
     }
-}
 
-async fn handle_message(
-    cancel: &CancellationToken,
-    queue: &async_channel::Sender<ReceivedMessage>,
-    client: &SubscriberClient,
-    subscription: &str,
-    messages: Vec<InternalReceivedMessage>,
-) -> usize {
-    let mut nack_targets = vec![];
-    for received_message in messages {
-        if let Some(message) = received_message.message {
-            let id = message.message_id.clone();
-            tracing::debug!("message received: msg_id={id}");
-            let msg = ReceivedMessage::new(
-                subscription.to_string(),
-                client.clone(),
-                message,
-                received_message.ack_id.clone(),
-                (received_message.delivery_attempt > 0).then_some(received_message.delivery_attempt as usize),
-            );
-            let should_nack = select! {
-                result = queue.send(msg) => result.is_err(),
-                _ = cancel.cancelled() => true
-            };
-            if should_nack {
-                tracing::info!("cancelled -> so nack immediately : msg_id={id}");
-                nack_targets.push(received_message.ack_id);
-            }
+    #[test]
+    fn test_validate_subscriber_settings() {
+        let config = SubscriberConfig {
+            max_outstanding_messages: -1,
+            max_outstanding_bytes: -1,
+            prefetch_messages: Some(1),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        for (config, field) in [
+            (
+                SubscriberConfig {
+                    ping_interval: Duration::ZERO,
+                    ..Default::default()
+                },
+                "ping_interval",
+            ),
+            (
+                SubscriberConfig {
+                    prefetch_messages: Some(0),
+                    ..Default::default()
+                },
+                "prefetch_messages",
+            ),
+            (
+                SubscriberConfig {
+                    streams: Some(0),
+                    ..Default::default()
+                },
+                "streams",
+            ),
+            (
+                SubscriberConfig {
+                    ack_batching: Some(AckBatchConfig {
+                        max_ack_batch: 0,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                "ack_batching.max_ack_batch",
+            ),
+            (
+                SubscriberConfig {
+                    stall_after_pings: Some(0),
+                    ..Default::default()
+                },
+                "stall_after_pings",
+            ),
+            (
+                SubscriberConfig {
+                    on_cancel_policy: CancelPolicy::ExtendDeadline(601),
+                    ..Default::default()
+                },
+                "on_cancel_policy",
+            ),
+        ] {
+            let err = config.validate().unwrap_err();
+            assert!(matches!(err, PubSubError::ConfigInvalid(_)), "{err:?}");
+            assert!(err.to_string().contains(field), "{err}");
         }
     }
-    let size = nack_targets.len();
-    if size > 0 {
-        // Nack immediately although the queue is closed only when the cancellation token is closed.
-        if let Err(err) = nack(client, subscription.to_string(), nack_targets).await {
-            tracing::error!(
-                "failed to nack immediately {err}. The messages will be redelivered after the ack deadline."
-            );
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let backoff = ReconnectBackoff {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: None,
+        };
+        let delays: Vec<Duration> = (1..=6).map(|v| backoff.delay(v)).collect();
+        assert_eq!(delays[0], Duration::from_millis(100));
+        assert_eq!(delays[1], Duration::from_millis(200));
+        assert_eq!(delays[3], Duration::from_millis(800));
+        assert_eq!(delays[4], Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+
+        let backoff = ReconnectBackoff {
+            jitter: Some(0.5),
+            ..backoff
+        };
+        for _ in 0..100 {
+            let v = backoff.delay(1);
+            assert!(v >= Duration::from_millis(50) && v <= Duration::from_millis(150), "{v:?}");
         }
     }
-    size
-}
 
-async fn modify_ack_deadline(
-    subscriber_client: &SubscriberClient,
-    subscription: String,
-    ack_ids: Vec<String>,
-    ack_deadline_seconds: i32,
-) -> Result<(), Status> {
-    if ack_ids.is_empty() {
-        return Ok(());
+    #[test]
+    fn test_validate_lease_extension() {
+        let config = |secs| SubscriberConfig {
+            stream_ack_deadline_seconds: 10,
+            lease_extension: Some(LeaseConfig {
+                extension_interval: Duration::from_secs(secs),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(config(5).validate().is_ok());
+        for v in [0, 10, 30] {
+            let err = config(v).validate().unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+            assert!(err.to_string().contains("extension_interval"), "{err}");
+        }
     }
-    let req = ModifyAckDeadlineRequest {
-        subscription,
-        ack_deadline_seconds,
-        ack_ids,
-    };
-    subscriber_client
-        .modify_ack_deadline(req, None)
-        .await
-        .map(|e| e.into_inner())
-}
 
-async fn nack(subscriber_client: &SubscriberClient, subscription: String, ack_ids: Vec<String>) -> Result<(), Status> {
-    modify_ack_deadline(subscriber_client, subscription, ack_ids, 0).await
-}
+    #[test]
+    fn test_leases() {
+        let leases = Leases::default();
+        let now = Instant::now();
+        leases.insert("a1".to_string(), now);
+        leases.insert("a2".to_string(), now + Duration::from_secs(30));
+        leases.insert("a3".to_string(), now);
+        leases.remove("a3");
 
-pub(crate) async fn ack(
-    subscriber_client: &SubscriberClient,
-    subscription: String,
-    ack_ids: Vec<String>,
-) -> Result<(), Status> {
-    if ack_ids.is_empty() {
-        return Ok(());
+        let mut ack_ids = leases.extendable(Duration::from_secs(60), now + Duration::from_secs(40));
+        ack_ids.sort();
+        assert_eq!(ack_ids, vec!["a1".to_string(), "a2".to_string()]);
+
+        // a1 exceeds the max lease duration and is forgotten.
+        assert_eq!(
+            leases.extendable(Duration::from_secs(60), now + Duration::from_secs(60)),
+            vec!["a2".to_string()]
+        );
+        assert_eq!(
+            leases.extendable(Duration::from_secs(60), now + Duration::from_secs(40)),
+            vec!["a2".to_string()]
+        );
     }
-    let req = AcknowledgeRequest { subscription, ack_ids };
-    subscriber_client.acknowledge(req, None).await.map(|e| e.into_inner())
-}
 
-#[cfg(test)]
-mod tests {
-    use serial_test::serial;
-    use tokio_util::sync::CancellationToken;
+    #[test]
+    fn test_warn_low_concurrency() {
+        let config = |max_outstanding_messages, low_concurrency| SubscriberConfig {
+            max_outstanding_messages,
+            low_concurrency,
+            ..Default::default()
+        };
+        for v in [1, 9] {
+            assert!(config(v, false).warn_low_concurrency("s"));
+            assert!(!config(v, true).warn_low_concurrency("s"));
+        }
+        for v in [-1, 0, 10, 50] {
+            assert!(!config(v, false).warn_low_concurrency("s"));
+        }
+    }
 
-    use google_cloud_gax::conn::{ConnectionOptions, Environment};
-    use google_cloud_googleapis::pubsub::v1::{PublishRequest, PubsubMessage, PullRequest};
+    #[test]
+    fn test_decode_limit_exceeded() {
+        let e = Status::out_of_range(
+            "Error, decoded message length too large: found 10485780 bytes, the limit is: 10485760 bytes",
+        );
+        let err = decode_limit_exceeded(&e).unwrap();
+        assert_eq!(err.code(), Code::OutOfRange);
+        assert!(err.message().contains("max_outstanding_bytes"), "{}", err.message());
+        assert!(err.message().ends_with(e.message()), "{}", err.message());
 
-    use crate::apiv1::conn_pool::ConnectionManager;
-    use crate::apiv1::publisher_client::PublisherClient;
-    use crate::apiv1::subscriber_client::SubscriberClient;
-    use crate::subscriber::handle_message;
+        assert!(decode_limit_exceeded(&Status::out_of_range("seek out of range")).is_none());
+        assert!(decode_limit_exceeded(&Status::unavailable("Error, decoded message length too large")).is_none());
+    }
 
-    #[ctor::ctor]
-    fn init() {
-        let _ = tracing_subscriber::fmt().try_init();
+    #[tokio::test]
+    async fn test_stream_control_confirmation() {
+        let control = Arc::new(StreamControl::new(Arc::new(SystemClock)));
+
+        // at-least-once subscriptions never confirm
+        control.modify_ack_deadline("a0".to_string(), 10).await.unwrap();
+        let request = control.receiver.recv().await.unwrap();
+        assert_eq!(request.modify_deadline_ack_ids, vec!["a0".to_string()]);
+        assert_eq!(request.modify_deadline_seconds, vec![10]);
+
+        control.exactly_once.store(true, Ordering::Relaxed);
+        let tasks: Vec<_> = ["a1", "a2", "a3"]
+            .into_iter()
+            .map(|ack_id| {
+                let control = control.clone();
+                tokio::spawn(async move { control.modify_ack_deadline(ack_id.to_string(), 10).await })
+            })
+            .collect();
+        for _ in 0..3 {
+            control.receiver.recv().await.unwrap();
+        }
+        control.confirm(ModifyAckDeadlineConfirmation {
+            ack_ids: vec!["a1".to_string()],
+            invalid_ack_ids: vec!["a2".to_string()],
+            temporary_failed_ack_ids: vec!["a3".to_string()],
+        });
+        let results: Vec<_> = futures_util::future::join_all(tasks)
+            .await
+            .into_iter()
+            .map(|v| v.unwrap().map_err(|e| e.code()))
+            .collect();
+        assert_eq!(results, vec![Ok(()), Err(Code::FailedPrecondition), Err(Code::Unavailable)]);
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    #[serial]
-    async fn test_handle_message_immediately_nack() {
-        let cm = || async {
-            ConnectionManager::new(
-                4,
-                "",
-                &Environment::Emulator("localhost:8681".to_string()),
-                &ConnectionOptions::default(),
-            )
+    #[tokio::test]
+    async fn test_stream_control_batch_confirmation() {
+        let control = Arc::new(StreamControl::new(Arc::new(SystemClock)));
+        control.exactly_once.store(true, Ordering::Relaxed);
+
+        let task = {
+            let control = control.clone();
+            tokio::spawn(async move {
+                control
+                    .modify_ack_deadlines(vec!["a1".to_string(), "a2".to_string()], 10)
+                    .await
+            })
+        };
+        let request = control.receiver.recv().await.unwrap();
+        assert_eq!(request.modify_deadline_ack_ids, vec!["a1".to_string(), "a2".to_string()]);
+        assert_eq!(request.modify_deadline_seconds, vec![10, 10]);
+        control.confirm(ModifyAckDeadlineConfirmation {
+            ack_ids: vec!["a2".to_string()],
+            invalid_ack_ids: vec!["a1".to_string()],
+            temporary_failed_ack_ids: vec![],
+        });
+        let results: Vec<_> = task
             .await
             .unwrap()
-        };
-        let subc = SubscriberClient::new(cm().await, cm().await);
-        let pubc = PublisherClient::new(cm().await);
+            .into_iter()
+            .map(|(ack_id, result)| (ack_id, result.map_err(|e| e.code())))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("a1".to_string(), Err(Code::FailedPrecondition)),
+                ("a2".to_string(), Ok(()))
+            ]
+        );
 
-        pubc.publish(
-            PublishRequest {
-                topic: "projects/local-project/topics/test-topic1".to_string(),
-                messages: vec![PubsubMessage {
-                    data: "hoge".into(),
+        // the pending confirmations are removed if the request can't be sent
+        control.receiver.close();
+        let err = control.modify_ack_deadline("a3".to_string(), 10).await.unwrap_err();
+        assert_eq!(err.code(), Code::Cancelled);
+        assert!(control.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_group_ack_ids() {
+        use crate::subscriber::{group_ack_ids, AckGroup, AckRetrySettings};
+
+        let subc = empty_client().await;
+        let messages: Vec<ReceivedMessage> = [("s1", "a1"), ("s1", "a2"), ("s1", "a1"), ("s2", "a1")]
+            .into_iter()
+            .map(|(subscription, ack_id)| {
+                ReceivedMessage::new(
+                    subscription.to_string(),
+                    subc.clone(),
+                    PubsubMessage::default(),
+                    ack_id.to_string(),
+                    None,
+                )
+            })
+            .collect();
+        let (grouped, sent) = group_ack_ids(&messages, false);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped["s1"].ack_ids, vec!["a1".to_string(), "a2".to_string()]);
+        assert_eq!(grouped["s2"].ack_ids, vec!["a1".to_string()]);
+        assert!(grouped["s1"].retry(false).is_none());
+        assert_eq!(sent.len(), 4);
+
+        // The latest ack_id of a redelivered message is sent, and the acked messages are skipped by the ack.
+        let latest = Arc::new(LatestAckIds::default());
+        let recent_acks = Arc::new(RecentAcks::new(10));
+        recent_acks.insert("a3".to_string());
+        let mut messages: Vec<ReceivedMessage> = [("m1", "a1"), ("m3", "a3")]
+            .into_iter()
+            .map(|(message_id, ack_id)| {
+                ReceivedMessage::new(
+                    "s1".to_string(),
+                    subc.clone(),
+                    PubsubMessage {
+                        message_id: message_id.to_string(),
+                        ..Default::default()
+                    },
+                    ack_id.to_string(),
+                    None,
+                )
+            })
+            .collect();
+        for message in &mut messages {
+            message.handle.latest_ack_ids = Some(Arc::new(LatestAckIdGuard::new(
+                latest.clone(),
+                &message.handle.message_id,
+                &message.handle.ack_id,
+            )));
+            message.handle.recent_acks = Some(recent_acks.clone());
+        }
+        latest.insert("m1".to_string(), "a2".to_string());
+        let (grouped, sent) = group_ack_ids(&messages, false);
+        assert_eq!(grouped["s1"].ack_ids, vec!["a2".to_string()]);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, "a2");
+        let (grouped, sent) = group_ack_ids(&messages, true);
+        assert_eq!(grouped["s1"].ack_ids, vec!["a2".to_string(), "a3".to_string()]);
+        assert_eq!(sent.len(), 2);
+
+        let group = AckGroup {
+            client: subc.clone(),
+            retry_settings: Some(Arc::new(AckRetrySettings {
+                ack: Some(RetrySetting {
+                    take: 1,
                     ..Default::default()
-                }],
+                }),
+                modify_ack_deadline: None,
+            })),
+            clock: None,
+            ack_ids: vec![],
+        };
+        assert_eq!(group.retry(false).unwrap().take, 1);
+        assert!(group.retry(true).is_none());
+        assert!(group_ack_ids(&[], false).0.is_empty());
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_deserialize_json_empty_data() {
+        let subc = empty_client().await;
+        let message = ReceivedMessage::new("s1".to_string(), subc, PubsubMessage::default(), "a1".to_string(), None);
+        let err = message.deserialize_json::<HashMap<String, i32>>().unwrap_err();
+        assert!(err.is_eof(), "{err}");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_data_decompressed() {
+        use std::io::Write;
+
+        use crate::publisher::{CONTENT_ENCODING, GZIP};
+
+        let subc = empty_client().await;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"abc").unwrap();
+        let message = |data: Vec<u8>, encoding: Option<&str>| {
+            let message = PubsubMessage {
+                data: data.into(),
+                attributes: encoding
+                    .map(|v| HashMap::from([(CONTENT_ENCODING.to_string(), v.to_string())]))
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            ReceivedMessage::new("s1".to_string(), subc.clone(), message, "a1".to_string(), None)
+        };
+
+        let compressed = message(encoder.finish().unwrap(), Some(GZIP));
+        assert_eq!(b"abc", &compressed.data_decompressed().unwrap()[..]);
+        // The data without the attribute is passed through.
+        let plain = message(b"abc".to_vec(), None);
+        assert!(matches!(plain.data_decompressed().unwrap(), std::borrow::Cow::Borrowed(b"abc")));
+        let err = message(b"abc".to_vec(), Some(GZIP)).data_decompressed().unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind(), "{err}");
+        let err = message(b"abc".to_vec(), Some("br")).data_decompressed().unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_latest_ack_ids() {
+        let latest = LatestAckIds::default();
+        latest.insert("m1".to_string(), "a1".to_string());
+        latest.insert("m1".to_string(), "a2".to_string());
+        assert_eq!(latest.get("m1"), Some("a2".to_string()));
+        // The stale delivery doesn't remove the latest one.
+        latest.remove("m1", "a1");
+        assert_eq!(latest.get("m1"), Some("a2".to_string()));
+        latest.remove("m1", "a2");
+        assert_eq!(latest.get("m1"), None);
+        assert!(latest.inner.lock().unwrap().is_empty());
+
+        // The entry is removed when the handles are dropped without settling the message.
+        let latest = Arc::new(latest);
+        let first = Arc::new(LatestAckIdGuard::new(latest.clone(), "m1", "a1"));
+        let redelivered = LatestAckIdGuard::new(latest.clone(), "m1", "a2");
+        let clone = first.clone();
+        drop(first);
+        drop(clone);
+        assert_eq!(latest.get("m1"), Some("a2".to_string()));
+        drop(redelivered);
+        assert!(latest.inner.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_throttle() {
+        let mut throttle = LogThrottle::new(Duration::from_secs(30));
+        let now = Instant::now();
+        assert_eq!(throttle.check(now), Some(0));
+        assert_eq!(throttle.check(now + Duration::from_secs(1)), None);
+        assert_eq!(throttle.check(now + Duration::from_secs(29)), None);
+        assert_eq!(throttle.check(now + Duration::from_secs(30)), Some(2));
+        assert_eq!(throttle.check(now + Duration::from_secs(31)), None);
+        throttle.reset();
+        assert_eq!(throttle.check(now + Duration::from_secs(32)), Some(0));
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn test_telemetry() {
+        use std::sync::Mutex;
+
+        use opentelemetry::metrics::{
+            Counter, Histogram, HistogramBuilder, InstrumentBuilder, InstrumentProvider, Meter, SyncInstrument,
+        };
+        use opentelemetry::KeyValue;
+
+        use crate::subscriber::Telemetry;
+
+        type Values = Arc<Mutex<HashMap<String, f64>>>;
+
+        struct Recorder(Values, String);
+
+        impl SyncInstrument<u64> for Recorder {
+            fn measure(&self, measurement: u64, attributes: &[KeyValue]) {
+                assert_eq!("subscription", attributes[0].key.as_str());
+                *self.0.lock().unwrap().entry(self.1.clone()).or_default() += measurement as f64;
+            }
+        }
+
+        impl SyncInstrument<f64> for Recorder {
+            fn measure(&self, measurement: f64, _attributes: &[KeyValue]) {
+                *self.0.lock().unwrap().entry(self.1.clone()).or_default() += measurement;
+            }
+        }
+
+        struct Provider(Values);
+
+        impl InstrumentProvider for Provider {
+            fn u64_counter(&self, builder: InstrumentBuilder<'_, Counter<u64>>) -> Counter<u64> {
+                Counter::new(Arc::new(Recorder(self.0.clone(), builder.name.to_string())))
+            }
+
+            fn f64_histogram(&self, builder: HistogramBuilder<'_, Histogram<f64>>) -> Histogram<f64> {
+                Histogram::new(Arc::new(Recorder(self.0.clone(), builder.name.to_string())))
+            }
+        }
+
+        let values = Values::default();
+        let telemetry = Telemetry::new(&Meter::new(Arc::new(Provider(values.clone()))));
+        telemetry.received("s", 2);
+        telemetry.acked("s", Duration::from_millis(500));
+        telemetry.nacked("s", 3);
+        telemetry.reconnected("s");
+
+        let values = values.lock().unwrap();
+        assert_eq!(2.0, values["pubsub.subscriber.received_messages"]);
+        assert_eq!(1.0, values["pubsub.subscriber.acked_messages"]);
+        assert_eq!(3.0, values["pubsub.subscriber.nacked_messages"]);
+        assert_eq!(1.0, values["pubsub.subscriber.reconnects"]);
+        assert_eq!(0.5, values["pubsub.subscriber.ack_latency"]);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn test_parse_traceparent() {
+        use opentelemetry::trace::TraceFlags;
+
+        use crate::subscriber::parse_traceparent;
+
+        let context = parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", context.trace_id().to_string());
+        assert_eq!("00f067aa0ba902b7", context.span_id().to_string());
+        assert_eq!(TraceFlags::SAMPLED, context.trace_flags());
+        assert!(context.is_remote());
+        // the fields of the future versions are ignored
+        assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra").is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-zz",
+        ] {
+            assert!(parse_traceparent(invalid).is_none(), "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[tokio::test]
+    async fn test_extract_trace_context() {
+        let subc = empty_client().await;
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let message = |key: &str| {
+            let message = PubsubMessage {
+                attributes: HashMap::from([(key.to_string(), traceparent.to_string())]),
+                ..Default::default()
+            };
+            ReceivedMessage::new("s".to_string(), subc.clone(), message, "a".to_string(), None)
+        };
+        assert!(message("googclient_traceparent").extract_trace_context().is_some());
+        assert!(message("traceparent").extract_trace_context().is_some());
+        assert!(message("other").extract_trace_context().is_none());
+
+        let mut custom = message("other");
+        custom.trace_context_attribute = Some(Arc::from("other"));
+        assert!(custom.extract_trace_context().is_some());
+        let mut custom = message("traceparent");
+        custom.trace_context_attribute = Some(Arc::from("other"));
+        assert!(custom.extract_trace_context().is_none());
+    }
+
+    #[test]
+    fn test_stats_received_bytes_per_sec() {
+        let stats = vec![
+            SubscriberStats {
+                received_bytes: 100,
+                uptime: Duration::from_secs(2),
+                ..Default::default()
             },
-            None,
-        )
-        .await
-        .unwrap();
+            SubscriberStats {
+                received_bytes: 300,
+                uptime: Duration::from_secs(4),
+                ..Default::default()
+            },
+        ];
+        let total: SubscriberStats = stats.into_iter().sum();
+        assert_eq!(total.received_bytes, 400);
+        assert_eq!(total.reconnects, 0);
+        assert_eq!(total.uptime, Duration::from_secs(4));
+        assert_eq!(total.received_bytes_per_sec(), 100.0);
+        assert_eq!(SubscriberStats::default().received_bytes_per_sec(), 0.0);
+    }
 
-        let subscription = "projects/local-project/subscriptions/test-subscription1";
-        let response = subc
-            .pull(
-                PullRequest {
-                    subscription: subscription.to_string(),
-                    max_messages: 1,
-                    ..Default::default()
-                },
-                None,
-            )
-            .await
-            .unwrap()
-            .into_inner();
+    #[test]
+    fn test_stats_recommend_flow_control() {
+        assert_eq!(None, SubscriberStats::default().recommend_flow_control(1000));
+        let stats = SubscriberStats {
+            received_messages: 10,
+            received_bytes: 10 * 1024,
+            ..Default::default()
+        };
+        let flow_control = stats.recommend_flow_control(100 * 1024 * 1024).unwrap();
+        assert_eq!(
+            flow_control,
+            FlowControl {
+                max_outstanding_messages: 100 * 1024,
+                max_outstanding_bytes: 100 * 1024 * 1024,
+            }
+        );
+        let mut config = SubscriberConfig::default();
+        flow_control.apply_to(&mut config);
+        assert_eq!(config.max_outstanding_messages, 100 * 1024);
+        assert_eq!(config.max_outstanding_bytes, 100 * 1024 * 1024);
 
-        let messages = response.received_messages;
-        let (queue, _) = async_channel::unbounded();
-        queue.close();
-        let nack_size = handle_message(&CancellationToken::new(), &queue, &subc, subscription, messages).await;
-        assert_eq!(1, nack_size);
+        // The budget smaller than a message still allows a message to be delivered.
+        assert_eq!(1, stats.recommend_flow_control(100).unwrap().max_outstanding_messages);
+
+        let divided = flow_control.per_stream(3);
+        assert_eq!(divided.max_outstanding_messages, (100 * 1024 + 2) / 3);
+        assert_eq!(divided.max_outstanding_bytes, (100 * 1024 * 1024 + 2) / 3);
+        assert_eq!(flow_control, flow_control.per_stream(1));
+        assert_eq!(flow_control, flow_control.per_stream(0));
+        let unlimited = FlowControl {
+            max_outstanding_messages: 0,
+            max_outstanding_bytes: -1,
+        };
+        assert_eq!(unlimited, unlimited.per_stream(3));
+        let small = FlowControl {
+            max_outstanding_messages: 1,
+            max_outstanding_bytes: 2,
+        };
+        let divided = small.per_stream(3);
+        assert_eq!(divided.max_outstanding_messages, 1);
+        assert_eq!(divided.max_outstanding_bytes, 1);
+        // The unbounded budget recommended for usize::MAX doesn't overflow.
+        let large = FlowControl {
+            max_outstanding_messages: i64::MAX,
+            max_outstanding_bytes: i64::MAX,
+        };
+        let divided = large.per_stream(2);
+        assert_eq!(divided.max_outstanding_messages, i64::MAX / 2 + 1);
+        assert_eq!(divided.max_outstanding_bytes, i64::MAX / 2 + 1);
+        assert_eq!(large, large.per_stream(1));
+        assert_eq!(1, large.per_stream(usize::MAX).max_outstanding_bytes);
     }
 }
@@ -1,27 +1,34 @@
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime};
 
 use prost_types::{DurationError, FieldMask};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio_util::sync::CancellationToken;
 
 use google_cloud_gax::grpc::codegen::tokio_stream::Stream;
 use google_cloud_gax::grpc::{Code, Status};
 use google_cloud_gax::retry::RetrySetting;
 use google_cloud_googleapis::pubsub::v1::seek_request::Target;
+use google_cloud_googleapis::pubsub::v1::streaming_pull_response::SubscriptionProperties;
 use google_cloud_googleapis::pubsub::v1::subscription::AnalyticsHubSubscriptionInfo;
 use google_cloud_googleapis::pubsub::v1::{
     BigQueryConfig, CloudStorageConfig, CreateSnapshotRequest, DeadLetterPolicy, DeleteSnapshotRequest,
     DeleteSubscriptionRequest, ExpirationPolicy, GetSnapshotRequest, GetSubscriptionRequest, MessageTransform,
-    PullRequest, PushConfig, RetryPolicy, SeekRequest, Snapshot, Subscription as InternalSubscription,
-    UpdateSubscriptionRequest,
+    PushConfig, RetryPolicy, SeekRequest, Snapshot, Subscription as InternalSubscription, UpdateSubscriptionRequest,
 };
 
 use crate::apiv1::subscriber_client::SubscriberClient;
-
-use crate::subscriber::{ack, ReceivedMessage, Subscriber, SubscriberConfig};
+use crate::error::PubSubError;
+use crate::subscriber::{
+    ack, check_ack_deadline, modify_ack_deadline, pull, pull_batch, AckHandle, AckLatency, AckLatencyPercentiles,
+    Clock, FlowControl, HandOff, InFlight, MultiAckBatcher, ReceivedMessage, ShutdownOrder, Subscriber,
+    SubscriberConfig, SubscriberStats, SystemClock, MAX_STREAM_ACK_DEADLINE_SECONDS, MIN_STREAM_ACK_DEADLINE_SECONDS,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct SubscriptionConfig {
@@ -32,6 +39,10 @@ pub struct SubscriptionConfig {
     pub labels: HashMap<String, String>,
     pub enable_message_ordering: bool,
     pub expiration_policy: Option<ExpirationPolicy>,
+    /// The filter expression of the attributes, e.g. `attributes.region = "eu"`, with which the server only delivers
+    /// the matching messages and acks the others automatically. No filter if empty.
+    /// It is set only when the subscription is created and can't be changed afterwards, so `SubscriptionConfigToUpdate`
+    /// has no filter: delete and recreate the subscription to change it.
     pub filter: String,
     pub dead_letter_policy: Option<DeadLetterPolicy>,
     pub retry_policy: Option<RetryPolicy>,
@@ -113,6 +124,50 @@ pub struct ReceiveConfig {
     pub worker_count: usize,
     pub channel_capacity: Option<usize>,
     pub subscriber_config: Option<SubscriberConfig>,
+    /// The maximum time a single handler invocation may take.
+    /// When it is exceeded the message is nacked so that it can be redelivered to another worker.
+    pub handler_timeout: Option<Duration>,
+    /// Drop the handler future when `handler_timeout` is exceeded.
+    /// If false the handler keeps running to completion after the message is nacked.
+    pub abort_handler_on_timeout: bool,
+    /// The number of handler tasks per stream when message ordering is enabled.
+    /// Messages are assigned to a task by the hash of the ordering key, so the same key is always
+    /// handled by the same task in order while different keys are handled in parallel.
+    pub ordering_partitions: usize,
+    /// Counts the handlers running at the moment. Clone it before passing the config to observe it.
+    pub handler_gauge: HandlerGauge,
+    /// Pause the ordering key of a message nacked by the handler or timed out when message ordering is enabled.
+    /// While the key is paused the following messages with the same key are nacked without calling the handler,
+    /// because the server redelivers them after the failed message. Other keys are not blocked.
+    /// The key is resumed when the failed message is redelivered.
+    pub pause_ordering_key_on_nack: bool,
+}
+
+/// HandlerGauge is the number of the handlers currently running in `Subscription::receive`.
+/// When it stays at `worker_count` the handlers are saturated and `worker_count` should be raised.
+#[derive(Debug, Clone, Default)]
+pub struct HandlerGauge {
+    active: Arc<AtomicUsize>,
+}
+
+impl HandlerGauge {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn enter(&self) -> HandlerGaugeGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        HandlerGaugeGuard(self.active.clone())
+    }
+}
+
+/// HandlerGaugeGuard decrements the gauge even if the handler is aborted.
+struct HandlerGaugeGuard(Arc<AtomicUsize>);
+
+impl Drop for HandlerGaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Default for ReceiveConfig {
@@ -121,10 +176,58 @@ impl Default for ReceiveConfig {
             worker_count: 10,
             subscriber_config: None,
             channel_capacity: None,
+            handler_timeout: None,
+            abort_handler_on_timeout: true,
+            ordering_partitions: 1,
+            handler_gauge: HandlerGauge::default(),
+            pause_ordering_key_on_nack: false,
+        }
+    }
+}
+
+/// DispatchConfig is the config of `Subscription::subscribe_with`.
+#[derive(Debug, Clone)]
+pub struct DispatchConfig {
+    /// The maximum number of the handlers running concurrently.
+    pub max_concurrent_handlers: usize,
+    /// How long to wait for the running handlers after the cancellation.
+    pub grace: Duration,
+    pub subscribe_config: Option<SubscribeConfig>,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_handlers: 10,
+            grace: Duration::from_secs(30),
+            subscribe_config: None,
         }
     }
 }
 
+/// CatchUnwind resolves to Err with the payload if the future panics.
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = std::thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = self.0.as_mut();
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| inner.poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// panic_message returns the message of the panic payload if it is a string.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(v) => v,
+        None => payload.downcast_ref::<String>().map_or("unknown", |v| v.as_str()),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum SeekTo {
     Timestamp(SystemTime),
@@ -145,6 +248,21 @@ pub struct MessageStream {
     queue: async_channel::Receiver<ReceivedMessage>,
     cancel: CancellationToken,
     tasks: Vec<Subscriber>,
+    shutdown_order: ShutdownOrder,
+    /// The messages read from the stream and not yet released by the consumer.
+    in_flight: Arc<InFlight>,
+    /// The clock of `SubscriberConfig::clock` the grace periods are waited with.
+    clock: Arc<dyn Clock>,
+}
+
+/// ShutdownReport is the messages left behind by `MessageStream::shutdown`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The ack_ids of the messages still held by the consumer after the grace period without being acked or nacked.
+    /// They are redelivered after the ack deadline unless the consumer settles them, e.g. nacks them in a batch.
+    pub in_flight_ack_ids: Vec<String>,
+    /// The number of the messages not yet read, which were nacked.
+    pub nacked_messages: usize,
 }
 
 impl MessageStream {
@@ -152,11 +270,200 @@ impl MessageStream {
         self.cancel.clone()
     }
 
+    /// stats returns the counters summed over all the streaming pull tasks
+    /// and the depth of the queue shared by them.
+    pub fn stats(&self) -> SubscriberStats {
+        SubscriberStats {
+            queue_depth: self.queue.len() as u64,
+            ..self.tasks.iter().map(|task| task.stats()).sum()
+        }
+    }
+
+    /// subscription_properties returns the properties of the subscription sent by the server
+    /// to any of the streaming pull tasks. See `Subscriber::subscription_properties`.
+    pub fn subscription_properties(&self) -> Option<SubscriptionProperties> {
+        self.tasks.iter().find_map(|task| task.subscription_properties())
+    }
+
+    /// ack_latency_percentiles returns the percentiles of the latency of the acks of all the streaming pull tasks
+    /// recorded with `SubscriberConfig::record_ack_latency`, None if it is disabled or nothing is acked yet.
+    pub fn ack_latency_percentiles(&self) -> Option<AckLatencyPercentiles> {
+        AckLatency::percentiles(self.tasks.iter().filter_map(|task| task.ack_latency()))
+    }
+
+    /// is_backfilling reports whether any of the streaming pull tasks is still draining the backlog
+    /// with `SubscriberConfig::backfill`.
+    pub fn is_backfilling(&self) -> bool {
+        self.tasks.iter().any(|task| task.is_backfilling())
+    }
+
+    /// is_running reports whether any of the streaming pull tasks is still alive.
+    /// It is useful for liveness probes.
+    pub fn is_running(&self) -> bool {
+        self.tasks.iter().any(|task| task.is_running())
+    }
+
+    /// terminal_error returns the first non-retryable error that stopped a streaming pull task.
+    pub fn terminal_error(&self) -> Option<Status> {
+        self.tasks.iter().find_map(|task| task.terminal_error())
+    }
+
+    /// pause stops pulling messages without closing the streams.
+    /// The messages already in the queue can still be read.
+    pub fn pause(&self) {
+        self.tasks.iter().for_each(|task| task.pause());
+    }
+
+    /// resume restarts pulling messages paused by `pause`.
+    pub fn resume(&self) {
+        self.tasks.iter().for_each(|task| task.resume());
+    }
+
+    /// recommend_flow_control suggests the flow control settings within the memory budget based on the
+    /// messages received so far. See `SubscriberStats::recommend_flow_control`.
+    /// The settings are divided across the streams, since each of them applies the limits.
+    pub fn recommend_flow_control(&self, target_memory: usize) -> Option<FlowControl> {
+        self.stats()
+            .recommend_flow_control(target_memory)
+            .map(|v| v.per_stream(self.tasks.len()))
+    }
+
+    /// apply_config reconnects the streams with the flow control of the config, which the server only
+    /// accepts on a fresh stream: `stream_ack_deadline_seconds`, `max_outstanding_messages` and
+    /// `max_outstanding_bytes`. The other fields are ignored.
+    /// The messages already received are not dropped; the ones not acked in time are redelivered.
+    pub fn apply_config(&self, config: &SubscriberConfig) -> Result<(), PubSubError> {
+        config.validate()?;
+        self.tasks.iter().try_for_each(|task| task.apply_config(config))
+    }
+
     pub async fn dispose(&mut self) {
+        let _ = self.dispose_with(&mut MultiAckBatcher::new()).await;
+    }
+
+    /// shutdown stops pulling new messages and waits up to `grace` for the messages already read from the stream
+    /// to be released by the consumer, i.e. for their handlers to finish and ack them, then disposes the stream.
+    /// Unlike cancelling the stream while the handlers are running, the handlers can complete their work
+    /// before the shutdown, so it avoids the redelivery of the messages being handled on every deployment.
+    /// Only the messages not yet read are nacked.
+    /// It returns the ack_ids of the messages the consumer still holds unsettled after the grace period
+    /// and the number of the nacked messages.
+    pub async fn shutdown(&mut self, grace: Duration) -> ShutdownReport {
+        self.pause();
+        let mut in_flight = self.in_flight.count.subscribe();
+        let released = tokio::select! {
+            _ = in_flight.wait_for(|v| *v == 0) => true,
+            _ = self.clock.sleep(grace) => false,
+        };
+        if !released {
+            tracing::warn!("grace period elapsed with {} messages in flight", *in_flight.borrow());
+        }
+        let in_flight_ack_ids = self.in_flight.unsettled_ack_ids();
+        let (_, nacked_messages) = self.dispose_counting(&mut MultiAckBatcher::new()).await;
+        ShutdownReport {
+            in_flight_ack_ids,
+            nacked_messages,
+        }
+    }
+
+    /// dispatch reads the messages and runs `handler` for each of them with up to `max_concurrent_handlers` running
+    /// concurrently. The message is acked when the handler returns Ok and nacked when it returns Err or panics,
+    /// so that a poison message doesn't stop the dispatch.
+    /// It returns when the stream is cancelled or the streaming pull stops, after waiting up to `grace` for the running
+    /// handlers. The handlers still running are aborted and their messages are redelivered after the ack deadline.
+    /// The stream is disposed on return.
+    /// ```
+    /// use std::time::Duration;
+    /// use google_cloud_pubsub::subscription::Subscription;
+    /// use google_cloud_gax::grpc::Status;
+    ///
+    /// async fn run(subscription: Subscription) -> Result<(), Status> {
+    ///     let mut stream = subscription.subscribe(None).await?;
+    ///     stream
+    ///         .dispatch(8, Duration::from_secs(30), |message| async move {
+    ///             std::str::from_utf8(&message.message.data).map(|data| println!("{data}"))
+    ///         })
+    ///         .await;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn dispatch<H, F, E>(&mut self, max_concurrent_handlers: usize, grace: Duration, mut handler: H)
+    where
+        H: FnMut(ReceivedMessage) -> F,
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Debug + Send + 'static,
+    {
+        let max_concurrent_handlers = max_concurrent_handlers.max(1);
+        let mut handlers = JoinSet::new();
+        loop {
+            let mut message = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => break,
+                Some(joined) = handlers.join_next() => {
+                    if let Err(e) = joined {
+                        tracing::error!("handler panicked: {:?}", e);
+                    }
+                    continue;
+                }
+                message = self.queue.recv(), if handlers.len() < max_concurrent_handlers => match message {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+            };
+            message.track_in_flight(&self.in_flight);
+            let handle = message.handle().clone();
+            let handled = CatchUnwind(Box::pin(handler(message)));
+            handlers.spawn(async move {
+                let result = match handled.await {
+                    Ok(Ok(())) => handle.ack().await,
+                    Ok(Err(e)) => {
+                        tracing::debug!("handler failed -> so nack : ack_id={} {:?}", handle.ack_id(), e);
+                        handle.nack().await
+                    }
+                    Err(payload) => {
+                        tracing::error!(
+                            "handler panicked -> so nack : ack_id={} {}",
+                            handle.ack_id(),
+                            panic_message(payload.as_ref())
+                        );
+                        handle.nack().await
+                    }
+                };
+                if let Err(e) = result {
+                    tracing::warn!("failed to ack or nack the handled message : ack_id={} {:?}", handle.ack_id(), e);
+                }
+            });
+        }
+        let wait = async { while handlers.join_next().await.is_some() {} };
+        let finished = tokio::select! {
+            _ = wait => true,
+            _ = self.clock.sleep(grace) => false,
+        };
+        if !finished {
+            tracing::warn!("grace period elapsed with {} handlers running", handlers.len());
+            handlers.abort_all();
+        }
+        self.dispose().await;
+    }
+
+    /// dispose_with is the same as `dispose` but also flushes the acks pending in the batcher,
+    /// before or after nacking the remaining messages according to `SubscriberConfig::shutdown_order`.
+    /// It returns the error of the flush; the failures of the nacks are only logged because the
+    /// messages are redelivered after the ack deadline anyway.
+    pub async fn dispose_with(&mut self, pending_acks: &mut MultiAckBatcher) -> Result<(), PubSubError> {
+        self.dispose_counting(pending_acks).await.0
+    }
+
+    /// dispose_counting is `dispose_with` also returning the number of the nacked messages.
+    async fn dispose_counting(&mut self, pending_acks: &mut MultiAckBatcher) -> (Result<(), PubSubError>, usize) {
         // Close streaming pull task
         if !self.cancel.is_cancelled() {
             self.cancel.cancel();
         }
+        let mut result = Ok(());
+        if self.shutdown_order == ShutdownOrder::AcksFirst {
+            result = pending_acks.flush().await;
+        }
 
         // Wait for all the streaming pull close.
         for task in &mut self.tasks {
@@ -164,11 +471,17 @@ impl MessageStream {
         }
 
         // Nack for remaining messages.
+        let mut nacked = 0;
         while let Ok(message) = self.queue.recv().await {
+            nacked += 1;
             if let Err(err) = message.nack().await {
                 tracing::warn!("failed to nack message messageId={} {:?}", message.message.message_id, err);
             }
         }
+        if self.shutdown_order == ShutdownOrder::NacksFirst {
+            result = pending_acks.flush().await;
+        }
+        (result, nacked)
     }
 
     /// Immediately Nack on cancel
@@ -177,13 +490,58 @@ impl MessageStream {
             msg = self.queue.recv() => msg.ok(),
             _ = self.cancel.cancelled() => None
         };
-        if message.is_none() {
-            self.dispose().await;
+        match message {
+            Some(mut message) => {
+                message.track_in_flight(&self.in_flight);
+                Some(message)
+            }
+            None => {
+                self.dispose().await;
+                None
+            }
+        }
+    }
+
+    /// Decodes the data of each message as JSON and pairs it with the handle to ack it.
+    /// Messages that can't be decoded are nacked, then skipped or surfaced as `Err` according to `on_error`.
+    #[cfg(feature = "json")]
+    pub fn messages_as<T: serde::de::DeserializeOwned>(
+        mut self,
+        on_error: DecodeErrorPolicy,
+    ) -> impl Stream<Item = Result<(T, crate::subscriber::AckHandle), PubSubError>> {
+        async_stream::stream! {
+            while let Some(message) = self.read().await {
+                let (message, handle) = message.into_parts();
+                match serde_json::from_slice::<T>(&message.data) {
+                    Ok(v) => yield Ok((v, handle)),
+                    Err(e) => {
+                        tracing::warn!("failed to decode message messageId={} {:?}", message.message_id, e);
+                        if let Err(err) = handle.nack().await {
+                            tracing::warn!("failed to nack message messageId={} {:?}", message.message_id, err);
+                        }
+                        if on_error == DecodeErrorPolicy::Surface {
+                            yield Err(PubSubError::SerializationError(format!(
+                                "failed to decode message messageId={}: {e}",
+                                message.message_id
+                            )));
+                        }
+                    }
+                }
+            }
         }
-        message
     }
 }
 
+/// DecodeErrorPolicy decides how `MessageStream::messages_as` reports a message that can't be decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Nack the message and continue with the next one.
+    #[default]
+    NackAndSkip,
+    /// Nack the message and yield `Err`.
+    Surface,
+}
+
 impl Drop for MessageStream {
     fn drop(&mut self) {
         if !self.queue.is_empty() {
@@ -201,7 +559,85 @@ impl Stream for MessageStream {
     /// Return None unless the queue is open.
     /// Use CancellationToken for SubscribeConfig to get None
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.get_mut().queue).poll_next(cx)
+        let this = self.get_mut();
+        Pin::new(&mut this.queue).poll_next(cx).map(|message| {
+            message.map(|mut message| {
+                message.track_in_flight(&this.in_flight);
+                message
+            })
+        })
+    }
+}
+
+/// SubscriptionName is the validated `projects/{project}/subscriptions/{subscription}` name of a subscription.
+/// The id of the subscription must start with a letter, must not start with `goog`, must be 3 to 255 characters
+/// and may contain letters, numbers and `-_.~+%`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionName {
+    project: String,
+    subscription: String,
+}
+
+impl SubscriptionName {
+    pub fn new(project: &str, subscription: &str) -> Result<Self, PubSubError> {
+        if project.is_empty() || project.contains('/') {
+            return Err(PubSubError::InvalidSubscriptionName(format!("invalid project {project:?}")));
+        }
+        let valid_chars = subscription
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.~+%".contains(c));
+        if !(3..=255).contains(&subscription.len())
+            || !subscription.starts_with(|c: char| c.is_ascii_alphabetic())
+            || subscription.starts_with("goog")
+            || !valid_chars
+        {
+            return Err(PubSubError::InvalidSubscriptionName(format!(
+                "invalid subscription id {subscription:?}"
+            )));
+        }
+        Ok(Self {
+            project: project.to_string(),
+            subscription: subscription.to_string(),
+        })
+    }
+
+    /// parse validates the fully qualified name `projects/{project}/subscriptions/{subscription}`.
+    pub fn parse(full_path: &str) -> Result<Self, PubSubError> {
+        match full_path.split('/').collect::<Vec<_>>()[..] {
+            ["projects", project, "subscriptions", subscription] => Self::new(project, subscription),
+            _ => Err(PubSubError::InvalidSubscriptionName(format!(
+                "{full_path:?} is not projects/{{project}}/subscriptions/{{subscription}}"
+            ))),
+        }
+    }
+
+    pub fn project(&self) -> &str {
+        &self.project
+    }
+
+    /// subscription returns the id of the subscription within its project.
+    pub fn subscription(&self) -> &str {
+        &self.subscription
+    }
+}
+
+impl std::fmt::Display for SubscriptionName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "projects/{}/subscriptions/{}", self.project, self.subscription)
+    }
+}
+
+impl std::str::FromStr for SubscriptionName {
+    type Err = PubSubError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl From<SubscriptionName> for String {
+    fn from(v: SubscriptionName) -> Self {
+        v.to_string()
     }
 }
 
@@ -297,6 +733,51 @@ impl Subscription {
             .map(|_v| ())
     }
 
+    /// ensure creates the subscription if it doesn't exist and reports whether it was created.
+    /// The configuration of an existing subscription is not updated. Only its filter is compared, because it can't
+    /// be changed: FAILED_PRECONDITION is returned if it differs from `cfg.filter`, rather than receiving the messages
+    /// the caller doesn't expect.
+    /// It is idempotent even if another process creates the subscription at the same time.
+    pub async fn ensure(
+        &self,
+        fqtn: &str,
+        cfg: SubscriptionConfig,
+        retry: Option<RetrySetting>,
+    ) -> Result<bool, Status> {
+        match self.config(retry.clone()).await {
+            Ok((_, existing)) => {
+                return self
+                    .check_filter(&existing.filter, &cfg.filter)
+                    .map(|_| false)
+                    .map_err(Status::from)
+            }
+            Err(e) if e.code() != Code::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        let filter = cfg.filter.clone();
+        match self.create(fqtn, cfg, retry.clone()).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == Code::AlreadyExists => {
+                let (_, existing) = self.config(retry).await?;
+                self.check_filter(&existing.filter, &filter)
+                    .map(|_| false)
+                    .map_err(Status::from)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_filter(&self, existing: &str, expected: &str) -> Result<(), PubSubError> {
+        if existing == expected {
+            return Ok(());
+        }
+        Err(Status::failed_precondition(format!(
+            "subscription {} has the filter {existing:?} instead of {expected:?}: the filter can't be changed, so delete and recreate the subscription",
+            self.fqsn
+        ))
+        .into())
+    }
+
     /// delete deletes the subscription.
     pub async fn delete(&self, retry: Option<RetrySetting>) -> Result<(), Status> {
         let req = DeleteSubscriptionRequest {
@@ -333,6 +814,16 @@ impl Subscription {
         })
     }
 
+    /// dead_letter_topic returns the dead letter topic configured in the dead letter policy of the subscription.
+    /// It returns `None` if the subscription has no dead letter policy.
+    pub async fn dead_letter_topic(&self, retry: Option<RetrySetting>) -> Result<Option<String>, Status> {
+        let (_, cfg) = self.config(retry).await?;
+        Ok(cfg
+            .dead_letter_policy
+            .map(|v| v.dead_letter_topic)
+            .filter(|v| !v.is_empty()))
+    }
+
     /// update changes an existing subscription according to the fields set in updating.
     /// It returns the new SubscriptionConfig.
     pub async fn update(
@@ -395,27 +886,46 @@ impl Subscription {
 
     /// pull get message synchronously.
     /// It blocks until at least one message is available.
-    pub async fn pull(&self, max_messages: i32, retry: Option<RetrySetting>) -> Result<Vec<ReceivedMessage>, Status> {
-        #[allow(deprecated)]
-        let req = PullRequest {
-            subscription: self.fqsn.clone(),
-            return_immediately: false,
-            max_messages,
-        };
-        let messages = self.subc.pull(req, retry).await?.into_inner().received_messages;
-        Ok(messages
-            .into_iter()
-            .filter(|m| m.message.is_some())
-            .map(|m| {
-                ReceivedMessage::new(
-                    self.fqsn.clone(),
-                    self.subc.clone(),
-                    m.message.unwrap(),
-                    m.ack_id,
-                    (m.delivery_attempt > 0).then_some(m.delivery_attempt as usize),
-                )
-            })
-            .collect())
+    pub async fn pull(
+        &self,
+        max_messages: i32,
+        retry: Option<RetrySetting>,
+    ) -> Result<Vec<ReceivedMessage>, PubSubError> {
+        Ok(pull(&self.subc, self.fqsn.clone(), max_messages, retry).await?)
+    }
+
+    /// pull_once pulls up to `max_messages` messages with a single Pull request and returns them.
+    /// It returns an empty list if no message is available within `timeout`, the deadline of the Pull request.
+    /// See `subscriber::pull_batch` for the callers holding only the `SubscriberClient`.
+    ///
+    /// No lease management is done: neither the streaming pull nor the deadline extensions are started,
+    /// so the caller is responsible for acking the messages within the ack deadline of the subscription.
+    /// This is the leanest way for a batch job to consume a fixed set of messages and exit.
+    pub async fn pull_once(
+        &self,
+        max_messages: i32,
+        timeout: Duration,
+        retry: Option<RetrySetting>,
+    ) -> Result<Vec<ReceivedMessage>, PubSubError> {
+        pull_batch(&self.subc, &self.fqsn, max_messages, timeout, retry).await
+    }
+
+    /// take_over returns the handle to ack the message handed off by another subscriber instance
+    /// with `AckHandle::hand_off`. See `AckHandle::hand_off` for the race conditions.
+    pub fn take_over(&self, hand_off: HandOff) -> Result<AckHandle, PubSubError> {
+        if hand_off.subscription != self.fqsn {
+            return Err(Status::invalid_argument(format!(
+                "the message was handed off from another subscription: {}",
+                hand_off.subscription
+            ))
+            .into());
+        }
+        Ok(AckHandle::new(
+            self.fqsn.clone(),
+            self.subc.clone(),
+            hand_off.ack_id,
+            hand_off.message_id,
+        ))
     }
 
     /// subscribe creates a `Stream` of `ReceivedMessage`
@@ -459,36 +969,77 @@ impl Subscription {
     ///     Ok(())
     ///  }
     /// ```
-    pub async fn subscribe(&self, opt: Option<SubscribeConfig>) -> Result<MessageStream, Status> {
+    pub async fn subscribe(&self, opt: Option<SubscribeConfig>) -> Result<MessageStream, PubSubError> {
         let opt = opt.unwrap_or_default();
-        let (tx, rx) = create_channel(opt.channel_capacity);
-        let cancel = CancellationToken::new();
         let sub_opt = self.unwrap_subscribe_config(opt.subscriber_config).await?;
+        let name = SubscriptionName::parse(&self.fqsn)?;
+        let (tx, rx) = create_channel(opt.channel_capacity.or(sub_opt.queue_capacity()));
+        let cancel = CancellationToken::new();
 
         // spawn a separate subscriber task for each connection in the pool
-        let subscribers = if opt.enable_multiple_subscriber {
-            self.streaming_pool_size()
-        } else {
-            1
+        let subscribers = match sub_opt.streams {
+            Some(streams) => streams,
+            None if opt.enable_multiple_subscriber => self.streaming_pool_size(),
+            None => 1,
         };
         let mut tasks = Vec::with_capacity(subscribers);
         for _ in 0..subscribers {
-            tasks.push(Subscriber::start(
-                cancel.clone(),
-                self.fqsn.clone(),
-                self.subc.clone(),
-                tx.clone(),
-                sub_opt.clone(),
-            ));
+            tasks.push(
+                Subscriber::builder(self.subc.clone(), name.clone())
+                    .config(sub_opt.clone())
+                    .cancellation_token(cancel.clone())
+                    .queue(tx.clone())
+                    .start()?,
+            );
         }
 
         Ok(MessageStream {
             queue: rx,
             cancel,
             tasks,
+            shutdown_order: sub_opt.shutdown_order,
+            in_flight: Arc::default(),
+            clock: sub_opt.clock.clone().unwrap_or_else(|| Arc::new(SystemClock)),
         })
     }
 
+    /// subscribe_with_handle is the same as `subscribe` but also returns the handle of a task driving
+    /// the streaming pull tasks. The handle completes when all of them stop, with the first terminal error
+    /// if any of them stopped by the error, so that it can be supervised like any other task.
+    ///
+    /// ```
+    /// use google_cloud_pubsub::subscription::Subscription;
+    /// use google_cloud_gax::grpc::Status;
+    ///
+    /// async fn run(subscription: Subscription) -> Result<(), Status> {
+    ///     let (mut iter, handle) = subscription.subscribe_with_handle(None).await?;
+    ///     tokio::spawn(async move {
+    ///         while let Some(message) = iter.read().await {
+    ///             let _ = message.ack().await;
+    ///         }
+    ///     });
+    ///     // Returns when the subscriber stops.
+    ///     Ok(handle.await.unwrap()?)
+    /// }
+    /// ```
+    pub async fn subscribe_with_handle(
+        &self,
+        opt: Option<SubscribeConfig>,
+    ) -> Result<(MessageStream, JoinHandle<Result<(), PubSubError>>), PubSubError> {
+        let stream = self.subscribe(opt).await?;
+        let stopped: Vec<_> = stream.tasks.iter().map(|v| v.stopped()).collect();
+        let handle = tokio::spawn(async move {
+            let mut first_error = None;
+            for stopped in stopped {
+                if let Err(e) = stopped.await {
+                    first_error.get_or_insert(e);
+                }
+            }
+            first_error.map_or(Ok(()), |e| Err(e.into()))
+        });
+        Ok((stream, handle))
+    }
+
     /// receive calls f with the outstanding messages from the subscription.
     /// It blocks until cancellation token is cancelled, or the service returns a non-retryable error.
     /// The standard way to terminate a receive is to use CancellationToken.
@@ -497,7 +1048,7 @@ impl Subscription {
         f: impl Fn(ReceivedMessage, CancellationToken) -> F + Send + 'static + Sync + Clone,
         cancel: CancellationToken,
         config: Option<ReceiveConfig>,
-    ) -> Result<(), Status>
+    ) -> Result<(), PubSubError>
     where
         F: Future<Output = ()> + Send + 'static,
     {
@@ -505,45 +1056,83 @@ impl Subscription {
         let mut receivers = Vec::with_capacity(op.worker_count);
         let mut senders = Vec::with_capacity(receivers.len());
         let sub_opt = self.unwrap_subscribe_config(op.subscriber_config).await?;
+        let name = SubscriptionName::parse(&self.fqsn)?;
+        let channel_capacity = op.channel_capacity.or(sub_opt.queue_capacity());
 
-        if self
+        let ordered = self
             .config(sub_opt.retry_setting.clone())
             .await?
             .1
-            .enable_message_ordering
-        {
+            .enable_message_ordering;
+        if ordered {
             (0..op.worker_count).for_each(|_v| {
-                let (sender, receiver) = create_channel(op.channel_capacity);
+                let (sender, receiver) = create_channel(channel_capacity);
                 receivers.push(receiver);
                 senders.push(sender);
             });
         } else {
-            let (sender, receiver) = create_channel(op.channel_capacity);
-            (0..op.worker_count).for_each(|_v| {
-                receivers.push(receiver.clone());
-                senders.push(sender.clone());
-            });
+            let (sender, receiver) = create_channel(channel_capacity);
+            (0..op.worker_count).for_each(|_v| receivers.push(receiver.clone()));
+            (0..sub_opt.streams.unwrap_or(op.worker_count)).for_each(|_v| senders.push(sender.clone()));
         }
 
         //same ordering key is in same stream.
-        let subscribers: Vec<Subscriber> = senders
+        let subscribers = senders
             .into_iter()
             .map(|queue| {
-                Subscriber::start(cancel.clone(), self.fqsn.clone(), self.subc.clone(), queue, sub_opt.clone())
+                Subscriber::builder(self.subc.clone(), name.clone())
+                    .config(sub_opt.clone())
+                    .cancellation_token(cancel.clone())
+                    .queue(queue)
+                    .start()
             })
-            .collect();
+            .collect::<Result<Vec<_>, _>>()?;
 
         let mut message_receivers = Vec::with_capacity(receivers.len());
         for receiver in receivers {
-            let f_clone = f.clone();
-            let cancel_clone = cancel.clone();
-            let name = self.fqsn.clone();
+            let spawn = |receiver| {
+                spawn_handler(
+                    receiver,
+                    f.clone(),
+                    cancel.clone(),
+                    self.fqsn.clone(),
+                    HandlerOptions {
+                        timeout: op.handler_timeout,
+                        abort_on_timeout: op.abort_handler_on_timeout,
+                        gauge: op.handler_gauge.clone(),
+                        pause_ordering_key_on_nack: ordered && op.pause_ordering_key_on_nack,
+                    },
+                )
+            };
+            if !ordered || op.ordering_partitions <= 1 {
+                message_receivers.push(spawn(receiver));
+                continue;
+            }
+            let (partitions, handlers): (Vec<_>, Vec<_>) = (0..op.ordering_partitions)
+                .map(|_| {
+                    let (sender, receiver) = create_channel(channel_capacity);
+                    (sender, spawn(receiver))
+                })
+                .unzip();
             message_receivers.push(tokio::spawn(async move {
+                let mut next = 0;
                 while let Ok(message) = receiver.recv().await {
-                    f_clone(message, cancel_clone.clone()).await;
+                    let key = message.message.ordering_key.as_str();
+                    // Messages without ordering key have no ordering constraint.
+                    let index = if key.is_empty() {
+                        next = (next + 1) % partitions.len();
+                        next
+                    } else {
+                        ordering_partition(key, partitions.len())
+                    };
+                    if partitions[index].send(message).await.is_err() {
+                        break;
+                    }
+                }
+                drop(partitions);
+                for handler in handlers {
+                    let _ = handler.await;
                 }
-                // queue is closed by subscriber when the cancellation token is cancelled
-                tracing::trace!("stop message receiver : {}", name);
             }));
         }
         cancel.cancelled().await;
@@ -560,6 +1149,35 @@ impl Subscription {
         Ok(())
     }
 
+    /// subscribe_with calls `handler` for the messages of the subscription with up to
+    /// `DispatchConfig::max_concurrent_handlers` running concurrently, and acks the message when the handler
+    /// returns Ok or nacks it when the handler returns Err or panics. See `MessageStream::dispatch`.
+    /// It blocks until `cancel` is cancelled or the service returns a non-retryable error, which is returned.
+    pub async fn subscribe_with<H, F, E>(
+        &self,
+        handler: H,
+        cancel: CancellationToken,
+        config: Option<DispatchConfig>,
+    ) -> Result<(), PubSubError>
+    where
+        H: FnMut(ReceivedMessage) -> F,
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Debug + Send + 'static,
+    {
+        let config = config.unwrap_or_default();
+        let mut stream = self.subscribe(config.subscribe_config).await?;
+        let stream_cancel = stream.cancellable();
+        let linked = tokio::spawn(async move {
+            cancel.cancelled().await;
+            stream_cancel.cancel();
+        });
+        stream
+            .dispatch(config.max_concurrent_handlers, config.grace, handler)
+            .await;
+        linked.abort();
+        stream.terminal_error().map_or(Ok(()), |e| Err(e.into()))
+    }
+
     /// Ack acknowledges the messages associated with the ack_ids in the AcknowledgeRequest.
     /// The Pub/Sub system can remove the relevant messages from the subscription.
     /// This method is for batch acking.
@@ -622,8 +1240,22 @@ impl Subscription {
     ///     Ok(())
     ///  }
     /// ```
-    pub async fn ack(&self, ack_ids: Vec<String>) -> Result<(), Status> {
-        ack(&self.subc, self.fqsn.to_string(), ack_ids).await
+    pub async fn ack(&self, ack_ids: Vec<String>) -> Result<(), PubSubError> {
+        ack(&self.subc, &self.fqsn, ack_ids, None, &SystemClock).await?;
+        Ok(())
+    }
+
+    /// modify_ack_deadline modifies the ack deadline of the ack_ids, e.g. the ones of `ShutdownReport`
+    /// with 0 to have them redelivered. More than 2000 ack_ids are sent in multiple requests and
+    /// an error is returned if any of them fails.
+    pub async fn modify_ack_deadline(
+        &self,
+        ack_ids: Vec<String>,
+        ack_deadline_seconds: i32,
+    ) -> Result<(), PubSubError> {
+        check_ack_deadline(ack_deadline_seconds)?;
+        modify_ack_deadline(&self.subc, &self.fqsn, ack_ids, ack_deadline_seconds, None, &SystemClock).await?;
+        Ok(())
     }
 
     /// seek seeks the subscription a past timestamp or a saved snapshot.
@@ -642,6 +1274,20 @@ impl Subscription {
         Ok(())
     }
 
+    /// seek_to_time seeks the subscription to the time with the default retry setting.
+    /// The messages published before the time are marked as acknowledged and the messages retained after the time
+    /// are marked as unacknowledged. It is used to replay the messages, for example, after a bad deploy.
+    /// `retain_acked_messages` must be enabled on the subscription to replay the acknowledged messages.
+    pub async fn seek_to_time(&self, time: SystemTime) -> Result<(), Status> {
+        self.seek(SeekTo::Timestamp(time), None).await
+    }
+
+    /// seek_to_snapshot seeks the subscription to the snapshot with the default retry setting.
+    /// The snapshot is either the id in the project of the subscription or the fully qualified name.
+    pub async fn seek_to_snapshot(&self, snapshot: &str) -> Result<(), Status> {
+        self.seek(SeekTo::Snapshot(snapshot.to_string()), None).await
+    }
+
     /// get_snapshot fetches an existing pubsub snapshot.
     pub async fn get_snapshot(&self, name: &str, retry: Option<RetrySetting>) -> Result<Snapshot, Status> {
         let req = GetSnapshotRequest {
@@ -682,22 +1328,128 @@ impl Subscription {
         Ok(())
     }
 
-    async fn unwrap_subscribe_config(&self, cfg: Option<SubscriberConfig>) -> Result<SubscriberConfig, Status> {
+    async fn unwrap_subscribe_config(&self, cfg: Option<SubscriberConfig>) -> Result<SubscriberConfig, PubSubError> {
         if let Some(cfg) = cfg {
+            cfg.validate()?;
+            cfg.warn_low_concurrency(&self.fqsn);
+            if cfg.require_delivery_attempt && self.config(None).await?.1.dead_letter_policy.is_none() {
+                return Err(Status::failed_precondition(format!(
+                    "delivery_attempt is required but the subscription has no dead letter policy: {}",
+                    self.fqsn
+                ))
+                .into());
+            }
             return Ok(cfg);
         }
         let cfg = self.config(None).await?;
         let mut default_cfg = SubscriberConfig {
-            stream_ack_deadline_seconds: cfg.1.ack_deadline_seconds.clamp(10, 600),
+            stream_ack_deadline_seconds: cfg
+                .1
+                .ack_deadline_seconds
+                .clamp(MIN_STREAM_ACK_DEADLINE_SECONDS, MAX_STREAM_ACK_DEADLINE_SECONDS),
             ..Default::default()
         };
         if cfg.1.enable_exactly_once_delivery {
             default_cfg.max_outstanding_messages = 5;
+            default_cfg.track_latest_ack_id = true;
         }
         Ok(default_cfg)
     }
 }
 
+/// HandlerOptions is the part of the `ReceiveConfig` used by each handler task.
+struct HandlerOptions {
+    timeout: Option<Duration>,
+    abort_on_timeout: bool,
+    gauge: HandlerGauge,
+    pause_ordering_key_on_nack: bool,
+}
+
+fn spawn_handler<F>(
+    receiver: async_channel::Receiver<ReceivedMessage>,
+    f: impl Fn(ReceivedMessage, CancellationToken) -> F + Send + 'static,
+    cancel: CancellationToken,
+    name: String,
+    options: HandlerOptions,
+) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        // ordering key -> message_id of the failed message the key waits for
+        let mut paused_keys: HashMap<String, String> = HashMap::new();
+        while let Ok(mut message) = receiver.recv().await {
+            let key = message.ordering_key().map(str::to_string);
+            let message_id = message.message.message_id.clone();
+            let Some(key) = key.filter(|_| options.pause_ordering_key_on_nack) else {
+                let _guard = options.gauge.enter();
+                let handle = message.handle().clone();
+                call_handler(f(message, cancel.clone()), &handle, &options).await;
+                continue;
+            };
+            if let Some(failed) = paused_keys.get(&key) {
+                if *failed != message_id {
+                    tracing::debug!("nack message of paused ordering key : key={}, msg_id={}", key, message_id);
+                    if let Err(err) = message.nack().await {
+                        tracing::error!("failed to nack message of paused ordering key: {:?}", err);
+                    }
+                    continue;
+                }
+                tracing::debug!("resume ordering key : key={}, msg_id={}", key, message_id);
+                paused_keys.remove(&key);
+            }
+            let nacked = message.watch_nack();
+            let _guard = options.gauge.enter();
+            let handle = message.handle().clone();
+            let timed_out = call_handler(f(message, cancel.clone()), &handle, &options).await;
+            if timed_out || nacked.load(Ordering::Relaxed) {
+                tracing::debug!("pause ordering key : key={}, msg_id={}", key, message_id);
+                paused_keys.insert(key, message_id);
+            }
+        }
+        // queue is closed by subscriber when the cancellation token is cancelled
+        tracing::trace!("stop message receiver : {}", name);
+    })
+}
+
+/// call_handler awaits the handler and returns true if it timed out and the message was nacked.
+/// The message is nacked through its handle, so that its lease is not extended any more.
+async fn call_handler<F>(handler: F, handle: &AckHandle, options: &HandlerOptions) -> bool
+where
+    F: Future<Output = ()>,
+{
+    let timeout = match options.timeout {
+        Some(timeout) => timeout,
+        None => {
+            handler.await;
+            return false;
+        }
+    };
+    tokio::pin!(handler);
+    tokio::select! {
+        _ = &mut handler => false,
+        _ = handle.clock().sleep(timeout) => {
+            tracing::warn!("handler timed out after {:?} : ack_id={}", timeout, handle.ack_id());
+            if let Err(err) = handle.nack().await {
+                tracing::error!("failed to nack timed out message: {:?}", err);
+            }
+            if !options.abort_on_timeout {
+                handler.await;
+            }
+            true
+        }
+    }
+}
+
+/// ordering_partition maps the ordering key to a partition with the FNV-1a hash,
+/// which is stable across processes and releases.
+fn ordering_partition(ordering_key: &str, partitions: usize) -> usize {
+    let hash = ordering_key
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    (hash % partitions as u64) as usize
+}
+
 fn create_channel(
     channel_capacity: Option<usize>,
 ) -> (async_channel::Sender<ReceivedMessage>, async_channel::Receiver<ReceivedMessage>) {
@@ -709,7 +1461,7 @@ fn create_channel(
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::sync::atomic::AtomicU32;
     use std::sync::atomic::Ordering::SeqCst;
     use std::sync::{Arc, Mutex};
@@ -721,14 +1473,23 @@ mod tests {
     use uuid::Uuid;
 
     use google_cloud_gax::conn::{ConnectionOptions, Environment};
-    use google_cloud_googleapis::pubsub::v1::{PublishRequest, PubsubMessage};
+    use google_cloud_gax::grpc::Code;
+    use google_cloud_googleapis::pubsub::v1::{
+        AcknowledgeRequest, DeadLetterPolicy, PublishRequest, PubsubMessage, StreamingPullResponse,
+    };
 
     use crate::apiv1::conn_pool::ConnectionManager;
     use crate::apiv1::publisher_client::PublisherClient;
     use crate::apiv1::subscriber_client::SubscriberClient;
-    use crate::subscriber::ReceivedMessage;
+    use crate::error::PubSubError;
+    use crate::subscriber::{
+        ack_all, ack_all_with_result, nack_all, pull_batch, with_lease_extension, AckBatchConfig, AckOutcome, Backfill,
+        Hook, LeaseConfig, MultiAckBatcher, ReceivedMessage, ReconnectReason, ShutdownOrder, StopReason,
+        SubscriberConfig, SubscriberObserver, SystemClock,
+    };
     use crate::subscription::{
-        ReceiveConfig, SeekTo, SubscribeConfig, Subscription, SubscriptionConfig, SubscriptionConfigToUpdate,
+        ordering_partition, HandlerGauge, MessageStream, ReceiveConfig, SeekTo, ShutdownReport, SubscribeConfig,
+        Subscription, SubscriptionConfig, SubscriptionConfigToUpdate, SubscriptionName,
     };
 
     const PROJECT_NAME: &str = "local-project";
@@ -794,6 +1555,18 @@ mod tests {
         let _ = pubc.publish(req, None).await;
     }
 
+    /// publish_until_received publishes a message every second until one is received. After a reconnect,
+    /// the emulator keeps routing some deliveries to the abandoned stream, where they wait for the ack deadline.
+    async fn publish_until_received(iter: &mut MessageStream) -> ReceivedMessage {
+        for _ in 0..30 {
+            publish(None).await;
+            if let Ok(Some(message)) = tokio::time::timeout(Duration::from_secs(1), iter.next()).await {
+                return message;
+            }
+        }
+        panic!("no message received in 30 seconds");
+    }
+
     async fn test_subscription(enable_exactly_once_delivery: bool) {
         let subscription = create_subscription(enable_exactly_once_delivery).await;
 
@@ -847,436 +1620,1867 @@ mod tests {
         subscription.delete(None).await.unwrap();
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_subscription_exactly_once() {
-        test_subscription(true).await;
-    }
+    async fn test_pull_into_ack() {
+        let subscription = create_subscription(false).await;
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base])).await;
+        let mut messages = vec![];
+        while messages.len() < 2 {
+            messages.extend(subscription.pull_once(2, Duration::from_secs(5), None).await.unwrap());
+        }
+        let nacked = messages.pop().unwrap();
+        let nacked_id = nacked.message.message_id.clone();
+        messages.pop().unwrap().into_ack().await.unwrap();
+        nacked.into_nack().await.unwrap();
 
-    #[tokio::test]
-    #[serial]
-    async fn test_subscription_at_least_once() {
-        test_subscription(false).await;
+        // Only the nacked message is redelivered.
+        let messages = subscription.pull(2, None).await.unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!(nacked_id, messages[0].message.message_id);
+        messages.into_iter().next().unwrap().into_ack().await.unwrap();
+        subscription.delete(None).await.unwrap();
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_multi_subscriber_single_subscription_unbound() {
-        test_multi_subscriber_single_subscription(None).await;
-    }
+    async fn test_subscription_modify_ack_deadline() {
+        let subscription = create_subscription(false).await;
+        subscription.modify_ack_deadline(vec![], 0).await.unwrap();
+        let err = subscription.modify_ack_deadline(vec![], 601).await.unwrap_err();
+        assert!(matches!(err, PubSubError::DeadlineOutOfRange(601)), "{err:?}");
 
-    #[tokio::test(flavor = "multi_thread")]
+        publish(None).await;
+        let message = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        let message_id = message.message.message_id.clone();
+
+        // More than the limit of a request, with the real ack_id in the last chunk.
+        let mut ack_ids: Vec<String> = (0..2500)
+            .map(|i| format!("{}:{}", subscription.fully_qualified_name(), 1_000_000_000 + i))
+            .collect();
+        ack_ids.push(message.ack_id().to_string());
+        subscription.modify_ack_deadline(ack_ids, 0).await.unwrap();
+
+        let redelivered = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(message_id, redelivered.message.message_id);
+
+        // The chunks after a failed one are still sent.
+        let mut ack_ids: Vec<String> = (0..2000).map(|i| format!("invalid-{i}")).collect();
+        ack_ids.push(redelivered.ack_id().to_string());
+        let err = subscription.modify_ack_deadline(ack_ids, 0).await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+        let redelivered = subscription.pull(1, None).await.unwrap().pop().unwrap();
+        assert_eq!(message_id, redelivered.message.message_id);
+        redelivered.ack().await.unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_pull_once() {
+        let subscription = create_subscription(false).await;
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base.clone(), base])).await;
+        let mut received = 0;
+        while received < 3 {
+            let messages = subscription.pull_once(5, Duration::from_secs(5), None).await.unwrap();
+            assert!(!messages.is_empty() && messages.len() <= 3, "{}", messages.len());
+            for m in &messages {
+                m.ack().await.unwrap();
+            }
+            received += messages.len();
+        }
+        assert_eq!(3, received);
+
+        // No message is available.
+        let messages = subscription.pull_once(5, Duration::from_secs(1), None).await.unwrap();
+        assert!(messages.is_empty());
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_pull_batch() {
+        let subscription = create_subscription(false).await;
+        publish(None).await;
+        let mut messages = vec![];
+        while messages.is_empty() {
+            messages = pull_batch(&subscription.subc, &subscription.fqsn, 5, Duration::from_secs(5), None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(messages[0].subscription(), subscription.fqsn);
+        ack_all(&messages).await.unwrap();
+
+        let messages = pull_batch(&subscription.subc, &subscription.fqsn, 5, Duration::from_secs(1), None)
+            .await
+            .unwrap();
+        assert!(messages.is_empty(), "{messages:?}");
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_with_lease_extension() {
+        let subscription = create_subscription(false).await;
+        let ack_deadline_seconds = subscription.config(None).await.unwrap().1.ack_deadline_seconds;
+        publish(None).await;
+        let messages = subscription.pull(1, None).await.unwrap();
+
+        // The handler runs longer than the ack deadline of the subscription.
+        let handler_time = Duration::from_secs(ack_deadline_seconds as u64 + 2);
+        let output = with_lease_extension(&messages[0], ack_deadline_seconds, async {
+            tokio::time::sleep(handler_time).await;
+            "done"
+        })
+        .await
+        .unwrap();
+        assert_eq!("done", output);
+
+        // The handler is not polled with a deadline that can't be extended.
+        for invalid in [-1, 601] {
+            let err = with_lease_extension(&messages[0], invalid, async { unreachable!() })
+                .await
+                .unwrap_err();
+            assert!(matches!(err, PubSubError::DeadlineOutOfRange(v) if v == invalid), "{err:?}");
+        }
+
+        // The message is not redelivered while the lease is extended.
+        let redelivered = subscription.pull_once(1, Duration::from_secs(1), None).await.unwrap();
+        assert!(redelivered.is_empty());
+        messages[0].ack().await.unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_shutdown() {
+        let subscription = create_subscription(false).await;
+        let mut stream = subscription.subscribe(None).await.unwrap();
+        publish(None).await;
+        let message = stream.next().await.unwrap();
+        let handler = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            message.ack().await.unwrap();
+        });
+
+        // The shutdown waits for the handler to finish.
+        let start = std::time::Instant::now();
+        let report = stream.shutdown(Duration::from_secs(20)).await;
+        assert_eq!(report, ShutdownReport::default());
+        assert!(start.elapsed() >= Duration::from_secs(3), "{:?}", start.elapsed());
+        assert!(start.elapsed() < Duration::from_secs(20), "{:?}", start.elapsed());
+        assert!(!stream.is_running());
+        handler.await.unwrap();
+        let redelivered = subscription.pull_once(1, Duration::from_secs(3), None).await.unwrap();
+        assert!(redelivered.is_empty(), "{redelivered:?}");
+
+        // The shutdown gives up after the grace period.
+        let mut stream = subscription.subscribe(None).await.unwrap();
+        publish(None).await;
+        let message = stream.next().await.unwrap();
+        let start = std::time::Instant::now();
+        let report = stream.shutdown(Duration::from_secs(1)).await;
+        assert!(start.elapsed() < Duration::from_secs(10), "{:?}", start.elapsed());
+        // The message held by the consumer is reported until it is acked.
+        assert_eq!(report.in_flight_ack_ids, vec![message.ack_id().to_string()]);
+        message.ack().await.unwrap();
+
+        // The acked message is not reported even if it is still held, and the unread ones are nacked.
+        let mut stream = subscription.subscribe(None).await.unwrap();
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base])).await;
+        let message = stream.next().await.unwrap();
+        message.ack().await.unwrap();
+        while stream.stats().queue_depth == 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let report = stream.shutdown(Duration::from_secs(1)).await;
+        assert!(report.in_flight_ack_ids.is_empty());
+        assert_eq!(report.nacked_messages, 1);
+        drop(message);
+        let redelivered = subscription.pull(1, None).await.unwrap();
+        redelivered[0].ack().await.unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_shutdown_after_batch_ack() {
+        let subscription = create_subscription(false).await;
+        let mut stream = subscription.subscribe(None).await.unwrap();
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base])).await;
+        let first = stream.next().await.unwrap();
+        let second = stream.next().await.unwrap();
+        ack_all(std::slice::from_ref(&first)).await.unwrap();
+        let mut batcher = MultiAckBatcher::new();
+        batcher.push(&second);
+        batcher.flush().await.unwrap();
+
+        // The batch-acked messages are not reported even if they are still held.
+        let report = stream.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(report, ShutdownReport::default());
+        drop((first, second));
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_streams() {
+        let subscription = create_subscription(false).await;
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            streams: Some(3),
+            ..Default::default()
+        });
+        let mut stream = subscription.subscribe(Some(config)).await.unwrap();
+        assert_eq!(stream.tasks.len(), 3);
+        publish(None).await;
+        let message = stream.next().await.unwrap();
+        message.ack().await.unwrap();
+        stream.dispose().await;
+
+        // The number of streams takes precedence over the pool size.
+        let config = SubscribeConfig::default()
+            .with_enable_multiple_subscriber(true)
+            .with_subscriber_config(SubscriberConfig {
+                streams: Some(2),
+                ..Default::default()
+            });
+        let mut stream = subscription.subscribe(Some(config)).await.unwrap();
+        assert_eq!(stream.tasks.len(), 2);
+        stream.dispose().await;
+        // The dispose joins all the streams.
+        assert!(stream.tasks.iter().all(|task| !task.is_running()));
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_open_streaming_pull() {
+        let subscription = create_subscription(false).await;
+        // The emulator responds to the stream with the first message.
+        publish(None).await;
+        let (mut stream, pings) = subscription
+            .subc
+            .open_streaming_pull(&subscription.fqsn, &SubscriberConfig::default())
+            .await
+            .unwrap();
+        assert!(pings.ping().await);
+        let mut ack_ids = vec![];
+        while ack_ids.is_empty() {
+            let response = stream.message().await.unwrap().unwrap();
+            ack_ids.extend(response.received_messages.into_iter().map(|v| v.ack_id));
+        }
+        let request = AcknowledgeRequest {
+            subscription: subscription.fqsn.clone(),
+            ack_ids,
+        };
+        subscription.subc.acknowledge(request, None).await.unwrap();
+
+        // Dropping the sender closes the stream.
+        drop(pings);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Ok(Some(_)) = stream.message().await {}
+        })
+        .await
+        .unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_ack_latency_percentiles() {
+        let subscription = create_subscription(false).await;
+        let mut stream = subscription.subscribe(None).await.unwrap();
+        publish(None).await;
+        stream.next().await.unwrap().ack().await.unwrap();
+        // Not recorded by default.
+        assert_eq!(None, stream.ack_latency_percentiles());
+        stream.dispose().await;
+
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            record_ack_latency: true,
+            ..Default::default()
+        });
+        let mut stream = subscription.subscribe(Some(config)).await.unwrap();
+        assert_eq!(None, stream.ack_latency_percentiles());
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base])).await;
+        stream.next().await.unwrap().ack().await.unwrap();
+        stream.next().await.unwrap().into_ack().await.unwrap();
+        let percentiles = stream.ack_latency_percentiles().unwrap();
+        assert_eq!(percentiles.count, 2);
+        assert!(percentiles.p50 <= percentiles.p99);
+        stream.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_ack_batching() {
+        let subscription = create_subscription(false).await;
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            ack_batching: Some(AckBatchConfig {
+                max_ack_batch: 3,
+                max_ack_latency: Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+        let mut stream = subscription.subscribe(Some(config)).await.unwrap();
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base; 4])).await;
+
+        // The full batch is sent without waiting for the latency.
+        let mut acks = vec![];
+        for _ in 0..3 {
+            let message = stream.next().await.unwrap();
+            acks.push(tokio::spawn(async move { message.ack().await }));
+        }
+        for ack in acks {
+            tokio::time::timeout(Duration::from_secs(5), ack)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+
+        // The pending ack is sent when the subscriber stops.
+        let message = stream.next().await.unwrap();
+        let ack = tokio::spawn(async move { message.ack().await });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!ack.is_finished());
+        stream.dispose().await;
+        tokio::time::timeout(Duration::from_secs(5), ack)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Nothing is redelivered.
+        let messages = subscription.pull_once(5, Duration::from_secs(1), None).await.unwrap();
+        assert!(messages.is_empty());
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_subscription_properties() {
+        for exactly_once in [true, false] {
+            let subscription = create_subscription(exactly_once).await;
+            let mut stream = subscription.subscribe(None).await.unwrap();
+            publish(None).await;
+            let message = stream.next().await.unwrap();
+            let properties = stream.subscription_properties();
+            message.ack().await.unwrap();
+            stream.dispose().await;
+            subscription.delete(None).await.unwrap();
+            let properties = properties.unwrap();
+            assert_eq!(properties.exactly_once_delivery_enabled, exactly_once);
+            assert!(!properties.message_ordering_enabled);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_dispatch() {
+        let subscription = create_subscription(false).await;
+        let mut stream = subscription.subscribe(None).await.unwrap();
+        let messages = (0..6)
+            .map(|i| PubsubMessage {
+                data: if i == 0 {
+                    "fail".into()
+                } else {
+                    format!("msg{i}").into()
+                },
+                ..Default::default()
+            })
+            .collect();
+        publish(Some(messages)).await;
+
+        let cancel = stream.cancellable();
+        let running = Arc::new(AtomicU32::new(0));
+        let max_running = Arc::new(AtomicU32::new(0));
+        let succeeded = Arc::new(AtomicU32::new(0));
+        let failed = Arc::new(AtomicU32::new(0));
+        let (running2, max_running2, succeeded2, failed2) =
+            (running.clone(), max_running.clone(), succeeded.clone(), failed.clone());
+        stream
+            .dispatch(2, Duration::from_secs(10), move |message| {
+                let (running, max_running, succeeded, failed, cancel) = (
+                    running2.clone(),
+                    max_running2.clone(),
+                    succeeded2.clone(),
+                    failed2.clone(),
+                    cancel.clone(),
+                );
+                async move {
+                    max_running.fetch_max(running.fetch_add(1, SeqCst) + 1, SeqCst);
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    running.fetch_sub(1, SeqCst);
+                    // fails the first delivery so that the message is nacked and redelivered.
+                    if &message.message.data[..] == b"fail" && failed.fetch_add(1, SeqCst) == 0 {
+                        return Err("failed");
+                    }
+                    if succeeded.fetch_add(1, SeqCst) + 1 == 6 {
+                        cancel.cancel();
+                    }
+                    Ok(())
+                }
+            })
+            .await;
+        assert_eq!(6, succeeded.load(SeqCst));
+        assert_eq!(2, failed.load(SeqCst));
+        assert_eq!(2, max_running.load(SeqCst));
+        assert!(!stream.is_running());
+
+        let redelivered = subscription.pull_once(10, Duration::from_secs(3), None).await.unwrap();
+        assert!(redelivered.is_empty(), "{redelivered:?}");
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_with() {
+        let subscription = create_subscription(false).await;
+        let messages = ["panic", "fail", "ok"]
+            .into_iter()
+            .map(|data| PubsubMessage {
+                data: data.into(),
+                ..Default::default()
+            })
+            .collect();
+        publish(Some(messages)).await;
+
+        let cancel = CancellationToken::new();
+        let cancel_for_handler = cancel.clone();
+        let calls = Arc::new(Mutex::new(Vec::<String>::new()));
+        let calls_for_handler = calls.clone();
+        let result = subscription
+            .subscribe_with(
+                move |message| {
+                    let (calls, cancel) = (calls_for_handler.clone(), cancel_for_handler.clone());
+                    async move {
+                        let data = String::from_utf8(message.message.data.to_vec()).unwrap();
+                        let first = {
+                            let mut calls = calls.lock().unwrap();
+                            calls.push(data.clone());
+                            calls.iter().filter(|v| **v == data).count() == 1
+                        };
+                        match data.as_str() {
+                            "panic" if first => panic!("poison message"),
+                            "fail" if first => return Err("failed"),
+                            _ => {}
+                        }
+                        if calls.lock().unwrap().len() == 5 {
+                            cancel.cancel();
+                        }
+                        Ok(())
+                    }
+                },
+                cancel,
+                None,
+            )
+            .await;
+        assert!(result.is_ok(), "{result:?}");
+        let mut calls = calls.lock().unwrap().clone();
+        calls.sort();
+        assert_eq!(vec!["fail", "fail", "ok", "panic", "panic"], calls);
+
+        let redelivered = subscription.pull_once(10, Duration::from_secs(3), None).await.unwrap();
+        assert!(redelivered.is_empty(), "{redelivered:?}");
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_lease_extension() {
+        let subscription = create_subscription(false).await;
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            stream_ack_deadline_seconds: 10,
+            lease_extension: Some(LeaseConfig {
+                extension_interval: Duration::from_secs(3),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let mut stream = subscription.subscribe(Some(config)).await.unwrap();
+        publish(None).await;
+        let message = stream.next().await.unwrap();
+
+        // The handler runs longer than the ack deadline of the stream.
+        tokio::time::sleep(Duration::from_secs(15)).await;
+
+        // The message is not redelivered while the lease is extended.
+        let redelivered = tokio::time::timeout(Duration::from_secs(1), stream.next()).await;
+        assert!(redelivered.is_err(), "{redelivered:?}");
+        message.ack().await.unwrap();
+        stream.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_take_over() {
+        let subscription = create_subscription(false).await;
+        publish(None).await;
+        let messages = subscription.pull(1, None).await.unwrap();
+        for invalid in [-1, 601] {
+            let err = messages[0].hand_off(invalid).await.unwrap_err();
+            assert!(matches!(err, PubSubError::DeadlineOutOfRange(v) if v == invalid), "{err:?}");
+        }
+        let hand_off = messages[0].hand_off(60).await.unwrap();
+        assert_eq!(messages[0].ack_id(), hand_off.ack_id);
+        assert!(hand_off.remaining_lease(&SystemClock) > Duration::from_secs(50));
+
+        // The subscription of the other instance.
+        let taker = Subscription::new(subscription.fqsn.clone(), subscription.subc.clone());
+        taker.take_over(hand_off.clone()).unwrap().ack().await.unwrap();
+
+        let other = create_subscription(false).await;
+        assert_eq!(Code::InvalidArgument, other.take_over(hand_off).unwrap_err().code());
+        other.delete(None).await.unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subscription_exactly_once() {
+        test_subscription(true).await;
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subscription_at_least_once() {
+        test_subscription(false).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_multi_subscriber_single_subscription_unbound() {
+        test_multi_subscriber_single_subscription(None).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
     #[serial]
     async fn test_multi_subscriber_single_subscription_bound() {
         let opt = Some(ReceiveConfig {
             channel_capacity: Some(1),
             ..Default::default()
         });
-        test_multi_subscriber_single_subscription(opt).await;
+        test_multi_subscriber_single_subscription(opt).await;
+    }
+
+    #[test]
+    fn test_ordering_partition() {
+        assert_eq!(ordering_partition("key", 1), 0);
+        // FNV-1a("a") = 0xaf63dc4c8601ec8c
+        assert_eq!(ordering_partition("a", 7), (0xaf63dc4c8601ec8c_u64 % 7) as usize);
+        for key in ["order1", "order2", "order3"] {
+            let partition = ordering_partition(key, 4);
+            assert!(partition < 4);
+            assert_eq!(partition, ordering_partition(key, 4));
+        }
+        let used: HashSet<usize> = (0..100).map(|i| ordering_partition(&format!("key{i}"), 4)).collect();
+        assert_eq!(used.len(), 4);
+    }
+
+    #[test]
+    fn test_subscription_name() {
+        let name = SubscriptionName::parse("projects/local-project/subscriptions/s-1.a~b+c%d_e").unwrap();
+        assert_eq!(name.project(), "local-project");
+        assert_eq!(name.subscription(), "s-1.a~b+c%d_e");
+        assert_eq!(name.to_string(), "projects/local-project/subscriptions/s-1.a~b+c%d_e");
+        assert_eq!(name, SubscriptionName::new("local-project", "s-1.a~b+c%d_e").unwrap());
+        assert_eq!(name, name.to_string().parse().unwrap());
+
+        for invalid in [
+            "local-project/subscriptions/sub",
+            "projects/local-project/subscription/sub",
+            "projects/local-project/subscriptions/sub/extra",
+            "projects//subscriptions/sub",
+            "projects/local-project/subscriptions/",
+            "projects/local-project/subscriptions/1sub",
+            "projects/local-project/subscriptions/goog-sub",
+            "projects/local-project/subscriptions/ab",
+            "projects/local-project/subscriptions/sub space",
+        ] {
+            let err = SubscriptionName::parse(invalid).unwrap_err();
+            assert!(matches!(err, PubSubError::InvalidSubscriptionName(_)), "{invalid}: {err:?}");
+        }
+        assert!(SubscriptionName::new("local-project", &"s".repeat(256)).is_err());
+        assert!(SubscriptionName::new("local-project", &"s".repeat(255)).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_receive_handler_gauge() {
+        let subscription = create_subscription(false).await;
+        let cancellation_token = CancellationToken::new();
+        let cancel_receiver = cancellation_token.clone();
+        let gauge = HandlerGauge::default();
+        let opt = Some(ReceiveConfig {
+            worker_count: 2,
+            handler_gauge: gauge.clone(),
+            ..Default::default()
+        });
+        let handle = tokio::spawn(async move {
+            let _ = subscription
+                .receive(
+                    move |message, _ctx| async move {
+                        tokio::time::sleep(Duration::from_secs(3)).await;
+                        let _ = message.ack().await;
+                    },
+                    cancel_receiver,
+                    opt,
+                )
+                .await;
+        });
+        let msg = PubsubMessage {
+            data: "test".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![msg.clone(), msg.clone(), msg])).await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(gauge.active(), 2);
+        cancellation_token.cancel();
+        let _ = handle.await;
+        assert_eq!(gauge.active(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_receive_handler_timeout() {
+        let subscription = create_subscription(false).await;
+        let cancellation_token = CancellationToken::new();
+        let cancel_receiver = cancellation_token.clone();
+        let v = Arc::new(AtomicU32::new(0));
+        let v2 = v.clone();
+        let observer = Arc::new(CountingObserver::default());
+        let opt = Some(ReceiveConfig {
+            worker_count: 1,
+            handler_timeout: Some(Duration::from_secs(1)),
+            // The timed out message is nacked through its handle, which also ends its lease.
+            subscriber_config: Some(SubscriberConfig {
+                lease_extension: Some(LeaseConfig::default()),
+                observer: Some(observer.clone()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let handle = tokio::spawn(async move {
+            let _ = subscription
+                .receive(
+                    move |message, _ctx| {
+                        let v2 = v2.clone();
+                        async move {
+                            // The first delivery exceeds the timeout and is redelivered after the nack.
+                            if v2.fetch_add(1, SeqCst) == 0 {
+                                tokio::time::sleep(Duration::from_secs(60)).await;
+                            }
+                            let _ = message.ack().await;
+                        }
+                    },
+                    cancel_receiver,
+                    opt,
+                )
+                .await;
+        });
+        publish(None).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        cancellation_token.cancel();
+        let _ = handle.await;
+        assert_eq!(v.load(SeqCst), 2);
+        assert_eq!(observer.nacked.load(SeqCst), 1);
+    }
+
+    async fn test_multi_subscriber_single_subscription(opt: Option<ReceiveConfig>) {
+        let msg = PubsubMessage {
+            data: "test".into(),
+            ..Default::default()
+        };
+        let msg_size = 10;
+        let msgs: Vec<PubsubMessage> = (0..msg_size).map(|_v| msg.clone()).collect();
+        let subscription = create_subscription(false).await;
+        let cancellation_token = CancellationToken::new();
+        let cancel_receiver = cancellation_token.clone();
+        let v = Arc::new(AtomicU32::new(0));
+        let v2 = v.clone();
+        let handle = tokio::spawn(async move {
+            let _ = subscription
+                .receive(
+                    move |message, _ctx| {
+                        let v2 = v2.clone();
+                        async move {
+                            tracing::info!("received {}", message.message.message_id);
+                            v2.fetch_add(1, SeqCst);
+                            let _ = message.ack().await;
+                        }
+                    },
+                    cancel_receiver,
+                    opt,
+                )
+                .await;
+        });
+        publish(Some(msgs)).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        cancellation_token.cancel();
+        let _ = handle.await;
+        assert_eq!(v.load(SeqCst), msg_size);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_multi_subscriber_multi_subscription() {
+        let mut subscriptions = vec![];
+
+        let ctx = CancellationToken::new();
+        for _ in 0..3 {
+            let subscription = create_subscription(false).await;
+            let v = Arc::new(AtomicU32::new(0));
+            let ctx = ctx.clone();
+            let v2 = v.clone();
+            let handle = tokio::spawn(async move {
+                let _ = subscription
+                    .receive(
+                        move |message, _ctx| {
+                            let v2 = v2.clone();
+                            async move {
+                                v2.fetch_add(1, SeqCst);
+                                let _ = message.ack().await;
+                            }
+                        },
+                        ctx,
+                        None,
+                    )
+                    .await;
+            });
+            subscriptions.push((handle, v))
+        }
+
+        publish(None).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        ctx.cancel();
+        for (task, v) in subscriptions {
+            let _ = task.await;
+            assert_eq!(v.load(SeqCst), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_batch_acking() {
+        let ctx = CancellationToken::new();
+        let subscription = create_subscription(false).await;
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let subscription_for_receive = subscription.clone();
+        let ctx_for_receive = ctx.clone();
+        let handle = tokio::spawn(async move {
+            let _ = subscription_for_receive
+                .receive(
+                    move |message, _ctx| {
+                        let sender = sender.clone();
+                        async move {
+                            let _ = sender.send(message.ack_id().to_string());
+                        }
+                    },
+                    ctx_for_receive.clone(),
+                    None,
+                )
+                .await;
+        });
+
+        let ctx_for_ack_manager = ctx.clone();
+        let ack_manager = tokio::spawn(async move {
+            let mut ack_ids = Vec::new();
+            while !ctx_for_ack_manager.is_cancelled() {
+                match tokio::time::timeout(Duration::from_secs(10), receiver.recv()).await {
+                    Ok(ack_id) => {
+                        if let Some(ack_id) = ack_id {
+                            ack_ids.push(ack_id);
+                            if ack_ids.len() > 10 {
+                                subscription.ack(ack_ids).await.unwrap();
+                                ack_ids = Vec::new();
+                            }
+                        }
+                    }
+                    Err(_e) => {
+                        // timeout
+                        subscription.ack(ack_ids).await.unwrap();
+                        ack_ids = Vec::new();
+                    }
+                }
+            }
+            // flush
+            subscription.ack(ack_ids).await
+        });
+
+        publish(None).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        ctx.cancel();
+        let _ = handle.await;
+        assert!(ack_manager.await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_multi_ack_batcher() {
+        let subscriptions = vec![create_subscription(false).await, create_subscription(false).await];
+        publish(None).await;
+
+        let mut batcher = MultiAckBatcher::new();
+        for subscription in &subscriptions {
+            for message in subscription.pull(100, None).await.unwrap() {
+                batcher.push(&message);
+            }
+        }
+        assert_eq!(batcher.len(), 2);
+        batcher.flush().await.unwrap();
+        assert!(batcher.is_empty());
+
+        // no effect
+        batcher.flush().await.unwrap();
+        for subscription in subscriptions {
+            subscription.delete(None).await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_multi_ack_batcher_with_observer() {
+        let subscription = create_subscription(false).await;
+        let observer = Arc::new(CountingObserver::default());
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            lease_extension: Some(LeaseConfig::default()),
+            observer: Some(observer.clone()),
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(config)).await.unwrap();
+        let message = publish_until_received(&mut iter).await;
+        let mut batcher = MultiAckBatcher::new();
+        batcher.push(&message);
+        batcher.flush().await.unwrap();
+        assert_eq!(observer.acked.load(SeqCst), 1);
+        // no effect
+        batcher.flush().await.unwrap();
+        assert_eq!(observer.acked.load(SeqCst), 1);
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_ack_all_and_nack_all() {
+        let subscription = create_subscription(false).await;
+        let messages = (0..3)
+            .map(|i| PubsubMessage {
+                data: format!("msg{i}").into(),
+                ..Default::default()
+            })
+            .collect();
+        publish(Some(messages)).await;
+
+        let mut received = vec![];
+        while received.len() < 3 {
+            received.extend(subscription.pull(100, None).await.unwrap());
+        }
+        nack_all(&received).await.unwrap();
+
+        let mut redelivered = vec![];
+        while redelivered.len() < 3 {
+            redelivered.extend(subscription.pull(100, None).await.unwrap());
+        }
+        ack_all(&redelivered).await.unwrap();
+        // no effect
+        ack_all(&[]).await.unwrap();
+
+        let remaining = subscription.pull_once(100, Duration::from_secs(3), None).await.unwrap();
+        assert!(remaining.is_empty(), "{remaining:?}");
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_ack_all_with_observer() {
+        let subscription = create_subscription(false).await;
+        let observer = Arc::new(CountingObserver::default());
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            lease_extension: Some(LeaseConfig::default()),
+            observer: Some(observer.clone()),
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(config)).await.unwrap();
+        let message = publish_until_received(&mut iter).await;
+        ack_all(&[message]).await.unwrap();
+        assert_eq!(observer.acked.load(SeqCst), 1);
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_nack_all_with_observer() {
+        let subscription = create_subscription(false).await;
+        let observer = Arc::new(CountingObserver::default());
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            lease_extension: Some(LeaseConfig::default()),
+            observer: Some(observer.clone()),
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(config)).await.unwrap();
+        let mut message = publish_until_received(&mut iter).await;
+        let nacked = message.watch_nack();
+        nack_all(&[message]).await.unwrap();
+        assert!(nacked.load(SeqCst));
+        assert_eq!(observer.nacked.load(SeqCst), 1);
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_ack_with_result() {
+        let subscription = create_subscription(true).await;
+        let messages = (0..3)
+            .map(|i| PubsubMessage {
+                data: format!("msg{i}").into(),
+                ..Default::default()
+            })
+            .collect();
+        publish(Some(messages)).await;
+
+        let mut received = vec![];
+        while received.len() < 3 {
+            received.extend(subscription.pull(100, None).await.unwrap());
+        }
+        let first = received.remove(0);
+        assert_eq!(AckOutcome::Success, first.ack_with_result().await.unwrap());
+
+        let outcomes = ack_all_with_result(&received).await.unwrap();
+        assert_eq!(outcomes.len(), 2);
+        for message in &received {
+            assert_eq!(Some(&AckOutcome::Success), outcomes.get(message.ack_id()));
+        }
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_snapshots() {
+        let subscription = create_subscription(false).await;
+
+        let snapshot_name = format!("snapshot-{}", rand::random::<u64>());
+        let labels: HashMap<String, String> =
+            HashMap::from_iter([("label-1".into(), "v1".into()), ("label-2".into(), "v2".into())]);
+        let expected_fq_snap_name = format!("projects/{PROJECT_NAME}/snapshots/{snapshot_name}");
+
+        // cleanup; TODO: remove?
+        let _response = subscription.delete_snapshot(snapshot_name.as_str(), None).await;
+
+        // create
+        let created_snapshot = subscription
+            .create_snapshot(snapshot_name.as_str(), labels.clone(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(created_snapshot.name, expected_fq_snap_name);
+        // NOTE: we don't assert the labels due to lack of label support in the pubsub emulator.
+
+        // get
+        let retrieved_snapshot = subscription.get_snapshot(snapshot_name.as_str(), None).await.unwrap();
+        assert_eq!(created_snapshot, retrieved_snapshot);
+
+        // delete
+        subscription
+            .delete_snapshot(snapshot_name.as_str(), None)
+            .await
+            .unwrap();
+
+        let _deleted_snapshot_status = subscription
+            .get_snapshot(snapshot_name.as_str(), None)
+            .await
+            .expect_err("snapshot should have been deleted");
+
+        let _delete_again = subscription
+            .delete_snapshot(snapshot_name.as_str(), None)
+            .await
+            .expect_err("snapshot should already be deleted");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_seek_snapshot() {
+        let subscription = create_subscription(false).await;
+        let snapshot_name = format!("snapshot-{}", rand::random::<u64>());
+
+        // publish and receive a message
+        publish(None).await;
+        let messages = subscription.pull(100, None).await.unwrap();
+        ack_all(&messages).await.unwrap();
+        assert_eq!(messages.len(), 1);
+
+        // snapshot at received = 1
+        let _snapshot = subscription
+            .create_snapshot(snapshot_name.as_str(), HashMap::new(), None)
+            .await
+            .unwrap();
+
+        // publish and receive another message
+        publish(None).await;
+        let messages = subscription.pull(100, None).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        ack_all(&messages).await.unwrap();
+
+        // rewind to snapshot at received = 1
+        subscription
+            .seek(SeekTo::Snapshot(snapshot_name.clone()), None)
+            .await
+            .unwrap();
+
+        // assert we receive the 1 message we should receive again
+        let messages = subscription.pull(100, None).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        ack_all(&messages).await.unwrap();
+
+        // rewind again with the helper
+        subscription.seek_to_snapshot(snapshot_name.as_str()).await.unwrap();
+        let messages = subscription.pull(100, None).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        ack_all(&messages).await.unwrap();
+
+        // cleanup
+        subscription
+            .delete_snapshot(snapshot_name.as_str(), None)
+            .await
+            .unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_seek_timestamp() {
+        let subscription = create_subscription(false).await;
+
+        // enable acked message retention on subscription -- required for timestamp-based seeks
+        subscription
+            .update(
+                SubscriptionConfigToUpdate {
+                    retain_acked_messages: Some(true),
+                    message_retention_duration: Some(Duration::new(60 * 60 * 2, 0)),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        // publish and receive a message
+        publish(None).await;
+        let messages = subscription.pull(100, None).await.unwrap();
+        ack_all(&messages).await.unwrap();
+        assert_eq!(messages.len(), 1);
+
+        let message_publish_time = messages.first().unwrap().message.publish_time.to_owned().unwrap();
+
+        // rewind to a timestamp where message was just published
+        subscription
+            .seek(SeekTo::Timestamp(message_publish_time.to_owned().try_into().unwrap()), None)
+            .await
+            .unwrap();
+
+        // consume -- should receive the first message again
+        let messages = subscription.pull(100, None).await.unwrap();
+        ack_all(&messages).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        let seek_message_publish_time = messages.first().unwrap().message.publish_time.to_owned().unwrap();
+        assert_eq!(seek_message_publish_time, message_publish_time);
+
+        // rewind again with the helper
+        subscription
+            .seek_to_time(message_publish_time.to_owned().try_into().unwrap())
+            .await
+            .unwrap();
+        let messages = subscription.pull(100, None).await.unwrap();
+        ack_all(&messages).await.unwrap();
+        assert_eq!(messages.len(), 1);
+
+        // cleanup
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_single_subscriber() {
+        test_subscribe(None).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_multiple_subscriber() {
+        test_subscribe(Some(SubscribeConfig::default().with_enable_multiple_subscriber(true))).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_multiple_subscriber_bound() {
+        test_subscribe(Some(
+            SubscribeConfig::default()
+                .with_enable_multiple_subscriber(true)
+                .with_channel_capacity(1),
+        ))
+        .await;
+    }
+
+    async fn test_subscribe(opt: Option<SubscribeConfig>) {
+        let msg = PubsubMessage {
+            data: "test".into(),
+            ..Default::default()
+        };
+        let msg_count = 10;
+        let msg: Vec<PubsubMessage> = (0..msg_count).map(|_v| msg.clone()).collect();
+        let subscription = create_subscription(false).await;
+        let received = Arc::new(Mutex::new(0));
+        let checking = received.clone();
+        let mut iter = subscription.subscribe(opt).await.unwrap();
+        let cancellable = iter.cancellable();
+        let handler = tokio::spawn(async move {
+            while let Some(message) = iter.next().await {
+                tracing::info!("received {}", message.message.message_id);
+                *received.lock().unwrap() += 1;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let _ = message.ack().await;
+            }
+        });
+        publish(Some(msg)).await;
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        cancellable.cancel();
+        let _ = handler.await;
+        assert_eq!(*checking.lock().unwrap(), msg_count);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_nack_on_cancel_read() {
+        subscribe_nack_on_cancel_read(10, true).await;
+        subscribe_nack_on_cancel_read(0, true).await;
+        subscribe_nack_on_cancel_read(10, false).await;
+        subscribe_nack_on_cancel_read(0, false).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_nack_on_cancel_next() {
+        // cancel after subscribe all message
+        subscribe_nack_on_cancel_next(10, Duration::from_secs(3)).await;
+        // cancel after process all message
+        subscribe_nack_on_cancel_next(10, Duration::from_millis(0)).await;
+        // no message
+        subscribe_nack_on_cancel_next(0, Duration::from_secs(3)).await;
+    }
+
+    async fn subscribe_nack_on_cancel_read(msg_count: usize, should_cancel: bool) {
+        let opt = Some(SubscribeConfig::default().with_enable_multiple_subscriber(true));
+
+        let msg = PubsubMessage {
+            data: "test".into(),
+            ..Default::default()
+        };
+        let msg: Vec<PubsubMessage> = (0..msg_count).map(|_v| msg.clone()).collect();
+        let subscription = create_subscription(false).await;
+        let received = Arc::new(Mutex::new(0));
+        let checking = received.clone();
+
+        let mut iter = subscription.subscribe(opt).await.unwrap();
+        let ctx = iter.cancellable();
+        let handler = tokio::spawn(async move {
+            while let Some(message) = iter.read().await {
+                tracing::info!("received {}", message.message.message_id);
+                *received.lock().unwrap() += 1;
+                if should_cancel {
+                    // expect cancel
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                } else {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                let _ = message.ack().await;
+            }
+        });
+        publish(Some(msg)).await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        ctx.cancel();
+        handler.await.unwrap();
+        if should_cancel && msg_count > 0 {
+            // expect nack
+            assert!(*checking.lock().unwrap() < msg_count);
+        } else {
+            // all delivered
+            assert_eq!(*checking.lock().unwrap(), msg_count);
+        }
+    }
+
+    async fn subscribe_nack_on_cancel_next(msg_count: usize, recv_time: Duration) {
+        let opt = Some(SubscribeConfig::default().with_enable_multiple_subscriber(true));
+
+        let msg = PubsubMessage {
+            data: "test".into(),
+            ..Default::default()
+        };
+        let msg: Vec<PubsubMessage> = (0..msg_count).map(|_v| msg.clone()).collect();
+        let subscription = create_subscription(false).await;
+        let received = Arc::new(Mutex::new(0));
+        let checking = received.clone();
+
+        let mut iter = subscription.subscribe(opt).await.unwrap();
+        let ctx = iter.cancellable();
+        let handler = tokio::spawn(async move {
+            while let Some(message) = iter.next().await {
+                tracing::info!("received {}", message.message.message_id);
+                *received.lock().unwrap() += 1;
+                tokio::time::sleep(recv_time).await;
+                let _ = message.ack().await;
+            }
+        });
+        publish(Some(msg)).await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        ctx.cancel();
+        handler.await.unwrap();
+        assert_eq!(*checking.lock().unwrap(), msg_count);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_messages_as() {
+        use crate::subscription::DecodeErrorPolicy;
+
+        let subscription = create_subscription(false).await;
+        let stream = subscription.subscribe(None).await.unwrap();
+        let messages = ["not json", r#"{"n":1}"#]
+            .into_iter()
+            .map(|data| PubsubMessage {
+                data: data.into(),
+                ..Default::default()
+            })
+            .collect();
+        publish(Some(messages)).await;
+
+        let decoded = stream.messages_as::<HashMap<String, i32>>(DecodeErrorPolicy::NackAndSkip);
+        futures_util::pin_mut!(decoded);
+        let (value, handle) = tokio::time::timeout(Duration::from_secs(10), decoded.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(value.get("n"), Some(&1));
+        handle.ack().await.unwrap();
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subscribe_invalid_stream_ack_deadline() {
+        let subscription = create_subscription(false).await;
+        let config = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            stream_ack_deadline_seconds: 5,
+            ..Default::default()
+        });
+        let err = subscription.subscribe(Some(config)).await.err().unwrap();
+        assert!(matches!(err, PubSubError::ConfigInvalid(_)), "{err:?}");
+        assert!(err.to_string().contains("stream_ack_deadline_seconds"), "{err}");
+        subscription.delete(None).await.unwrap();
     }
 
-    async fn test_multi_subscriber_single_subscription(opt: Option<ReceiveConfig>) {
-        let msg = PubsubMessage {
-            data: "test".into(),
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_require_delivery_attempt() {
+        let subscription = create_subscription(false).await;
+        let config = || {
+            SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+                require_delivery_attempt: true,
+                ..Default::default()
+            })
+        };
+        let err = subscription.subscribe(Some(config())).await.err().unwrap();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+        subscription.delete(None).await.unwrap();
+
+        let topic_name = format!("projects/{PROJECT_NAME}/topics/test-topic1");
+        let subscription_name = format!("projects/{PROJECT_NAME}/subscriptions/s{}", Uuid::new_v4());
+        let subscription = Subscription::new(subscription_name, subscription.subc.clone());
+        let dead_letter = SubscriptionConfig {
+            dead_letter_policy: Some(DeadLetterPolicy {
+                dead_letter_topic: topic_name.clone(),
+                max_delivery_attempts: 5,
+            }),
             ..Default::default()
         };
-        let msg_size = 10;
-        let msgs: Vec<PubsubMessage> = (0..msg_size).map(|_v| msg.clone()).collect();
+        subscription
+            .create(topic_name.as_str(), dead_letter, None)
+            .await
+            .unwrap();
+        let mut iter = subscription.subscribe(Some(config())).await.unwrap();
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_fault_injection() {
+        use crate::subscriber::{FaultInjector, FaultPoint};
+
+        // retryable faults are recovered by reconnecting
         let subscription = create_subscription(false).await;
-        let cancellation_token = CancellationToken::new();
-        let cancel_receiver = cancellation_token.clone();
-        let v = Arc::new(AtomicU32::new(0));
-        let v2 = v.clone();
-        let handle = tokio::spawn(async move {
-            let _ = subscription
-                .receive(
-                    move |message, _ctx| {
-                        let v2 = v2.clone();
-                        async move {
-                            tracing::info!("received {}", message.message.message_id);
-                            v2.fetch_add(1, SeqCst);
-                            let _ = message.ack().await;
-                        }
-                    },
-                    cancel_receiver,
-                    opt,
-                )
-                .await;
+        let injector = FaultInjector::new();
+        injector.inject(FaultPoint::StreamingPull, Code::Unavailable);
+        injector.inject(FaultPoint::Recv, Code::Unavailable);
+        injector.inject(FaultPoint::Recv, Code::Unavailable);
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            stream_ack_deadline_seconds: 10,
+            fault_injector: Some(injector.clone()),
+            ..Default::default()
         });
-        publish(Some(msgs)).await;
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        cancellation_token.cancel();
-        let _ = handle.await;
-        assert_eq!(v.load(SeqCst), msg_size);
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        let message = publish_until_received(&mut iter).await;
+        message.ack().await.unwrap();
+        assert_eq!(injector.remaining(FaultPoint::StreamingPull), 0);
+        assert_eq!(injector.remaining(FaultPoint::Recv), 0);
+        assert!(iter.is_running());
+        iter.dispose().await;
+
+        // non-retryable faults stop the subscriber
+        let injector = FaultInjector::new();
+        injector.inject(FaultPoint::StreamingPull, Code::PermissionDenied);
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            fault_injector: Some(injector),
+            ..Default::default()
+        });
+        let iter = subscription.subscribe(Some(opt)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!iter.is_running());
+        assert_eq!(iter.terminal_error().unwrap().code(), Code::PermissionDenied);
+        subscription.delete(None).await.unwrap();
     }
 
+    #[cfg(feature = "fault-injection")]
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_multi_subscriber_multi_subscription() {
-        let mut subscriptions = vec![];
+    async fn test_observer_stream_reconnected() {
+        use std::sync::Mutex;
 
-        let ctx = CancellationToken::new();
-        for _ in 0..3 {
-            let subscription = create_subscription(false).await;
-            let v = Arc::new(AtomicU32::new(0));
-            let ctx = ctx.clone();
-            let v2 = v.clone();
-            let handle = tokio::spawn(async move {
-                let _ = subscription
-                    .receive(
-                        move |message, _ctx| {
-                            let v2 = v2.clone();
-                            async move {
-                                v2.fetch_add(1, SeqCst);
-                                let _ = message.ack().await;
-                            }
-                        },
-                        ctx,
-                        None,
-                    )
-                    .await;
-            });
-            subscriptions.push((handle, v))
-        }
+        use crate::subscriber::{FaultInjector, FaultPoint, ReconnectBackoff, StreamReconnected};
 
-        publish(None).await;
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        #[derive(Default)]
+        struct ReconnectObserver {
+            events: Mutex<Vec<(u32, Option<Code>)>>,
+        }
 
-        ctx.cancel();
-        for (task, v) in subscriptions {
-            let _ = task.await;
-            assert_eq!(v.load(SeqCst), 1);
+        impl SubscriberObserver for ReconnectObserver {
+            fn on_stream_reconnected(&self, _subscription: &str, event: &StreamReconnected) {
+                let code = event.last_error.as_ref().map(|e| e.code());
+                self.events.lock().unwrap().push((event.attempt, code));
+            }
         }
+
+        let subscription = create_subscription(false).await;
+        let injector = FaultInjector::new();
+        injector.inject(FaultPoint::StreamingPull, Code::Unavailable);
+        injector.inject(FaultPoint::StreamingPull, Code::Unavailable);
+        injector.inject(FaultPoint::Recv, Code::Unavailable);
+        let observer = Arc::new(ReconnectObserver::default());
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            reconnect_backoff: ReconnectBackoff {
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                jitter: None,
+            },
+            fault_injector: Some(injector.clone()),
+            observer: Some(observer.clone()),
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        let message = publish_until_received(&mut iter).await;
+        message.ack().await.unwrap();
+
+        // The attempts count the consecutive failures and restart once the stream is established.
+        let unavailable = Some(Code::Unavailable);
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![(1, unavailable), (2, unavailable), (1, unavailable)]
+        );
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
     }
 
+    #[cfg(feature = "fault-injection")]
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_batch_acking() {
-        let ctx = CancellationToken::new();
-        let subscription = create_subscription(false).await;
-        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
-        let subscription_for_receive = subscription.clone();
-        let ctx_for_receive = ctx.clone();
-        let handle = tokio::spawn(async move {
-            let _ = subscription_for_receive
-                .receive(
-                    move |message, _ctx| {
-                        let sender = sender.clone();
-                        async move {
-                            let _ = sender.send(message.ack_id().to_string());
-                        }
-                    },
-                    ctx_for_receive.clone(),
-                    None,
-                )
-                .await;
-        });
+    async fn test_reconnect_backoff() {
+        use crate::subscriber::{FaultInjector, FaultPoint, ReconnectBackoff};
 
-        let ctx_for_ack_manager = ctx.clone();
-        let ack_manager = tokio::spawn(async move {
-            let mut ack_ids = Vec::new();
-            while !ctx_for_ack_manager.is_cancelled() {
-                match tokio::time::timeout(Duration::from_secs(10), receiver.recv()).await {
-                    Ok(ack_id) => {
-                        if let Some(ack_id) = ack_id {
-                            ack_ids.push(ack_id);
-                            if ack_ids.len() > 10 {
-                                subscription.ack(ack_ids).await.unwrap();
-                                ack_ids = Vec::new();
-                            }
-                        }
-                    }
-                    Err(_e) => {
-                        // timeout
-                        subscription.ack(ack_ids).await.unwrap();
-                        ack_ids = Vec::new();
-                    }
-                }
-            }
-            // flush
-            subscription.ack(ack_ids).await
+        let subscription = create_subscription(false).await;
+        let injector = FaultInjector::new();
+        for _ in 0..3 {
+            injector.inject(FaultPoint::StreamingPull, Code::Unavailable);
+        }
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            reconnect_backoff: ReconnectBackoff {
+                base_delay: Duration::from_millis(300),
+                max_delay: Duration::from_secs(1),
+                jitter: None,
+            },
+            fault_injector: Some(injector.clone()),
+            ..Default::default()
         });
-
-        publish(None).await;
-        tokio::time::sleep(Duration::from_secs(5)).await;
-
-        ctx.cancel();
-        let _ = handle.await;
-        assert!(ack_manager.await.is_ok());
+        let start = std::time::Instant::now();
+        let iter = subscription.subscribe(Some(opt)).await.unwrap();
+        while injector.remaining(FaultPoint::StreamingPull) > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The third attempt is made after waiting 300ms and 600ms.
+        assert!(start.elapsed() >= Duration::from_millis(900), "{:?}", start.elapsed());
+        assert!(iter.is_running());
+        drop(iter);
+        subscription.delete(None).await.unwrap();
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_snapshots() {
+    async fn test_stream_modify_ack_deadline() {
+        // The emulator doesn't return the confirmations for exactly-once delivery.
         let subscription = create_subscription(false).await;
-
-        let snapshot_name = format!("snapshot-{}", rand::random::<u64>());
-        let labels: HashMap<String, String> =
-            HashMap::from_iter([("label-1".into(), "v1".into()), ("label-2".into(), "v2".into())]);
-        let expected_fq_snap_name = format!("projects/{PROJECT_NAME}/snapshots/{snapshot_name}");
-
-        // cleanup; TODO: remove?
-        let _response = subscription.delete_snapshot(snapshot_name.as_str(), None).await;
-
-        // create
-        let created_snapshot = subscription
-            .create_snapshot(snapshot_name.as_str(), labels.clone(), None)
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            stream_modify_ack_deadline: true,
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        publish(None).await;
+        let message = tokio::time::timeout(Duration::from_secs(10), iter.next())
             .await
+            .unwrap()
             .unwrap();
 
-        assert_eq!(created_snapshot.name, expected_fq_snap_name);
-        // NOTE: we don't assert the labels due to lack of label support in the pubsub emulator.
-
-        // get
-        let retrieved_snapshot = subscription.get_snapshot(snapshot_name.as_str(), None).await.unwrap();
-        assert_eq!(created_snapshot, retrieved_snapshot);
+        let before = std::time::Instant::now();
+        let expiry = message.modify_ack_deadline_with_expiry(60).await.unwrap();
+        assert!(expiry >= before + Duration::from_secs(60));
+        assert!(expiry <= std::time::Instant::now() + Duration::from_secs(60));
+        for invalid in [-1, 601] {
+            let err = message.modify_ack_deadline(invalid).await.unwrap_err();
+            assert!(matches!(err, PubSubError::DeadlineOutOfRange(v) if v == invalid), "{err:?}");
+        }
 
-        // delete
-        subscription
-            .delete_snapshot(snapshot_name.as_str(), None)
+        // The message is redelivered immediately by the deadline 0 sent on the stream.
+        message.modify_ack_deadline(0).await.unwrap();
+        let redelivered = tokio::time::timeout(Duration::from_secs(10), iter.next())
             .await
+            .unwrap()
             .unwrap();
+        assert_eq!(message.message.message_id, redelivered.message.message_id);
+        redelivered.ack().await.unwrap();
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
 
-        let _deleted_snapshot_status = subscription
-            .get_snapshot(snapshot_name.as_str(), None)
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_subscribe_with_handle() {
+        let subscription = create_subscription(false).await;
+        let (mut iter, handle) = subscription.subscribe_with_handle(None).await.unwrap();
+        assert!(!handle.is_finished());
+        iter.dispose().await;
+        tokio::time::timeout(Duration::from_secs(3), handle)
             .await
-            .expect_err("snapshot should have been deleted");
+            .unwrap()
+            .unwrap()
+            .unwrap();
 
-        let _delete_again = subscription
-            .delete_snapshot(snapshot_name.as_str(), None)
+        // streaming pull fails with NOT_FOUND
+        subscription.delete(None).await.unwrap();
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig::default());
+        let (_iter, handle) = subscription.subscribe_with_handle(Some(opt)).await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(3), handle)
             .await
-            .expect_err("snapshot should already be deleted");
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
     }
 
-    async fn ack_all(messages: &[ReceivedMessage]) {
-        for message in messages.iter() {
-            message.ack().await.unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_queue_depth() {
+        let subscription = create_subscription(false).await;
+        let mut iter = subscription.subscribe(None).await.unwrap();
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base.clone(), base])).await;
+        for _ in 0..50 {
+            if iter.stats().queue_depth == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        assert_eq!(3, iter.stats().queue_depth);
+        iter.next().await.unwrap().ack().await.unwrap();
+        assert_eq!(2, iter.stats().queue_depth);
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_seek_snapshot() {
+    async fn test_message_stream_prefetch_messages() {
         let subscription = create_subscription(false).await;
-        let snapshot_name = format!("snapshot-{}", rand::random::<u64>());
-
-        // publish and receive a message
-        publish(None).await;
-        let messages = subscription.pull(100, None).await.unwrap();
-        ack_all(&messages).await;
-        assert_eq!(messages.len(), 1);
-
-        // snapshot at received = 1
-        let _snapshot = subscription
-            .create_snapshot(snapshot_name.as_str(), HashMap::new(), None)
-            .await
-            .unwrap();
-
-        // publish and receive another message
-        publish(None).await;
-        let messages = subscription.pull(100, None).await.unwrap();
-        assert_eq!(messages.len(), 1);
-        ack_all(&messages).await;
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            prefetch_messages: Some(1),
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base.clone(), base])).await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
 
-        // rewind to snapshot at received = 1
-        subscription
-            .seek(SeekTo::Snapshot(snapshot_name.clone()), None)
-            .await
-            .unwrap();
+        // The outstanding messages are not buffered beyond the prefetch.
+        assert_eq!(1, iter.stats().queue_depth);
+        for _ in 0..3 {
+            iter.next().await.unwrap().ack().await.unwrap();
+        }
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
 
-        // assert we receive the 1 message we should receive again
-        let messages = subscription.pull(100, None).await.unwrap();
-        assert_eq!(messages.len(), 1);
-        ack_all(&messages).await;
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_bounded_by_max_outstanding_messages() {
+        let subscription = create_subscription(false).await;
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            max_outstanding_messages: 2,
+            low_concurrency: true,
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        let base = PubsubMessage {
+            data: "test_message".into(),
+            ..Default::default()
+        };
+        publish(Some(vec![base.clone(), base.clone(), base.clone(), base])).await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
 
-        // cleanup
-        subscription
-            .delete_snapshot(snapshot_name.as_str(), None)
-            .await
-            .unwrap();
+        assert_eq!(2, iter.stats().queue_depth);
+        for _ in 0..4 {
+            iter.next().await.unwrap().ack().await.unwrap();
+        }
+        iter.dispose().await;
         subscription.delete(None).await.unwrap();
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_seek_timestamp() {
+    async fn test_message_stream_backfill() {
         let subscription = create_subscription(false).await;
+        publish(None).await;
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+            backfill: Some(Backfill {
+                window: Duration::from_secs(1),
+                ..Default::default()
+            }),
+            stream_ack_deadline_seconds: 10,
+            ..Default::default()
+        });
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        assert!(iter.is_backfilling());
+        iter.next().await.unwrap().ack().await.unwrap();
 
-        // enable acked message retention on subscription -- required for timestamp-based seeks
-        subscription
-            .update(
-                SubscriptionConfigToUpdate {
-                    retain_acked_messages: Some(true),
-                    message_retention_duration: Some(Duration::new(60 * 60 * 2, 0)),
-                    ..Default::default()
-                },
-                None,
-            )
-            .await
-            .unwrap();
+        // No message is received within the window after the backlog is drained.
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(!iter.is_backfilling());
+        assert!(iter.is_running());
 
-        // publish and receive a message
-        publish(None).await;
-        let messages = subscription.pull(100, None).await.unwrap();
-        ack_all(&messages).await;
-        assert_eq!(messages.len(), 1);
+        // The live traffic is received by the re-established stream.
+        let message = publish_until_received(&mut iter).await;
+        message.ack().await.unwrap();
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
 
-        let message_publish_time = messages.first().unwrap().message.publish_time.to_owned().unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_apply_config() {
+        let subscription = create_subscription(false).await;
+        publish(None).await;
+        let config = SubscriberConfig {
+            stream_ack_deadline_seconds: 10,
+            max_outstanding_messages: 1,
+            ..Default::default()
+        };
+        let opt = SubscribeConfig::default().with_subscriber_config(config.clone());
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        let outstanding = iter.next().await.unwrap();
 
-        // rewind to a timestamp where message was just published
-        subscription
-            .seek(SeekTo::Timestamp(message_publish_time.to_owned().try_into().unwrap()), None)
-            .await
-            .unwrap();
+        let invalid = SubscriberConfig {
+            stream_ack_deadline_seconds: 1,
+            ..config.clone()
+        };
+        assert_eq!(iter.apply_config(&invalid).unwrap_err().code(), Code::InvalidArgument);
+        let config = SubscriberConfig {
+            max_outstanding_messages: 100,
+            ..config
+        };
+        iter.apply_config(&config).unwrap();
 
-        // consume -- should receive the first message again
-        let messages = subscription.pull(100, None).await.unwrap();
-        ack_all(&messages).await;
-        assert_eq!(messages.len(), 1);
-        let seek_message_publish_time = messages.first().unwrap().message.publish_time.to_owned().unwrap();
-        assert_eq!(seek_message_publish_time, message_publish_time);
+        // The message received by the previous stream can still be acked.
+        outstanding.ack().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(iter.is_running());
 
-        // cleanup
+        let message = publish_until_received(&mut iter).await;
+        message.ack().await.unwrap();
+        iter.dispose().await;
         subscription.delete(None).await.unwrap();
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_subscribe_single_subscriber() {
-        test_subscribe(None).await;
+    async fn test_message_stream_health() {
+        let subscription = create_subscription(false).await;
+        let mut iter = subscription.subscribe(None).await.unwrap();
+        assert!(iter.is_running());
+        iter.dispose().await;
+        assert!(!iter.is_running());
+        assert!(iter.terminal_error().is_none());
+
+        // streaming pull fails with NOT_FOUND
+        subscription.delete(None).await.unwrap();
+        let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig::default());
+        let iter = subscription.subscribe(Some(opt)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(!iter.is_running());
+        assert_eq!(iter.terminal_error().unwrap().code(), Code::NotFound);
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_subscribe_multiple_subscriber() {
-        test_subscribe(Some(SubscribeConfig::default().with_enable_multiple_subscriber(true))).await;
+    async fn test_on_stopped() {
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let reasons_for_hook = reasons.clone();
+        let config = SubscriberConfig {
+            on_stopped: Some(Hook::new(move |reason: &StopReason| {
+                reasons_for_hook.lock().unwrap().push(reason.clone());
+            })),
+            ..Default::default()
+        };
+        let opt = SubscribeConfig::default().with_subscriber_config(config);
+
+        let subscription = create_subscription(false).await;
+        let mut iter = subscription.subscribe(Some(opt.clone())).await.unwrap();
+        iter.dispose().await;
+        assert!(matches!(reasons.lock().unwrap().as_slice(), [StopReason::GracefulShutdown]));
+
+        subscription.delete(None).await.unwrap();
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        iter.dispose().await;
+        let reasons = reasons.lock().unwrap();
+        match reasons.as_slice() {
+            [_, StopReason::TerminalError(e)] => assert_eq!(e.code(), Code::NotFound),
+            v => panic!("unexpected {v:?}"),
+        }
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_subscribe_multiple_subscriber_bound() {
-        test_subscribe(Some(
-            SubscribeConfig::default()
-                .with_enable_multiple_subscriber(true)
-                .with_channel_capacity(1),
-        ))
-        .await;
-    }
-
-    async fn test_subscribe(opt: Option<SubscribeConfig>) {
-        let msg = PubsubMessage {
-            data: "test".into(),
+    async fn test_on_response() {
+        let received = Arc::new(AtomicU32::new(0));
+        let received_for_hook = received.clone();
+        let config = SubscriberConfig {
+            on_response: Some(Hook::new(move |response: &StreamingPullResponse| {
+                received_for_hook.fetch_add(response.received_messages.len() as u32, SeqCst);
+            })),
             ..Default::default()
         };
-        let msg_count = 10;
-        let msg: Vec<PubsubMessage> = (0..msg_count).map(|_v| msg.clone()).collect();
+        let opt = SubscribeConfig::default().with_subscriber_config(config);
+
         let subscription = create_subscription(false).await;
-        let received = Arc::new(Mutex::new(0));
-        let checking = received.clone();
-        let mut iter = subscription.subscribe(opt).await.unwrap();
-        let cancellable = iter.cancellable();
-        let handler = tokio::spawn(async move {
-            while let Some(message) = iter.next().await {
-                tracing::info!("received {}", message.message.message_id);
-                *received.lock().unwrap() += 1;
-                tokio::time::sleep(Duration::from_millis(500)).await;
-                let _ = message.ack().await;
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        publish(None).await;
+        iter.next().await.unwrap().ack().await.unwrap();
+        assert_eq!(1, received.load(SeqCst));
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[derive(Default)]
+    struct CountingObserver {
+        received: AtomicU32,
+        acked: AtomicU32,
+        nacked: AtomicU32,
+        stalled: AtomicU32,
+    }
+
+    impl SubscriberObserver for CountingObserver {
+        fn on_received(&self, _subscription: &str, _message_id: &str) {
+            self.received.fetch_add(1, SeqCst);
+        }
+        fn on_acked(&self, _subscription: &str, _message_id: &str, _latency: Duration) {
+            self.acked.fetch_add(1, SeqCst);
+        }
+        fn on_nacked(&self, _subscription: &str, _message_id: &str) {
+            self.nacked.fetch_add(1, SeqCst);
+        }
+        fn on_stream_reconnect(&self, _subscription: &str, reason: &ReconnectReason) {
+            if matches!(reason, ReconnectReason::Stalled) {
+                self.stalled.fetch_add(1, SeqCst);
             }
-        });
-        publish(Some(msg)).await;
-        tokio::time::sleep(Duration::from_secs(8)).await;
-        cancellable.cancel();
-        let _ = handler.await;
-        assert_eq!(*checking.lock().unwrap(), msg_count);
+        }
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_subscribe_nack_on_cancel_read() {
-        subscribe_nack_on_cancel_read(10, true).await;
-        subscribe_nack_on_cancel_read(0, true).await;
-        subscribe_nack_on_cancel_read(10, false).await;
-        subscribe_nack_on_cancel_read(0, false).await;
+    async fn test_observer() {
+        let observer = Arc::new(CountingObserver::default());
+        let config = SubscriberConfig {
+            observer: Some(observer.clone()),
+            ..Default::default()
+        };
+        let opt = SubscribeConfig::default().with_subscriber_config(config);
+
+        let subscription = create_subscription(false).await;
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        publish(None).await;
+        iter.next().await.unwrap().nack().await.unwrap();
+        // redelivered after the nack
+        iter.next().await.unwrap().ack().await.unwrap();
+        assert_eq!(2, observer.received.load(SeqCst));
+        assert_eq!(1, observer.nacked.load(SeqCst));
+        assert_eq!(1, observer.acked.load(SeqCst));
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[serial]
-    async fn test_subscribe_nack_on_cancel_next() {
-        // cancel after subscribe all message
-        subscribe_nack_on_cancel_next(10, Duration::from_secs(3)).await;
-        // cancel after process all message
-        subscribe_nack_on_cancel_next(10, Duration::from_millis(0)).await;
-        // no message
-        subscribe_nack_on_cancel_next(0, Duration::from_secs(3)).await;
-    }
-
-    async fn subscribe_nack_on_cancel_read(msg_count: usize, should_cancel: bool) {
-        let opt = Some(SubscribeConfig::default().with_enable_multiple_subscriber(true));
-
-        let msg = PubsubMessage {
-            data: "test".into(),
+    async fn test_stall_after_pings() {
+        let observer = Arc::new(CountingObserver::default());
+        let config = SubscriberConfig {
+            ping_interval: Duration::from_millis(100),
+            stall_after_pings: Some(3),
+            observer: Some(observer.clone()),
             ..Default::default()
         };
-        let msg: Vec<PubsubMessage> = (0..msg_count).map(|_v| msg.clone()).collect();
+        let opt = SubscribeConfig::default().with_subscriber_config(config);
+
         let subscription = create_subscription(false).await;
-        let received = Arc::new(Mutex::new(0));
-        let checking = received.clone();
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        // The idle stream is re-established every 300ms.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(observer.stalled.load(SeqCst) >= 2, "{}", observer.stalled.load(SeqCst));
+        iter.dispose().await;
 
-        let mut iter = subscription.subscribe(opt).await.unwrap();
-        let ctx = iter.cancellable();
-        let handler = tokio::spawn(async move {
-            while let Some(message) = iter.read().await {
-                tracing::info!("received {}", message.message.message_id);
-                *received.lock().unwrap() += 1;
-                if should_cancel {
-                    // expect cancel
-                    tokio::time::sleep(Duration::from_secs(10)).await;
-                } else {
-                    tokio::time::sleep(Duration::from_millis(1)).await;
-                }
-                let _ = message.ack().await;
-            }
-        });
-        publish(Some(msg)).await;
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        ctx.cancel();
-        handler.await.unwrap();
-        if should_cancel && msg_count > 0 {
-            // expect nack
-            assert!(*checking.lock().unwrap() < msg_count);
-        } else {
-            // all delivered
-            assert_eq!(*checking.lock().unwrap(), msg_count);
-        }
+        // The consecutive stalls wait for the growing backoff: 1s after the first one and 2s after the second.
+        use crate::subscriber::ReconnectBackoff;
+        let observer = Arc::new(CountingObserver::default());
+        let config = SubscriberConfig {
+            ping_interval: Duration::from_millis(100),
+            stall_after_pings: Some(3),
+            reconnect_backoff: ReconnectBackoff {
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(10),
+                jitter: None,
+            },
+            observer: Some(observer.clone()),
+            ..Default::default()
+        };
+        let opt = SubscribeConfig::default().with_subscriber_config(config);
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let stalled = observer.stalled.load(SeqCst);
+        assert!((1..=2).contains(&stalled), "{stalled}");
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
     }
 
-    async fn subscribe_nack_on_cancel_next(msg_count: usize, recv_time: Duration) {
-        let opt = Some(SubscribeConfig::default().with_enable_multiple_subscriber(true));
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_pause() {
+        test_pause_resume(None).await;
+        // messages buffered in the paused stream are redelivered after the ack deadline.
+        test_pause_resume(Some(Duration::ZERO)).await;
+    }
 
-        let msg = PubsubMessage {
-            data: "test".into(),
+    async fn test_pause_resume(reconnect_after_pause: Option<Duration>) {
+        let subscription = create_subscription(false).await;
+        let config = SubscriberConfig {
+            reconnect_after_pause,
+            stream_ack_deadline_seconds: 10,
             ..Default::default()
         };
-        let msg: Vec<PubsubMessage> = (0..msg_count).map(|_v| msg.clone()).collect();
-        let subscription = create_subscription(false).await;
-        let received = Arc::new(Mutex::new(0));
-        let checking = received.clone();
+        let opt = SubscribeConfig::default().with_subscriber_config(config);
+        let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+        iter.pause();
+        publish(None).await;
+        let message = tokio::time::timeout(Duration::from_secs(3), iter.next()).await;
+        assert!(message.is_err(), "message must not be delivered while paused");
 
-        let mut iter = subscription.subscribe(opt).await.unwrap();
-        let ctx = iter.cancellable();
-        let handler = tokio::spawn(async move {
-            while let Some(message) = iter.next().await {
-                tracing::info!("received {}", message.message.message_id);
-                *received.lock().unwrap() += 1;
-                tokio::time::sleep(recv_time).await;
-                let _ = message.ack().await;
-            }
-        });
-        publish(Some(msg)).await;
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        ctx.cancel();
-        handler.await.unwrap();
-        assert_eq!(*checking.lock().unwrap(), msg_count);
+        iter.resume();
+        let message = tokio::time::timeout(Duration::from_secs(30), iter.next()).await;
+        message.unwrap().unwrap().ack().await.unwrap();
+        iter.dispose().await;
+        subscription.delete(None).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_message_stream_dispose_with() {
+        for shutdown_order in [ShutdownOrder::AcksFirst, ShutdownOrder::NacksFirst] {
+            let subscription = create_subscription(false).await;
+            let opt = SubscribeConfig::default().with_subscriber_config(SubscriberConfig {
+                shutdown_order,
+                ..Default::default()
+            });
+            let mut iter = subscription.subscribe(Some(opt)).await.unwrap();
+            let base = PubsubMessage {
+                data: "test_message".into(),
+                ..Default::default()
+            };
+            publish(Some(vec![base.clone(), base])).await;
+            let completed = iter.next().await.unwrap();
+            let mut pending_acks = MultiAckBatcher::new();
+            pending_acks.push(&completed);
+
+            // The completed message is acked and the remaining one is nacked.
+            iter.dispose_with(&mut pending_acks).await.unwrap();
+            assert!(pending_acks.is_empty());
+            let redelivered = subscription.pull(10, None).await.unwrap();
+            assert_eq!(1, redelivered.len(), "{shutdown_order:?}");
+            assert_ne!(completed.message.message_id, redelivered[0].message.message_id);
+            subscription.delete(None).await.unwrap();
+        }
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -1289,4 +3493,81 @@ mod tests {
         iter.dispose().await;
         assert!(iter.next().await.is_none());
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial]
+    async fn test_receive_pause_ordering_key_on_nack() {
+        let subscription = create_subscription(false).await;
+        let topic_name = format!("projects/{PROJECT_NAME}/topics/test-topic1");
+        let subscription_name = format!("projects/{PROJECT_NAME}/subscriptions/s{}", Uuid::new_v4());
+        let subscription = Subscription::new(subscription_name, subscription.subc.clone());
+        let config = SubscriptionConfig {
+            enable_message_ordering: true,
+            ..Default::default()
+        };
+        subscription.create(topic_name.as_str(), config, None).await.unwrap();
+
+        let cancellation_token = CancellationToken::new();
+        let cancel_receiver = cancellation_token.clone();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let handled2 = handled.clone();
+        let opt = Some(ReceiveConfig {
+            worker_count: 1,
+            pause_ordering_key_on_nack: true,
+            ..Default::default()
+        });
+        let receiver = subscription.clone();
+        let handle = tokio::spawn(async move {
+            let _ = receiver
+                .receive(
+                    move |message, _ctx| {
+                        let handled2 = handled2.clone();
+                        async move {
+                            let key = message.ordering_key().unwrap().to_string();
+                            let data = String::from_utf8(message.message.data.to_vec()).unwrap();
+                            let first = {
+                                let mut handled = handled2.lock().unwrap();
+                                let first = !handled.contains(&(key.clone(), data.clone()));
+                                handled.push((key, data.clone()));
+                                first
+                            };
+                            // The first delivery of "1" fails.
+                            if data == "1" && first {
+                                let _ = message.nack().await;
+                            } else {
+                                let _ = message.ack().await;
+                            }
+                        }
+                    },
+                    cancel_receiver,
+                    opt,
+                )
+                .await;
+        });
+        let messages = ["0", "1", "2", "3", "4"]
+            .into_iter()
+            .map(|data| ("k1", data))
+            .chain([("k2", "a")])
+            .map(|(key, data)| PubsubMessage {
+                data: data.into(),
+                ordering_key: key.into(),
+                ..Default::default()
+            })
+            .collect();
+        publish(Some(messages)).await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        cancellation_token.cancel();
+        let _ = handle.await;
+        subscription.delete(None).await.unwrap();
+
+        let handled = handled.lock().unwrap();
+        let k1: Vec<&str> = handled
+            .iter()
+            .filter(|(key, _)| key == "k1")
+            .map(|(_, data)| data.as_str())
+            .collect();
+        // The messages following the failed one are not handled until it is redelivered.
+        assert_eq!(k1, vec!["0", "1", "1", "2", "3", "4"]);
+        assert!(handled.contains(&("k2".to_string(), "a".to_string())));
+    }
 }
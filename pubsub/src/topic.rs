@@ -129,7 +129,7 @@ mod tests {
 
     use serial_test::serial;
     use tokio::task::JoinHandle;
-    use tokio::time::sleep;
+    use tokio::time::{sleep, timeout, Instant};
     use uuid::Uuid;
 
     use google_cloud_gax::conn::{ConnectionOptions, Environment};
@@ -139,6 +139,7 @@ mod tests {
     use crate::apiv1::conn_pool::ConnectionManager;
     use crate::apiv1::publisher_client::PublisherClient;
     use crate::apiv1::subscriber_client::SubscriberClient;
+    use crate::error::PubSubError;
     use crate::publisher::{Publisher, PublisherConfig};
     use crate::topic::Topic;
 
@@ -272,6 +273,96 @@ mod tests {
         publish_after_shutdown(true).await;
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_publish_flush_triggers() {
+        let topic = create_topic().await;
+
+        // The byte size flushes the bundle before the interval and the count.
+        let publisher = topic.new_publisher(Some(PublisherConfig {
+            flush_interval: Duration::from_secs(30),
+            bundle_size: 100,
+            bundle_byte_size: 10,
+            ..Default::default()
+        }));
+        let tasks = publish(publisher.clone()).await;
+        for task in tasks {
+            let message_id = timeout(Duration::from_secs(5), task).await.unwrap().unwrap().unwrap();
+            assert!(!message_id.is_empty());
+        }
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+
+        // The interval is the max latency even while the messages keep arriving.
+        let publisher = topic.new_publisher(Some(PublisherConfig {
+            workers: 1,
+            flush_interval: Duration::from_secs(1),
+            bundle_size: 100,
+            ..Default::default()
+        }));
+        let msg = || PubsubMessage {
+            data: "abc".into(),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let first = publisher.publish(msg()).await;
+        let keep = {
+            let publisher = publisher.clone();
+            tokio::spawn(async move {
+                let mut awaiters = vec![];
+                for _ in 0..10 {
+                    sleep(Duration::from_millis(300)).await;
+                    awaiters.push(publisher.publish(msg()).await);
+                }
+                awaiters
+            })
+        };
+        first.get().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+        for awaiter in keep.await.unwrap() {
+            awaiter.get().await.unwrap();
+        }
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+
+        topic.delete(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_publish_resume_ordering_key() {
+        let topic = create_topic().await;
+        let publisher = topic.new_publisher(None);
+        let msg = |key: &str| PubsubMessage {
+            data: "abc".into(),
+            ordering_key: key.to_string(),
+            ..Default::default()
+        };
+
+        // The publish to the missing topic pauses the key.
+        topic.delete(None).await.unwrap();
+        let err = publisher.publish(msg("key1")).await.get().await.unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+
+        topic.create(None, None).await.unwrap();
+        for awaiter in publisher.publish_bulk(vec![msg("key1"), msg("key1")]).await {
+            assert_eq!(Code::FailedPrecondition, awaiter.get().await.unwrap_err().code());
+        }
+        let err = publisher.publish(msg("key1")).await.get().await.unwrap_err();
+        assert_eq!(Code::FailedPrecondition, err.code());
+
+        // The other keys and the messages without the key are not paused.
+        assert!(!publisher.publish(msg("key2")).await.get().await.unwrap().is_empty());
+        assert!(!publisher.publish(msg("")).await.get().await.unwrap().is_empty());
+
+        publisher.resume_publish("key1");
+        assert!(!publisher.publish(msg("key1")).await.get().await.unwrap().is_empty());
+
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+        topic.delete(None).await.unwrap();
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_publish_immediately() {
@@ -293,4 +384,54 @@ mod tests {
         publisher.shutdown().await;
         topic.delete(None).await.unwrap();
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_publish_message_too_large() {
+        let topic = create_topic().await;
+        let publisher = topic.new_publisher(Some(PublisherConfig {
+            max_message_bytes: 10,
+            ..Default::default()
+        }));
+        let msg = |data: &str| PubsubMessage {
+            data: data.to_string().into(),
+            attributes: [("k".to_string(), "v".to_string())].into(),
+            ..Default::default()
+        };
+
+        // The attributes count toward the size.
+        match publisher.try_publish(msg("abcdefghi")).await {
+            Err(PubSubError::MessageTooLarge { size, max }) => assert_eq!((size, max), (11, 10)),
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("the message must be rejected"),
+        }
+        let err = publisher.publish(msg("abcdefghi")).await.get().await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+        assert_eq!("message of 11 bytes exceeds the limit of 10 bytes", err.message());
+
+        // Only the large message of the bulk fails.
+        let awaiters = publisher.publish_bulk(vec![msg("abcdefghi"), msg("abc")]).await;
+        let results: Vec<_> = futures_util::future::join_all(awaiters.into_iter().map(|v| v.get())).await;
+        assert_eq!(Code::InvalidArgument, results[0].as_ref().unwrap_err().code());
+        assert!(!results[1].as_ref().unwrap().is_empty());
+
+        let err = publisher
+            .publish_immediately(vec![msg("abc"), msg("abcdefghi")], None)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+
+        assert!(!publisher
+            .try_publish(msg("abc"))
+            .await
+            .unwrap()
+            .get()
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut publisher = publisher;
+        publisher.shutdown().await;
+        topic.delete(None).await.unwrap();
+    }
 }
@@ -34,10 +34,7 @@ impl Client {
 }
 
 async fn internal_client(config: &AdminClientConfig) -> Result<(Channel, OperationsClient), Error> {
-    let conn_options = ConnectionOptions {
-        timeout: Some(Duration::from_secs(30)),
-        connect_timeout: Some(Duration::from_secs(30)),
-    };
+    let conn_options = ConnectionOptions::new(Some(Duration::from_secs(30)), Some(Duration::from_secs(30)));
     let conn_pool = ConnectionManager::new(1, SPANNER, AUDIENCE, &config.environment, &conn_options).await?;
     let conn = conn_pool.conn();
     let lro_client = OperationsClient::new(conn).await?;
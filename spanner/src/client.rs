@@ -177,10 +177,8 @@ impl Client {
         }
 
         let pool_size = config.channel_config.num_channels;
-        let options = ConnectionOptions {
-            timeout: Some(config.channel_config.timeout),
-            connect_timeout: Some(config.channel_config.connect_timeout),
-        };
+        let options =
+            ConnectionOptions::new(Some(config.channel_config.timeout), Some(config.channel_config.connect_timeout));
         let conn_pool =
             ConnectionManager::new(pool_size, &config.environment, config.endpoint.as_str(), &options).await?;
         let session_manager = SessionManager::new(database, conn_pool, config.session_config).await?;